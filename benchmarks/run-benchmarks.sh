@@ -10,6 +10,9 @@
 #
 # Uncompressed formats benchmarked:
 # - .tar
+#
+# The `.tar.gz` benchmarks are also run with several `--buffer-size`, and `list --tree`
+# is benchmarked on the `.tar` of `rust`.
 
 set -e
 
@@ -92,6 +95,40 @@ function zip_decompression() {
     $DESCOMPRESSION_CLEANUP
 }
 
+function buffer_size_compression() {
+    cleanup="rm output.tar.gz"
+
+    call_hyperfine \
+        --parameter-list size 8KiB,64KiB,1MiB,16MiB \
+        'ouch compress compiler output.tar.gz --buffer-size {size}' \
+        --prepare "$cleanup || true"
+
+    $cleanup
+}
+
+function buffer_size_decompression() {
+    echo "Creating tar.gz archive to benchmark decompression..."
+    ouch compress compiler input.tar.gz --yes &> /dev/null
+
+    call_hyperfine \
+        --parameter-list size 8KiB,64KiB,1MiB,16MiB \
+        'ouch decompress input.tar.gz --dir output --buffer-size {size}' \
+        --prepare "$DESCOMPRESSION_CLEANUP || true"
+
+    $DESCOMPRESSION_CLEANUP
+}
+
+function tree_listing() {
+    echo "Creating tar archive to benchmark listing..."
+    ouch compress rust input.tar --yes &> /dev/null
+
+    call_hyperfine \
+        'ouch list input.tar --tree' \
+        'ouch list input.tar'
+
+    rm input.tar
+}
+
 function run_benches() {
     tar_compression
     tar_decompression
@@ -99,6 +136,9 @@ function run_benches() {
     tar_gz_decompression
     zip_compression
     zip_decompression
+    buffer_size_compression
+    buffer_size_decompression
+    tree_listing
 }
 
 function concatenate_results() {
@@ -107,7 +147,10 @@ function concatenate_results() {
         tar_gz_compression.md <(echo) \
         tar_gz_decompression.md <(echo) \
         zip_compression.md <(echo) \
-        zip_decompression.md > results.md
+        zip_decompression.md <(echo) \
+        buffer_size_compression.md <(echo) \
+        buffer_size_decompression.md <(echo) \
+        tree_listing.md > results.md
 }
 
 run_benches
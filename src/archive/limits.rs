@@ -0,0 +1,317 @@
+//! Limits on how much an archive expands when extracted, against archives crafted to fill the
+//! disk, like zip bombs, or to exhaust its inodes and path limits.
+
+use std::{
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock, PoisonError,
+    },
+};
+
+use crate::{
+    archive::zip::ChecksumMismatch,
+    error::FinalError,
+    utils::{Bytes, EscapedPathDisplay},
+};
+
+/// Default for `--max-output-size`, 100 GB
+pub const DEFAULT_MAX_OUTPUT_SIZE: u64 = 100_000_000_000;
+
+/// Default for `--max-ratio`
+pub const DEFAULT_MAX_RATIO: u64 = 1000;
+
+/// Default for `--max-entries`
+pub const DEFAULT_MAX_ENTRIES: u64 = 1_000_000;
+
+/// Default for `--max-path-depth`
+pub const DEFAULT_MAX_PATH_DEPTH: usize = 256;
+
+/// Default for `--max-path-length`, `PATH_MAX` of Linux
+pub const DEFAULT_MAX_PATH_LENGTH: usize = 4096;
+
+/// Default for `--max-nesting`
+pub const DEFAULT_MAX_NESTING: usize = 8;
+
+/// Outputs smaller than this aren't checked against the ratio, a few repeated bytes can
+/// legitimately compress that well
+const RATIO_GRACE_SIZE: u64 = 1024 * 1024;
+
+/// How much an archive is allowed to expand when extracted, `None` disables a limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtractionLimits {
+    /// Maximum number of bytes written in total
+    pub max_output_size: Option<u64>,
+    /// Maximum ratio between the size of the output and the size of the compressed data
+    pub max_ratio: Option<u64>,
+    /// Maximum number of entries extracted
+    pub max_entries: Option<u64>,
+    /// Maximum number of components in the path of an entry
+    pub max_path_depth: Option<usize>,
+    /// Maximum length in bytes of the path of an entry
+    pub max_path_length: Option<usize>,
+}
+
+impl Default for ExtractionLimits {
+    fn default() -> Self {
+        Self {
+            max_output_size: Some(DEFAULT_MAX_OUTPUT_SIZE),
+            max_ratio: Some(DEFAULT_MAX_RATIO),
+            max_entries: Some(DEFAULT_MAX_ENTRIES),
+            max_path_depth: Some(DEFAULT_MAX_PATH_DEPTH),
+            max_path_length: Some(DEFAULT_MAX_PATH_LENGTH),
+        }
+    }
+}
+
+impl ExtractionLimits {
+    /// No limits at all, set by `--no-limits`
+    pub fn none() -> Self {
+        Self {
+            max_output_size: None,
+            max_ratio: None,
+            max_entries: None,
+            max_path_depth: None,
+            max_path_length: None,
+        }
+    }
+}
+
+/// The limit that stopped an extraction
+#[derive(Debug, Clone, Copy)]
+enum ExceededLimit {
+    OutputSize(u64),
+    Ratio(u64),
+}
+
+/// Output of the extraction of one archive, shared by the readers of all of its entries, so
+/// that the limits apply to the archive as a whole.
+///
+/// It also keeps the entries that were skipped because they don't match their checksum.
+#[derive(Debug, Clone)]
+pub struct ExtractionBudget {
+    limits: ExtractionLimits,
+    /// Size of the archive being extracted
+    archive_size: u64,
+    written: Arc<AtomicU64>,
+    entries: Arc<AtomicU64>,
+    /// Path of the entry counted last, in the archive
+    last_entry: Arc<Mutex<Option<PathBuf>>>,
+    corrupt_entries: Arc<Mutex<Vec<ChecksumMismatch>>>,
+    exceeded: Arc<OnceLock<ExceededLimit>>,
+}
+
+impl ExtractionBudget {
+    pub fn new(limits: ExtractionLimits, archive_size: u64) -> Self {
+        Self {
+            limits,
+            archive_size,
+            written: Arc::default(),
+            entries: Arc::default(),
+            last_entry: Arc::default(),
+            corrupt_entries: Arc::default(),
+            exceeded: Arc::default(),
+        }
+    }
+
+    /// Wrap `reader`, the contents of an entry, or of the whole decompressed stream, so that every
+    /// byte read from it counts towards the limits.
+    ///
+    /// `compressed_size` is the size of the compressed data of the entry, if the format stores
+    /// it, used to check its ratio on its own.
+    pub fn reader<R: Read>(&self, reader: R, compressed_size: Option<u64>) -> BudgetReader<R> {
+        BudgetReader {
+            inner: reader,
+            budget: self.clone(),
+            compressed_size,
+            read: 0,
+        }
+    }
+
+    /// Count one more extracted entry, at `entry_path` in the archive, erroring if there are too
+    /// many of them or its path is too deep or too long.
+    ///
+    /// Unlike the size limits, these are checked before anything is written for the entry.
+    pub fn count_entry(&self, entry_path: &Path) -> crate::Result<()> {
+        let entries = self.entries.fetch_add(1, Ordering::Relaxed) + 1;
+        *self.last_entry.lock().unwrap_or_else(PoisonError::into_inner) = Some(entry_path.to_path_buf());
+        let depth = entry_path.components().count();
+        let length = entry_path.as_os_str().len();
+
+        let (limit, flag) = match self.limits {
+            ExtractionLimits {
+                max_entries: Some(max_entries),
+                ..
+            } if entries > max_entries => (
+                format!("The archive has more than {max_entries} entries, the maximum number of entries"),
+                "--max-entries",
+            ),
+            ExtractionLimits {
+                max_path_depth: Some(max_path_depth),
+                ..
+            } if depth > max_path_depth => (
+                format!("Its path has {depth} components, more than the maximum path depth of {max_path_depth}"),
+                "--max-path-depth",
+            ),
+            ExtractionLimits {
+                max_path_length: Some(max_path_length),
+                ..
+            } if length > max_path_length => (
+                format!("Its path is {length} bytes long, more than the maximum path length of {max_path_length}"),
+                "--max-path-length",
+            ),
+            _ => return Ok(()),
+        };
+
+        let error = FinalError::with_title(format!(
+            "Stopped extracting at the entry '{}'.",
+            EscapedPathDisplay::new(entry_path)
+        ))
+        .detail(limit)
+        .detail("The partially extracted files were removed")
+        .hint("To see what the archive contains, use 'ouch list'")
+        .hint(format!("If you trust this archive, raise the limit with '{flag}',"))
+        .hint("or disable the limits with '--no-limits'");
+        Err(error.into())
+    }
+
+    /// Count `bytes` more of output, from an entry that was extracted without being read by ouch.
+    pub fn spend(&self, bytes: u64) -> io::Result<()> {
+        self.spend_on_entry(bytes, bytes, None)
+    }
+
+    fn spend_on_entry(&self, bytes: u64, entry_written: u64, entry_compressed_size: Option<u64>) -> io::Result<()> {
+        let written = self.written.fetch_add(bytes, Ordering::Relaxed) + bytes;
+
+        if let Some(max_output_size) = self.limits.max_output_size {
+            if written > max_output_size {
+                return Err(self.exceed(ExceededLimit::OutputSize(max_output_size)));
+            }
+        }
+
+        if let Some(max_ratio) = self.limits.max_ratio {
+            let too_large = |written: u64, compressed_size: u64| {
+                written > RATIO_GRACE_SIZE && written > compressed_size.saturating_mul(max_ratio)
+            };
+            if too_large(written, self.archive_size)
+                || entry_compressed_size.is_some_and(|compressed_size| too_large(entry_written, compressed_size))
+            {
+                return Err(self.exceed(ExceededLimit::Ratio(max_ratio)));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn exceed(&self, limit: ExceededLimit) -> io::Error {
+        let _ = self.exceeded.set(limit);
+        io::Error::other("the extraction limits were exceeded")
+    }
+
+    /// How much was extracted so far, in bytes
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    /// Path of the last entry that started being extracted, if the archive has entries.
+    ///
+    /// When they're extracted in parallel, it's only one of the entries being extracted.
+    pub fn last_entry(&self) -> Option<PathBuf> {
+        self.last_entry.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Count an entry that doesn't match its checksum, which is reported by
+    /// [`corrupt_entries_error`](Self::corrupt_entries_error) once the others were extracted, when
+    /// it's skipped with `--continue-on-error`.
+    pub fn add_corrupt_entry(&self, mismatch: ChecksumMismatch) {
+        self.corrupt_entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(mismatch);
+    }
+
+    /// Whether an entry didn't match its checksum
+    pub fn has_corrupt_entries(&self) -> bool {
+        !self
+            .corrupt_entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_empty()
+    }
+
+    /// The error naming the entries of `archive_path` that were skipped because they don't match
+    /// their checksum, if any were.
+    pub fn corrupt_entries_error(&self, archive_path: &Path) -> Option<crate::Error> {
+        let corrupt_entries = self.corrupt_entries.lock().unwrap_or_else(PoisonError::into_inner);
+        if corrupt_entries.is_empty() {
+            return None;
+        }
+
+        let mut error = FinalError::with_title(format!(
+            "Some entries of '{}' don't match their checksum.",
+            EscapedPathDisplay::new(archive_path)
+        ));
+        for mismatch in corrupt_entries.iter() {
+            error = error.detail(mismatch.to_string());
+        }
+        let error = error
+            .detail("They were left out, the other entries were extracted")
+            .hint("Download or copy the archive again");
+        Some(crate::Error::CorruptArchive { reason: error })
+    }
+
+    /// Whether the extraction was stopped by one of the limits
+    pub fn is_exceeded(&self) -> bool {
+        self.exceeded.get().is_some()
+    }
+
+    /// The error explaining which limit stopped the extraction of `archive_path`, if any did.
+    pub fn exceeded_error(&self, archive_path: &Path) -> Option<crate::Error> {
+        let limit = match *self.exceeded.get()? {
+            ExceededLimit::OutputSize(max_output_size) => {
+                format!(
+                    "It expands to more than {}, the maximum output size",
+                    Bytes::new(max_output_size)
+                )
+            }
+            ExceededLimit::Ratio(max_ratio) => {
+                format!("It expands to more than {max_ratio} times its compressed size, the maximum ratio")
+            }
+        };
+
+        let error = FinalError::with_title(format!(
+            "Stopped extracting '{}'.",
+            EscapedPathDisplay::new(archive_path)
+        ))
+        .detail(limit)
+        .detail(format!(
+            "Extraction got through {} before stopping",
+            Bytes::new(self.written())
+        ))
+        .detail("The partially extracted files were removed")
+        .hint("If you trust this archive, raise the limits with '--max-output-size' and '--max-ratio',")
+        .hint("or disable them with '--no-limits'");
+
+        Some(error.into())
+    }
+}
+
+/// Reader that counts the bytes read from it towards an [`ExtractionBudget`], erroring once a
+/// limit is exceeded.
+pub struct BudgetReader<R> {
+    inner: R,
+    budget: ExtractionBudget,
+    compressed_size: Option<u64>,
+    read: u64,
+}
+
+impl<R: Read> Read for BudgetReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = self.inner.read(buf)?;
+        self.read += bytes as u64;
+        self.budget
+            .spend_on_entry(bytes as u64, self.read, self.compressed_size)?;
+        Ok(bytes)
+    }
+}
@@ -1,9 +1,434 @@
 //! Archive compression algorithms
 
+pub mod limits;
 #[cfg(feature = "unrar")]
 pub mod rar;
 #[cfg(not(feature = "unrar"))]
 pub mod rar_stub;
 pub mod sevenz;
 pub mod tar;
+#[cfg(windows)]
+pub mod windows_names;
 pub mod zip;
+
+use std::path::{Component, Path, PathBuf};
+
+use same_file::Handle;
+
+use self::limits::ExtractionLimits;
+use crate::{
+    cli::SparseMode,
+    error::FinalError,
+    utils::{
+        self,
+        logger::{info_accessible, warning},
+        EntryFilter, EscapedPathDisplay, ExecHook, Transforms,
+    },
+    QuestionPolicy,
+};
+
+/// The archive being written, whose files are skipped when they're among the inputs, so it's
+/// never compressed into itself, see [`check_output_is_not_input`](crate::check::check_output_is_not_input).
+pub struct ArchiveOutput<'a> {
+    /// Where the archive is placed once it's complete
+    pub path: &'a Path,
+    /// The file it's written to, and the one it replaces at `path`, if any
+    handles: Vec<Handle>,
+}
+
+impl<'a> ArchiveOutput<'a> {
+    /// The archive at `path`, written to `written_path` until it's complete.
+    pub fn new(path: &'a Path, written_path: &Path) -> Self {
+        let handles = [path, written_path]
+            .into_iter()
+            .filter_map(|path| Handle::from_path(path).ok())
+            .collect();
+        Self { path, handles }
+    }
+
+    /// The archive written to stdout, given as `path`, which is never among the inputs.
+    pub fn stdout(path: &'a Path) -> Self {
+        Self { path, handles: vec![] }
+    }
+
+    /// Whether the input at `path` is one of the files of the archive.
+    pub fn is_output(&self, path: &Path) -> bool {
+        // Opening a FIFO to get its handle would block
+        !self.handles.is_empty()
+            && path.is_file()
+            && Handle::from_path(path).is_ok_and(|handle| self.handles.contains(&handle))
+    }
+}
+
+/// Options that change how files are packed into an archive
+#[derive(Debug, Default)]
+pub struct PackOptions {
+    /// Renames applied to the path of each entry before it's written
+    pub transforms: Transforms,
+    /// Whether entries are stored with their absolute paths, instead of relative to the input
+    pub absolute_paths: bool,
+    /// Directory that entries are stored relative to, instead of the one containing each input
+    pub input_dir: Option<PathBuf>,
+    /// Name that the input directory is stored under, instead of its own
+    pub top_level_name: Option<PathBuf>,
+    /// Whether files that barely shrink are compressed anyway, instead of stored by zip as they are
+    pub force_compress: bool,
+    /// Modification time set on the archive file once it's written, in seconds since the Unix epoch
+    pub archive_mtime: Option<i64>,
+    /// Whether a single big file is read through a buffer, instead of memory-mapped
+    pub no_mmap: bool,
+    /// Whether the extended attributes of files are stored, only by tar
+    pub xattrs: bool,
+    /// Whether an output archive inside of an input directory fails, instead of being left out
+    pub strict: bool,
+    /// Whether the output is written as a framed stream, see [`utils::framed`](crate::utils::framed)
+    pub framed: bool,
+    /// Whether inputs are compressed again with the format they're already compressed with,
+    /// without a warning
+    pub force: bool,
+}
+
+impl PackOptions {
+    /// Name of the entry for the file at `path`, which is relative to `input_parent`, the
+    /// directory that contains the input being compressed.
+    ///
+    /// The name is empty for the input directory itself, which has no entry.
+    pub fn entry_name(&self, input_parent: &Path, path: &Path) -> crate::Result<PathBuf> {
+        let relative_to_input_dir;
+        let path = match &self.input_dir {
+            Some(input_dir) => {
+                relative_to_input_dir = input_parent
+                    .join(path)
+                    .strip_prefix(input_dir)
+                    .expect("inputs were checked to be inside of the input directory")
+                    .to_path_buf();
+                &relative_to_input_dir
+            }
+            None => path,
+        };
+        if path.as_os_str().is_empty() {
+            return Ok(PathBuf::new());
+        }
+
+        let renamed;
+        let path = match &self.top_level_name {
+            Some(name) => {
+                let inside: PathBuf = path.components().skip(1).collect();
+                renamed = if inside.as_os_str().is_empty() {
+                    name.clone()
+                } else {
+                    name.join(inside)
+                };
+                &renamed
+            }
+            None => path,
+        };
+
+        // Names escaped when they were extracted on Windows are stored as they originally were
+        #[cfg(windows)]
+        let path = &windows_names::unescape_path(path).unwrap_or_else(|| path.to_path_buf());
+
+        let entry_name = self.transforms.apply(path)?;
+
+        if self.absolute_paths {
+            Ok(input_parent.join(entry_name))
+        } else {
+            Ok(entry_name)
+        }
+    }
+}
+
+/// What to do with a file whose name collides with one that was already extracted, see
+/// [`Flattener`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Ask the user if the previous file should be overwritten, following `--yes` and `--no`
+    #[default]
+    Ask,
+    /// Extract the file with a number appended to its name, like "file_1.txt"
+    Rename,
+    /// Keep the previous file
+    Skip,
+    /// Replace the previous file
+    Overwrite,
+}
+
+/// Options that change how the entries of an archive are unpacked
+#[derive(Debug, Clone, Default)]
+pub struct UnpackOptions {
+    /// Whether to discard the directory structure, see [`Flattener`]
+    pub flatten: bool,
+    /// How name collisions are solved while flattening
+    pub conflict_policy: ConflictPolicy,
+    /// Only the entries selected by this filter are unpacked
+    pub filter: EntryFilter,
+    /// Renames applied to the path of each entry before it's joined to the output folder
+    pub transforms: Transforms,
+    /// Command to run on every file once it reaches its final location
+    pub exec: Option<ExecHook>,
+    /// Whether entries with absolute paths are unpacked to that location, instead of refused, or
+    /// made relative for tar
+    pub absolute_paths: bool,
+    /// Whether the entries that would be unpacked outside of the output folder fail the
+    /// extraction, instead of being skipped
+    pub strict: bool,
+    /// Whether the symbolic and hard links are skipped
+    pub no_symlinks: bool,
+    /// Whether the first of the zip entries with the same path is extracted, instead of the last
+    pub first_wins: bool,
+    /// How much an archive may expand when extracted
+    pub limits: ExtractionLimits,
+    /// Password that decrypts the encrypted entries of zip archives
+    pub password: Option<String>,
+    /// Whether runs of zeroes are written as holes, see [`SparseWriter`](crate::utils::SparseWriter)
+    pub sparse: SparseMode,
+    /// Whether the extended attributes stored by tar are set on the unpacked files
+    pub xattrs: bool,
+    /// Whether what was unpacked is kept when the disk gets full, instead of removed
+    pub keep_partial: bool,
+    /// Umask that clears bits of the stored permissions, `None` to apply them as they are, with
+    /// `--same-permissions`
+    pub umask: Option<u32>,
+    /// Whether the archives are read as framed streams, see [`utils::framed`](crate::utils::framed)
+    pub framed: bool,
+    /// Whether the zip entries that don't match their checksum are skipped, instead of failing
+    pub continue_on_error: bool,
+    /// Whether tar archives are read past the blocks of zeroes that end them, for concatenated ones
+    pub ignore_zeros: bool,
+}
+
+impl UnpackOptions {
+    /// Permissions of a file unpacked with the stored `mode`, which lose the setuid, setgid and
+    /// sticky bits and the ones cleared by the umask, unless `--same-permissions` is used.
+    #[cfg(unix)]
+    pub fn permissions(&self, mode: u32) -> u32 {
+        match self.umask {
+            Some(umask) => mode & 0o777 & !umask,
+            None => mode & 0o7777,
+        }
+    }
+
+    /// Path that the entry at `entry_path` is unpacked to, relative to the output folder unless
+    /// it's an absolute path allowed by `absolute_paths`.
+    ///
+    /// Returns `None` for the entries that would be unpacked outside of the output folder, like
+    /// `../file`, which are skipped with a warning, unless `strict` makes them fail instead.
+    ///
+    /// Transforms of absolute paths only apply to the part after the root.
+    pub fn entry_path(&self, entry_path: &Path) -> crate::Result<Option<PathBuf>> {
+        if let Some(reason) = unsafe_path_reason(entry_path) {
+            return self.skip_unsafe_entry(entry_path, reason);
+        }
+
+        let path = if entry_path.has_root() {
+            self.absolute_entry_path(entry_path)?
+        } else {
+            self.transforms.apply(&normalize(entry_path))?
+        };
+
+        // Like 'aux.txt' or 'what?', which are common in archives made on other systems
+        #[cfg(windows)]
+        if let Some(escaped) = windows_names::escape_path(&path) {
+            if self.strict {
+                return self.skip_unsafe_entry(entry_path, "Its name isn't valid on Windows");
+            }
+            warning(format!(
+                "Extracting '{}' as '{}', as its name isn't valid on Windows.",
+                EscapedPathDisplay::new(entry_path),
+                EscapedPathDisplay::new(&escaped)
+            ));
+            return Ok(Some(escaped));
+        }
+
+        Ok(Some(path))
+    }
+
+    /// Path that the entry at the absolute `entry_path` is unpacked to, if `absolute_paths`
+    /// allows it.
+    fn absolute_entry_path(&self, entry_path: &Path) -> crate::Result<PathBuf> {
+        if !self.absolute_paths {
+            let error =
+                FinalError::with_title(format!("Refusing to extract '{}'", EscapedPathDisplay::new(entry_path)))
+                    .detail("The archive contains an absolute path, which could overwrite any file of the system")
+                    .hint("If you trust this archive, use '--absolute-paths' to extract it to that location");
+            return Err(error.into());
+        }
+
+        let root: PathBuf = entry_path
+            .components()
+            .take_while(|component| matches!(component, Component::Prefix(_) | Component::RootDir))
+            .collect();
+        let relative = entry_path.strip_prefix(&root).expect("root is a prefix of the path");
+
+        if relative.as_os_str().is_empty() {
+            Ok(root)
+        } else {
+            Ok(root.join(self.transforms.apply(relative)?))
+        }
+    }
+
+    /// Skip the entry at `entry_path`, which can't be unpacked safely because of `reason`, with a
+    /// warning, or fail the extraction if `strict` is set.
+    pub fn skip_unsafe_entry<T>(&self, entry_path: &Path, reason: &'static str) -> crate::Result<Option<T>> {
+        let display = EscapedPathDisplay::new(entry_path);
+        if self.strict {
+            let error = FinalError::with_title(format!("Refusing to extract '{display}'"))
+                .detail(reason)
+                .hint("Without '--strict', such entries are skipped");
+            return Err(error.into());
+        }
+        warning(format!("Skipping '{display}': {reason}."));
+        Ok(None)
+    }
+}
+
+/// Why the entry at `entry_path` can't be unpacked inside of the output folder, if it can't.
+///
+/// Absolute paths aren't reported, as they can be allowed by `--absolute-paths`.
+fn unsafe_path_reason(entry_path: &Path) -> Option<&'static str> {
+    let mut depth = 0usize;
+    for component in entry_path.components() {
+        match component {
+            // Like 'C:file', relative to the current directory of the drive, not to the output folder
+            Component::Prefix(_) if !entry_path.has_root() => {
+                return Some("Its path is relative to a drive, and would be extracted outside of the output folder")
+            }
+            #[cfg(windows)]
+            Component::Prefix(prefix) if matches!(prefix.kind(), std::path::Prefix::DeviceNS(_)) => {
+                return Some("Its path is a device, like '\\\\.\\COM1'")
+            }
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(parent) => depth = parent,
+                None => return Some("Its path has '..', and would be extracted outside of the output folder"),
+            },
+            Component::Normal(_) => depth += 1,
+        }
+    }
+    None
+}
+
+/// `path` with the `..` that go back into it resolved, like `a/../b` into `b`, the others were
+/// already refused by [`unsafe_path_reason`].
+fn normalize(path: &Path) -> PathBuf {
+    if !path.components().any(|component| component == Component::ParentDir) {
+        return path.to_path_buf();
+    }
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::CurDir => {}
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Whether the entry unpacked to `entry_path`, given by [`UnpackOptions::entry_path`], can be
+/// written.
+///
+/// Archives are unpacked into empty folders, except for the absolute paths allowed by
+/// `--absolute-paths`, so the user is asked before overwriting those. Directories are merged.
+pub fn can_write_entry(entry_path: &Path, is_dir: bool, question_policy: QuestionPolicy) -> crate::Result<bool> {
+    if !entry_path.has_root() || is_dir {
+        return Ok(true);
+    }
+
+    utils::clear_path(entry_path, question_policy)
+}
+
+/// Metadata of an archive entry, given along with its contents to the visitors of the
+/// `for_each_entry` functions of each format
+#[derive(Debug, Clone)]
+pub struct EntryMetadata {
+    pub path: PathBuf,
+    pub is_dir: bool,
+    /// Uncompressed size in bytes
+    pub size: u64,
+    /// Unix permission bits, if the archive stores them
+    pub mode: Option<u32>,
+    /// Modification time in seconds since the Unix epoch, if the archive stores it
+    pub mtime: Option<i64>,
+    /// Target of symbolic links, which are given without contents
+    pub link_target: Option<PathBuf>,
+}
+
+/// Decides where files are extracted to when using `--flatten`, which places every file
+/// directly inside of the output folder, discarding the directory structure of the archive.
+pub struct Flattener<'a> {
+    output_folder: &'a Path,
+    conflict_policy: ConflictPolicy,
+    question_policy: QuestionPolicy,
+    overwritten: usize,
+    renamed: usize,
+    skipped: usize,
+}
+
+impl<'a> Flattener<'a> {
+    pub fn new(output_folder: &'a Path, conflict_policy: ConflictPolicy, question_policy: QuestionPolicy) -> Self {
+        Self {
+            output_folder,
+            conflict_policy,
+            question_policy,
+            overwritten: 0,
+            renamed: 0,
+            skipped: 0,
+        }
+    }
+
+    /// Returns the path that the file entry at `entry_path` should be extracted to, or `None` if
+    /// it should be skipped.
+    ///
+    /// Files from different directories of the archive might end up with the same name, these
+    /// collisions are solved according to the [`ConflictPolicy`].
+    pub fn file_path(&mut self, entry_path: &Path) -> crate::Result<Option<PathBuf>> {
+        let Some(file_name) = entry_path.file_name() else {
+            return Ok(None);
+        };
+        let file_path = self.output_folder.join(file_name);
+
+        if !file_path.exists() {
+            return Ok(Some(file_path));
+        }
+
+        let overwrite = match self.conflict_policy {
+            ConflictPolicy::Ask => utils::clear_path(&file_path, self.question_policy)?,
+            ConflictPolicy::Overwrite => utils::clear_path(&file_path, QuestionPolicy::AlwaysYes)?,
+            ConflictPolicy::Skip => false,
+            ConflictPolicy::Rename => {
+                self.renamed += 1;
+                return Ok(Some(utils::free_path(&file_path)));
+            }
+        };
+
+        if overwrite {
+            self.overwritten += 1;
+            Ok(Some(file_path))
+        } else {
+            self.skipped += 1;
+            Ok(None)
+        }
+    }
+
+    /// Warn that a link entry is skipped, its target would be meaningless after flattening.
+    pub fn skip_link(&self, entry_path: &Path) {
+        warning(format!(
+            "Skipping link '{}' since its target is meaningless after flattening.",
+            EscapedPathDisplay::new(entry_path)
+        ));
+    }
+
+    /// Inform the user about the name collisions found while flattening, if any.
+    pub fn report_collisions(&self) {
+        let collisions = self.overwritten + self.renamed + self.skipped;
+        if collisions > 0 {
+            info_accessible(format!(
+                "Found {collisions} name collisions while flattening: {} files overwritten, {} renamed and {} skipped.",
+                self.overwritten, self.renamed, self.skipped
+            ));
+        }
+    }
+}
@@ -1,22 +1,69 @@
 //! Contains RAR-specific building and unpacking functions
 
-use std::path::Path;
+use std::{
+    io::{self, Read},
+    path::Path,
+};
 
 use unrar::Archive;
 
-use crate::{error::Error, list::FileInArchive, utils::logger::info};
+use crate::{
+    archive::{can_write_entry, limits::ExtractionBudget, EntryMetadata, Flattener, UnpackOptions},
+    error::Error,
+    list::FileInArchive,
+    utils::{self, interrupt::check_interrupted, logger::info},
+    QuestionPolicy,
+};
 
 /// Unpacks the archive given by `archive_path` into the folder given by `output_folder`.
 /// Assumes that output_folder is empty
-pub fn unpack_archive(archive_path: &Path, output_folder: &Path, quiet: bool) -> crate::Result<usize> {
+pub fn unpack_archive(
+    archive_path: &Path,
+    output_folder: &Path,
+    quiet: bool,
+    question_policy: QuestionPolicy,
+    options: &UnpackOptions,
+    budget: &ExtractionBudget,
+) -> crate::Result<usize> {
     assert!(output_folder.read_dir().expect("dir exists").count() == 0);
 
     let mut archive = Archive::new(archive_path).open_for_processing()?;
     let mut unpacked = 0;
+    let mut flattener = options
+        .flatten
+        .then(|| Flattener::new(output_folder, options.conflict_policy, question_policy));
 
     while let Some(header) = archive.read_header()? {
+        check_interrupted()?;
         let entry = header.entry();
-        archive = if entry.is_file() {
+        archive = if entry.is_file() && options.filter.is_match(&entry.filename) {
+            budget.count_entry(&entry.filename)?;
+            let Some(entry_path) = options.entry_path(&entry.filename)? else {
+                archive = header.skip()?;
+                continue;
+            };
+            let extract_path = match &mut flattener {
+                Some(flattener) => match flattener.file_path(&entry_path)? {
+                    Some(file_path) => Some(file_path),
+                    None => {
+                        archive = header.skip()?;
+                        continue;
+                    }
+                },
+                None if options.transforms.is_empty() && !entry_path.has_root() && entry_path == entry.filename => None,
+                None => {
+                    if !can_write_entry(&entry_path, false, question_policy)? {
+                        archive = header.skip()?;
+                        continue;
+                    }
+                    let file_path = output_folder.join(entry_path);
+                    if let Some(parent) = file_path.parent() {
+                        utils::create_dir_all(parent)?;
+                    }
+                    Some(file_path)
+                }
+            };
+
             if !quiet {
                 info(format!(
                     "{} extracted. ({})",
@@ -25,15 +72,56 @@ pub fn unpack_archive(archive_path: &Path, output_folder: &Path, quiet: bool) ->
                 ));
             }
             unpacked += 1;
-            header.extract_with_base(output_folder)?
+            // The entry is written by unrar, so its size is counted before extracting it
+            budget.spend(entry.unpacked_size)?;
+
+            match extract_path {
+                Some(file_path) => header.extract_to(file_path)?,
+                None => header.extract_with_base(output_folder)?,
+            }
         } else {
             header.skip()?
         };
     }
 
+    if let Some(flattener) = flattener {
+        flattener.report_collisions();
+    }
+
     Ok(unpacked)
 }
 
+/// Calls `visit` with the metadata and contents of every entry of the archive at `archive_path`.
+pub fn for_each_entry(
+    archive_path: &Path,
+    mut visit: impl FnMut(EntryMetadata, &mut dyn Read) -> crate::Result<()>,
+) -> crate::Result<()> {
+    let mut archive = Archive::new(archive_path).open_for_processing()?;
+
+    while let Some(header) = archive.read_header()? {
+        let entry = header.entry();
+        let metadata = EntryMetadata {
+            path: entry.filename.clone(),
+            is_dir: entry.is_directory(),
+            size: entry.unpacked_size,
+            mode: None,
+            mtime: None,
+            link_target: None,
+        };
+
+        archive = if entry.is_file() {
+            let (contents, archive) = header.read()?;
+            visit(metadata, &mut contents.as_slice())?;
+            archive
+        } else {
+            visit(metadata, &mut io::empty())?;
+            header.skip()?
+        };
+    }
+
+    Ok(())
+}
+
 /// List contents of `archive_path`, returning a vector of archive entries
 pub fn list_archive(archive_path: &Path) -> impl Iterator<Item = crate::Result<FileInArchive>> {
     Archive::new(archive_path)
@@ -42,9 +130,19 @@ pub fn list_archive(archive_path: &Path) -> impl Iterator<Item = crate::Result<F
         .map(|item| {
             let item = item?;
             let is_dir = item.is_directory();
+            let size = item.unpacked_size;
             let path = item.filename;
 
-            Ok(FileInArchive { path, is_dir })
+            Ok(FileInArchive {
+                path,
+                is_dir,
+                is_symlink: false,
+                mode: None,
+                size: Some(size),
+                compressed_size: None,
+                method: None,
+                duplicate: None,
+            })
         })
 }
 
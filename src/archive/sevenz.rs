@@ -1,57 +1,58 @@
 //! SevenZip archive format compress function
 
 use std::{
-    env,
-    io::{self, Read, Seek, Write},
+    io::{self, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use fs_err as fs;
-use same_file::Handle;
 
 use crate::{
+    archive::{
+        can_write_entry, limits::ExtractionBudget, ArchiveOutput, EntryMetadata, Flattener, PackOptions, UnpackOptions,
+    },
     error::FinalError,
+    list::FileInArchive,
     utils::{
-        self, cd_into_same_dir_as,
-        logger::{info, warning},
-        Bytes, EscapedPathDisplay, FileVisibilityPolicy,
+        self,
+        interrupt::check_interrupted,
+        io::{buf_reader, buf_writer},
+        logger::info,
+        progress::ProgressReader,
+        Bytes, EscapedPathDisplay, FileVisibilityPolicy, SparseWriter,
     },
+    QuestionPolicy,
 };
 
 pub fn compress_sevenz<W>(
     files: &[PathBuf],
-    output_path: &Path,
+    output: &ArchiveOutput,
     writer: W,
     file_visibility_policy: FileVisibilityPolicy,
     quiet: bool,
+    options: &PackOptions,
 ) -> crate::Result<W>
 where
     W: Write + Seek,
 {
     let mut writer = sevenz_rust::SevenZWriter::new(writer)?;
-    let output_handle = Handle::from_path(output_path);
 
     for filename in files {
-        let previous_location = cd_into_same_dir_as(filename)?;
-
-        // Unwrap safety:
-        //   paths should be canonicalized by now, and the root directory rejected.
-        let filename = filename.file_name().unwrap();
+        let input_parent = filename.parent().ok_or(crate::Error::CompressingRootFolder)?;
 
         for entry in file_visibility_policy.build_walker(filename) {
+            check_interrupted()?;
             let entry = entry?;
-            let path = entry.path();
+            let file_path = entry.path();
+            // Relative to the directory containing the input, like it's shown to the user
+            let path = file_path
+                .strip_prefix(input_parent)
+                .expect("walked paths start with the input");
 
-            // If the output_path is the same as the input file, warn the user and skip the input (in order to avoid compression recursion)
-            if let Ok(handle) = &output_handle {
-                if matches!(Handle::from_path(path), Ok(x) if &x == handle) {
-                    warning(format!(
-                        "The output file and the input file are the same: `{}`, skipping...",
-                        output_path.display()
-                    ));
-
-                    continue;
-                }
+            // Never compress the output into itself
+            if output.is_output(file_path) {
+                continue;
             }
 
             // This is printed for every file in `input_filenames` and has
@@ -62,10 +63,10 @@ where
                 info(format!("Compressing '{}'.", EscapedPathDisplay::new(path)));
             }
 
-            let metadata = match path.metadata() {
+            let metadata = match file_path.metadata() {
                 Ok(metadata) => metadata,
                 Err(e) => {
-                    if e.kind() == std::io::ErrorKind::NotFound && utils::is_symlink(path) {
+                    if e.kind() == std::io::ErrorKind::NotFound && utils::is_symlink(file_path) {
                         // This path is for a broken symlink
                         // We just ignore it
                         continue;
@@ -73,42 +74,153 @@ where
                     return Err(e.into());
                 }
             };
+            if utils::is_special_file(&metadata) {
+                utils::warn_about_special_file(file_path);
+                continue;
+            }
+
+            let entry_name = options.entry_name(input_parent, path)?;
 
-            let entry_name = path.to_str().ok_or_else(|| {
+            // The input directory itself has no entry
+            if entry_name.as_os_str().is_empty() {
+                continue;
+            }
+            let entry_name = entry_name.to_str().ok_or_else(|| {
                 FinalError::with_title("7z requires that all entry names are valid UTF-8")
                     .detail(format!("File at '{path:?}' has a non-UTF-8 name"))
             })?;
 
-            let entry = sevenz_rust::SevenZArchiveEntry::from_path(path, entry_name.to_owned());
+            let entry = sevenz_rust::SevenZArchiveEntry::from_path(file_path, entry_name.to_owned());
             let entry_data = if metadata.is_dir() {
                 None
             } else {
-                Some(fs::File::open(path)?)
+                Some(buf_reader(ProgressReader(fs::File::open(utils::long_path(file_path))?)))
             };
 
-            writer.push_archive_entry::<fs::File>(entry, entry_data)?;
+            writer.push_archive_entry(entry, entry_data)?;
         }
-
-        env::set_current_dir(previous_location)?;
     }
 
     let bytes = writer.finish()?;
     Ok(bytes)
 }
 
-pub fn decompress_sevenz<R>(reader: R, output_path: &Path, quiet: bool) -> crate::Result<usize>
+/// Fail with a clear error if the archive ends before its headers, like the first volume of an
+/// archive split in several, which sevenz_rust would only report as an unexpected end of file.
+fn check_is_complete(reader: &mut (impl Read + Seek)) -> crate::Result<()> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    reader.rewind()?;
+    let mut start_header = [0; 32];
+    let read = reader.read_exact(&mut start_header);
+    reader.rewind()?;
+    // Too short or not a 7z archive, which sevenz_rust reports
+    if read.is_err() || start_header[..6] != [b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C] {
+        return Ok(());
+    }
+
+    let next_header_offset = u64::from_le_bytes(start_header[12..20].try_into().unwrap());
+    let next_header_size = u64::from_le_bytes(start_header[20..28].try_into().unwrap());
+    let headers_end = 32u64
+        .saturating_add(next_header_offset)
+        .saturating_add(next_header_size);
+    if headers_end > len {
+        let error = FinalError::with_title("Incomplete 7z archive")
+            .detail(format!(
+                "Its headers end at byte {headers_end}, but it's only {len} bytes long"
+            ))
+            .detail(
+                "It was either truncated, or split into volumes, like 'archive.7z.001', and this is only one of them",
+            )
+            .hint("Split archives need to be joined before being decompressed, like:")
+            .hint("  cat archive.7z.0* > archive.7z");
+        return Err(error.into());
+    }
+    Ok(())
+}
+
+pub fn decompress_sevenz<R>(
+    mut reader: R,
+    output_path: &Path,
+    quiet: bool,
+    question_policy: QuestionPolicy,
+    options: &UnpackOptions,
+    budget: &ExtractionBudget,
+) -> crate::Result<usize>
 where
     R: Read + Seek,
 {
+    check_is_complete(&mut reader)?;
     let mut count: usize = 0;
-    sevenz_rust::decompress_with_extract_fn(reader, output_path, |entry, reader, path| {
-        count += 1;
-        // Manually handle writing all files from 7z archive, due to library exluding empty files
-        use std::io::BufWriter;
+    let mut flattener = options
+        .flatten
+        .then(|| Flattener::new(output_path, options.conflict_policy, question_policy));
+    // Errors that can't be returned as a `sevenz_rust::Error` from the closure below
+    let mut entry_error = None;
 
+    sevenz_rust::decompress_with_extract_fn(reader, output_path, |entry, reader, _| {
+        // Manually handle writing all files from 7z archive, due to library exluding empty files
         use filetime_creation as ft;
 
-        let file_path = output_path.join(entry.name());
+        if let Err(err) = check_interrupted() {
+            entry_error = Some(err);
+            return Ok(false);
+        }
+        if !options.filter.is_match(Path::new(entry.name())) {
+            // Entries share the decoder stream, so skipped ones still need to be read
+            io::copy(reader, &mut io::sink())?;
+            return Ok(true);
+        }
+        if let Err(err) = budget.count_entry(Path::new(entry.name())) {
+            entry_error = Some(err);
+            return Ok(false);
+        }
+        let entry_path = match options.entry_path(Path::new(entry.name())) {
+            Ok(Some(entry_path)) => entry_path,
+            Ok(None) => {
+                io::copy(reader, &mut io::sink())?;
+                return Ok(true);
+            }
+            Err(err) => {
+                entry_error = Some(err);
+                return Ok(false);
+            }
+        };
+
+        let file_path = match &mut flattener {
+            Some(flattener) => {
+                let file_path = if entry.is_directory() {
+                    None
+                } else {
+                    match flattener.file_path(&entry_path) {
+                        Ok(file_path) => file_path,
+                        Err(err) => {
+                            entry_error = Some(err);
+                            return Ok(false);
+                        }
+                    }
+                };
+
+                let Some(file_path) = file_path else {
+                    // Entries share the decoder stream, so skipped ones still need to be read
+                    io::copy(reader, &mut io::sink())?;
+                    return Ok(true);
+                };
+                file_path
+            }
+            None => match can_write_entry(&entry_path, entry.is_directory(), question_policy) {
+                Ok(true) => output_path.join(entry_path),
+                Ok(false) => {
+                    io::copy(reader, &mut io::sink())?;
+                    return Ok(true);
+                }
+                Err(err) => {
+                    entry_error = Some(err);
+                    return Ok(false);
+                }
+            },
+        };
+
+        count += 1;
 
         if entry.is_directory() {
             if !quiet {
@@ -118,8 +230,8 @@ where
                     file_path.display()
                 ));
             }
-            if !path.exists() {
-                fs::create_dir_all(path)?;
+            if !file_path.exists() {
+                utils::create_dir_all(&file_path)?;
             }
         } else {
             if !quiet {
@@ -130,18 +242,27 @@ where
                 ));
             }
 
-            if let Some(parent) = path.parent() {
+            if let Some(parent) = file_path.parent() {
                 if !parent.exists() {
-                    fs::create_dir_all(parent)?;
+                    utils::create_dir_all(parent)?;
                 }
             }
 
-            let file = fs::File::create(path)?;
-            let mut writer = BufWriter::new(file);
-            io::copy(reader, &mut writer)?;
+            let file = fs::File::create(utils::long_path(&file_path))?;
+            let mut writer = SparseWriter::new(buf_writer(file), options.sparse);
+            let copied = io::copy(&mut budget.reader(reader, None), &mut writer);
+            // Written before the times are set
+            let file = match copied.and_then(|_| writer.finish()) {
+                Ok(file) => file,
+                Err(err) => {
+                    let size = Some(entry.size());
+                    entry_error = Some(utils::io::entry_write_error(err, &file_path, &file_path, size));
+                    return Ok(false);
+                }
+            };
 
             ft::set_file_handle_times(
-                writer.get_ref().file(),
+                file.file(),
                 Some(ft::FileTime::from_system_time(entry.access_date().into())),
                 Some(ft::FileTime::from_system_time(entry.last_modified_date().into())),
                 Some(ft::FileTime::from_system_time(entry.creation_date().into())),
@@ -152,5 +273,157 @@ where
         Ok(true)
     })?;
 
+    if let Some(err) = entry_error {
+        return Err(err);
+    }
+    if let Some(flattener) = flattener {
+        flattener.report_collisions();
+    }
+
     Ok(count)
 }
+
+/// Calls `visit` with the metadata and contents of every entry of the archive read from `reader`.
+pub fn for_each_entry<R>(
+    mut reader: R,
+    mut visit: impl FnMut(EntryMetadata, &mut dyn Read) -> crate::Result<()>,
+) -> crate::Result<()>
+where
+    R: Read + Seek,
+{
+    check_is_complete(&mut reader)?;
+    // Errors that can't be returned as a `sevenz_rust::Error` from the closure below
+    let mut visit_error = None;
+
+    sevenz_rust::decompress_with_extract_fn(reader, ".", |entry, reader, _| {
+        // The high 16 bits hold the unix permissions, when flagged by 0x8000
+        let has_mode = entry.has_windows_attributes && entry.windows_attributes & 0x8000 != 0;
+        let mtime = SystemTime::from(entry.last_modified_date())
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .filter(|_| entry.has_last_modified_date);
+
+        let metadata = EntryMetadata {
+            path: entry.name().into(),
+            is_dir: entry.is_directory(),
+            size: entry.size(),
+            mode: has_mode.then_some(entry.windows_attributes >> 16),
+            mtime: mtime.map(|mtime| mtime.as_secs() as i64),
+            link_target: None,
+        };
+
+        if let Err(err) = visit(metadata, reader) {
+            visit_error = Some(err);
+            return Ok(false);
+        }
+
+        // Entries share the decoder stream, so whatever wasn't read still needs to be
+        io::copy(reader, &mut io::sink())?;
+        Ok(true)
+    })?;
+
+    match visit_error {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// List the entries of the archive read from `reader`, only reading its headers.
+///
+/// Entries compressed together in a folder share its codecs, and their compressed size is only
+/// known when the folder has no other entries.
+pub fn list_archive<R>(mut reader: R) -> crate::Result<Vec<FileInArchive>>
+where
+    R: Read + Seek,
+{
+    check_is_complete(&mut reader)?;
+    let len = reader.seek(SeekFrom::End(0))?;
+    reader.rewind()?;
+    let archive = sevenz_rust::Archive::read(&mut reader, len, &[])?;
+
+    let files = archive
+        .files
+        .iter()
+        .enumerate()
+        .map(|(idx, entry)| {
+            let folder = archive.stream_map.file_folder_index[idx].map(|folder_idx| &archive.folders[folder_idx]);
+            let method = folder.map(|folder| {
+                let codecs: Vec<_> = folder
+                    .coders
+                    .iter()
+                    .map(|coder| {
+                        let id = coder.decompression_method_id();
+                        sevenz_rust::SevenZMethod::by_id(id)
+                            .map_or_else(|| format!("{id:02x?}"), |method| method.name().to_string())
+                    })
+                    .collect();
+                codecs.join("+")
+            });
+            // Entries without contents aren't stored in any folder
+            let compressed_size = match folder {
+                Some(folder) => (folder.num_unpack_sub_streams == 1).then_some(entry.compressed_size),
+                None => Some(0),
+            };
+
+            FileInArchive {
+                path: entry.name().into(),
+                is_dir: entry.is_directory(),
+                is_symlink: false,
+                mode: None,
+                size: Some(entry.size()),
+                compressed_size,
+                method,
+                duplicate: None,
+            }
+        })
+        .collect();
+
+    Ok(files)
+}
+
+/// Appends an entry read from another archive, described by `metadata`, to `writer`.
+///
+/// Links are stored as regular files containing their target, since 7z has no links.
+pub fn append_entry<W>(
+    writer: &mut sevenz_rust::SevenZWriter<W>,
+    metadata: &EntryMetadata,
+    contents: &mut dyn Read,
+) -> crate::Result<()>
+where
+    W: Write + Seek,
+{
+    let name = metadata.path.to_str().ok_or_else(|| {
+        FinalError::with_title("7z requires that all entry names are valid UTF-8")
+            .detail(format!("Entry '{:?}' has a non-UTF-8 name", metadata.path))
+    })?;
+
+    let mut entry = sevenz_rust::SevenZArchiveEntry::new();
+    entry.name = name.to_owned();
+    entry.is_directory = metadata.is_dir;
+    entry.has_stream = !metadata.is_dir;
+    if let Some(mode) = metadata.mode {
+        // Unix file type and permissions go in the high 16 bits, like `for_each_entry` reads them
+        let file_type = if metadata.is_dir { 0o040000 } else { 0o100000 };
+        entry.has_windows_attributes = true;
+        entry.windows_attributes = 0x8000 | ((file_type | mode & 0o7777) << 16);
+    }
+    let last_modified_date = metadata
+        .mtime
+        .and_then(|mtime| u64::try_from(mtime).ok())
+        .and_then(|mtime| (UNIX_EPOCH + Duration::from_secs(mtime)).try_into().ok());
+    if let Some(last_modified_date) = last_modified_date {
+        entry.last_modified_date = last_modified_date;
+        entry.has_last_modified_date = true;
+    }
+
+    match &metadata.link_target {
+        _ if metadata.is_dir => writer.push_archive_entry::<&mut dyn Read>(entry, None)?,
+        Some(target) => {
+            let target = target.to_string_lossy();
+            writer.push_archive_entry(entry, Some(target.as_bytes()))?
+        }
+        None => writer.push_archive_entry(entry, Some(contents))?,
+    };
+
+    Ok(())
+}
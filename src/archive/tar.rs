@@ -1,37 +1,186 @@
 //! Contains Tar-specific building and unpacking functions
 
 use std::{
-    env,
-    io::prelude::*,
-    path::{Path, PathBuf},
+    borrow::Cow,
+    io::{self, prelude::*},
+    path::{Component, Path, PathBuf},
     sync::mpsc::{self, Receiver},
     thread,
 };
 
+use filetime_creation::FileTime;
 use fs_err as fs;
-use same_file::Handle;
 
 use crate::{
+    archive::{
+        can_write_entry, limits::ExtractionBudget, ArchiveOutput, EntryMetadata, Flattener, PackOptions, UnpackOptions,
+    },
+    cli::SparseMode,
     error::FinalError,
-    list::FileInArchive,
+    list::{FileInArchive, ENTRIES_AHEAD},
     utils::{
         self,
+        interrupt::check_interrupted,
+        io::{buf_reader, buf_writer},
         logger::{info, warning},
-        Bytes, EscapedPathDisplay, FileVisibilityPolicy,
+        progress::ProgressReader,
+        Bytes, EscapedPathDisplay, FileVisibilityPolicy, SparseWriter,
     },
+    QuestionPolicy,
 };
 
 /// Unpacks the archive given by `archive` into the folder given by `into`.
 /// Assumes that output_folder is empty
-pub fn unpack_archive(reader: Box<dyn Read>, output_folder: &Path, quiet: bool) -> crate::Result<usize> {
+pub fn unpack_archive(
+    reader: Box<dyn Read>,
+    output_folder: &Path,
+    quiet: bool,
+    question_policy: QuestionPolicy,
+    options: &UnpackOptions,
+    budget: &ExtractionBudget,
+) -> crate::Result<usize> {
     assert!(output_folder.read_dir().expect("dir exists").count() == 0);
     let mut archive = tar::Archive::new(reader);
+    // The entries that the tar crate unpacks get the same permissions as the ones unpacked here
+    archive.set_preserve_permissions(options.umask.is_none());
+    archive.set_mask(options.umask.unwrap_or(0));
+    archive.set_ignore_zeros(options.ignore_zeros);
+    let mut flattener = options
+        .flatten
+        .then(|| Flattener::new(output_folder, options.conflict_policy, question_policy));
 
     let mut files_unpacked = 0;
+    let mut warned_about_roots = false;
     for file in archive.entries()? {
+        check_interrupted()?;
         let mut file = file?;
+        let sparse_file = PaxSparseFile::from_entry(&mut file)?;
+        let mut original_path = match &sparse_file {
+            Some(sparse_file) => Cow::Borrowed(sparse_file.path.as_path()),
+            None => file.path()?,
+        };
+        if !options.filter.is_match(&original_path) {
+            continue;
+        }
+        budget.count_entry(&original_path)?;
 
-        file.unpack_in(output_folder)?;
+        // Archives made with `tar -P` are extracted inside of the output folder, like GNU tar does
+        if !options.absolute_paths {
+            if let Some(relative) = strip_root(&original_path)? {
+                if !warned_about_roots {
+                    warned_about_roots = true;
+                    warning("Removing leading '/' from entry names.".to_string());
+                }
+                if relative.as_os_str().is_empty() {
+                    continue;
+                }
+                original_path = Cow::Owned(relative);
+            }
+        }
+
+        let Some(entry_path) = options.entry_path(&original_path)? else {
+            continue;
+        };
+        let entry_type = file.header().entry_type();
+        if options.no_symlinks && (entry_type.is_symlink() || entry_type.is_hard_link()) {
+            continue;
+        }
+
+        if let Some(sparse_file) = sparse_file {
+            let file_path = match &mut flattener {
+                Some(flattener) => match flattener.file_path(&entry_path)? {
+                    Some(file_path) => file_path,
+                    None => continue,
+                },
+                None => {
+                    if !can_write_entry(&entry_path, false, question_policy)? {
+                        continue;
+                    }
+                    if entry_path.has_root() {
+                        if let Some(parent) = entry_path.parent() {
+                            utils::create_dir_all(parent)?;
+                        }
+                        entry_path
+                    } else {
+                        match path_inside(output_folder, &entry_path, options)? {
+                            Some(file_path) => file_path,
+                            None => continue,
+                        }
+                    }
+                }
+            };
+            sparse_file.unpack(&mut file, &file_path, options)?;
+
+            if !quiet {
+                info(format!(
+                    "{:?} extracted. ({})",
+                    utils::strip_cur_dir(&file_path),
+                    Bytes::new(sparse_file.size),
+                ));
+            }
+            files_unpacked += 1;
+            continue;
+        }
+
+        let file_path = match &mut flattener {
+            Some(flattener) => {
+                if entry_type.is_dir() {
+                    continue;
+                }
+
+                if entry_type.is_symlink() || entry_type.is_hard_link() {
+                    flattener.skip_link(&entry_path);
+                    continue;
+                }
+
+                let Some(file_path) = flattener.file_path(&entry_path)? else {
+                    continue;
+                };
+                if !unpack_entry(&mut file, &file_path, options)? {
+                    continue;
+                }
+                file_path
+            }
+            None if options.transforms.is_empty()
+                && !entry_path.has_root()
+                && entry_path == *original_path
+                && !is_special_entry(entry_type) =>
+            {
+                // `unpack_in` checks it too, but fails the whole extraction
+                let Some(file_path) = path_inside(output_folder, &entry_path, options)? else {
+                    continue;
+                };
+                if is_written_sparse(&file, options.sparse) {
+                    unpack_file(&mut file, &file_path, options)?;
+                } else {
+                    file.unpack_in(output_folder)?;
+                    restore_xattrs(&mut file, &file_path, options);
+                }
+                file_path
+            }
+            None => {
+                // `unpack_in` always uses the original entry path and strips its root, while the
+                // transformed or normalized one is checked like it does by `path_inside`
+                if !can_write_entry(&entry_path, entry_type.is_dir(), question_policy)? {
+                    continue;
+                }
+                let file_path = if entry_path.has_root() {
+                    if let Some(parent) = entry_path.parent() {
+                        utils::create_dir_all(parent)?;
+                    }
+                    entry_path
+                } else {
+                    match path_inside(output_folder, &entry_path, options)? {
+                        Some(file_path) => file_path,
+                        None => continue,
+                    }
+                };
+                if !unpack_entry(&mut file, &file_path, options)? {
+                    continue;
+                }
+                file_path
+            }
+        };
 
         // This is printed for every file in the archive and has little
         // importance for most users, but would generate lots of
@@ -40,17 +189,396 @@ pub fn unpack_archive(reader: Box<dyn Read>, output_folder: &Path, quiet: bool)
         if !quiet {
             info(format!(
                 "{:?} extracted. ({})",
-                utils::strip_cur_dir(&output_folder.join(file.path()?)),
+                utils::strip_cur_dir(&file_path),
                 Bytes::new(file.size()),
             ));
-
-            files_unpacked += 1;
         }
+        files_unpacked += 1;
+    }
+
+    // The rest of the stream, past the end of the archive, is read too, so that the checksums at
+    // the end of the formats it's compressed with and the end of framed streams are checked
+    io::copy(&mut archive.into_inner(), &mut io::sink())?;
+
+    if let Some(flattener) = flattener {
+        flattener.report_collisions();
     }
 
     Ok(files_unpacked)
 }
 
+/// Whether `file` is written by [`unpack_file`], instead of by the tar crate, which writes every
+/// zero of regular files, and leaves holes for the ones of the GNU sparse files.
+fn is_written_sparse(file: &tar::Entry<impl Read>, sparse: SparseMode) -> bool {
+    let entry_type = file.header().entry_type();
+    match sparse {
+        SparseMode::Auto | SparseMode::Always => entry_type.is_file(),
+        SparseMode::Never => entry_type.is_gnu_sparse(),
+    }
+}
+
+/// Unpack any kind of entry to `file_path`, returning whether it was, since device files are
+/// skipped when they can't be created.
+fn unpack_entry(file: &mut tar::Entry<impl Read>, file_path: &Path, options: &UnpackOptions) -> crate::Result<bool> {
+    if is_special_entry(file.header().entry_type()) {
+        unpack_special_file(file, file_path, options)
+    } else if is_written_sparse(file, options.sparse) {
+        unpack_file(file, file_path, options)?;
+        Ok(true)
+    } else {
+        // Symlinks are created by the tar crate too
+        file.unpack(utils::long_path(file_path))?;
+        restore_xattrs(file, file_path, options);
+        Ok(true)
+    }
+}
+
+/// Whether the entry is a FIFO or a device file, which the tar crate would extract as an empty
+/// regular file.
+fn is_special_entry(entry_type: tar::EntryType) -> bool {
+    entry_type.is_fifo() || entry_type.is_character_special() || entry_type.is_block_special()
+}
+
+/// Create the FIFO or device file of `file` at `file_path` with `mknod`, returning whether it was,
+/// since creating device files requires root.
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast, clippy::useless_conversion)] // The types of `mknod` differ between systems
+fn unpack_special_file(
+    file: &mut tar::Entry<impl Read>,
+    file_path: &Path,
+    options: &UnpackOptions,
+) -> crate::Result<bool> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let header = file.header();
+    let entry_type = header.entry_type();
+    let (kind, device) = if entry_type.is_fifo() {
+        (libc::S_IFIFO, 0)
+    } else {
+        let major = header.device_major()?.unwrap_or(0);
+        let minor = header.device_minor()?.unwrap_or(0);
+        let kind = if entry_type.is_character_special() {
+            libc::S_IFCHR
+        } else {
+            libc::S_IFBLK
+        };
+        (kind, libc::makedev(major as _, minor as _))
+    };
+
+    // Like the other files, what's already there is replaced
+    let long_path = utils::long_path(file_path);
+    match fs::remove_file(&*long_path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+    let path = CString::new(long_path.as_os_str().as_bytes()).map_err(io::Error::from)?;
+    // SAFETY: `path` is a valid C string
+    if unsafe { libc::mknod(path.as_ptr(), kind | 0o600, device) } != 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::PermissionDenied && !entry_type.is_fifo() {
+            warning(format!(
+                "Skipping the device file '{}', creating it requires root.",
+                EscapedPathDisplay::new(file_path)
+            ));
+            return Ok(false);
+        }
+        return Err(err.into());
+    }
+
+    set_file_metadata(file, file_path, options)?;
+    Ok(true)
+}
+
+/// FIFOs and device files can only be created on Unix.
+#[cfg(not(unix))]
+fn unpack_special_file(
+    _file: &mut tar::Entry<impl Read>,
+    file_path: &Path,
+    _options: &UnpackOptions,
+) -> crate::Result<bool> {
+    warning(format!(
+        "Skipping '{}', FIFOs and device files can only be extracted on Unix.",
+        EscapedPathDisplay::new(file_path)
+    ));
+    Ok(false)
+}
+
+/// Write the contents of the file `file` to `file_path`, leaving holes in place of its runs of
+/// zeroes, see [`SparseWriter`].
+fn unpack_file(file: &mut tar::Entry<impl Read>, file_path: &Path, options: &UnpackOptions) -> crate::Result<()> {
+    // Like `unpack` does, what's already there is replaced, instead of followed if it's a symlink
+    let long_path = utils::long_path(file_path);
+    let open = || fs::OpenOptions::new().write(true).create_new(true).open(&*long_path);
+    let output = match open() {
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            fs::remove_file(&*long_path)?;
+            open()?
+        }
+        output => output?,
+    };
+
+    let size = file.size();
+    let write_error = |err| utils::io::entry_write_error(err, file_path, file_path, Some(size));
+    let mut output = SparseWriter::new(buf_writer(output), options.sparse);
+    io::copy(file, &mut output).map_err(write_error)?;
+    output.finish().map_err(write_error)?;
+    set_file_metadata(file, file_path, options)
+}
+
+/// Path that `unpack_in` would extract the entry at `entry_path` to, checking like it does that
+/// it stays inside of `output_folder`, even through the symlinks extracted before it.
+///
+/// Returns `None` for the paths with `..`, which `unpack_in` skips, and for the ones that go
+/// through a symlink to outside of `output_folder`, which are skipped like the other unsafe
+/// entries.
+fn path_inside(output_folder: &Path, entry_path: &Path, options: &UnpackOptions) -> crate::Result<Option<PathBuf>> {
+    if entry_path
+        .components()
+        .any(|component| component == Component::ParentDir)
+    {
+        return Ok(None);
+    }
+    let file_path = output_folder.join(entry_path);
+    let Some(parent) = file_path.parent() else {
+        return Ok(Some(file_path));
+    };
+
+    // Checked before creating the missing folders, which could otherwise be created anywhere
+    let existing = parent
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(output_folder);
+    if !existing.canonicalize()?.starts_with(output_folder.canonicalize()?) {
+        return options.skip_unsafe_entry(
+            entry_path,
+            "It would be written outside of the output folder, through a symbolic link",
+        );
+    }
+    utils::create_dir_all(parent)?;
+    Ok(Some(file_path))
+}
+
+/// `entry_path` without its leading '/', and drive or UNC prefix on Windows, if it has one.
+///
+/// Fails if the path still leads outside of the output folder once relative, like '/../file',
+/// as only a malicious archive would store that.
+fn strip_root(entry_path: &Path) -> crate::Result<Option<PathBuf>> {
+    let mut components = entry_path.components().peekable();
+    let mut has_root = false;
+    while components
+        .next_if(|component| matches!(component, Component::Prefix(_) | Component::RootDir))
+        .is_some()
+    {
+        has_root = true;
+    }
+    if !has_root {
+        return Ok(None);
+    }
+
+    let relative: PathBuf = components.collect();
+    if let Some(reason) = super::unsafe_path_reason(&relative) {
+        let error = FinalError::with_title(format!("Refusing to extract '{}'", EscapedPathDisplay::new(entry_path)))
+            .detail(reason)
+            .detail("Its absolute path leads outside of the output folder, even without its leading '/'");
+        return Err(error.into());
+    }
+    Ok(Some(relative))
+}
+
+/// Set the modification time and permissions of the file extracted at `file_path`, like the
+/// tar crate does, and its extended attributes with `--xattrs`, before it may become read-only.
+fn set_file_metadata(file: &mut tar::Entry<impl Read>, file_path: &Path, options: &UnpackOptions) -> crate::Result<()> {
+    restore_xattrs(file, file_path, options);
+
+    let header = file.header();
+    if let Ok(mtime) = header.mtime() {
+        utils::set_mtime(file_path, FileTime::from_unix_time(mtime as i64, 0))?;
+    }
+    #[cfg(unix)]
+    if let Ok(mode) = header.mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::set_permissions(file_path, std::fs::Permissions::from_mode(options.permissions(mode)))?;
+    }
+
+    Ok(())
+}
+
+/// Prefix of the keys of the PAX records that store extended attributes, like GNU tar writes them
+#[cfg(unix)]
+const XATTR_PAX_PREFIX: &[u8] = b"SCHILY.xattr.";
+
+/// Set the extended attributes stored in the PAX records of `file` on the file extracted at
+/// `file_path`, with `--xattrs`.
+///
+/// The ones that can't be set, like the SELinux labels of another system without being root, are
+/// skipped with a warning, the file itself was extracted.
+#[cfg(unix)]
+fn restore_xattrs(file: &mut tar::Entry<impl Read>, file_path: &Path, options: &UnpackOptions) {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    if !options.xattrs {
+        return;
+    }
+    let Ok(Some(extensions)) = file.pax_extensions() else {
+        return;
+    };
+    for extension in extensions.flatten() {
+        let Some(name) = extension.key_bytes().strip_prefix(XATTR_PAX_PREFIX) else {
+            continue;
+        };
+        let name = OsStr::from_bytes(name);
+        if let Err(err) = xattr::set(file_path, name, extension.value_bytes()) {
+            warning(format!(
+                "Could not restore the extended attribute '{}' of '{}': {err}.",
+                name.to_string_lossy(),
+                EscapedPathDisplay::new(file_path)
+            ));
+        }
+    }
+}
+
+/// Extended attributes are only restored on Unix.
+#[cfg(not(unix))]
+fn restore_xattrs(_file: &mut tar::Entry<impl Read>, _file_path: &Path, _options: &UnpackOptions) {}
+
+/// A sparse file stored with the PAX headers that GNU tar writes with `--format=posix`.
+///
+/// The tar crate only recognizes the older GNU sparse entries, and would extract these as a
+/// regular file with a mangled name, containing the map of the blocks followed by their data.
+struct PaxSparseFile {
+    /// The real path of the file
+    path: PathBuf,
+    /// The real size of the file, holes included
+    size: u64,
+    /// Offset and length of every block of data, `None` for version 1.0, which stores the map
+    /// before the data instead of in the headers
+    map: Option<Vec<u64>>,
+}
+
+impl PaxSparseFile {
+    fn from_entry(file: &mut tar::Entry<impl Read>) -> crate::Result<Option<Self>> {
+        let Some(extensions) = file.pax_extensions()? else {
+            return Ok(None);
+        };
+
+        let (mut path, mut size, mut major) = (None, None, None);
+        let mut map = vec![];
+        for extension in extensions {
+            let extension = extension?;
+            let (Ok(key), Ok(value)) = (extension.key(), extension.value()) else {
+                continue;
+            };
+            match key {
+                "GNU.sparse.name" => path = Some(PathBuf::from(value)),
+                "GNU.sparse.realsize" | "GNU.sparse.size" => size = Some(parse_sparse_number(value)?),
+                "GNU.sparse.major" => major = Some(parse_sparse_number(value)?),
+                // Version 0.1 lists every block in a single key
+                "GNU.sparse.map" => {
+                    for number in value.split(',') {
+                        map.push(parse_sparse_number(number)?);
+                    }
+                }
+                // Version 0.0 repeats these keys for every block
+                "GNU.sparse.offset" | "GNU.sparse.numbytes" => map.push(parse_sparse_number(value)?),
+                _ => {}
+            }
+        }
+
+        let Some(size) = size else {
+            return Ok(None);
+        };
+        let path = match path {
+            Some(path) => path,
+            // Version 0.0 keeps the real path in the header
+            None if !map.is_empty() => file.path()?.into_owned(),
+            None => return Ok(None),
+        };
+        let map = (major != Some(1)).then_some(map);
+        Ok(Some(Self { path, size, map }))
+    }
+
+    /// Write the blocks of data of `file` to `file_path`, leaving holes between them.
+    fn unpack(&self, file: &mut tar::Entry<impl Read>, file_path: &Path, options: &UnpackOptions) -> crate::Result<()> {
+        let map = match &self.map {
+            Some(map) => Cow::Borrowed(map),
+            None => Cow::Owned(read_sparse_map(file)?),
+        };
+        if map.len() % 2 != 0 {
+            return Err(sparse_error(file_path, "Its map of blocks is incomplete"));
+        }
+
+        let write_error = |err| utils::io::entry_write_error(err, file_path, file_path, Some(self.size));
+        let mut output = SparseWriter::new(
+            buf_writer(fs::File::create(utils::long_path(file_path))?),
+            options.sparse,
+        );
+        for block in map.chunks_exact(2) {
+            let (offset, length) = (block[0], block[1]);
+            let Some(hole) = offset.checked_sub(output.size()) else {
+                return Err(sparse_error(file_path, "Its map of blocks isn't in order"));
+            };
+            output.hole(hole).map_err(write_error)?;
+            let copied = io::copy(&mut file.take(length), &mut output).map_err(write_error)?;
+            if copied != length {
+                return Err(sparse_error(file_path, "It has less data than its map of blocks lists"));
+            }
+        }
+        output
+            .hole(self.size.saturating_sub(output.size()))
+            .map_err(write_error)?;
+        output.finish().map_err(write_error)?;
+
+        set_file_metadata(file, file_path, options)
+    }
+}
+
+/// Read the map of a version 1.0 sparse file, which precedes its data: the number of blocks,
+/// then the offset and length of each, as decimal numbers on their own lines, padded to 512 bytes
+fn read_sparse_map(reader: &mut impl Read) -> crate::Result<Vec<u64>> {
+    let mut consumed = 0;
+    let mut read_number = || -> crate::Result<u64> {
+        // The data that follows must not be consumed, so the map is read a byte at a time
+        let mut digits = String::new();
+        let mut byte = [0];
+        loop {
+            reader.read_exact(&mut byte)?;
+            consumed += 1;
+            match byte[0] {
+                b'\n' => return parse_sparse_number(&digits),
+                byte => digits.push(byte as char),
+            }
+        }
+    };
+
+    let blocks = read_number()?;
+    let mut map = vec![];
+    for _ in 0..blocks.saturating_mul(2) {
+        map.push(read_number()?);
+    }
+
+    let padding = (512 - consumed % 512) % 512;
+    io::copy(&mut reader.take(padding), &mut io::sink())?;
+    Ok(map)
+}
+
+fn parse_sparse_number(number: &str) -> crate::Result<u64> {
+    number.trim().parse().map_err(|_| {
+        FinalError::with_title("Failed to read a sparse file.")
+            .detail(format!("'{number}' isn't a valid number in its map of blocks"))
+            .into()
+    })
+}
+
+fn sparse_error(file_path: &Path, reason: &'static str) -> crate::Error {
+    FinalError::with_title(format!(
+        "Failed to extract the sparse file '{}'.",
+        EscapedPathDisplay::new(file_path)
+    ))
+    .detail(reason)
+    .into()
+}
+
 /// List contents of `archive`, returning a vector of archive entries
 pub fn list_archive(
     mut archive: tar::Archive<impl Read + Send + 'static>,
@@ -64,57 +592,159 @@ pub fn list_archive(
         }
     }
 
-    let (tx, rx) = mpsc::channel();
+    let (tx, rx) = mpsc::sync_channel(ENTRIES_AHEAD);
     thread::spawn(move || {
         for file in archive.entries().expect("entries is only used once") {
             let file_in_archive = (|| {
-                let file = file?;
-                let path = file.path()?.into_owned();
+                let mut file = file?;
+                let (path, size) = match PaxSparseFile::from_entry(&mut file)? {
+                    Some(sparse_file) => (sparse_file.path, sparse_file.size),
+                    None => (file.path()?.into_owned(), file.size()),
+                };
                 let is_dir = file.header().entry_type().is_dir();
-                Ok(FileInArchive { path, is_dir })
+                Ok(FileInArchive {
+                    path,
+                    is_dir,
+                    is_symlink: file.header().entry_type().is_symlink(),
+                    mode: file.header().mode().ok(),
+                    size: Some(size),
+                    compressed_size: None,
+                    method: None,
+                    duplicate: None,
+                })
             })();
-            tx.send(file_in_archive).unwrap();
+            // The listing stopped
+            if tx.send(file_in_archive).is_err() {
+                break;
+            }
         }
     });
 
     Files(rx)
 }
 
+/// Calls `visit` with the metadata and contents of every entry of the archive read from `reader`.
+pub fn for_each_entry(
+    reader: Box<dyn Read>,
+    mut visit: impl FnMut(EntryMetadata, &mut dyn Read) -> crate::Result<()>,
+) -> crate::Result<()> {
+    let mut archive = tar::Archive::new(reader);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let header = entry.header();
+        let metadata = EntryMetadata {
+            path: entry.path()?.into_owned(),
+            is_dir: header.entry_type().is_dir(),
+            size: entry.size(),
+            mode: header.mode().ok(),
+            mtime: header.mtime().ok().map(|mtime| mtime as i64),
+            link_target: match header.entry_type() {
+                tar::EntryType::Symlink => entry.link_name()?.map(Cow::into_owned),
+                _ => None,
+            },
+        };
+
+        visit(metadata, &mut entry)?;
+    }
+
+    Ok(())
+}
+
+/// Copies the entries of the archive read from `reader` into `builder`, skipping the ones
+/// rejected by `keep_entry`, which receives each entry path and modification time.
+pub fn copy_entries<W>(
+    reader: Box<dyn Read>,
+    builder: &mut tar::Builder<W>,
+    mut keep_entry: impl FnMut(&Path, Option<u64>) -> bool,
+) -> crate::Result<()>
+where
+    W: Write,
+{
+    let mut archive = tar::Archive::new(reader);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+
+        if keep_entry(&path, entry.header().mtime().ok()) {
+            // Paths that don't fit in the header are written again as extensions by `append_data`
+            let mut header = entry.header().clone();
+            builder.append_data(&mut header, &path, &mut entry)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Appends an entry read from another archive, described by `metadata`, to `builder`.
+pub fn append_entry<W>(
+    builder: &mut tar::Builder<W>,
+    metadata: &EntryMetadata,
+    contents: &mut dyn Read,
+) -> crate::Result<()>
+where
+    W: Write,
+{
+    let mut header = tar::Header::new_gnu();
+    let default_mode = if metadata.is_dir { 0o755 } else { 0o644 };
+    header.set_mode(metadata.mode.unwrap_or(default_mode) & 0o7777);
+    header.set_mtime(metadata.mtime.unwrap_or_default().max(0) as u64);
+
+    if let Some(target) = &metadata.link_target {
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder.append_link(&mut header, &metadata.path, target)?;
+    } else if metadata.is_dir {
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        builder.append_data(&mut header, &metadata.path, io::empty())?;
+    } else {
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(metadata.size);
+        builder.append_data(&mut header, &metadata.path, contents)?;
+    }
+
+    Ok(())
+}
+
+/// Files from this size on are copied into the archive by the kernel, when it's written straight
+/// to a file, smaller ones aren't worth flushing the writer for
+const KERNEL_COPY_MIN_SIZE: u64 = 64 * 1024;
+
 /// Compresses the archives given by `input_filenames` into the file given previously to `writer`.
+///
+/// `output_file` is that file if `writer` only buffers what's written to it, so that the contents
+/// of big files can be copied to it by the kernel, see [`utils::copy_file_range`].
 pub fn build_archive_from_paths<W>(
     input_filenames: &[PathBuf],
-    output_path: &Path,
+    output: &ArchiveOutput,
     writer: W,
+    output_file: Option<&fs::File>,
     file_visibility_policy: FileVisibilityPolicy,
     quiet: bool,
+    options: &PackOptions,
 ) -> crate::Result<W>
 where
     W: Write,
 {
     let mut builder = tar::Builder::new(writer);
-    let output_handle = Handle::from_path(output_path);
 
     for filename in input_filenames {
-        let previous_location = utils::cd_into_same_dir_as(filename)?;
-
-        // Unwrap safety:
-        //   paths should be canonicalized by now, and the root directory rejected.
-        let filename = filename.file_name().unwrap();
+        let input_parent = filename.parent().ok_or(crate::Error::CompressingRootFolder)?;
 
         for entry in file_visibility_policy.build_walker(filename) {
+            check_interrupted()?;
             let entry = entry?;
-            let path = entry.path();
+            let file_path = entry.path();
+            // Relative to the directory containing the input, like it's shown to the user
+            let path = file_path
+                .strip_prefix(input_parent)
+                .expect("walked paths start with the input");
 
-            // If the output_path is the same as the input file, warn the user and skip the input (in order to avoid compression recursion)
-            if let Ok(handle) = &output_handle {
-                if matches!(Handle::from_path(path), Ok(x) if &x == handle) {
-                    warning(format!(
-                        "The output file and the input file are the same: `{}`, skipping...",
-                        output_path.display()
-                    ));
-
-                    continue;
-                }
+            // Never compress the output into itself
+            if output.is_output(file_path) {
+                continue;
             }
 
             // This is printed for every file in `input_filenames` and has
@@ -125,13 +755,36 @@ where
                 info(format!("Compressing '{}'.", EscapedPathDisplay::new(path)));
             }
 
-            if path.is_dir() {
-                builder.append_dir(path, path)?;
+            let entry_name = options.entry_name(input_parent, path)?;
+
+            // The input directory itself has no entry
+            if entry_name.as_os_str().is_empty() {
+                continue;
+            }
+
+            if let Some(mut header) = special_file_header(file_path)? {
+                if options.xattrs {
+                    append_xattrs(&mut builder, file_path)?;
+                }
+                if entry_name.has_root() {
+                    append_absolute(&mut builder, header, &entry_name, io::empty())?;
+                } else {
+                    builder.append_data(&mut header, entry_name, io::empty())?;
+                }
+            } else if file_path.is_dir() {
+                if options.xattrs {
+                    append_xattrs(&mut builder, file_path)?;
+                }
+                if entry_name.has_root() {
+                    append_absolute(&mut builder, metadata_header(file_path)?, &entry_name, io::empty())?;
+                } else {
+                    builder.append_dir(entry_name, file_path)?;
+                }
             } else {
-                let mut file = match fs::File::open(path) {
+                let file = match fs::File::open(utils::long_path(file_path)) {
                     Ok(f) => f,
                     Err(e) => {
-                        if e.kind() == std::io::ErrorKind::NotFound && utils::is_symlink(path) {
+                        if e.kind() == std::io::ErrorKind::NotFound && utils::is_symlink(file_path) {
                             // This path is for a broken symlink
                             // We just ignore it
                             continue;
@@ -139,15 +792,268 @@ where
                         return Err(e.into());
                     }
                 };
-                builder.append_file(path, file.file_mut()).map_err(|err| {
-                    FinalError::with_title("Could not create archive")
-                        .detail("Unexpected error while trying to read file")
-                        .detail(format!("Error: {err}."))
-                })?;
+                if options.xattrs {
+                    append_xattrs(&mut builder, file_path)?;
+                }
+                if entry_name.has_root() {
+                    let header = metadata_header(file_path)?;
+                    append_absolute(&mut builder, header, &entry_name, buf_reader(ProgressReader(file)))?;
+                } else {
+                    let mut header = tar::Header::new_gnu();
+                    header.set_metadata(&file.metadata()?);
+                    match output_file {
+                        Some(output_file)
+                            if header.entry_type().is_file() && header.size()? >= KERNEL_COPY_MIN_SIZE =>
+                        {
+                            append_copied_by_kernel(&mut builder, &mut header, &entry_name, file, output_file)?;
+                        }
+                        _ => {
+                            builder
+                                .append_data(&mut header, entry_name, buf_reader(ProgressReader(file)))
+                                .map_err(|err| {
+                                    FinalError::with_title("Could not create archive")
+                                        .detail("Unexpected error while trying to read file")
+                                        .detail(format!("Error: {err}."))
+                                })?;
+                        }
+                    }
+                }
             }
         }
-        env::set_current_dir(previous_location)?;
     }
 
     Ok(builder.into_inner()?)
 }
+
+/// Header of the entry for the FIFO or device file at `path`, which is stored with its device
+/// numbers instead of its contents, or `None` if it's another kind of file.
+#[cfg(unix)]
+#[allow(unused_unsafe, clippy::unnecessary_cast)] // `major` and `minor` are safe and return i32 on some systems
+fn special_file_header(path: &Path) -> crate::Result<Option<tar::Header>> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    // Broken symlinks are skipped like the other files
+    let Ok(metadata) = fs::metadata(path) else {
+        return Ok(None);
+    };
+    if !utils::is_special_file(&metadata) {
+        return Ok(None);
+    }
+
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata(&metadata);
+    header.set_size(0);
+    if !metadata.file_type().is_fifo() {
+        let device = metadata.rdev() as libc::dev_t;
+        // SAFETY: these only extract bits of `device`
+        let (major, minor) = unsafe { (libc::major(device), libc::minor(device)) };
+        header.set_device_major(major as u32)?;
+        header.set_device_minor(minor as u32)?;
+    }
+    Ok(Some(header))
+}
+
+/// There are no FIFOs or device files outside of Unix.
+#[cfg(not(unix))]
+fn special_file_header(_path: &Path) -> crate::Result<Option<tar::Header>> {
+    Ok(None)
+}
+
+/// Append the extended attributes of the file at `path` as PAX records, which apply to the entry
+/// appended right after them, with `--xattrs`.
+#[cfg(unix)]
+fn append_xattrs<W>(builder: &mut tar::Builder<W>, path: &Path) -> crate::Result<()>
+where
+    W: Write,
+{
+    use std::os::unix::ffi::OsStrExt;
+
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        // Like on filesystems without extended attributes
+        Err(err) if err.kind() == io::ErrorKind::Unsupported => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    let mut records = vec![];
+    for name in names {
+        let Some(value) = xattr::get(path, &name)? else {
+            continue;
+        };
+        let key = [XATTR_PAX_PREFIX, name.as_bytes()].concat();
+        // Each record is 'LENGTH KEY=VALUE\n', where its length counts its own digits
+        let rest = key.len() + value.len() + 3;
+        let mut length = rest + 1;
+        while length != rest + length.to_string().len() {
+            length = rest + length.to_string().len();
+        }
+        records.extend_from_slice(format!("{length} ").as_bytes());
+        records.extend_from_slice(&key);
+        records.push(b'=');
+        records.extend_from_slice(&value);
+        records.push(b'\n');
+    }
+    if records.is_empty() {
+        return Ok(());
+    }
+
+    let mut header = tar::Header::new_ustar();
+    header.as_ustar_mut().expect("it's a ustar header").name[..14].copy_from_slice(b"././@PaxHeader");
+    header.set_mode(0o644);
+    header.set_mtime(0);
+    header.set_size(records.len() as u64);
+    header.set_entry_type(tar::EntryType::XHeader);
+    header.set_cksum();
+    builder.append(&header, records.as_slice())?;
+    Ok(())
+}
+
+/// Extended attributes are only stored on Unix.
+#[cfg(not(unix))]
+fn append_xattrs<W>(_builder: &mut tar::Builder<W>, _path: &Path) -> crate::Result<()>
+where
+    W: Write,
+{
+    Ok(())
+}
+
+/// Append `file` like `append_data` does, but with its contents copied by the kernel to
+/// `output_file`, which `builder` writes to, falling back to a buffer where it can't.
+fn append_copied_by_kernel<W>(
+    builder: &mut tar::Builder<W>,
+    header: &mut tar::Header,
+    entry_name: &Path,
+    mut file: fs::File,
+    output_file: &fs::File,
+) -> crate::Result<()>
+where
+    W: Write,
+{
+    let size = header.size()?;
+    // Only writes the header, and the entry holding its long name if there's one
+    builder.append_data(header, entry_name, io::empty())?;
+    let writer = builder.get_mut();
+    writer.flush()?;
+
+    let copied = utils::copy_file_range(file.file(), output_file.file(), size);
+    utils::progress::advance(copied);
+    let rest = io::copy(&mut ProgressReader(&mut file).take(size - copied), writer)?;
+    if copied + rest != size {
+        let error = FinalError::with_title("Could not create archive")
+            .detail(format!(
+                "'{}' was truncated while being archived",
+                EscapedPathDisplay::new(file.path())
+            ))
+            .hint("Compress it again once nothing else writes to it");
+        return Err(error.into());
+    }
+
+    // Padded to a whole block, like `append_data` does
+    let padding = (512 - size % 512) % 512;
+    writer.write_all(&[0; 512][..padding as usize])?;
+    Ok(())
+}
+
+/// Header with the metadata of the file at `path`, for [`append_absolute`].
+fn metadata_header(path: &Path) -> crate::Result<tar::Header> {
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata(&fs::metadata(path)?);
+    Ok(header)
+}
+
+/// Appends the file with `header` under an absolute `entry_name`, which `tar::Builder` refuses, so
+/// its name is written by hand.
+///
+/// Like `tar::Builder` does for long relative paths, names that don't fit in the header are
+/// stored in a GNU long name entry right before it.
+fn append_absolute<W>(
+    builder: &mut tar::Builder<W>,
+    mut header: tar::Header,
+    entry_name: &Path,
+    data: impl Read,
+) -> crate::Result<()>
+where
+    W: Write,
+{
+    #[cfg(unix)]
+    let name = {
+        use std::os::unix::ffi::OsStrExt;
+        entry_name.as_os_str().as_bytes().to_vec()
+    };
+    #[cfg(not(unix))]
+    let name = entry_name.to_string_lossy().replace('\\', "/").into_bytes();
+
+    let name_field = &mut header.as_old_mut().name;
+    if name.len() > name_field.len() {
+        let mut long_name = tar::Header::new_gnu();
+        long_name.as_old_mut().name[..13].copy_from_slice(b"././@LongLink");
+        long_name.set_mode(0o644);
+        long_name.set_uid(0);
+        long_name.set_gid(0);
+        long_name.set_mtime(0);
+        // The trailing nul is expected by GNU tar
+        long_name.set_size(name.len() as u64 + 1);
+        long_name.set_entry_type(tar::EntryType::GNULongName);
+        long_name.set_cksum();
+        builder.append(&long_name, name.as_slice().chain(&[0][..]))?;
+    }
+
+    let len = name.len().min(name_field.len());
+    name_field[..len].copy_from_slice(&name[..len]);
+    header.set_cksum();
+    builder.append(&header, data)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::*;
+
+    /// Counts the bytes read from the archive
+    struct CountingReader(Cursor<Vec<u8>>, Arc<AtomicUsize>);
+
+    impl Read for CountingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let read = self.0.read(buf)?;
+            self.1.fetch_add(read, Ordering::SeqCst);
+            Ok(read)
+        }
+    }
+
+    #[test]
+    fn test_list_archive_streams() {
+        const ENTRIES: usize = 20 * ENTRIES_AHEAD;
+
+        let mut builder = tar::Builder::new(Vec::new());
+        for i in 0..ENTRIES {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(0);
+            builder
+                .append_data(&mut header, format!("dir/file{i}"), io::empty())
+                .unwrap();
+        }
+        let archive = builder.into_inner().unwrap();
+        let archive_len = archive.len();
+
+        let read = Arc::new(AtomicUsize::new(0));
+        let reader = CountingReader(Cursor::new(archive), read.clone());
+        let mut files = list_archive(tar::Archive::new(reader));
+
+        // The first entry comes before the archive is read, which is only read as far ahead as
+        // the entries waiting to be listed, however long it's left waiting
+        assert_eq!(files.next().unwrap().unwrap().path, Path::new("dir/file0"));
+        thread::sleep(Duration::from_millis(200));
+        assert!(read.load(Ordering::SeqCst) < archive_len / 4);
+
+        assert_eq!(files.count(), ENTRIES - 1);
+    }
+}
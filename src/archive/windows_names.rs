@@ -0,0 +1,174 @@
+//! Escaping of the entry names that can't be used as file names on Windows, like `con`, `aux.txt`
+//! or `notes.`, which are common in archives made on other systems.
+//!
+//! The characters that make a name invalid are percent-encoded, along with every '%' of that
+//! name, so `what?` is extracted as `what%3F`. Only names that need it are escaped, and names
+//! are only unescaped when compressing if they're exactly what escaping their original would
+//! give, so other names with a '%' are left untouched both ways.
+
+use std::{
+    ffi::OsStr,
+    path::{Component, Path, PathBuf},
+};
+
+/// Characters that NTFS doesn't allow in file names, besides the control characters.
+const INVALID_CHARS: &[char] = &['<', '>', ':', '"', '|', '?', '*'];
+
+/// Whether `name` is one of the names that Windows reserves for devices, which open the device
+/// instead of a file, even with an extension, like 'NUL.txt'.
+fn is_device_name(name: &OsStr) -> bool {
+    let name = name.to_string_lossy();
+    let upper = device_stem(&name).to_ascii_uppercase();
+    matches!(upper.as_str(), "CON" | "PRN" | "AUX" | "NUL" | "CONIN$" | "CONOUT$")
+        || (upper.len() == 4
+            && (upper.starts_with("COM") || upper.starts_with("LPT"))
+            && matches!(upper.as_bytes()[3], b'1'..=b'9'))
+}
+
+/// The part of `name` that Windows compares with the device names, before its extension.
+fn device_stem(name: &str) -> &str {
+    name.split('.').next().unwrap_or_default().trim_end_matches(' ')
+}
+
+fn is_invalid_char(char: char) -> bool {
+    INVALID_CHARS.contains(&char) || char.is_ascii_control()
+}
+
+/// Whether `name` can't be used as a file name on Windows.
+fn needs_escape(name: &str) -> bool {
+    is_device_name(OsStr::new(name)) || name.contains(is_invalid_char) || name.ends_with(['.', ' '])
+}
+
+/// `name` escaped to be a valid file name on Windows, or `None` if it already is one.
+fn escape(name: &str) -> Option<String> {
+    if !needs_escape(name) {
+        return None;
+    }
+
+    // Escaping its last character is enough for a name to stop being a device, or to stop ending
+    // with a dot or space
+    let device_char = is_device_name(OsStr::new(name)).then(|| device_stem(name).len() - 1);
+    let last_char = name.ends_with(['.', ' ']).then(|| name.len() - 1);
+
+    let mut escaped = String::with_capacity(name.len() + 8);
+    for (index, char) in name.char_indices() {
+        if char == '%' || is_invalid_char(char) || Some(index) == device_char || Some(index) == last_char {
+            // All of these are ASCII characters
+            escaped.push_str(&format!("%{:02X}", char as u32));
+        } else {
+            escaped.push(char);
+        }
+    }
+    Some(escaped)
+}
+
+/// The original name of `name` if it was given by [`escape`], or `None` otherwise.
+fn unescape(name: &str) -> Option<String> {
+    if !name.contains('%') {
+        return None;
+    }
+
+    let mut unescaped = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(position) = rest.find('%') {
+        unescaped.push_str(&rest[..position]);
+        let byte = rest
+            .get(position + 1..position + 3)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .filter(u8::is_ascii)?;
+        unescaped.push(byte as char);
+        rest = &rest[position + 3..];
+    }
+    unescaped.push_str(rest);
+
+    (escape(&unescaped).as_deref() == Some(name)).then_some(unescaped)
+}
+
+/// `path` with the names of its components escaped to be valid on Windows, or `None` if they
+/// already are.
+pub fn escape_path(path: &Path) -> Option<PathBuf> {
+    map_names(path, escape)
+}
+
+/// `path` with the names escaped by [`escape_path`] back to their original, or `None` if it has
+/// no such name.
+pub fn unescape_path(path: &Path) -> Option<PathBuf> {
+    map_names(path, unescape)
+}
+
+fn map_names(path: &Path, map: impl Fn(&str) -> Option<String>) -> Option<PathBuf> {
+    let mut changed = false;
+    let mut mapped = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => match name.to_str().and_then(&map) {
+                Some(name) => {
+                    changed = true;
+                    mapped.push(name);
+                }
+                None => mapped.push(name),
+            },
+            component => mapped.push(component),
+        }
+    }
+    changed.then_some(mapped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("file.txt"), None);
+        assert_eq!(escape("100%.txt"), None);
+        assert_eq!(escape("console"), None);
+        assert_eq!(escape("con").as_deref(), Some("co%6E"));
+        assert_eq!(escape("AUX.txt").as_deref(), Some("AU%58.txt"));
+        assert_eq!(escape("com1.tar.gz").as_deref(), Some("com%31.tar.gz"));
+        assert_eq!(escape("nul .txt").as_deref(), Some("nu%6C .txt"));
+        assert_eq!(escape("what?").as_deref(), Some("what%3F"));
+        assert_eq!(escape("a<b>:c\"d|e*").as_deref(), Some("a%3Cb%3E%3Ac%22d%7Ce%2A"));
+        assert_eq!(escape("tab\there").as_deref(), Some("tab%09here"));
+        assert_eq!(escape("notes.").as_deref(), Some("notes%2E"));
+        assert_eq!(escape("notes ").as_deref(), Some("notes%20"));
+        assert_eq!(escape("50%?").as_deref(), Some("50%25%3F"));
+    }
+
+    #[test]
+    fn test_unescape() {
+        for name in [
+            "con",
+            "AUX.txt",
+            "nul .txt",
+            "what?",
+            "a<b>:c\"d|e*",
+            "notes.",
+            "notes ",
+            "50%?",
+        ] {
+            assert_eq!(unescape(&escape(name).unwrap()).as_deref(), Some(name));
+        }
+
+        // Names that escaping doesn't give are left as they are
+        assert_eq!(unescape("100%.txt"), None);
+        assert_eq!(unescape("100%25.txt"), None);
+        assert_eq!(unescape("what%3f"), None);
+        assert_eq!(unescape("a%2Fb"), None);
+        assert_eq!(unescape("%"), None);
+    }
+
+    #[test]
+    fn test_escape_path() {
+        assert_eq!(escape_path(Path::new("dir/file")), None);
+        assert_eq!(
+            escape_path(Path::new("aux/dir/what?")),
+            Some(PathBuf::from("au%78/dir/what%3F"))
+        );
+        assert_eq!(
+            unescape_path(Path::new("au%78/dir/what%3F")),
+            Some(PathBuf::from("aux/dir/what?"))
+        );
+        assert_eq!(unescape_path(Path::new("dir/100%25")), None);
+    }
+}
@@ -3,92 +3,636 @@
 #[cfg(unix)]
 use std::os::unix::fs::PermissionsExt;
 use std::{
-    env,
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt,
     io::{self, prelude::*},
     path::{Path, PathBuf},
-    sync::mpsc,
+    sync::{mpsc, OnceLock},
     thread,
 };
 
-use filetime_creation::{set_file_mtime, FileTime};
+use encoding_rs::Encoding;
+use filetime_creation::FileTime;
 use fs_err as fs;
-use same_file::Handle;
+use rayon::prelude::*;
 use time::OffsetDateTime;
-use zip::{read::ZipFile, DateTime, ZipArchive};
+use zip::{read::ZipFile, DateTime, ZipArchive, ZipWriter};
 
 use crate::{
+    archive::{
+        can_write_entry, limits::ExtractionBudget, ArchiveOutput, EntryMetadata, Flattener, PackOptions, UnpackOptions,
+    },
     error::FinalError,
-    list::FileInArchive,
+    extension::CompressionFormat,
+    list::{FileInArchive, ENTRIES_AHEAD},
     utils::{
-        self, cd_into_same_dir_as, get_invalid_utf8_paths,
-        logger::{info, info_accessible, warning},
-        pretty_format_list_of_paths, strip_cur_dir, Bytes, EscapedPathDisplay, FileVisibilityPolicy,
+        self, get_invalid_utf8_paths,
+        interrupt::check_interrupted,
+        io::{buf_reader, buf_writer, SharedFileReader},
+        logger::{info, info_accessible, print_error, warning},
+        pretty_format_list_of_paths,
+        progress::ProgressReader,
+        strip_cur_dir, Bytes, EscapedPathDisplay, FileVisibilityPolicy, SparseWriter,
     },
+    QuestionPolicy,
 };
 
+/// An entry whose contents don't match the CRC32 recorded for it in the archive
+#[derive(Debug, Clone)]
+pub struct ChecksumMismatch {
+    /// Path of the entry in the archive
+    pub entry: PathBuf,
+    pub expected: u32,
+    pub computed: u32,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "'{}' has the CRC32 {:08x}, but the archive records {:08x}",
+            EscapedPathDisplay::new(&self.entry),
+            self.computed,
+            self.expected
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+impl ChecksumMismatch {
+    /// The mismatch that `err` was made from by [`ChecksumReader`], if it was.
+    fn from_io_error(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+
+    /// Error for the extraction of an archive that stopped at this entry.
+    fn into_error(self) -> crate::Error {
+        let entry = EscapedPathDisplay::new(&self.entry).to_string();
+        let error = FinalError::with_title(format!("The entry '{entry}' doesn't match its checksum."))
+            .detail(format!(
+                "Its CRC32 is {:08x}, but the archive records {:08x}, it's corrupted",
+                self.computed, self.expected
+            ))
+            .detail("The partially extracted files were removed")
+            .hint("Download or copy the archive again, or extract its other entries with '--continue-on-error'");
+        crate::Error::CorruptArchive { reason: error }
+    }
+}
+
+/// Reader of the contents of an entry that computes their CRC32, so that when the zip crate finds
+/// that it doesn't match the recorded one, its error tells which entry it was and both values.
+struct ChecksumReader<R> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+    read: u64,
+    entry: PathBuf,
+    expected: u32,
+    size: u64,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    /// Reader of `inner`, the contents of the entry named `entry_name` in the archive
+    fn new(inner: R, entry_name: &str, file_crc32: u32, file_size: u64) -> Self {
+        Self {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+            read: 0,
+            entry: PathBuf::from(entry_name),
+            expected: file_crc32,
+            size: file_size,
+        }
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner.read(buf) {
+            Ok(read) => {
+                self.hasher.update(&buf[..read]);
+                self.read += read as u64;
+                Ok(read)
+            }
+            // The zip crate checks the CRC32 once all of the contents were read
+            Err(_) if self.read == self.size && self.hasher.clone().finalize() != self.expected => {
+                let mismatch = ChecksumMismatch {
+                    entry: self.entry.clone(),
+                    expected: self.expected,
+                    computed: self.hasher.clone().finalize(),
+                };
+                Err(io::Error::new(io::ErrorKind::InvalidData, mismatch))
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Set by `--zip-encoding`, the names of the entries that aren't marked as UTF-8 are decoded with
+/// it, instead of with CP437 like the zip crate does, as the zip specification says
+static NAME_ENCODING: OnceLock<&'static Encoding> = OnceLock::new();
+
+/// Decode the names of the entries that aren't marked as UTF-8 with the encoding named `label`,
+/// like 'shift_jis' or 'windows-1252', from now on.
+pub fn set_name_encoding(label: &str) -> crate::Result<()> {
+    if ["cp437", "ibm437", "437"].contains(&label.to_ascii_lowercase().as_str()) {
+        return Ok(());
+    }
+    let Some(encoding) = Encoding::for_label(label.as_bytes()) else {
+        let error = FinalError::with_title(format!("Unknown zip encoding '{label}'."))
+            .detail("It's not one of the encodings of the WHATWG Encoding Standard, or 'cp437'")
+            .hint("Use a name like 'shift_jis', 'gbk', 'euc-kr' or 'windows-1252'");
+        return Err(error.into());
+    };
+    let _ = NAME_ENCODING.set(encoding);
+    Ok(())
+}
+
+/// Name of the entry, from its Info-ZIP Unicode Path extra field if it has one, or decoded with
+/// the encoding set by `set_name_encoding` unless it's marked as UTF-8.
+fn entry_name<'a>(file: &'a ZipFile) -> Cow<'a, str> {
+    if let Some(name) = unicode_path_name(file) {
+        return Cow::Owned(name);
+    }
+    match NAME_ENCODING.get() {
+        // The zip crate decodes the other names as CP437, which only leaves the ASCII ones, and
+        // the UTF-8 ones, as they were
+        Some(encoding) if file.name().as_bytes() != file.name_raw() => {
+            encoding.decode_without_bom_handling(file.name_raw()).0
+        }
+        _ => Cow::Borrowed(file.name()),
+    }
+}
+
+/// The UTF-8 name of the Info-ZIP Unicode Path extra field of `file`, which archivers that write
+/// names in the local encoding may add, only if it was made for the name it still has.
+fn unicode_path_name(file: &ZipFile) -> Option<String> {
+    const UNICODE_PATH_ID: u16 = 0x7075;
+
+    let mut extra = file.extra_data();
+    while extra.len() >= 4 {
+        let id = u16::from_le_bytes([extra[0], extra[1]]);
+        let size = usize::from(u16::from_le_bytes([extra[2], extra[3]]));
+        let data = extra.get(4..4 + size)?;
+        extra = &extra[4 + size..];
+
+        // A version, the CRC-32 of the name it was made for, and the name
+        if id != UNICODE_PATH_ID || data.len() < 5 || data[0] != 1 {
+            continue;
+        }
+        let mut crc = flate2::Crc::new();
+        crc.update(file.name_raw());
+        if crc.sum().to_le_bytes() != data[1..5] {
+            // The name was changed by another tool since
+            return None;
+        }
+        return String::from_utf8(data[5..].to_vec()).ok();
+    }
+    None
+}
+
+/// Whether a zip archive with `formats`, in compression order, can be read in place from its
+/// file, seeking to the entries listed in its central directory.
+///
+/// Otherwise it's wrapped in other formats, which can't seek, so it has to be decompressed into a
+/// buffer first.
+pub fn is_read_in_place(formats: &[CompressionFormat]) -> bool {
+    formats == [CompressionFormat::Zip]
+}
+
+/// Open the zip archive at `archive_path` to be read in place, only the entries that are read are
+/// loaded, through a buffer of `buffer_size`, however large the archive is.
+///
+/// Its clones read the file on their own, to extract its entries in parallel.
+pub fn open_archive(archive_path: &Path) -> crate::Result<ZipArchive<ProgressReader<SharedFileReader>>> {
+    let (file, _) = fs::File::open(archive_path)?.into_parts();
+    Ok(ZipArchive::new(ProgressReader(SharedFileReader::new(file)))?)
+}
+
 /// Unpacks the archive given by `archive` into the folder given by `output_folder`.
 /// Assumes that output_folder is empty
-pub fn unpack_archive<R>(mut archive: ZipArchive<R>, output_folder: &Path, quiet: bool) -> crate::Result<usize>
+pub fn unpack_archive<R>(
+    mut archive: ZipArchive<R>,
+    output_folder: &Path,
+    quiet: bool,
+    question_policy: QuestionPolicy,
+    options: &UnpackOptions,
+    budget: &ExtractionBudget,
+) -> crate::Result<usize>
 where
     R: Read + Seek,
 {
     assert!(output_folder.read_dir().expect("dir exists").count() == 0);
 
     let mut unpacked_files = 0;
+    let mut flattener = options
+        .flatten
+        .then(|| Flattener::new(output_folder, options.conflict_policy, question_policy));
+    let skipped = skipped_duplicates(&mut archive, options)?;
 
-    for idx in 0..archive.len() {
-        let mut file = archive.by_index(idx)?;
-        let file_path = match file.enclosed_name() {
-            Some(path) => path.to_owned(),
-            None => continue,
+    for idx in (0..archive.len()).filter(|idx| !skipped.contains(idx)) {
+        check_interrupted()?;
+        let mut file = open_entry(&mut archive, idx, options)?;
+        let Some(file_path) = unpack_path(&file, output_folder, question_policy, options, budget, &mut flattener)?
+        else {
+            continue;
         };
 
-        let file_path = output_folder.join(file_path);
+        if unpack_entry(&mut file, idx, &file_path, quiet, options, budget)? {
+            unpacked_files += 1;
+        }
+    }
 
-        display_zip_comment_if_exists(&file);
+    if let Some(flattener) = flattener {
+        flattener.report_collisions();
+    }
 
-        match file.name().ends_with('/') {
-            _is_dir @ true => {
-                // This is printed for every file in the archive and has little
-                // importance for most users, but would generate lots of
-                // spoken text for users using screen readers, braille displays
-                // and so on
-                if !quiet {
-                    info(format!("File {} extracted to \"{}\"", idx, file_path.display()));
-                }
-                fs::create_dir_all(&file_path)?;
+    Ok(unpacked_files)
+}
+
+/// Same as `unpack_archive`, but the files are extracted in parallel on the rayon pool, each
+/// thread reading the archive through its own clone of it.
+///
+/// Where every entry goes is decided first, on this thread and in order, so questions are asked
+/// one at a time, and the directories are created before any file is extracted into them.
+///
+/// Every entry is attempted even after one fails, so that all the entries that failed are named.
+pub fn unpack_archive_in_parallel<R>(
+    mut archive: ZipArchive<R>,
+    output_folder: &Path,
+    quiet: bool,
+    question_policy: QuestionPolicy,
+    options: &UnpackOptions,
+    budget: &ExtractionBudget,
+) -> crate::Result<usize>
+where
+    R: Read + Seek + Clone + Send + Sync,
+{
+    assert!(output_folder.read_dir().expect("dir exists").count() == 0);
+
+    let mut unpacked_dirs = 0;
+    let mut files = vec![];
+    let mut flattener = options
+        .flatten
+        .then(|| Flattener::new(output_folder, options.conflict_policy, question_policy));
+    let skipped = skipped_duplicates(&mut archive, options)?;
+
+    for idx in (0..archive.len()).filter(|idx| !skipped.contains(idx)) {
+        check_interrupted()?;
+        // Only the metadata is needed here, the contents are decrypted when they are extracted
+        let mut file = archive.by_index_raw(idx)?;
+        let Some(file_path) = unpack_path(&file, output_folder, question_policy, options, budget, &mut flattener)?
+        else {
+            continue;
+        };
+
+        if file.name().ends_with('/') {
+            unpack_entry(&mut file, idx, &file_path, quiet, options, budget)?;
+            unpacked_dirs += 1;
+        } else {
+            if let Some(parent) = file_path.parent() {
+                utils::create_dir_all(parent)?;
             }
-            _is_file @ false => {
-                if let Some(path) = file_path.parent() {
-                    if !path.exists() {
-                        fs::create_dir_all(path)?;
-                    }
+            files.push((idx, file_path));
+        }
+    }
+
+    if let Some(flattener) = flattener {
+        flattener.report_collisions();
+    }
+
+    // A wrong password would fail every entry, so it's checked once before starting
+    if let (Some(_), Some((idx, _))) = (&options.password, files.first()) {
+        open_entry(&mut archive, *idx, options)?;
+    }
+
+    let results: Vec<crate::Result<bool>> = files
+        .par_iter()
+        .map_init(
+            || archive.clone(),
+            |archive, (idx, file_path)| {
+                // Past a limit, the entry that exceeded it already failed
+                if budget.is_exceeded() {
+                    return Ok(false);
                 }
-                let file_path = strip_cur_dir(file_path.as_path());
-
-                // same reason is in _is_dir: long, often not needed text
-                if !quiet {
-                    info(format!(
-                        "{:?} extracted. ({})",
-                        file_path.display(),
-                        Bytes::new(file.size())
-                    ));
+                open_entry(archive, *idx, options)
+                    .and_then(|mut file| unpack_entry(&mut file, *idx, file_path, quiet, options, budget))
+            },
+        )
+        .collect();
+
+    let unpacked_files = results.iter().filter(|result| matches!(result, Ok(true))).count();
+    let (failed_paths, mut errors): (Vec<_>, Vec<_>) = files
+        .iter()
+        .zip(results)
+        .filter_map(|((_, file_path), result)| {
+            // Named as in the archive, the output folder may still be a temporary one
+            let entry = file_path.strip_prefix(output_folder).unwrap_or(file_path);
+            Some((entry, result.err()?))
+        })
+        .unzip();
+    if errors.is_empty() {
+        return Ok(unpacked_dirs + unpacked_files);
+    }
+
+    // Past a limit, the other entries only failed because of it
+    let first_error = errors.remove(0);
+    if !budget.is_exceeded() {
+        for error in &errors {
+            print_error(error);
+        }
+        if !errors.is_empty() {
+            warning(format!(
+                "Failed to extract {} entries: {}.",
+                failed_paths.len(),
+                pretty_format_list_of_paths(&failed_paths)
+            ));
+        }
+    }
+    Err(first_error)
+}
+
+/// Entry `idx` of `archive`, decrypted with the password of `options` if there's one.
+fn open_entry<'a, R>(archive: &'a mut ZipArchive<R>, idx: usize, options: &UnpackOptions) -> crate::Result<ZipFile<'a>>
+where
+    R: Read + Seek,
+{
+    Ok(match &options.password {
+        Some(password) => archive
+            .by_index_decrypt(idx, password.as_bytes())?
+            .map_err(|_| wrong_password(idx))?,
+        None => archive.by_index(idx)?,
+    })
+}
+
+/// Where `file` is unpacked to, or `None` if it's skipped because it's filtered out, would be
+/// unpacked outside of the output folder, was flattened away or the user chose not to overwrite
+/// it.
+fn unpack_path(
+    file: &ZipFile,
+    output_folder: &Path,
+    question_policy: QuestionPolicy,
+    options: &UnpackOptions,
+    budget: &ExtractionBudget,
+    flattener: &mut Option<Flattener>,
+) -> crate::Result<Option<PathBuf>> {
+    // Checked by `entry_path` of the options instead of `enclosed_name`, which would skip the
+    // unsafe paths without a warning
+    let name = entry_name(file);
+    let file_path = Path::new(name.as_ref());
+    if name.contains('\0') || !options.filter.is_match(file_path) {
+        return Ok(None);
+    }
+    budget.count_entry(file_path)?;
+    let Some(file_path) = options.entry_path(file_path)? else {
+        return Ok(None);
+    };
+    if options.no_symlinks && is_symlink(file) {
+        return Ok(None);
+    }
+
+    let file_path = match flattener {
+        Some(flattener) => {
+            if file.is_dir() {
+                return Ok(None);
+            }
+            if is_symlink(file) {
+                flattener.skip_link(&file_path);
+                return Ok(None);
+            }
+            match flattener.file_path(&file_path)? {
+                Some(file_path) => file_path,
+                None => return Ok(None),
+            }
+        }
+        None => {
+            if !can_write_entry(&file_path, file.is_dir(), question_policy)? {
+                return Ok(None);
+            }
+            output_folder.join(file_path)
+        }
+    };
+
+    display_zip_comment_if_exists(file);
+    Ok(Some(file_path))
+}
+
+/// Unpack `file`, the entry number `idx`, to `file_path`.
+///
+/// Returns whether it was unpacked, a file that doesn't match its checksum is removed and fails
+/// the extraction, or only skipped with `--continue-on-error`, see [`ExtractionBudget::add_corrupt_entry`].
+fn unpack_entry(
+    file: &mut ZipFile,
+    idx: usize,
+    file_path: &Path,
+    quiet: bool,
+    options: &UnpackOptions,
+    budget: &ExtractionBudget,
+) -> crate::Result<bool> {
+    match file.name().ends_with('/') {
+        _is_dir @ true => {
+            // This is printed for every file in the archive and has little
+            // importance for most users, but would generate lots of
+            // spoken text for users using screen readers, braille displays
+            // and so on
+            if !quiet {
+                info(format!("File {} extracted to \"{}\"", idx, file_path.display()));
+            }
+            utils::create_dir_all(file_path)?;
+        }
+        _is_file @ false => {
+            if let Some(path) = file_path.parent() {
+                if !path.exists() {
+                    utils::create_dir_all(path)?;
                 }
+            }
+            let file_path = strip_cur_dir(file_path);
 
-                let mut output_file = fs::File::create(file_path)?;
-                io::copy(&mut file, &mut output_file)?;
+            // same reason is in _is_dir: long, often not needed text
+            if !quiet {
+                info(format!(
+                    "{:?} extracted. ({})",
+                    file_path.display(),
+                    Bytes::new(file.size())
+                ));
+            }
 
-                set_last_modified_time(&file, file_path)?;
+            let mut output_file = SparseWriter::new(
+                buf_writer(fs::File::create(utils::long_path(file_path))?),
+                options.sparse,
+            );
+            let (size, compressed_size) = (file.size(), file.compressed_size());
+            let write_error = |err| utils::io::entry_write_error(err, file_path, file_path, Some(size));
+            let (name, crc32) = (entry_name(file).into_owned(), file.crc32());
+            let mut contents =
+                ChecksumReader::new(budget.reader(&mut *file, Some(compressed_size)), &name, crc32, size);
+            if let Err(err) = io::copy(&mut contents, &mut output_file) {
+                let Some(mismatch) = ChecksumMismatch::from_io_error(&err) else {
+                    return Err(write_error(err));
+                };
+                let mismatch = mismatch.clone();
+                drop(output_file);
+                fs::remove_file(utils::long_path(file_path))?;
+                budget.add_corrupt_entry(mismatch.clone());
+                if !options.continue_on_error {
+                    return Err(mismatch.into_error());
+                }
+                warning(format!(
+                    "Skipped '{}', it doesn't match its checksum.",
+                    EscapedPathDisplay::new(&mismatch.entry)
+                ));
+                return Ok(false);
             }
+            // Written before the modification time is set
+            output_file.finish().map_err(write_error)?;
+
+            set_last_modified_time(file, file_path)?;
         }
+    }
+
+    #[cfg(unix)]
+    unix_set_permissions(file_path, file, options)?;
 
-        #[cfg(unix)]
-        unix_set_permissions(&file_path, &file)?;
+    Ok(true)
+}
 
-        unpacked_files += 1;
+fn wrong_password(idx: usize) -> crate::Error {
+    FinalError::with_title("Failed to decrypt the zip archive.")
+        .detail(format!("The password is wrong for its entry number {}", idx + 1))
+        .into()
+}
+
+/// Path of the entry, if it's safe to use.
+///
+/// Absolute paths are kept, so that they are listed and refused when unpacking unless
+/// `--absolute-paths` is used, but other paths that aren't enclosed are skipped.
+fn entry_path(file: &ZipFile) -> Option<PathBuf> {
+    let name = entry_name(file);
+    let path = Path::new(name.as_ref());
+    // Like `enclosed_name`, which only knows of the name decoded by the zip crate
+    let is_safe = path.has_root() || super::unsafe_path_reason(path).is_none();
+    (!name.contains('\0') && is_safe).then(|| path.to_owned())
+}
+
+/// Paths of the file entries of `archive`, decoded and checked like when they're extracted, with
+/// the index of each.
+pub fn file_entries<R>(archive: &mut ZipArchive<R>) -> crate::Result<Vec<(PathBuf, usize)>>
+where
+    R: Read + Seek,
+{
+    let mut files = vec![];
+    for idx in 0..archive.len() {
+        let file = archive.by_index_raw(idx)?;
+        if let Some(path) = entry_path(&file).filter(|_| !file.is_dir()) {
+            files.push((path, idx));
+        }
     }
+    Ok(files)
+}
 
-    Ok(unpacked_files)
+/// Indexes of the entries of `archive` whose path is also the one of other entries, grouped by
+/// path, in the order of the archive.
+///
+/// Directories aren't counted, as their copies are merged.
+fn duplicated_entries<R>(archive: &mut ZipArchive<R>) -> crate::Result<Vec<(PathBuf, Vec<usize>)>>
+where
+    R: Read + Seek,
+{
+    // The names of the central directory are already in memory, without their duplicates
+    if archive.file_names().count() == archive.len() {
+        return Ok(vec![]);
+    }
+
+    let mut indexes: HashMap<PathBuf, Vec<usize>> = HashMap::new();
+    for idx in 0..archive.len() {
+        let file = archive.by_index_raw(idx)?;
+        if let Some(path) = entry_path(&file).filter(|_| !file.is_dir()) {
+            indexes.entry(path).or_default().push(idx);
+        }
+    }
+    let mut duplicates: Vec<_> = indexes.into_iter().filter(|(_, indexes)| indexes.len() > 1).collect();
+    duplicates.sort_by_key(|(_, indexes)| indexes[0]);
+    Ok(duplicates)
+}
+
+/// Indexes of the entries that aren't extracted because other entries have the same path, all of
+/// them but the last one of each path, or the first one with `first_wins`.
+///
+/// The paths are warned about, or fail the extraction with `strict`, as a harmless copy of an
+/// entry can hide another one, from the tools that extract the other copy.
+fn skipped_duplicates<R>(archive: &mut ZipArchive<R>, options: &UnpackOptions) -> crate::Result<HashSet<usize>>
+where
+    R: Read + Seek,
+{
+    let duplicates = duplicated_entries(archive)?;
+    if duplicates.is_empty() {
+        return Ok(HashSet::new());
+    }
+
+    let paths: Vec<_> = duplicates.iter().map(|(path, _)| path).collect();
+    if options.strict {
+        let error = FinalError::with_title("Refusing to extract a zip archive with duplicated paths.")
+            .detail(format!(
+                "Several entries are at {}",
+                pretty_format_list_of_paths(&paths)
+            ))
+            .hint("Without '--strict', only the last copy of each is extracted, or the first one with '--first-wins'");
+        return Err(error.into());
+    }
+    let kept = if options.first_wins { "first" } else { "last" };
+    warning(format!(
+        "Several entries are at {}, only the {kept} copy of each is extracted.",
+        pretty_format_list_of_paths(&paths)
+    ));
+
+    Ok(duplicates
+        .into_iter()
+        .flat_map(|(_, mut indexes)| {
+            if options.first_wins {
+                indexes.remove(0);
+            } else {
+                indexes.pop();
+            }
+            indexes
+        })
+        .collect())
+}
+
+/// Calls `visit` with the metadata and contents of every entry of `archive`.
+pub fn for_each_entry<R>(
+    mut archive: ZipArchive<R>,
+    mut visit: impl FnMut(EntryMetadata, &mut dyn Read) -> crate::Result<()>,
+) -> crate::Result<()>
+where
+    R: Read + Seek,
+{
+    for idx in 0..archive.len() {
+        let mut file = archive.by_index(idx)?;
+        let Some(path) = entry_path(&file) else {
+            continue;
+        };
+        let mut metadata = EntryMetadata {
+            path,
+            is_dir: file.is_dir(),
+            size: file.size(),
+            mode: file.unix_mode(),
+            mtime: file.last_modified().to_time().ok().map(|time| time.unix_timestamp()),
+            link_target: None,
+        };
+
+        if is_symlink(&file) {
+            let mut target = String::new();
+            file.read_to_string(&mut target)?;
+            metadata.link_target = Some(target.into());
+            visit(metadata, &mut io::empty())?;
+        } else {
+            // Reading the ones that don't match their checksum fails like when they're extracted
+            let (name, crc32, size) = (entry_name(&file).into_owned(), file.crc32(), file.size());
+            visit(metadata, &mut ChecksumReader::new(&mut file, &name, crc32, size))?;
+        }
+    }
+
+    Ok(())
 }
 
 /// List contents of `archive`, returning a vector of archive entries
@@ -105,22 +649,52 @@ where
         }
     }
 
-    let (tx, rx) = mpsc::channel();
+    let (tx, rx) = mpsc::sync_channel(ENTRIES_AHEAD);
     thread::spawn(move || {
+        let copies: HashMap<usize, (usize, usize)> = match duplicated_entries(&mut archive) {
+            Ok(duplicates) => duplicates
+                .into_iter()
+                .flat_map(|(_, indexes)| {
+                    let count = indexes.len();
+                    indexes
+                        .into_iter()
+                        .enumerate()
+                        .map(move |(copy, idx)| (idx, (copy + 1, count)))
+                })
+                .collect(),
+            Err(err) => {
+                let _ = tx.send(Err(err));
+                return;
+            }
+        };
+
         for idx in 0..archive.len() {
             let maybe_file_in_archive = (|| {
-                let file = match archive.by_index(idx) {
+                // Only the metadata is needed, encrypted entries can be listed without their password
+                let file = match archive.by_index_raw(idx) {
                     Ok(f) => f,
                     Err(e) => return Some(Err(e.into())),
                 };
 
-                let path = file.enclosed_name()?.to_owned();
+                let path = entry_path(&file)?;
                 let is_dir = file.is_dir();
 
-                Some(Ok(FileInArchive { path, is_dir }))
+                Some(Ok(FileInArchive {
+                    path,
+                    is_dir,
+                    is_symlink: is_symlink(&file),
+                    mode: None,
+                    size: Some(file.size()),
+                    compressed_size: Some(file.compressed_size()),
+                    method: Some(file.compression().to_string()),
+                    duplicate: copies.get(&idx).copied(),
+                }))
             })();
             if let Some(file_in_archive) = maybe_file_in_archive {
-                tx.send(file_in_archive).unwrap();
+                // The listing stopped
+                if tx.send(file_in_archive).is_err() {
+                    break;
+                }
             }
         }
     });
@@ -131,22 +705,20 @@ where
 /// Compresses the archives given by `input_filenames` into the file given previously to `writer`.
 pub fn build_archive_from_paths<W>(
     input_filenames: &[PathBuf],
-    output_path: &Path,
+    output: &ArchiveOutput,
     writer: W,
     file_visibility_policy: FileVisibilityPolicy,
     quiet: bool,
+    comment: Option<&str>,
+    options: &PackOptions,
 ) -> crate::Result<W>
 where
     W: Write + Seek,
 {
     let mut writer = zip::ZipWriter::new(writer);
-    // always use ZIP64 to allow compression of files larger than 4GB
-    // the format is widely supported and the extra 20B is negligible in most cases
-    let options = zip::write::FileOptions::default().large_file(true);
-    let output_handle = Handle::from_path(output_path);
-
-    #[cfg(not(unix))]
-    let executable = options.unix_permissions(0o755);
+    if let Some(comment) = comment {
+        writer.set_comment(comment);
+    }
 
     // Vec of any filename that failed the UTF-8 check
     let invalid_unicode_filenames = get_invalid_utf8_paths(input_filenames);
@@ -163,24 +735,20 @@ where
     }
 
     for filename in input_filenames {
-        let previous_location = cd_into_same_dir_as(filename)?;
-
-        // Unwrap safety:
-        //   paths should be canonicalized by now, and the root directory rejected.
-        let filename = filename.file_name().unwrap();
+        let input_parent = filename.parent().ok_or(crate::Error::CompressingRootFolder)?;
 
         for entry in file_visibility_policy.build_walker(filename) {
+            check_interrupted()?;
             let entry = entry?;
-            let path = entry.path();
-
-            // If the output_path is the same as the input file, warn the user and skip the input (in order to avoid compression recursion)
-            if let Ok(handle) = &output_handle {
-                if matches!(Handle::from_path(path), Ok(x) if &x == handle) {
-                    warning(format!(
-                        "The output file and the input file are the same: `{}`, skipping...",
-                        output_path.display()
-                    ));
-                }
+            let file_path = entry.path();
+            // Relative to the directory containing the input, like it's shown to the user
+            let path = file_path
+                .strip_prefix(input_parent)
+                .expect("walked paths start with the input");
+
+            // Never compress the output into itself
+            if output.is_output(file_path) {
+                continue;
             }
 
             // This is printed for every file in `input_filenames` and has
@@ -191,51 +759,191 @@ where
                 info(format!("Compressing '{}'.", EscapedPathDisplay::new(path)));
             }
 
-            let metadata = match path.metadata() {
-                Ok(metadata) => metadata,
-                Err(e) => {
-                    if e.kind() == std::io::ErrorKind::NotFound && utils::is_symlink(path) {
-                        // This path is for a broken symlink
-                        // We just ignore it
-                        continue;
-                    }
-                    return Err(e.into());
-                }
-            };
+            let entry_name = options.entry_name(input_parent, path)?;
 
-            #[cfg(unix)]
-            let options = options.unix_permissions(metadata.permissions().mode());
-
-            let entry_name = path.to_str().ok_or_else(|| {
+            // The input directory itself has no entry
+            if entry_name.as_os_str().is_empty() {
+                continue;
+            }
+            let entry_name = entry_name.to_str().ok_or_else(|| {
                 FinalError::with_title("Zip requires that all directories names are valid UTF-8")
                     .detail(format!("File at '{path:?}' has a non-UTF-8 name"))
             })?;
 
-            if metadata.is_dir() {
-                writer.add_directory(entry_name, options)?;
-            } else {
-                #[cfg(not(unix))]
-                let options = if is_executable::is_executable(path) {
-                    executable
-                } else {
-                    options
-                };
+            append_path(&mut writer, file_path, entry_name, !options.force_compress)?;
+        }
+    }
 
-                let mut file = fs::File::open(path)?;
+    let bytes = writer.finish()?;
+    Ok(bytes)
+}
 
-                // Updated last modified time
-                let last_modified_time = options.last_modified_time(get_last_modified_time(&file));
+/// Adds the file or directory at `path` to `writer` as a new entry named `entry_name`.
+///
+/// With `store_incompressible`, files that barely shrink when compressed, like images and
+/// videos, are stored without compression, which is much faster.
+///
+/// Broken symlinks are ignored.
+pub fn append_path<W>(
+    writer: &mut ZipWriter<W>,
+    path: &Path,
+    entry_name: &str,
+    store_incompressible: bool,
+) -> crate::Result<()>
+where
+    W: Write + Seek,
+{
+    // always use ZIP64 to allow compression of files larger than 4GB
+    // the format is widely supported and the extra 20B is negligible in most cases
+    let options = zip::write::FileOptions::default().large_file(true);
 
-                writer.start_file(entry_name, last_modified_time)?;
-                io::copy(&mut file, &mut writer)?;
+    let metadata = match path.metadata() {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            if e.kind() == std::io::ErrorKind::NotFound && utils::is_symlink(path) {
+                // This path is for a broken symlink
+                // We just ignore it
+                return Ok(());
             }
+            return Err(e.into());
         }
+    };
 
-        env::set_current_dir(previous_location)?;
+    if utils::is_special_file(&metadata) {
+        utils::warn_about_special_file(path);
+        return Ok(());
     }
 
-    let bytes = writer.finish()?;
-    Ok(bytes)
+    #[cfg(unix)]
+    let options = options.unix_permissions(metadata.permissions().mode());
+
+    if metadata.is_dir() {
+        writer.add_directory(entry_name, options)?;
+    } else {
+        #[cfg(not(unix))]
+        let options = if is_executable::is_executable(path) {
+            options.unix_permissions(0o755)
+        } else {
+            options
+        };
+
+        let mut file = fs::File::open(utils::long_path(path))?;
+
+        // Updated last modified time
+        let options = options.last_modified_time(get_last_modified_time(&file));
+        let options = if store_incompressible && utils::is_incompressible(&mut file)? {
+            options.compression_method(zip::CompressionMethod::Stored)
+        } else {
+            options
+        };
+
+        writer.start_file(entry_name, options)?;
+        io::copy(&mut buf_reader(ProgressReader(file)), writer)?;
+    }
+
+    Ok(())
+}
+
+/// Copies the entries of `archive` into `writer` without recompressing them, skipping the ones
+/// rejected by `keep_entry`, which receives each entry path and modification time.
+pub fn copy_entries<R, W>(
+    archive: &mut ZipArchive<R>,
+    writer: &mut ZipWriter<W>,
+    mut keep_entry: impl FnMut(&Path, Option<i64>) -> bool,
+) -> crate::Result<()>
+where
+    R: Read + Seek,
+    W: Write + Seek,
+{
+    for idx in 0..archive.len() {
+        let file = archive.by_index_raw(idx)?;
+        let modification_time = file.last_modified().to_time().ok().map(|time| time.unix_timestamp());
+
+        // Entries with unsafe paths can't be compared against the files on disk, so keep them
+        let keep = match entry_path(&file) {
+            Some(path) if !path.has_root() => keep_entry(&path, modification_time),
+            _ => true,
+        };
+        if keep {
+            writer.raw_copy_file(file)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies the entries of `archive` chosen by `keep_entry` into `writer` without recompressing
+/// them, their compressed data, CRC and sizes are written as they are.
+///
+/// `keep_entry` receives the path of every entry that `for_each_entry` visits, in the same order.
+pub fn copy_raw_entries<R, W>(
+    archive: &mut ZipArchive<R>,
+    writer: &mut ZipWriter<W>,
+    mut keep_entry: impl FnMut(&Path) -> bool,
+) -> crate::Result<()>
+where
+    R: Read + Seek,
+    W: Write + Seek,
+{
+    for idx in 0..archive.len() {
+        let file = archive.by_index_raw(idx)?;
+        let Some(path) = entry_path(&file) else {
+            continue;
+        };
+        if keep_entry(&path) {
+            writer.raw_copy_file(file)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Appends an entry read from another archive, described by `metadata`, to `writer`.
+pub fn append_entry<W>(
+    writer: &mut ZipWriter<W>,
+    metadata: &EntryMetadata,
+    contents: &mut dyn Read,
+) -> crate::Result<()>
+where
+    W: Write + Seek,
+{
+    let entry_name = metadata.path.to_str().ok_or_else(|| {
+        FinalError::with_title("Zip requires that all directories names are valid UTF-8")
+            .detail(format!("Entry '{:?}' has a non-UTF-8 name", metadata.path))
+    })?;
+
+    let mut options = zip::write::FileOptions::default().large_file(true);
+    if let Some(mode) = metadata.mode {
+        options = options.unix_permissions(mode & 0o7777);
+    }
+    if let Some(mtime) = metadata.mtime {
+        let last_modified_time = OffsetDateTime::from_unix_timestamp(mtime)
+            .ok()
+            .and_then(|time| DateTime::try_from(time).ok());
+        if let Some(last_modified_time) = last_modified_time {
+            options = options.last_modified_time(last_modified_time);
+        }
+    }
+
+    if let Some(target) = &metadata.link_target {
+        writer.add_symlink(entry_name, target.to_string_lossy(), options)?;
+    } else if metadata.is_dir {
+        writer.add_directory(entry_name, options)?;
+    } else {
+        writer.start_file(entry_name, options)?;
+        io::copy(contents, writer)?;
+    }
+
+    Ok(())
+}
+
+/// Symlinks are stored as files containing the link target, which can only be told apart by
+/// the file type bits of their unix mode
+fn is_symlink(file: &ZipFile) -> bool {
+    const S_IFMT: u32 = 0o170000;
+    const S_IFLNK: u32 = 0o120000;
+
+    file.unix_mode().is_some_and(|mode| mode & S_IFMT == S_IFLNK)
 }
 
 fn display_zip_comment_if_exists(file: &ZipFile) {
@@ -273,18 +981,86 @@ fn set_last_modified_time(zip_file: &ZipFile, path: &Path) -> crate::Result<()>
     // Zip does not support nanoseconds, so we can assume zero here
     let modification_time = FileTime::from_unix_time(time_in_seconds.unix_timestamp(), 0);
 
-    set_file_mtime(path, modification_time)?;
+    utils::set_mtime(path, modification_time)?;
 
     Ok(())
 }
 
 #[cfg(unix)]
-fn unix_set_permissions(file_path: &Path, file: &ZipFile) -> crate::Result<()> {
+fn unix_set_permissions(file_path: &Path, file: &ZipFile, options: &UnpackOptions) -> crate::Result<()> {
     use std::fs::Permissions;
 
     if let Some(mode) = file.unix_mode() {
-        fs::set_permissions(file_path, Permissions::from_mode(mode))?;
+        fs::set_permissions(file_path, Permissions::from_mode(options.permissions(mode)))?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extension::CompressionFormat::*;
+
+    #[test]
+    fn test_is_read_in_place() {
+        assert!(is_read_in_place(&[Zip]));
+
+        // Wrapped in other formats, or wrapping them
+        assert!(!is_read_in_place(&[Zip, Gzip]));
+        assert!(!is_read_in_place(&[Zip, Lzma, Zstd]));
+        assert!(!is_read_in_place(&[Tar, Zip]));
+        assert!(!is_read_in_place(&[Tar]));
+    }
+
+    /// CRC-32 of `bytes` computed a bit at a time, as a reference for the accelerated one of zip
+    fn bitwise_crc32(bytes: &[u8]) -> u32 {
+        let mut crc = !0u32;
+        for &byte in bytes {
+            crc ^= byte as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0xEDB8_8320
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+        !crc
+    }
+
+    #[test]
+    fn test_entry_crc32() {
+        use rand::{rngs::SmallRng, RngCore, SeedableRng};
+
+        // The check value of CRC-32, and lengths around the blocks of the SIMD implementations
+        let mut rng = SmallRng::seed_from_u64(0);
+        let mut contents = vec![b"".to_vec(), b"123456789".to_vec()];
+        for len in [1, 3, 15, 16, 17, 63, 64, 65, 127, 128, 129, 4095, 4097, 100_003] {
+            let mut buffer = vec![0; len];
+            rng.fill_bytes(&mut buffer);
+            contents.push(buffer);
+        }
+
+        let mut writer = zip::ZipWriter::new(io::Cursor::new(vec![]));
+        for (idx, contents) in contents.iter().enumerate() {
+            let method = if idx % 2 == 0 {
+                zip::CompressionMethod::Stored
+            } else {
+                zip::CompressionMethod::Deflated
+            };
+            let options = zip::write::FileOptions::default().compression_method(method);
+            writer.start_file(format!("{idx}"), options).unwrap();
+            writer.write_all(contents).unwrap();
+        }
+        let mut archive = ZipArchive::new(writer.finish().unwrap()).unwrap();
+
+        assert_eq!(bitwise_crc32(b"123456789"), 0xCBF4_3926);
+        for (idx, contents) in contents.iter().enumerate() {
+            let mut file = archive.by_index(idx).unwrap();
+            assert_eq!(file.crc32(), bitwise_crc32(contents), "length: {}", contents.len());
+            // Checked against the stored CRC once the entry is read to the end
+            io::copy(&mut file, &mut io::sink()).unwrap();
+        }
+    }
+}
@@ -4,35 +4,79 @@
 
 use std::{
     ffi::OsString,
+    io::{self, Read},
     ops::ControlFlow,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
 };
 
+use fs_err as fs;
+
 use crate::{
+    cli::VerifyFormat,
+    commands::chain_reader_decoder,
     error::FinalError,
-    extension::{build_archive_file_suggestion, Extension, PRETTY_SUPPORTED_ALIASES, PRETTY_SUPPORTED_EXTENSIONS},
+    extension::{
+        build_archive_file_suggestion, display_formats, flatten_compression_formats, CompressionFormat, Extension,
+        PRETTY_SUPPORTED_ALIASES, PRETTY_SUPPORTED_EXTENSIONS,
+    },
     utils::{
+        infer_extension,
         logger::{info_accessible, warning},
-        pretty_format_list_of_paths, try_infer_extension, user_wants_to_continue, EscapedPathDisplay,
+        pretty_format_list_of_paths, try_infer_extension, user_wants_to_continue, user_wants_to_use_detected_format,
+        EscapedPathDisplay, MAGIC_HEAD_LEN,
     },
     QuestionAction, QuestionPolicy, Result,
 };
 
+/// Most formats detected in a row by [`try_infer_format_chain`], deeper ones are left to be
+/// detected once the outer ones are decompressed
+const MAX_DETECTED_FORMATS: usize = 4;
+
 /// Check if the mime type matches the detected extensions.
 ///
 /// In case the file doesn't has any extensions, try to infer the format.
 ///
+/// `verify_format` tells whether a mismatch is asked about, fails, or isn't looked for at all.
+/// When the user accepts the detected format of a file whose extension doesn't match, it replaces
+/// `formats`, and `true` is returned.
+///
 /// TODO: maybe the name of this should be "magic numbers" or "file signature",
 /// and not MIME.
 pub fn check_mime_type(
     path: &Path,
     formats: &mut Vec<Extension>,
     question_policy: QuestionPolicy,
-) -> Result<ControlFlow<()>> {
+    verify_format: VerifyFormat,
+) -> Result<ControlFlow<(), bool>> {
+    if verify_format == VerifyFormat::Off {
+        return Ok(ControlFlow::Continue(false));
+    }
+
+    // The contents of the formats handled by external commands are unknown to ouch
+    let outer_format = formats
+        .last()
+        .and_then(|extension| extension.compression_formats.last());
+    if matches!(outer_format, Some(CompressionFormat::External(_))) {
+        return Ok(ControlFlow::Continue(false));
+    }
+
     if formats.is_empty() {
         // File with no extension
         // Try to detect it automatically and prompt the user about it
-        if let Some(detected_format) = try_infer_extension(path) {
+        let detected_formats = try_infer_format_chain(path);
+        if !detected_formats.is_empty() {
+            let detected_format = display_formats(&detected_formats);
+            if verify_format == VerifyFormat::Strict {
+                return Err(FinalError::with_title(format!(
+                    "Cannot confirm the format of {}",
+                    EscapedPathDisplay::new(path)
+                ))
+                .detail("It has no extension")
+                .detail(format!("Its contents look like a `{detected_format}` file"))
+                .hint("Rename it after its format, or give its format with '--format'")
+                .into());
+            }
+
             // Inferring the file extension can have unpredicted consequences (e.g. the user just
             // mistyped, ...) which we should always inform the user about.
             info_accessible(format!(
@@ -42,7 +86,7 @@ pub fn check_mime_type(
             ));
 
             if user_wants_to_continue(path, question_policy, QuestionAction::Decompression)? {
-                formats.push(detected_format);
+                formats.extend(detected_formats);
             } else {
                 return Ok(ControlFlow::Break(()));
             }
@@ -56,14 +100,28 @@ pub fn check_mime_type(
             .compression_formats
             .ends_with(detected_format.compression_formats)
         {
+            if verify_format == VerifyFormat::Strict {
+                return Err(FinalError::with_title(format!(
+                    "The contents of {} don't match its extension",
+                    EscapedPathDisplay::new(path)
+                ))
+                .detail(format!("Its extension is `{outer_ext}`"))
+                .detail(format!("Its contents look like a `{detected_format}` file"))
+                .hint("Rename it after its format, or give its format with '--format'")
+                .into());
+            }
+
             warning(format!(
-                "The file extension: `{}` differ from the detected extension: `{}`",
-                outer_ext, detected_format
+                "'{}' is named like a `{outer_ext}` file, but its contents look like a `{detected_format}` file.",
+                EscapedPathDisplay::new(path)
             ));
 
-            if !user_wants_to_continue(path, question_policy, QuestionAction::Decompression)? {
+            if !user_wants_to_use_detected_format(path, &detected_format, question_policy)? {
                 return Ok(ControlFlow::Break(()));
             }
+            // For this file only, the chain of formats of its contents replaces its extensions
+            *formats = try_infer_format_chain(path);
+            return Ok(ControlFlow::Continue(true));
         }
     } else {
         // NOTE: If this actually produces no false positives, we can upgrade it in the future
@@ -73,7 +131,45 @@ pub fn check_mime_type(
             path.display()
         ));
     }
-    Ok(ControlFlow::Continue(()))
+    Ok(ControlFlow::Continue(false))
+}
+
+/// Detect the formats of the file at `path` by their magic bytes, from the innermost to the
+/// outermost one, like `tar` then `gz` for a gzip stream of a tar archive.
+///
+/// The head of each detected compression format is decoded to look for the next one, so that
+/// files without extension get all of their formats, and not only the outermost one.
+fn try_infer_format_chain(path: &Path) -> Vec<Extension> {
+    let Some(outer_format) = try_infer_extension(path) else {
+        return vec![];
+    };
+    let mut formats = vec![outer_format];
+    let Ok(file) = std::fs::File::open(path) else {
+        return formats;
+    };
+
+    let mut reader: Box<dyn Read + Send> = Box::new(file);
+    while formats.len() < MAX_DETECTED_FORMATS {
+        let inner_format = formats[0].compression_formats[0];
+        if inner_format.is_archive_format() {
+            break;
+        }
+        let Ok(decoder) = chain_reader_decoder(&inner_format, reader) else {
+            break;
+        };
+        let mut head = Vec::with_capacity(MAGIC_HEAD_LEN);
+        let mut decoder = decoder.take(MAGIC_HEAD_LEN as u64);
+        if decoder.read_to_end(&mut head).is_err() {
+            break;
+        }
+        let Some(format) = infer_extension(&head) else {
+            break;
+        };
+        formats.insert(0, format);
+        // The head that was looked at is read again by the next decoder
+        reader = Box::new(io::Cursor::new(head).chain(decoder.into_inner()));
+    }
+    formats
 }
 
 /// In the context of listing archives, this function checks if `ouch` was told to list
@@ -179,6 +275,94 @@ pub fn check_missing_formats_when_decompressing(files: &[PathBuf], formats: &[Ve
     Err(error.into())
 }
 
+/// Show error if an archive comment was given, but the archive format can't store it.
+pub fn check_archive_comment_support(formats: &[Extension], output_path: &Path, comment: Option<&str>) -> Result<()> {
+    let stores_comments = formats
+        .first()
+        .is_some_and(|extension| extension.compression_formats.first() == Some(&CompressionFormat::Zip));
+
+    if comment.is_some() && !stores_comments {
+        let error = FinalError::with_title(format!(
+            "Cannot compress to '{}'.",
+            EscapedPathDisplay::new(output_path)
+        ))
+        .detail("The '--comment' flag is only supported by zip archives")
+        .hint("Remove the '--comment' flag, or compress to a '.zip' archive instead.");
+
+        return Err(error.into());
+    }
+
+    Ok(())
+}
+
+/// Show error if `--name` can't rename the top-level directory, because the inputs aren't a single
+/// directory, or the name isn't a relative path that stays inside of the archive.
+pub fn check_top_level_name(name: &Path, files: &[PathBuf]) -> Result<()> {
+    let is_relative = name
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if name.as_os_str().is_empty() || !is_relative {
+        let error = FinalError::with_title(format!("Invalid '--name' '{}'.", EscapedPathDisplay::new(name)))
+            .detail("It must be a relative path, without '..'");
+        return Err(error.into());
+    }
+
+    if let [input] = files {
+        if input.is_dir() {
+            return Ok(());
+        }
+    }
+
+    let error = FinalError::with_title("Cannot compress with '--name'.")
+        .detail("It renames the input directory, so it must be the only input")
+        .hint("Put the inputs in a directory first, or rename entries with '--transform' instead");
+    Err(error.into())
+}
+
+/// Show error if `output_path` is one of the inputs, and tell that it's left out of the archive, or
+/// fail with `strict`, if it's inside of an input directory.
+///
+/// Both are compared once canonicalized, with only the parent of the output, which usually doesn't
+/// exist yet. The files of the archive are skipped while walking the inputs by
+/// [`ArchiveOutput`](crate::archive::ArchiveOutput).
+pub fn check_output_is_not_input(files: &[PathBuf], output_path: &Path, strict: bool) -> Result<()> {
+    let Some(file_name) = output_path.file_name() else {
+        return Ok(());
+    };
+    let parent = output_path.parent().filter(|parent| !parent.as_os_str().is_empty());
+    let Ok(output) = fs::canonicalize(parent.unwrap_or(Path::new("."))).map(|parent| parent.join(file_name)) else {
+        return Ok(());
+    };
+    let display = EscapedPathDisplay::new(output_path);
+
+    for file in files {
+        let Ok(input) = fs::canonicalize(file) else {
+            continue;
+        };
+        if input == output {
+            let error = FinalError::with_title(format!("Cannot compress '{display}' into itself."))
+                .detail("It's both an input and the output")
+                .hint("Choose another name for the output archive");
+            return Err(error.into());
+        }
+        if !output.starts_with(&input) {
+            continue;
+        }
+
+        let input = EscapedPathDisplay::new(file);
+        if strict {
+            let error = FinalError::with_title(format!("Cannot compress '{input}' into '{display}'."))
+                .detail("The output archive is inside of the input directory")
+                .hint("Place the output archive outside of it, or remove '--strict' to leave it out");
+            return Err(error.into());
+        }
+        info_accessible(format!(
+            "The output archive '{display}' is inside of the input '{input}', it's left out of the archive."
+        ));
+    }
+    Ok(())
+}
+
 /// Check if there is a first format when compressing, and returns it.
 pub fn check_first_format_when_compressing<'a>(formats: &'a [Extension], output_path: &Path) -> Result<&'a Extension> {
     formats.first().ok_or_else(|| {
@@ -195,6 +379,33 @@ pub fn check_first_format_when_compressing<'a>(formats: &'a [Extension], output_
     })
 }
 
+/// Warn when the input of a compression format is already compressed with it, like a '.gz' file
+/// compressed into gzip, which only makes it slower to decompress, see `--force`.
+///
+/// Files stored inside of archives are left alone, keeping them as they are is usually intended.
+pub fn warn_about_double_compression(files: &[PathBuf], formats: &[Extension]) {
+    let output_formats = flatten_compression_formats(formats);
+    let Some(&first_format) = output_formats.first() else {
+        return;
+    };
+    if first_format.is_archive_format() || matches!(first_format, CompressionFormat::External(_)) {
+        return;
+    }
+
+    for file in files.iter().filter(|file| file.is_file()) {
+        let Some(detected_format) = try_infer_extension(file) else {
+            continue;
+        };
+        if detected_format.compression_formats.last() == Some(&first_format) {
+            warning(format!(
+                "'{}' is already compressed as `{detected_format}`, compressing it again with the same format \
+                 barely shrinks it. Use '--force' to compress it anyway without this warning.",
+                EscapedPathDisplay::new(file)
+            ));
+        }
+    }
+}
+
 /// Check if compression is invalid because an archive format is necessary.
 ///
 /// Non-archive formats don't support multiple file compression or folder compression.
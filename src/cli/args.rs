@@ -33,36 +33,181 @@ pub struct CliArgs {
     #[arg(short = 'q', long, global = true)]
     pub quiet: bool,
 
+    /// Silences everything but errors, for scripts that rely on the exit code
+    #[arg(long, global = true)]
+    pub quiet_errors: bool,
+
     /// Ignores files matched by git's ignore files
     #[arg(short = 'g', long, global = true)]
     pub gitignore: bool,
 
-    /// Specify the format of the archive
+    /// Specify the format of the archive, or add outer layers to the detected one with '+', like '+xz'
     #[arg(short, long, global = true)]
     pub format: Option<OsString>,
 
+    /// How the format detected from the contents of the inputs is checked against their
+    /// extensions, 'strict' fails when they differ and 'off' doesn't look at the contents
+    #[arg(long, global = true, value_name = "MODE", value_enum, default_value_t = VerifyFormat::Ask)]
+    pub verify_format: VerifyFormat,
+
+    /// Maximum number of inputs processed in parallel, defaults to the number of CPUs
+    #[arg(long, global = true, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    pub threads: Option<u16>,
+
+    /// Report how long the operation took, and how long each input took
+    #[arg(long, global = true)]
+    pub time: bool,
+
+    /// Print the progress of compression and decompression as plain lines, like '40% (X/Y bytes)',
+    /// for logs that can't show a progress bar
+    #[arg(long, global = true)]
+    pub numeric_progress: bool,
+
+    /// Format of the logs printed to stderr, 'json' prints each one as a JSON line, for programs
+    /// that wrap ouch
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Human)]
+    pub log_format: LogFormat,
+
+    /// When to color the output, 'auto' colors it unless it's not a terminal or NO_COLOR is set
+    #[arg(long, global = true, value_name = "WHEN", value_enum, default_value_t = ColorMode::Auto)]
+    pub color: ColorMode,
+
+    /// Directory for the temporary files of archives that can't be streamed, like '.7z' or '.zip'
+    /// chained with other formats, defaults to $TMPDIR or the system's one
+    #[arg(long, visible_alias = "tempdir", global = true, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    pub temp_dir: Option<PathBuf>,
+
+    /// Size of the buffers that archives and files are read and written through, like '4M' or
+    /// '256KiB' [default: 1MiB]
+    #[arg(long, global = true, value_name = "SIZE", value_parser = parse_size)]
+    pub buffer_size: Option<u64>,
+
+    /// Most memory that archives which can't be streamed, like '.zip' chained with other formats,
+    /// are buffered in before they're moved to a temporary file, like '512MiB' [default: a quarter
+    /// of the available memory]
+    #[arg(long, global = true, value_name = "SIZE", value_parser = parse_size)]
+    pub max_memory: Option<u64>,
+
+    /// Keep archives which can't be streamed in memory however big they get, instead of moving
+    /// them to a temporary file
+    #[arg(long, global = true, conflicts_with = "max_memory")]
+    pub no_spill: bool,
+
+    /// Encoding of the names of zip entries that aren't marked as UTF-8, like 'shift_jis' for
+    /// archives made on a Japanese Windows [default: cp437]
+    #[arg(long, global = true, visible_alias = "encoding", value_name = "ENCODING")]
+    pub zip_encoding: Option<String>,
+
+    /// Decompress the files with this extension by piping them through a command, like
+    /// 'xyz=mycodec -d', can be used multiple times
+    #[arg(long, global = true, value_name = "EXT=COMMAND")]
+    pub codec_cmd: Vec<String>,
+
+    /// Compress the files with this extension by piping them through a command, like
+    /// 'xyz=mycodec', can be used multiple times
+    #[arg(long, global = true, value_name = "EXT=COMMAND")]
+    pub codec_compress_cmd: Vec<String>,
+
     // Ouch and claps subcommands
     #[command(subcommand)]
     pub cmd: Subcommand,
 }
 
+/// Format of the logs, set by `--log-format`
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LogFormat {
+    /// Colored messages, for people
+    Human,
+    /// One JSON object per line, like `{"level":"info","message":"...","accessible":true}`
+    Json,
+}
+
+/// When the output is colored, set by `--color`
+#[derive(clap::ValueEnum, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum ColorMode {
+    /// Colored when printed to a terminal, unless NO_COLOR is set
+    #[default]
+    Auto,
+    /// Always colored
+    Always,
+    /// Never colored
+    Never,
+}
+
+/// How the formats of the inputs are checked against their contents, set by `--verify-format`
+#[derive(clap::ValueEnum, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum VerifyFormat {
+    /// Warn when the contents don't match the extension, and ask whether to use the detected
+    /// format instead, like for files without extension
+    #[default]
+    Ask,
+    /// Fail when the contents don't match the extension, or when a file has no extension
+    Strict,
+    /// Trust the extensions without looking at the contents
+    Off,
+}
+
+/// Whether runs of zeroes are extracted as holes, set by `--sparse`
+#[derive(clap::ValueEnum, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum SparseMode {
+    /// Holes in place of the runs of zeroes of 64 KiB or more, and of the holes of sparse entries
+    #[default]
+    Auto,
+    /// Holes in place of every block of zeroes
+    Always,
+    /// Write every zero, even for the holes of sparse entries
+    Never,
+}
+
+/// Compression level set by `--level`
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum CompressionLevel {
+    /// The same level for every format
+    All(i16),
+    /// The level of each of the formats named by their extension, like 'gz=6,xz=9'
+    PerFormat(Vec<(String, i16)>),
+}
+
 #[derive(Parser, PartialEq, Eq, Debug)]
 #[allow(rustdoc::bare_urls)]
 pub enum Subcommand {
     /// Compress one or more files into one output file
-    #[command(visible_alias = "c")]
+    // Without files, the only one given is the output, when they're read from --files-from
+    #[command(visible_alias = "c", allow_missing_positional = true)]
     Compress {
-        /// Files to be compressed
-        #[arg(required = true, value_hint = ValueHint::FilePath)]
+        /// Files to be compressed, or stdin with '-'
+        #[arg(required_unless_present = "files_from", value_hint = ValueHint::FilePath)]
         files: Vec<PathBuf>,
 
-        /// The resulting file. Its extensions can be used to specify the compression formats
-        #[arg(required = true, value_hint = ValueHint::FilePath)]
+        /// The resulting file. Its extensions can be used to specify the compression formats.
+        /// With --each, the directory to place the resulting files in
+        #[arg(required = true, value_hint = ValueHint::AnyPath)]
         output: PathBuf,
 
-        /// Compression level, applied to all formats
-        #[arg(short, long, group = "compression-level")]
-        level: Option<i16>,
+        /// Also compress the files listed in this file, one per line, or in stdin with '-'
+        #[arg(short = 'T', long, value_name = "FILE", value_hint = ValueHint::FilePath)]
+        files_from: Option<PathBuf>,
+
+        /// With --files-from, the files are separated by NUL bytes instead of lines, like
+        /// 'find -print0' prints them
+        #[arg(short = '0', long, requires = "files_from")]
+        null: bool,
+
+        /// Only descend this many levels into directories, the deeper ones are stored empty
+        /// (0 for the given paths only, 1 for their direct children, and so on)
+        #[arg(long, value_name = "N")]
+        max_depth: Option<usize>,
+
+        /// Compression level, applied to all formats, or one for each format of the output, like
+        /// 'gz=6,xz=9', the others get their default level
+        #[arg(
+            short,
+            long,
+            alias = "compress-level-per-layer",
+            group = "compression-level",
+            value_parser = parse_level
+        )]
+        level: Option<CompressionLevel>,
 
         /// Fastest compression level possible,
         /// conflicts with --level and --slow
@@ -73,31 +218,432 @@ pub enum Subcommand {
         /// conflicts with --level and --fast
         #[arg(long, group = "compression-level")]
         slow: bool,
+
+        /// Remove the input files after they were successfully compressed
+        #[arg(long)]
+        rm: bool,
+
+        /// Compress each input into its own archive, placed inside of the output directory
+        #[arg(long)]
+        each: bool,
+
+        /// Comment to be stored in the archive, only supported by zip
+        #[arg(long)]
+        comment: Option<String>,
+
+        /// Rename entries with a regex substitution, can be used multiple times and applies in order
+        #[arg(long, value_name = "REGEX=REPLACEMENT")]
+        transform: Vec<String>,
+
+        /// Store the files with their absolute paths, instead of relative to the inputs
+        #[arg(short = 'P', long)]
+        absolute_paths: bool,
+
+        /// Store the files relative to this directory, which must contain every input
+        #[arg(long, value_hint = ValueHint::DirPath, conflicts_with = "absolute_paths")]
+        input_dir: Option<PathBuf>,
+
+        /// Store the input directory under this name, like 'project-1.2', instead of its own
+        #[arg(long, value_name = "DIR", conflicts_with_all = ["absolute_paths", "input_dir", "each"])]
+        name: Option<PathBuf>,
+
+        /// Compress every file, by default zip stores the ones that wouldn't shrink, like images and videos
+        #[arg(long)]
+        force_compress: bool,
+
+        /// Modification time of the archive file, in seconds since the Unix epoch, for reproducible builds
+        #[arg(long, value_name = "EPOCH", env = "SOURCE_DATE_EPOCH", allow_negative_numbers = true)]
+        archive_mtime: Option<i64>,
+
+        /// Read a big single file through a buffer instead of memory-mapping it, for files that other
+        /// programs could truncate meanwhile
+        #[arg(long)]
+        no_mmap: bool,
+
+        /// Store the extended attributes of files, like SELinux labels, in tar archives (Unix only)
+        #[arg(long)]
+        xattrs: bool,
+
+        /// Fail instead of leaving the output archive out when it's inside of an input directory
+        #[arg(long)]
+        strict: bool,
+
+        /// Write the archive to stdout, given as '-', in length-prefixed chunks with an end marker,
+        /// so that its receiver can tell if it was cut short, decompress it with '--framed'
+        #[arg(long, conflicts_with = "each")]
+        framed: bool,
+
+        /// Name of the file that stores stdin, given as '-' among the files, inside of archives
+        #[arg(long, value_name = "NAME", default_value = "stdin")]
+        stdin_name: PathBuf,
+
+        /// Don't warn about compressing a file again with the format it's already compressed with,
+        /// like a '.gz' file into gzip
+        #[arg(long)]
+        force: bool,
     },
     /// Decompresses one or more files, optionally into another folder
     #[command(visible_alias = "d")]
     Decompress {
-        /// Files to be decompressed
+        /// Files to be decompressed, the format of one can be given after a colon, like 'blob:tar.gz'
         #[arg(required = true, num_args = 1.., value_hint = ValueHint::FilePath)]
         files: Vec<PathBuf>,
 
         /// Place results in a directory other than the current one
         #[arg(short = 'd', long = "dir", value_hint = ValueHint::FilePath)]
         output_dir: Option<PathBuf>,
+
+        /// Write the decompressed contents to stdout instead of creating files, like 'gunzip -c',
+        /// archives need a --filter that selects one of their files
+        #[arg(
+            short = 'c',
+            long = "stdout",
+            conflicts_with_all = ["output_dir", "rm", "recursive", "flatten", "exec"]
+        )]
+        to_stdout: bool,
+
+        /// Remove the archives after they were successfully decompressed
+        #[arg(long)]
+        rm: bool,
+
+        /// Also decompress the archives found among the extracted files, and the ones inside of them
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// With --recursive, decompress archives nested at most this many levels deep [default: 8]
+        #[arg(long, value_name = "DEPTH", requires = "recursive")]
+        max_nesting: Option<usize>,
+
+        /// With --recursive, remove the nested archives after they were successfully decompressed
+        #[arg(long, requires = "recursive")]
+        rm_nested: bool,
+
+        /// Extract all files directly into the output directory, discarding the directory structure
+        #[arg(long)]
+        flatten: bool,
+
+        /// With --flatten, extract files whose names collide with a number appended to them
+        #[arg(long, group = "conflict-policy", requires = "flatten")]
+        rename: bool,
+
+        /// With --flatten, skip files whose names collide with a previous one
+        #[arg(long, group = "conflict-policy", requires = "flatten")]
+        skip: bool,
+
+        /// With --flatten, overwrite previous files whose names collide
+        #[arg(long, group = "conflict-policy", requires = "flatten")]
+        overwrite: bool,
+
+        /// Only extract the entries whose paths match this glob, can be used multiple times
+        #[arg(long, value_name = "GLOB")]
+        filter: Vec<String>,
+
+        /// Skip the entries whose paths match this glob, even if they match --filter, can be used
+        /// multiple times
+        #[arg(long, value_name = "GLOB", conflicts_with = "to_stdout")]
+        exclude: Vec<String>,
+
+        /// Skip the entries whose paths match the globs of this file, one per line, lines starting
+        /// with '#' are comments
+        #[arg(long, value_name = "FILE", value_hint = ValueHint::FilePath, conflicts_with = "to_stdout")]
+        exclude_from: Option<PathBuf>,
+
+        /// Match the --filter and --exclude globs ignoring case
+        #[arg(long, conflicts_with = "to_stdout")]
+        ignore_case: bool,
+
+        /// Ask which of the entries of each archive to extract, one by one
+        #[arg(long, conflicts_with = "to_stdout")]
+        interactive: bool,
+
+        /// Only extract the N most recently modified files of each archive, among the ones
+        /// selected by --filter and --exclude
+        #[arg(
+            long,
+            value_name = "N",
+            value_parser = clap::value_parser!(u64).range(1..),
+            conflicts_with_all = ["to_stdout", "interactive", "select_largest"]
+        )]
+        select_newest: Option<u64>,
+
+        /// Only extract the N largest files of each archive, among the ones selected by --filter
+        /// and --exclude
+        #[arg(
+            long,
+            value_name = "N",
+            value_parser = clap::value_parser!(u64).range(1..),
+            conflicts_with_all = ["to_stdout", "interactive"]
+        )]
+        select_largest: Option<u64>,
+
+        /// Run a command on each extracted file, '{}' is replaced by the file path
+        #[arg(long, value_name = "COMMAND")]
+        exec: Option<String>,
+
+        /// Keep going when the --exec command fails, instead of stopping
+        #[arg(long, requires = "exec")]
+        exec_ignore_errors: bool,
+
+        /// Rename entries with a regex substitution, can be used multiple times and applies in order
+        #[arg(long, value_name = "REGEX=REPLACEMENT")]
+        transform: Vec<String>,
+
+        /// Extract the entries with absolute paths to that location, instead of refusing them, or
+        /// extracting them inside of the output folder for tar
+        #[arg(short = 'P', long)]
+        absolute_paths: bool,
+
+        /// Fail instead of skipping the entries that would be extracted outside of the output
+        /// folder, like '../file', or the copies of the zip entries with the same path, and
+        /// instead of escaping the names that aren't valid on Windows, like 'aux.txt'
+        #[arg(long)]
+        strict: bool,
+
+        /// Skip the symbolic and hard links of the archive, for archives that aren't trusted
+        #[arg(long)]
+        no_symlinks: bool,
+
+        /// Extract the first of the zip entries with the same path, instead of the last one
+        #[arg(long, conflicts_with = "strict")]
+        first_wins: bool,
+
+        /// Leave holes, which take no disk space, in place of long runs of zeroes, like the free space
+        /// of disk images
+        #[arg(long, value_name = "WHEN", value_enum, default_value_t = SparseMode::Auto, num_args = 0..=1, require_equals = true, default_missing_value = "always")]
+        sparse: SparseMode,
+
+        /// Restore the extended attributes stored in tar archives, like SELinux labels (Unix only)
+        #[arg(long)]
+        xattrs: bool,
+
+        /// Keep what was extracted so far when the disk gets full or ouch is interrupted, instead of
+        /// removing it
+        #[arg(long)]
+        keep_partial: bool,
+
+        /// Apply the stored permissions as they are, without the umask clearing some of them, the
+        /// default for root (Unix only)
+        #[arg(long, visible_alias = "preserve-permissions")]
+        same_permissions: bool,
+
+        /// Clear the bits of the stored permissions that the umask clears, the default for other
+        /// users than root (Unix only)
+        #[arg(long, conflicts_with = "same_permissions")]
+        no_same_permissions: bool,
+
+        /// Decrypt encrypted zip archives with the password in the first line of this file
+        #[arg(long, value_name = "PATH", value_hint = ValueHint::FilePath)]
+        password_file: Option<PathBuf>,
+
+        /// Keep decompressing the other archives when one of them fails, and the other entries of
+        /// a zip archive when one doesn't match its checksum, instead of stopping
+        #[arg(short = 'k', long, visible_alias = "keep-going")]
+        continue_on_error: bool,
+
+        /// Keep reading tar archives past the blocks of zeroes that end them, to extract the
+        /// archives concatenated after them, like 'tar --ignore-zeros'
+        #[arg(long)]
+        ignore_zeros: bool,
+
+        /// Stop extracting an archive that writes more than this, like '500M' or '2G' [default: 100G]
+        #[arg(long, value_name = "SIZE", value_parser = parse_size, conflicts_with = "no_limits")]
+        max_output_size: Option<u64>,
+
+        /// Stop extracting an archive that expands more than this many times its compressed size
+        /// [default: 1000]
+        #[arg(long, value_name = "RATIO", value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "no_limits")]
+        max_ratio: Option<u64>,
+
+        /// Stop extracting an archive with more than this many entries [default: 1000000]
+        #[arg(long, value_name = "COUNT", conflicts_with = "no_limits")]
+        max_entries: Option<u64>,
+
+        /// Stop extracting an archive with an entry whose path has more than this many components
+        /// [default: 256]
+        #[arg(long, value_name = "COUNT", conflicts_with = "no_limits")]
+        max_path_depth: Option<usize>,
+
+        /// Stop extracting an archive with an entry whose path is longer than this many bytes
+        /// [default: 4096]
+        #[arg(long, value_name = "BYTES", conflicts_with = "no_limits")]
+        max_path_length: Option<usize>,
+
+        /// Extract archives no matter how much they expand or how many entries they have, disabling the
+        /// protection against zip bombs
+        #[arg(long)]
+        no_limits: bool,
+
+        /// Read the archives as framed streams, written by 'ouch compress --framed', failing if
+        /// they were cut short
+        #[arg(long, conflicts_with = "to_stdout")]
+        framed: bool,
+    },
+    /// Add files to an existing archive, replacing the entries with the same path
+    #[command(visible_alias = "a")]
+    Append {
+        /// The archive to add files to
+        #[arg(required = true, value_hint = ValueHint::FilePath)]
+        archive: PathBuf,
+
+        /// Files to be added
+        #[arg(required = true, num_args = 1.., value_hint = ValueHint::AnyPath)]
+        files: Vec<PathBuf>,
+
+        /// Only replace the entries whose files were modified after they were archived, and add the
+        /// files that aren't in it yet, like 'tar -u'
+        #[arg(short, long)]
+        update: bool,
     },
     /// List contents of an archive
     #[command(visible_aliases = ["l", "ls"])]
     List {
-        /// Archives whose contents should be listed
+        /// Archives whose contents should be listed, optionally followed by glob patterns, to
+        /// only list the entries that match them
         #[arg(required = true, num_args = 1.., value_hint = ValueHint::FilePath)]
         archives: Vec<PathBuf>,
 
+        /// Trailing arguments of `archives` that are patterns instead of files
+        #[arg(skip)]
+        patterns: Vec<String>,
+
         /// Show archive contents as a tree
         #[arg(short, long)]
         tree: bool,
+
+        /// Print only the paths of the entries, separated by NUL bytes, for `xargs -0`
+        #[arg(short = '0', long, conflicts_with = "tree")]
+        print0: bool,
+
+        /// Show the compression method, compressed and original sizes of each entry
+        #[arg(short, long, conflicts_with_all = ["tree", "print0"])]
+        long: bool,
+
+        /// Only list the entries whose paths match this glob, can be used multiple times
+        #[arg(long, value_name = "GLOB")]
+        filter: Vec<String>,
+
+        /// Match the patterns and --filter globs ignoring case
+        #[arg(long)]
+        ignore_case: bool,
+
+        /// Only print this many of the entries of each archive, after the ones skipped by --offset
+        #[arg(long, value_name = "N")]
+        limit: Option<usize>,
+
+        /// Skip this many of the entries of each archive, counting only the ones that match the
+        /// patterns and --filter globs
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        offset: usize,
+    },
+    /// Print the contents of a file inside of an archive
+    Cat {
+        /// The archive that contains the file
+        #[arg(value_hint = ValueHint::FilePath)]
+        archive: PathBuf,
+
+        /// Path of the file inside of the archive, or a glob pattern that matches only it
+        entry: String,
+    },
+    /// Merge the entries of several archives into a new one
+    Merge {
+        /// Archives to merge, entries with the same path are asked about
+        #[arg(required = true, num_args = 2.., value_hint = ValueHint::FilePath)]
+        archives: Vec<PathBuf>,
+
+        /// The resulting archive. Its extensions can be used to specify the archive format
+        #[arg(short, long, required = true, value_hint = ValueHint::FilePath)]
+        output: PathBuf,
+    },
+    /// Compare the contents of two archives, exiting with 1 if they are different
+    Diff {
+        /// The first archive
+        #[arg(value_hint = ValueHint::FilePath)]
+        first: PathBuf,
+
+        /// The archive to compare it with
+        #[arg(value_hint = ValueHint::FilePath)]
+        second: PathBuf,
+
+        /// Also compare the size, permissions and modification time of entries
+        #[arg(long)]
+        metadata: bool,
+
+        /// Print the differences as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check that a directory matches the contents of an archive, exiting with 1 if it doesn't
+    Verify {
+        /// The archive to check against
+        #[arg(value_hint = ValueHint::FilePath)]
+        archive: PathBuf,
+
+        /// The directory that the archive was extracted to
+        #[arg(long, required = true, value_hint = ValueHint::DirPath)]
+        against: PathBuf,
+
+        /// Also compare the size, permissions and modification time of files
+        #[arg(long)]
+        metadata: bool,
+
+        /// Also report files in the directory that aren't in the archive
+        #[arg(long)]
+        strict: bool,
+
+        /// Print the differences as JSON
+        #[arg(long)]
+        json: bool,
     },
 }
 
+/// Parse a size like '500M' or '2G', with decimal units, or binary ones like '2GiB'
+fn parse_size(size: &str) -> Result<u64, String> {
+    let digits = size.find(|c: char| !c.is_ascii_digit()).unwrap_or(size.len());
+    let (number, unit) = size.split_at(digits);
+    let number: u64 = number.parse().map_err(|_| format!("invalid size '{size}'"))?;
+
+    let multiplier: u64 = match unit.to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" => 1000,
+        "M" | "MB" => 1000_u64.pow(2),
+        "G" | "GB" => 1000_u64.pow(3),
+        "T" | "TB" => 1000_u64.pow(4),
+        "KIB" => 1024,
+        "MIB" => 1024_u64.pow(2),
+        "GIB" => 1024_u64.pow(3),
+        "TIB" => 1024_u64.pow(4),
+        _ => return Err(format!("unknown unit '{unit}', expected one like 'M', 'G' or 'GiB'")),
+    };
+
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size '{size}' is too large"))
+}
+
+/// Parse a level like '6', or one for each format like 'gz=6,xz=9'
+fn parse_level(level: &str) -> Result<CompressionLevel, String> {
+    if !level.contains('=') {
+        return level
+            .parse()
+            .map(CompressionLevel::All)
+            .map_err(|_| format!("invalid level '{level}'"));
+    }
+
+    level
+        .split(',')
+        .map(|format_level| {
+            let (extension, level) = format_level
+                .split_once('=')
+                .ok_or_else(|| format!("expected 'EXTENSION=LEVEL', like 'gz=6', got '{format_level}'"))?;
+            let level = level
+                .parse()
+                .map_err(|_| format!("invalid level '{level}' for '{extension}'"))?;
+            Ok((extension.trim_start_matches('.').to_string(), level))
+        })
+        .collect::<Result<_, String>>()
+        .map(CompressionLevel::PerFormat)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -130,13 +676,65 @@ mod tests {
             accessible: false,
             hidden: false,
             quiet: false,
+            quiet_errors: false,
             gitignore: false,
             format: None,
+            verify_format: VerifyFormat::Ask,
+            threads: None,
+            time: false,
+            numeric_progress: false,
+            log_format: LogFormat::Human,
+            color: ColorMode::Auto,
+            temp_dir: None,
+            buffer_size: None,
+            max_memory: None,
+            no_spill: false,
+            zip_encoding: None,
+            codec_cmd: vec![],
+            codec_compress_cmd: vec![],
             // This is usually replaced in assertion tests
             cmd: Subcommand::Decompress {
                 // Put a crazy value here so no test can assert it unintentionally
                 files: vec!["\x00\x11\x22".into()],
                 output_dir: None,
+                to_stdout: false,
+                rm: false,
+                recursive: false,
+                max_nesting: None,
+                rm_nested: false,
+                flatten: false,
+                rename: false,
+                skip: false,
+                overwrite: false,
+                filter: vec![],
+                exclude: vec![],
+                exclude_from: None,
+                ignore_case: false,
+                interactive: false,
+                select_newest: None,
+                select_largest: None,
+                exec: None,
+                exec_ignore_errors: false,
+                transform: vec![],
+                absolute_paths: false,
+                strict: false,
+                no_symlinks: false,
+                first_wins: false,
+                sparse: SparseMode::Auto,
+                xattrs: false,
+                keep_partial: false,
+                same_permissions: false,
+                no_same_permissions: false,
+                password_file: None,
+                continue_on_error: false,
+                ignore_zeros: false,
+                max_output_size: None,
+                max_ratio: None,
+                max_entries: None,
+                max_path_depth: None,
+                max_path_length: None,
+                no_limits: false,
+                framed: false,
             },
         }
     }
@@ -149,6 +747,44 @@ mod tests {
                 cmd: Subcommand::Decompress {
                     files: to_paths(["file.tar.gz"]),
                     output_dir: None,
+                    to_stdout: false,
+                    rm: false,
+                    recursive: false,
+                    max_nesting: None,
+                    rm_nested: false,
+                    flatten: false,
+                    rename: false,
+                    skip: false,
+                    overwrite: false,
+                    filter: vec![],
+                    exclude: vec![],
+                    exclude_from: None,
+                    ignore_case: false,
+                    interactive: false,
+                    select_newest: None,
+                    select_largest: None,
+                    exec: None,
+                    exec_ignore_errors: false,
+                    transform: vec![],
+                    absolute_paths: false,
+                    strict: false,
+                    no_symlinks: false,
+                    first_wins: false,
+                    sparse: SparseMode::Auto,
+                    xattrs: false,
+                    keep_partial: false,
+                    same_permissions: false,
+                    no_same_permissions: false,
+                    password_file: None,
+                    continue_on_error: false,
+                    ignore_zeros: false,
+                    max_output_size: None,
+                    max_ratio: None,
+                    max_entries: None,
+                    max_path_depth: None,
+                    max_path_length: None,
+                    no_limits: false,
+                    framed: false,
                 },
                 ..mock_cli_args()
             }
@@ -159,6 +795,44 @@ mod tests {
                 cmd: Subcommand::Decompress {
                     files: to_paths(["file.tar.gz"]),
                     output_dir: None,
+                    to_stdout: false,
+                    rm: false,
+                    recursive: false,
+                    max_nesting: None,
+                    rm_nested: false,
+                    flatten: false,
+                    rename: false,
+                    skip: false,
+                    overwrite: false,
+                    filter: vec![],
+                    exclude: vec![],
+                    exclude_from: None,
+                    ignore_case: false,
+                    interactive: false,
+                    select_newest: None,
+                    select_largest: None,
+                    exec: None,
+                    exec_ignore_errors: false,
+                    transform: vec![],
+                    absolute_paths: false,
+                    strict: false,
+                    no_symlinks: false,
+                    first_wins: false,
+                    sparse: SparseMode::Auto,
+                    xattrs: false,
+                    keep_partial: false,
+                    same_permissions: false,
+                    no_same_permissions: false,
+                    password_file: None,
+                    continue_on_error: false,
+                    ignore_zeros: false,
+                    max_output_size: None,
+                    max_ratio: None,
+                    max_entries: None,
+                    max_path_depth: None,
+                    max_path_length: None,
+                    no_limits: false,
+                    framed: false,
                 },
                 ..mock_cli_args()
             }
@@ -169,6 +843,44 @@ mod tests {
                 cmd: Subcommand::Decompress {
                     files: to_paths(["a", "b", "c"]),
                     output_dir: None,
+                    to_stdout: false,
+                    rm: false,
+                    recursive: false,
+                    max_nesting: None,
+                    rm_nested: false,
+                    flatten: false,
+                    rename: false,
+                    skip: false,
+                    overwrite: false,
+                    filter: vec![],
+                    exclude: vec![],
+                    exclude_from: None,
+                    ignore_case: false,
+                    interactive: false,
+                    select_newest: None,
+                    select_largest: None,
+                    exec: None,
+                    exec_ignore_errors: false,
+                    transform: vec![],
+                    absolute_paths: false,
+                    strict: false,
+                    no_symlinks: false,
+                    first_wins: false,
+                    sparse: SparseMode::Auto,
+                    xattrs: false,
+                    keep_partial: false,
+                    same_permissions: false,
+                    no_same_permissions: false,
+                    password_file: None,
+                    continue_on_error: false,
+                    ignore_zeros: false,
+                    max_output_size: None,
+                    max_ratio: None,
+                    max_entries: None,
+                    max_path_depth: None,
+                    max_path_length: None,
+                    no_limits: false,
+                    framed: false,
                 },
                 ..mock_cli_args()
             }
@@ -180,9 +892,27 @@ mod tests {
                 cmd: Subcommand::Compress {
                     files: to_paths(["file"]),
                     output: PathBuf::from("file.tar.gz"),
+                    files_from: None,
+                    null: false,
+                    max_depth: None,
                     level: None,
                     fast: false,
                     slow: false,
+                    rm: false,
+                    each: false,
+                    comment: None,
+                    transform: vec![],
+                    absolute_paths: false,
+                    input_dir: None,
+                    name: None,
+                    force_compress: false,
+                    archive_mtime: None,
+                    no_mmap: false,
+                    xattrs: false,
+                    strict: false,
+                    framed: false,
+                    stdin_name: PathBuf::from("stdin"),
+                    force: false,
                 },
                 ..mock_cli_args()
             }
@@ -193,9 +923,27 @@ mod tests {
                 cmd: Subcommand::Compress {
                     files: to_paths(["a", "b", "c"]),
                     output: PathBuf::from("archive.tar.gz"),
+                    files_from: None,
+                    null: false,
+                    max_depth: None,
                     level: None,
                     fast: false,
                     slow: false,
+                    rm: false,
+                    each: false,
+                    comment: None,
+                    transform: vec![],
+                    absolute_paths: false,
+                    input_dir: None,
+                    name: None,
+                    force_compress: false,
+                    archive_mtime: None,
+                    no_mmap: false,
+                    xattrs: false,
+                    strict: false,
+                    framed: false,
+                    stdin_name: PathBuf::from("stdin"),
+                    force: false,
                 },
                 ..mock_cli_args()
             }
@@ -206,9 +954,27 @@ mod tests {
                 cmd: Subcommand::Compress {
                     files: to_paths(["a", "b", "c"]),
                     output: PathBuf::from("archive.tar.gz"),
+                    files_from: None,
+                    null: false,
+                    max_depth: None,
                     level: None,
                     fast: false,
                     slow: false,
+                    rm: false,
+                    each: false,
+                    comment: None,
+                    transform: vec![],
+                    absolute_paths: false,
+                    input_dir: None,
+                    name: None,
+                    force_compress: false,
+                    archive_mtime: None,
+                    no_mmap: false,
+                    xattrs: false,
+                    strict: false,
+                    framed: false,
+                    stdin_name: PathBuf::from("stdin"),
+                    force: false,
                 },
                 ..mock_cli_args()
             }
@@ -230,9 +996,27 @@ mod tests {
                     cmd: Subcommand::Compress {
                         files: to_paths(["a", "b", "c"]),
                         output: PathBuf::from("output"),
+                        files_from: None,
+                        null: false,
+                        max_depth: None,
                         level: None,
                         fast: false,
                         slow: false,
+                        rm: false,
+                        each: false,
+                        comment: None,
+                        transform: vec![],
+                        absolute_paths: false,
+                        input_dir: None,
+                        name: None,
+                        force_compress: false,
+                        archive_mtime: None,
+                        no_mmap: false,
+                        xattrs: false,
+                        strict: false,
+                        framed: false,
+                        stdin_name: PathBuf::from("stdin"),
+                        force: false,
                     },
                     format: Some("tar.gz".into()),
                     ..mock_cli_args()
@@ -241,11 +1025,34 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("500M"), Ok(500_000_000));
+        assert_eq!(parse_size("2gb"), Ok(2_000_000_000));
+        assert_eq!(parse_size("1GiB"), Ok(1024 * 1024 * 1024));
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("2 parsecs").is_err());
+    }
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!(parse_level("6"), Ok(CompressionLevel::All(6)));
+        assert_eq!(
+            parse_level("gz=6,.xz=9"),
+            Ok(CompressionLevel::PerFormat(vec![("gz".into(), 6), ("xz".into(), 9)]))
+        );
+        assert!(parse_level("fast").is_err());
+        assert!(parse_level("gz=6,9").is_err());
+        assert!(parse_level("gz=max").is_err());
+    }
+
     #[test]
     fn test_clap_cli_err() {
         assert!(CliArgs::try_parse_from(args_splitter("ouch c")).is_err());
         assert!(CliArgs::try_parse_from(args_splitter("ouch c input")).is_err());
         assert!(CliArgs::try_parse_from(args_splitter("ouch d")).is_err());
         assert!(CliArgs::try_parse_from(args_splitter("ouch l")).is_err());
+        assert!(CliArgs::try_parse_from(args_splitter("ouch a archive.tar")).is_err());
     }
 }
@@ -3,31 +3,169 @@
 mod args;
 
 use std::{
+    env,
+    ffi::{OsStr, OsString},
     io,
     path::{Path, PathBuf},
 };
 
-use clap::Parser;
+use clap::{error::ErrorKind, CommandFactory, Parser};
 use fs_err as fs;
 
-pub use self::args::{CliArgs, Subcommand};
-use crate::{accessible::set_accessible, utils::FileVisibilityPolicy, QuestionPolicy};
+pub use self::args::{CliArgs, ColorMode, CompressionLevel, LogFormat, SparseMode, Subcommand, VerifyFormat};
+use crate::{
+    accessible::set_accessible,
+    error::FinalError,
+    extension::extensions_from_path,
+    utils::{
+        logger::{silence_logs, use_json_logs},
+        try_infer_extension, EntryFilter, EscapedPathDisplay, FileVisibilityPolicy,
+    },
+    QuestionPolicy,
+};
+
+/// Largest buffer size accepted by `--buffer-size`, as every reader and writer allocates one
+const MAX_BUFFER_SIZE: usize = 1024 * 1024 * 1024;
 
 impl CliArgs {
-    /// A helper method that calls `clap::Parser::parse`.
+    /// A helper method that calls `clap::Parser::try_parse`.
     ///
     /// And:
     ///   1. Make paths absolute.
     ///   2. Checks the QuestionPolicy.
     pub fn parse_and_validate_args() -> crate::Result<(Self, QuestionPolicy, FileVisibilityPolicy)> {
-        let mut args = Self::parse();
+        let argv: Vec<OsString> = env::args_os().collect();
+        let parsed = match Self::try_parse_from(&argv) {
+            // Like in `ouch archive.tar.gz`, the subcommand may have been left out
+            Err(err) if err.kind() == ErrorKind::InvalidSubcommand => match infer_subcommand(&argv)? {
+                Some(argv) => Self::try_parse_from(argv),
+                None => Err(err),
+            },
+            parsed => parsed,
+        };
+        let mut args = parsed.unwrap_or_else(|err| {
+            // Clap exits with 2 on usage errors, which is the status code of I/O errors in ouch
+            if err.use_stderr() {
+                let _ = err.print();
+                std::process::exit(crate::EXIT_FAILURE);
+            }
+            err.exit()
+        });
+
+        if args.log_format == LogFormat::Json {
+            use_json_logs();
+        }
+        crate::utils::colors::set_color_mode(args.color);
 
         set_accessible(args.accessible);
 
-        let (Subcommand::Compress { files, .. }
-        | Subcommand::Decompress { files, .. }
-        | Subcommand::List { archives: files, .. }) = &mut args.cmd;
-        *files = canonicalize_files(files)?;
+        if args.quiet_errors {
+            args.quiet = true;
+            silence_logs();
+        }
+
+        if let Some(temp_dir) = &args.temp_dir {
+            if !temp_dir.is_dir() {
+                let error = FinalError::with_title(format!(
+                    "Cannot create temporary files in '{}'.",
+                    EscapedPathDisplay::new(temp_dir)
+                ))
+                .detail("It's not an existing directory");
+                return Err(error.into());
+            }
+            crate::utils::io::set_temp_dir(temp_dir);
+        }
+
+        if let Some(buffer_size) = args.buffer_size {
+            match usize::try_from(buffer_size) {
+                Ok(buffer_size) if (1..=MAX_BUFFER_SIZE).contains(&buffer_size) => {
+                    crate::utils::io::set_buffer_size(buffer_size)
+                }
+                _ => {
+                    let error = FinalError::with_title(format!("Invalid buffer size '{buffer_size}'."))
+                        .detail("It must be at least 1 byte, and at most 1GiB");
+                    return Err(error.into());
+                }
+            }
+        }
+
+        if args.no_spill {
+            crate::utils::io::set_max_memory(None);
+        } else if let Some(max_memory) = args.max_memory {
+            crate::utils::io::set_max_memory(Some(max_memory));
+        }
+
+        if let Some(zip_encoding) = &args.zip_encoding {
+            crate::archive::zip::set_name_encoding(zip_encoding)?;
+        }
+
+        if !args.codec_cmd.is_empty() || !args.codec_compress_cmd.is_empty() {
+            crate::utils::external_codec::set_external_codecs(&args.codec_cmd, &args.codec_compress_cmd)?;
+        }
+
+        if let Some(threads) = args.threads {
+            // Only fails if the global pool was already built, which doesn't happen before this
+            let _ = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads.into())
+                .build_global();
+        }
+
+        // Like in `ouch list archive.tar.gz 'src/**/*.rs'`, trailing arguments that aren't files are
+        // patterns of the entries to list, unless they are named like a missing archive
+        if let Subcommand::List { archives, patterns, .. } = &mut args.cmd {
+            let is_pattern = |arg: &PathBuf| {
+                !arg.exists() && (EntryFilter::is_glob(&arg.to_string_lossy()) || extensions_from_path(arg).is_empty())
+            };
+            if let Some(position) = archives.iter().skip(1).position(is_pattern) {
+                *patterns = archives
+                    .split_off(position + 1)
+                    .into_iter()
+                    .map(|pattern| pattern.to_string_lossy().into_owned())
+                    .collect();
+            }
+        }
+
+        if let Subcommand::Compress {
+            files,
+            files_from: Some(files_from),
+            null,
+            ..
+        } = &mut args.cmd
+        {
+            files.extend(read_files_from(files_from, *null)?);
+        }
+
+        if let Subcommand::Compress { files, files_from, .. } = &mut args.cmd {
+            let reads_stdin = files.iter().any(|file| file.as_os_str() == "-");
+            if reads_stdin && files_from.as_deref() == Some(Path::new("-")) {
+                let error = FinalError::with_title("Cannot compress '-' with '--files-from -'.")
+                    .detail("Both read stdin, which can only be read once");
+                return Err(error.into());
+            }
+            // Stdin, given as '-', is read into a file once compressing starts
+            *files = files
+                .iter()
+                .map(|file| {
+                    if file.as_os_str() == "-" {
+                        Ok(file.clone())
+                    } else {
+                        fs::canonicalize(file)
+                    }
+                })
+                .collect::<io::Result<_>>()?;
+        }
+
+        if let Subcommand::Append { files, .. } = &mut args.cmd {
+            *files = canonicalize_files(files)?;
+        }
+
+        if let Subcommand::Decompress { files, .. } | Subcommand::List { archives: files, .. } = &mut args.cmd {
+            *files = canonicalize_archives(files)?;
+        }
+
+        if let Subcommand::Append { archive, .. } = &mut args.cmd {
+            *archive = fs::canonicalize(&archive)?;
+        }
 
         let skip_questions_positively = match (args.yes, args.no) {
             (false, false) => QuestionPolicy::Ask,
@@ -46,6 +184,175 @@ impl CliArgs {
     }
 }
 
+/// The paths listed in `list`, or in stdin if it's `-`, one per line, or separated by NUL bytes
+/// with `null`, for paths that contain newlines.
+fn read_files_from(list: &Path, null: bool) -> crate::Result<Vec<PathBuf>> {
+    let contents = if list == Path::new("-") {
+        let mut contents = vec![];
+        io::Read::read_to_end(&mut io::stdin().lock(), &mut contents)?;
+        contents
+    } else {
+        fs::read(list)?
+    };
+
+    let separator = if null { b'\0' } else { b'\n' };
+    let paths = contents
+        .split(|&byte| byte == separator)
+        .map(|path| {
+            if null {
+                path
+            } else {
+                path.strip_suffix(b"\r").unwrap_or(path)
+            }
+        })
+        .filter(|path| !path.is_empty())
+        .map(path_from_bytes)
+        .collect();
+    Ok(paths)
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    OsStr::from_bytes(bytes).into()
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    String::from_utf8_lossy(bytes).into_owned().into()
+}
+
 fn canonicalize_files(files: &[impl AsRef<Path>]) -> io::Result<Vec<PathBuf>> {
     files.iter().map(fs::canonicalize).collect()
 }
+
+/// Same as `canonicalize_files`, keeping the formats given to archives with `path:format`.
+fn canonicalize_archives(archives: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    archives
+        .iter()
+        .map(|archive| match split_format_suffix(archive) {
+            Some((path, format)) => {
+                let mut archive = fs::canonicalize(path)?.into_os_string();
+                archive.push(":");
+                archive.push(format);
+                Ok(archive.into())
+            }
+            None => fs::canonicalize(archive),
+        })
+        .collect()
+}
+
+/// Split an archive given with its format, like `blob:tar.gz`, into its path and format.
+///
+/// Paths can contain ':' themselves, so the argument is only split if it doesn't exist, while
+/// the path before the last ':' does.
+pub fn split_format_suffix(archive: &Path) -> Option<(&Path, &str)> {
+    let (path, format) = archive.to_str()?.rsplit_once(':')?;
+    let path = Path::new(path);
+    let is_format = !format.is_empty() && !format.contains(['/', '\\']);
+    (is_format && !archive.exists() && path.exists()).then_some((path, format))
+}
+
+/// The arguments given without a subcommand, with `decompress` or `compress` added to them when
+/// it's clear which one was meant:
+///
+/// - Archives only, like `ouch a.tar.gz b.zip`, are decompressed.
+/// - Files followed by an archive that doesn't exist yet, like `ouch file dir out.zip`, are
+///   compressed into it.
+///
+/// Returns `None` if the first argument doesn't look like a file at all, so that clap can report
+/// it as a mistyped subcommand, and an error explaining which subcommand to use otherwise.
+fn infer_subcommand(argv: &[OsString]) -> crate::Result<Option<Vec<OsString>>> {
+    let paths: Vec<&Path> = positional_arguments(argv).into_iter().map(Path::new).collect();
+    let Some(first) = paths.first() else {
+        return Ok(None);
+    };
+    if !first.exists() && extensions_from_path(first).is_empty() {
+        return Ok(None);
+    }
+
+    let is_archive =
+        |path: &Path| !extensions_from_path(path).is_empty() || (path.is_file() && try_infer_extension(path).is_some());
+
+    let subcommand = if paths.iter().all(|path| path.is_file() && is_archive(path)) {
+        "decompress"
+    } else if let [inputs @ .., output] = paths.as_slice() {
+        let compresses = !inputs.is_empty()
+            && inputs.iter().all(|input| input.exists())
+            && !inputs.iter().all(|input| input.is_file() && is_archive(input))
+            && !output.exists()
+            && !extensions_from_path(output).is_empty();
+        if !compresses {
+            return Err(ambiguous_subcommand_error(&paths));
+        }
+        "compress"
+    } else {
+        unreachable!("there's at least one path")
+    };
+
+    // Placed first, the subcommand keeps the meaning of all of the flags
+    let mut argv = argv.to_vec();
+    argv.insert(1, subcommand.into());
+    Ok(Some(argv))
+}
+
+/// Arguments that aren't flags or their values, these are the paths when there's no subcommand.
+///
+/// The flags that take a value are taken from the ones of `compress` and `decompress`.
+fn positional_arguments(argv: &[OsString]) -> Vec<&OsStr> {
+    let command = CliArgs::command();
+    let flags_with_value: Vec<_> = command
+        .get_arguments()
+        .chain(["compress", "decompress"].into_iter().flat_map(|name| {
+            command
+                .find_subcommand(name)
+                .expect("subcommand exists")
+                .get_arguments()
+        }))
+        .filter(|arg| arg.get_action().takes_values() && !arg.is_positional())
+        .collect();
+
+    let mut positionals = vec![];
+    let mut args = argv.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let text = arg.to_string_lossy();
+        let takes_value = if text == "--" {
+            positionals.extend(args.by_ref().map(OsString::as_os_str));
+            break;
+        } else if let Some(long) = text.strip_prefix("--") {
+            !long.contains('=') && flags_with_value.iter().any(|flag| flag.get_long() == Some(long))
+        } else if let Some(shorts) = text.strip_prefix('-').filter(|shorts| !shorts.is_empty()) {
+            // In a group like `-yf`, only the last flag can take the next argument as its value
+            let value_flag = shorts
+                .chars()
+                .position(|short| flags_with_value.iter().any(|flag| flag.get_short() == Some(short)));
+            value_flag == Some(shorts.chars().count() - 1)
+        } else {
+            positionals.push(arg.as_os_str());
+            false
+        };
+        if takes_value {
+            args.next();
+        }
+    }
+    positionals
+}
+
+fn ambiguous_subcommand_error(paths: &[&Path]) -> crate::Error {
+    let mut error = FinalError::with_title("Cannot tell whether to compress or decompress.");
+    // The last path is expected not to exist if it's named like the archive to compress into
+    let output = match paths {
+        [_, .., output] if !extensions_from_path(output).is_empty() => Some(output),
+        _ => None,
+    };
+    for path in paths.iter().filter(|path| !path.exists() && Some(*path) != output) {
+        error = error.detail(format!("'{}' doesn't exist", EscapedPathDisplay::new(path)));
+    }
+    error
+        .detail("Only archives, or files followed by a new archive, can be given without a subcommand")
+        .hint("Use a subcommand to be explicit:")
+        .hint("  ouch decompress <ARCHIVES>...")
+        .hint("  ouch compress <FILES>... <OUTPUT>")
+        .hint("  ouch append <ARCHIVE> <FILES>...")
+        .into()
+}
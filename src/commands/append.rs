@@ -0,0 +1,227 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use fs_err as fs;
+use same_file::Handle;
+
+use crate::{
+    archive,
+    commands::{
+        compress::{chain_writer_encoder, encoder_threads},
+        decompress::chain_reader_decoder,
+    },
+    error::FinalError,
+    extension::CompressionFormat::{self, *},
+    utils::{
+        self,
+        io::{buf_reader, buf_writer},
+        logger::{info, info_accessible, warning},
+        EscapedPathDisplay, FileVisibilityPolicy,
+    },
+};
+
+/// Decides what happens to each entry of the archive while files are appended to it.
+struct AppendPlan {
+    /// Files that are going to be added, indexed by their path inside of the archive
+    pending: BTreeMap<PathBuf, PathBuf>,
+    update: bool,
+    /// Paths of the pending files that replace entries, archives can have several entries with
+    /// the same path, like the ones appended to with `tar -r`
+    replaced: BTreeSet<PathBuf>,
+    skipped: usize,
+}
+
+impl AppendPlan {
+    /// Check if the existing entry at `entry_path`, last modified at `modification_time` (in
+    /// seconds since the unix epoch), stays in the archive.
+    ///
+    /// Entries are replaced by the files with the same path, or with `update`, only by the files
+    /// that were modified after the entry.
+    fn keep_entry(&mut self, entry_path: &Path, modification_time: Option<i64>) -> bool {
+        let Some(path) = self.pending.get(entry_path) else {
+            return true;
+        };
+
+        // All of the entries with its path go, not only the ones older than the file
+        if self.replaced.contains(entry_path) {
+            return false;
+        }
+
+        if self.update && !is_newer(path, modification_time) {
+            self.pending.remove(entry_path);
+            self.skipped += 1;
+            return true;
+        }
+
+        self.replaced.insert(entry_path.to_path_buf());
+        false
+    }
+}
+
+/// Check if the file at `path` was modified after `modification_time`, files are treated as
+/// newer when it's unknown.
+fn is_newer(path: &Path, modification_time: Option<i64>) -> bool {
+    let Some(modification_time) = modification_time else {
+        return true;
+    };
+
+    let file_modification_time = fs::symlink_metadata(path)
+        .ok()
+        .and_then(|metadata| metadata.modified().ok())
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok());
+
+    match file_modification_time {
+        Some(file_modification_time) => file_modification_time.as_secs() as i64 > modification_time,
+        None => true,
+    }
+}
+
+/// Add `files` to the archive at `archive_path`, replacing the entries with the same path.
+///
+/// The archive is rewritten into a temporary file, which only replaces it if everything went
+/// well. With `update`, entries are only replaced by files that were modified after them.
+pub fn append_to_archive(
+    archive_path: &Path,
+    files: &[PathBuf],
+    formats: Vec<CompressionFormat>,
+    update: bool,
+    quiet: bool,
+    file_visibility_policy: FileVisibilityPolicy,
+) -> crate::Result<()> {
+    let archive_display = EscapedPathDisplay::new(archive_path);
+
+    match formats.as_slice() {
+        [Tar] | [Zip] => {}
+        [Tar, compression_formats @ ..] if !compression_formats.iter().any(|format| format.is_archive_format()) => {
+            warning(format!(
+                "'{archive_display}' is compressed, appending requires decompressing and compressing all of it again."
+            ))
+        }
+        _ => {
+            let error = FinalError::with_title(format!("Cannot append to '{archive_display}'."))
+                .detail("Appending is only supported for tar archives, optionally compressed, and zip archives");
+            return Err(error.into());
+        }
+    }
+
+    let mut plan = AppendPlan {
+        pending: collect_pending_files(archive_path, files, file_visibility_policy)?,
+        update,
+        replaced: BTreeSet::new(),
+        skipped: 0,
+    };
+
+    // Unwrap safety:
+    //   paths are canonicalized, and the archive is a file, so it has a parent.
+    let mut temp_file = tempfile::NamedTempFile::new_in(archive_path.parent().unwrap())?;
+
+    if let [Zip] = formats.as_slice() {
+        let mut zip_archive = archive::zip::open_archive(archive_path)?;
+        let mut writer = zip::ZipWriter::new(buf_writer(temp_file.as_file_mut()));
+        writer.set_comment(String::from_utf8_lossy(zip_archive.comment()));
+
+        archive::zip::copy_entries(&mut zip_archive, &mut writer, |entry_path, modification_time| {
+            // Zip stores times with a precision of 2 seconds, rounding them down
+            plan.keep_entry(entry_path, modification_time.map(|time| time + 1))
+        })?;
+
+        for (entry_path, path) in &plan.pending {
+            let entry_name = entry_path.to_str().ok_or_else(|| {
+                FinalError::with_title("Zip requires that all directories names are valid UTF-8")
+                    .detail(format!("File at '{path:?}' has a non-UTF-8 name"))
+            })?;
+            if !quiet {
+                info(format!("Appending '{}'.", EscapedPathDisplay::new(path)));
+            }
+            archive::zip::append_path(&mut writer, path, entry_name, true)?;
+        }
+
+        writer.finish()?.flush()?;
+    } else {
+        let reader = buf_reader(fs::File::open(archive_path)?);
+        let mut reader: Box<dyn Read + Send> = Box::new(reader);
+        for format in formats.iter().skip(1).rev() {
+            reader = chain_reader_decoder(format, reader)?;
+        }
+
+        let writer = buf_writer(temp_file.reopen()?);
+        let mut writer: Box<dyn Send + Write> = Box::new(writer);
+        for format in formats.iter().skip(1).rev() {
+            writer = chain_writer_encoder(format, writer, None, encoder_threads())?;
+        }
+
+        let mut builder = tar::Builder::new(writer);
+
+        archive::tar::copy_entries(reader, &mut builder, |entry_path, modification_time| {
+            plan.keep_entry(entry_path, modification_time.map(|time| time as i64))
+        })?;
+
+        for (entry_path, path) in &plan.pending {
+            if !quiet {
+                info(format!("Appending '{}'.", EscapedPathDisplay::new(path)));
+            }
+            builder.append_path_with_name(path, entry_path)?;
+        }
+
+        // Dropping the encoders finishes their streams
+        builder.into_inner()?.flush()?;
+        utils::external_codec::check_encoders()?;
+    }
+
+    fs::set_permissions(temp_file.path(), fs::metadata(archive_path)?.permissions())?;
+    temp_file.persist(archive_path).map_err(|err| err.error)?;
+
+    info_accessible(format!(
+        "Successfully appended to '{archive_display}': {} added, {} replaced and {} skipped.",
+        plan.pending.len() - plan.replaced.len(),
+        plan.replaced.len(),
+        plan.skipped
+    ));
+
+    Ok(())
+}
+
+/// Walk through `files`, indexing them by the path they'll have inside of the archive, which is
+/// relative to the directory containing each of them.
+fn collect_pending_files(
+    archive_path: &Path,
+    files: &[PathBuf],
+    file_visibility_policy: FileVisibilityPolicy,
+) -> crate::Result<BTreeMap<PathBuf, PathBuf>> {
+    let archive_handle = Handle::from_path(archive_path)?;
+    let mut pending = BTreeMap::new();
+
+    for file in files {
+        let parent = file.parent().ok_or(crate::Error::CompressingRootFolder)?;
+
+        for entry in file_visibility_policy.build_walker(file) {
+            let entry = entry?;
+            let path = entry.path();
+
+            // The archive might be inside of one of the directories being appended
+            if path.is_file() && matches!(Handle::from_path(path), Ok(handle) if handle == archive_handle) {
+                warning(format!(
+                    "Skipping '{}', it's the archive being appended to.",
+                    EscapedPathDisplay::new(path)
+                ));
+                continue;
+            }
+
+            // This path is for a broken symlink, we just ignore it
+            if !path.exists() && utils::is_symlink(path) {
+                continue;
+            }
+
+            let entry_path = path
+                .strip_prefix(parent)
+                .expect("walked paths start with the walk root");
+            pending.insert(entry_path.to_path_buf(), path.to_path_buf());
+        }
+    }
+
+    Ok(pending)
+}
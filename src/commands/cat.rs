@@ -0,0 +1,194 @@
+//! Printing of a single entry of an archive, used by `ouch cat` and `ouch decompress --stdout`.
+
+use std::{
+    io::{self, stdout, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use fs_err as fs;
+
+use crate::{
+    commands::{decompress::chain_reader_decoder, list::for_each_archive_entry},
+    error::FinalError,
+    extension::CompressionFormat,
+    utils::{io::buf_reader, EntryFilter, EscapedPathDisplay},
+    QuestionPolicy,
+};
+
+/// Write the decompressed contents of `input_path` to stdout, like `gunzip -c`.
+///
+/// Archives can only be written if `filter` is a single exact path or glob pattern, which selects
+/// the file to write, like `ouch cat` does. Other files can't be filtered.
+///
+/// # Return value
+/// - Returns `Ok(true)` if the contents were printed.
+/// - Returns `Ok(false)` if the user opted not to buffer the archive.
+pub fn decompress_to_stdout(
+    input_path: &Path,
+    formats: Vec<CompressionFormat>,
+    filter: &[String],
+    question_policy: QuestionPolicy,
+) -> crate::Result<bool> {
+    if formats[0].is_archive_format() {
+        let [entry] = filter else {
+            let input_path = EscapedPathDisplay::new(input_path);
+            let error = FinalError::with_title(format!("Cannot write '{input_path}' to stdout."))
+                .detail("Only one of the files of an archive can be written to stdout")
+                .hint("Select it with '--filter', like:")
+                .hint(format!("  ouch decompress {input_path} --stdout --filter <PATH>"));
+            return Err(error.into());
+        };
+        return cat_archive_entry(input_path, formats, entry, question_policy);
+    }
+    if !filter.is_empty() {
+        let error = FinalError::with_title(format!("Cannot filter '{}'.", EscapedPathDisplay::new(input_path)))
+            .detail("It's not an archive, it has no entries to select with '--filter'");
+        return Err(error.into());
+    }
+
+    let mut reader: Box<dyn Read + Send> = Box::new(buf_reader(fs::File::open(input_path)?));
+    for format in formats.iter().rev() {
+        reader = chain_reader_decoder(format, reader)?;
+    }
+    copy_to_stdout(&mut reader)?;
+    Ok(true)
+}
+
+/// Write the contents of the file entry of `archive_path` selected by `entry`, an exact path or
+/// a glob pattern that matches only one file, to stdout.
+///
+/// # Return value
+/// - Returns `Ok(true)` if the entry was printed.
+/// - Returns `Ok(false)` if the user opted not to buffer the archive.
+pub fn cat_archive_entry(
+    archive_path: &Path,
+    formats: Vec<CompressionFormat>,
+    entry: &str,
+    question_policy: QuestionPolicy,
+) -> crate::Result<bool> {
+    if crate::archive::zip::is_read_in_place(&formats) {
+        cat_zip_entry(archive_path, entry)
+    } else {
+        cat_streamed_entry(archive_path, formats, entry, question_policy)
+    }
+}
+
+/// Zip archives list their entries in the central directory, so the entry is read directly,
+/// without going through the others.
+fn cat_zip_entry(archive_path: &Path, entry: &str) -> crate::Result<bool> {
+    let mut zip_archive = crate::archive::zip::open_archive(archive_path)?;
+
+    let files = crate::archive::zip::file_entries(&mut zip_archive)?;
+    let paths = files.iter().map(|(path, _)| path.clone()).collect();
+    let path = find_entry(archive_path, paths, entry)?;
+
+    // The first of the entries with that path, like for the other formats
+    let (_, idx) = files
+        .into_iter()
+        .find(|(file_path, _)| *file_path == path)
+        .expect("the path is one of the files");
+    let mut file = zip_archive.by_index(idx)?;
+    copy_to_stdout(&mut file)?;
+    Ok(true)
+}
+
+/// Other formats are streamed, an exact path is printed as soon as it's found, while a glob
+/// pattern needs a first pass over the archive to find the file it matches.
+fn cat_streamed_entry(
+    archive_path: &Path,
+    formats: Vec<CompressionFormat>,
+    entry: &str,
+    question_policy: QuestionPolicy,
+) -> crate::Result<bool> {
+    if !EntryFilter::is_glob(entry) {
+        return print_streamed_entry(archive_path, formats, Path::new(entry), question_policy);
+    }
+
+    let mut files = vec![];
+    let finished = for_each_archive_entry(archive_path, formats.clone(), question_policy, |metadata, _| {
+        if !metadata.is_dir {
+            files.push(metadata.path);
+        }
+        Ok(())
+    })?;
+    if !finished {
+        return Ok(false);
+    }
+
+    let path = find_entry(archive_path, files, entry)?;
+    // The user already agreed to buffer the archive in the first pass
+    print_streamed_entry(archive_path, formats, &path, QuestionPolicy::AlwaysYes)
+}
+
+/// Print the first file entry at `path`, an exact path.
+fn print_streamed_entry(
+    archive_path: &Path,
+    formats: Vec<CompressionFormat>,
+    path: &Path,
+    question_policy: QuestionPolicy,
+) -> crate::Result<bool> {
+    let mut found = false;
+    let finished = for_each_archive_entry(archive_path, formats, question_policy, |metadata, contents| {
+        if !found && !metadata.is_dir && relative_entry_path(&metadata.path) == relative_entry_path(path) {
+            found = true;
+            copy_to_stdout(contents)?;
+        }
+        Ok(())
+    })?;
+
+    if finished && !found {
+        return Err(entry_not_found(archive_path, &path.to_string_lossy()));
+    }
+    Ok(finished)
+}
+
+/// Path of the single file of `files` selected by `entry`, a file with exactly that path is
+/// preferred over the ones matched by it as a glob pattern.
+fn find_entry(archive_path: &Path, files: Vec<PathBuf>, entry: &str) -> crate::Result<PathBuf> {
+    let exact_path = relative_entry_path(Path::new(entry));
+    if let Some(path) = files.iter().find(|path| relative_entry_path(path) == exact_path) {
+        return Ok(path.clone());
+    }
+
+    let filter = EntryFilter::new(&[entry.to_string()], false)?;
+    let mut candidates: Vec<PathBuf> = files.into_iter().filter(|path| filter.is_match(path)).collect();
+    candidates.sort();
+    candidates.dedup();
+
+    match candidates.len() {
+        0 => Err(entry_not_found(archive_path, entry)),
+        1 => Ok(candidates.remove(0)),
+        _ => {
+            let mut error = FinalError::with_title(format!(
+                "'{entry}' matches more than one file of '{}'.",
+                EscapedPathDisplay::new(archive_path)
+            ));
+            for candidate in &candidates {
+                error = error.detail(format!("{}", EscapedPathDisplay::new(candidate)));
+            }
+            Err(error.hint("Use the exact path of one of them").into())
+        }
+    }
+}
+
+fn entry_not_found(archive_path: &Path, entry: &str) -> crate::Error {
+    let archive_path = EscapedPathDisplay::new(archive_path);
+    FinalError::with_title(format!("'{entry}' is not a file of '{archive_path}'."))
+        .hint("To see the entries of the archive, use:")
+        .hint(format!("  ouch list {archive_path}"))
+        .into()
+}
+
+/// Entries of archives created with relative paths may start with "./"
+fn relative_entry_path(path: &Path) -> &Path {
+    path.strip_prefix(".").unwrap_or(path)
+}
+
+fn copy_to_stdout(contents: &mut dyn Read) -> io::Result<()> {
+    let mut stdout = stdout().lock();
+    match io::copy(contents, &mut stdout).and_then(|_| stdout.flush()) {
+        // The reader of the output went away, like `head` does, there's nothing left to do
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        result => result,
+    }
+}
@@ -1,130 +1,270 @@
 use std::{
-    io::{self, BufWriter, Cursor, Seek, Write},
-    path::{Path, PathBuf},
+    ffi::OsStr,
+    io::{self, Seek, Write},
+    path::{Component, Path, PathBuf},
 };
 
 use fs_err as fs;
 
-use super::warn_user_about_loading_sevenz_in_memory;
 use crate::{
-    archive,
-    commands::warn_user_about_loading_zip_in_memory,
-    extension::{split_first_compression_format, CompressionFormat::*, Extension},
-    utils::{io::lock_and_flush_output_stdio, user_wants_to_continue, FileVisibilityPolicy},
-    QuestionAction, QuestionPolicy, BUFFER_CAPACITY,
+    archive::{self, ArchiveOutput, PackOptions},
+    cli::CompressionLevel,
+    commands::user_wants_to_buffer,
+    error::FinalError,
+    extension::{
+        flatten_compression_formats, parse_format, split_first_compression_format,
+        CompressionFormat::{self, *},
+        Extension,
+    },
+    utils::{
+        self,
+        external_codec::{self, ExternalEncoder},
+        framed::{self, FramedWriter},
+        io::{buf_reader, buf_writer},
+        logger::{info, warning},
+        mmap, pretty_format_list_of_paths,
+        progress::ProgressReader,
+        EscapedPathDisplay, FileVisibilityPolicy,
+    },
+    QuestionAction, QuestionPolicy,
 };
 
+/// Inputs with at least this percentage of incompressible bytes are warned about
+const MOSTLY_INCOMPRESSIBLE_PERCENTAGE: u64 = 80;
+
+/// Inputs smaller than this are compressed on a single thread, as they fit in one job of the zstd
+/// workers, and in a few blocks of the parallel gzip and snappy encoders
+const PARALLEL_MIN_INPUT_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Single files at least this big are memory-mapped, instead of read through a buffer
+const MMAP_MIN_INPUT_SIZE: u64 = 32 * 1024 * 1024;
+
+/// Threads that the encoders which compress in parallel can use.
+///
+/// `--threads` sizes the rayon pool, which the encoders get entirely, unless they already run in
+/// it, like with `--each`, where the other archives keep the threads busy, and more encoder
+/// threads would only oversubscribe the CPUs.
+pub fn encoder_threads() -> usize {
+    if rayon::current_thread_index().is_some() {
+        1
+    } else {
+        rayon::current_num_threads()
+    }
+}
+
+/// Let the user know about the inputs without contents, which are still stored, so an archive
+/// that looks empty isn't mistaken for a failure.
+fn inform_about_empty_inputs(files: &[PathBuf]) {
+    for file in files {
+        let kind = match fs::symlink_metadata(file) {
+            Ok(metadata) if metadata.is_file() && metadata.len() == 0 => "file",
+            Ok(metadata)
+                if metadata.is_dir() && fs::read_dir(file).is_ok_and(|mut entries| entries.next().is_none()) =>
+            {
+                "directory"
+            }
+            _ => continue,
+        };
+        info(format!(
+            "'{}' is an empty {kind}, it's stored without contents.",
+            EscapedPathDisplay::new(utils::strip_cur_dir(file))
+        ));
+    }
+}
+
+/// Warn when most of the first inputs are already compressed, like images and videos. Unlike zip,
+/// which stores those files as they are, compression formats compress the whole stream, wasting
+/// time.
+fn warn_about_incompressible_inputs(files: &[PathBuf], file_visibility_policy: FileVisibilityPolicy) {
+    match utils::incompressible_percentage(files, file_visibility_policy) {
+        Some(percentage) if percentage >= MOSTLY_INCOMPRESSIBLE_PERCENTAGE => {
+            warning(format!(
+                "{percentage}% of the input is already compressed and will barely shrink, compressing it again is \
+                 slow. Consider a '.zip' archive, which stores those files without compressing them."
+            ));
+        }
+        _ => {}
+    }
+}
+
+/// Read stdin, given as `-` among the `files`, into a file named `stdin_name`, which is compressed
+/// in its place, so it's stored under that name inside of archives.
+///
+/// The file is created inside of a temporary directory, removed once the returned one is dropped.
+pub fn read_stdin_input(files: &mut [PathBuf], stdin_name: &Path) -> crate::Result<Option<tempfile::TempDir>> {
+    let mut stdin_inputs = files.iter_mut().filter(|file| file.as_os_str() == "-");
+    let Some(stdin_input) = stdin_inputs.next() else {
+        return Ok(None);
+    };
+    if stdin_inputs.next().is_some() {
+        let error = FinalError::with_title("Cannot compress '-' more than once.")
+            .detail("It stands for stdin, which can only be read once");
+        return Err(error.into());
+    }
+
+    let mut components = stdin_name.components();
+    if !matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) {
+        let error = FinalError::with_title(format!(
+            "Invalid '--stdin-name' '{}'.",
+            EscapedPathDisplay::new(stdin_name)
+        ))
+        .detail("It must be a file name, without directories");
+        return Err(error.into());
+    }
+
+    let dir = tempfile::tempdir_in(utils::io::temp_dir())?;
+    let path = dir.path().join(stdin_name);
+    let mut file = buf_writer(fs::File::create(&path)?);
+    io::copy(&mut io::stdin().lock(), &mut file)?;
+    file.flush()?;
+    *stdin_input = path;
+    Ok(Some(dir))
+}
+
 /// Compress files into `output_file`.
 ///
 /// # Arguments:
 /// - `files`: is the list of paths to be compressed: ["dir/file1.txt", "dir/file2.txt"]
 /// - `extensions`: is a list of compression formats for compressing, example: [Tar, Gz] (in compression order)
 /// - `output_file` is the resulting compressed file name, example: "archive.tar.gz"
+/// - `output` is where the archive goes, `output_file` is either it or a temporary file next to it
+/// - `levels` has the level of each format of `extensions`, see [`format_levels`]
+/// - `comment` is stored in the archive, only supported by zip
+/// - `pack_options` changes how files are packed, only its `framed` option affects single file formats
+///
+/// Inputs are stored as opaque bytes, the archives among them are never decompressed, unlike the
+/// nested archives extracted by `decompress --recursive`.
 ///
 /// # Return value
 /// - Returns `Ok(true)` if compressed all files normally.
 /// - Returns `Ok(false)` if user opted to abort compression mid-way.
 #[allow(clippy::too_many_arguments)]
 pub fn compress_files(
-    files: Vec<PathBuf>,
+    files: &[PathBuf],
     extensions: Vec<Extension>,
     output_file: fs::File,
-    output_path: &Path,
+    output: &ArchiveOutput,
     quiet: bool,
     question_policy: QuestionPolicy,
     file_visibility_policy: FileVisibilityPolicy,
-    level: Option<i16>,
+    levels: &[Option<i16>],
+    comment: Option<&str>,
+    pack_options: &PackOptions,
 ) -> crate::Result<bool> {
-    // If the input files contain a directory, then the total size will be underestimated
-    let file_writer = BufWriter::with_capacity(BUFFER_CAPACITY, output_file);
-
-    let mut writer: Box<dyn Send + Write> = Box::new(file_writer);
-
-    // Grab previous encoder and wrap it inside of a new one
-    let chain_writer_encoder = |format: &_, encoder| -> crate::Result<_> {
-        let encoder: Box<dyn Send + Write> = match format {
-            Gzip => Box::new(
-                // by default, ParCompress uses a default compression level of 3
-                // instead of the regular default that flate2 uses
-                gzp::par::compress::ParCompress::<gzp::deflate::Gzip>::builder()
-                    .compression_level(
-                        level.map_or_else(Default::default, |l| gzp::Compression::new((l as u32).clamp(0, 9))),
-                    )
-                    .from_writer(encoder),
-            ),
-            Bzip => Box::new(bzip2::write::BzEncoder::new(
-                encoder,
-                level.map_or_else(Default::default, |l| bzip2::Compression::new((l as u32).clamp(1, 9))),
-            )),
-            Lz4 => Box::new(lz4_flex::frame::FrameEncoder::new(encoder).auto_finish()),
-            Lzma => Box::new(xz2::write::XzEncoder::new(
-                encoder,
-                level.map_or(6, |l| (l as u32).clamp(0, 9)),
-            )),
-            Snappy => Box::new(
-                gzp::par::compress::ParCompress::<gzp::snap::Snap>::builder()
-                    .compression_level(gzp::par::compress::Compression::new(
-                        level.map_or_else(Default::default, |l| (l as u32).clamp(0, 9)),
-                    ))
-                    .from_writer(encoder),
-            ),
-            Zstd => {
-                let zstd_encoder = zstd::stream::write::Encoder::new(
-                    encoder,
-                    level.map_or(zstd::DEFAULT_COMPRESSION_LEVEL, |l| {
-                        (l as i32).clamp(zstd::zstd_safe::min_c_level(), zstd::zstd_safe::max_c_level())
-                    }),
-                );
-                // Safety:
-                //     Encoder::new() can only fail if `level` is invalid, but the level
-                //     is `clamp`ed and therefore guaranteed to be valid
-                Box::new(zstd_encoder.unwrap().auto_finish())
-            }
-            Tar | Zip | Rar | SevenZip => unreachable!(),
-        };
-        Ok(encoder)
-    };
+    let output_path = output.path;
+    if !quiet {
+        inform_about_empty_inputs(files);
+    }
 
     let (first_format, formats) = split_first_compression_format(&extensions);
+    let (first_level, levels) = levels.split_first().expect("there's a level for each format");
+
+    // A plain tar archive is written straight to the file, where the inputs can be copied by the
+    // kernel, the clone shares its position
+    let raw_output_file = match first_format {
+        Tar if formats.is_empty() && !pack_options.framed => Some(output_file.try_clone()?),
+        _ => None,
+    };
+
+    let file_writer = buf_writer(output_file);
+
+    let mut writer: Box<dyn Send + Write> = if pack_options.framed {
+        Box::new(FramedWriter::new(file_writer)?)
+    } else {
+        Box::new(file_writer)
+    };
+
+    if pack_options.xattrs && first_format != Tar {
+        warning(format!(
+            "Only tar archives store extended attributes, '--xattrs' is ignored for '{}'.",
+            EscapedPathDisplay::new(output_path)
+        ));
+    }
+
+    let compresses_stream = !formats.is_empty() || !first_format.is_archive_format();
+    if compresses_stream && !pack_options.force_compress {
+        warn_about_incompressible_inputs(files, file_visibility_policy);
+    }
+
+    // The size of directories isn't known without walking them, so they're assumed to be big
+    let input_size: Option<u64> = files
+        .iter()
+        .map(|file| {
+            fs::metadata(file)
+                .ok()
+                .filter(|metadata| metadata.is_file())
+                .map(|metadata| metadata.len())
+        })
+        .sum();
+    let threads = match input_size {
+        Some(input_size) if input_size < PARALLEL_MIN_INPUT_SIZE => 1,
+        _ => encoder_threads(),
+    };
 
-    for format in formats.iter().rev() {
-        writer = chain_writer_encoder(format, writer)?;
+    for (format, level) in formats.iter().zip(levels).rev() {
+        writer = chain_writer_encoder(format, writer, *level, threads)?;
     }
 
     match first_format {
-        Gzip | Bzip | Lz4 | Lzma | Snappy | Zstd => {
-            writer = chain_writer_encoder(&first_format, writer)?;
-            let mut reader = fs::File::open(&files[0]).unwrap();
+        Gzip | Bzip | Lz4 | Lzma | Snappy | Zstd | External(_) => {
+            writer = chain_writer_encoder(&first_format, writer, *first_level, threads)?;
+            let file = fs::File::open(utils::long_path(&files[0])).unwrap();
 
-            io::copy(&mut reader, &mut writer)?;
+            let mapped = match input_size {
+                Some(input_size) if input_size >= MMAP_MIN_INPUT_SIZE && !pack_options.no_mmap => {
+                    mmap::copy_mapped(file.file(), &mut writer)?
+                }
+                _ => None,
+            };
+            let copied = match mapped {
+                Some(copied) => copied,
+                // Pipes and special files, or a file that can't be mapped, are read through a
+                // buffer of '--buffer-size', in as few reads as the encoders allow
+                None => io::copy(&mut buf_reader(ProgressReader(file)), &mut writer)?,
+            };
+            if input_size.is_some_and(|input_size| copied < input_size) {
+                let error =
+                    FinalError::with_title(format!("Cannot compress '{}'.", EscapedPathDisplay::new(&files[0])))
+                        .detail("It was truncated by another program while being compressed")
+                        .hint("Compress it again once nothing else writes to it");
+                return Err(error.into());
+            }
         }
         Tar => {
-            archive::tar::build_archive_from_paths(&files, output_path, &mut writer, file_visibility_policy, quiet)?;
+            archive::tar::build_archive_from_paths(
+                files,
+                output,
+                &mut writer,
+                raw_output_file.as_ref(),
+                file_visibility_policy,
+                quiet,
+                pack_options,
+            )?;
             writer.flush()?;
         }
         Zip => {
-            if !formats.is_empty() {
-                // Locking necessary to guarantee that warning and question
-                // messages stay adjacent
-                let _locks = lock_and_flush_output_stdio();
-
-                warn_user_about_loading_zip_in_memory();
-                if !user_wants_to_continue(output_path, question_policy, QuestionAction::Compression)? {
-                    return Ok(false);
-                }
+            if !formats.is_empty()
+                && !user_wants_to_buffer(output_path, Zip, QuestionAction::Compression, question_policy)?
+            {
+                return Ok(false);
             }
 
-            let mut vec_buffer = Cursor::new(vec![]);
+            let mut buffer = utils::io::spill_buffer();
 
             archive::zip::build_archive_from_paths(
-                &files,
-                output_path,
-                &mut vec_buffer,
+                files,
+                output,
+                &mut buffer,
                 file_visibility_policy,
                 quiet,
+                comment,
+                pack_options,
             )?;
-            vec_buffer.rewind()?;
-            io::copy(&mut vec_buffer, &mut writer)?;
+            buffer.rewind()?;
+            io::copy(&mut buffer, &mut writer)?;
         }
         Rar => {
             #[cfg(feature = "unrar")]
@@ -134,23 +274,177 @@ pub fn compress_files(
             return Err(archive::rar_stub::no_support());
         }
         SevenZip => {
-            if !formats.is_empty() {
-                // Locking necessary to guarantee that warning and question
-                // messages stay adjacent
-                let _locks = lock_and_flush_output_stdio();
-
-                warn_user_about_loading_sevenz_in_memory();
-                if !user_wants_to_continue(output_path, question_policy, QuestionAction::Compression)? {
-                    return Ok(false);
-                }
+            if !formats.is_empty()
+                && !user_wants_to_buffer(output_path, SevenZip, QuestionAction::Compression, question_policy)?
+            {
+                return Ok(false);
             }
 
-            let mut vec_buffer = Cursor::new(vec![]);
-            archive::sevenz::compress_sevenz(&files, output_path, &mut vec_buffer, file_visibility_policy, quiet)?;
-            vec_buffer.rewind()?;
-            io::copy(&mut vec_buffer, &mut writer)?;
+            let mut buffer = utils::io::spill_buffer();
+            archive::sevenz::compress_sevenz(files, output, &mut buffer, file_visibility_policy, quiet, pack_options)?;
+            buffer.rewind()?;
+            io::copy(&mut buffer, &mut writer)?;
         }
     }
 
+    // Dropping the encoders finishes their streams
+    drop(writer);
+    external_codec::check_encoders()?;
+    framed::check_framed_writers()?;
+
     Ok(true)
 }
+
+/// The level of each format of `extensions` in compression order, set by `--level`, `None` for
+/// the default level of the format.
+///
+/// Fails if a level is given for a format that `output_path` isn't compressed with.
+pub fn format_levels(
+    level: Option<&CompressionLevel>,
+    extensions: &[Extension],
+    output_path: &Path,
+) -> crate::Result<Vec<Option<i16>>> {
+    let formats = flatten_compression_formats(extensions);
+    let format_levels = match level {
+        None => return Ok(vec![None; formats.len()]),
+        Some(CompressionLevel::All(level)) => return Ok(vec![Some(*level); formats.len()]),
+        Some(CompressionLevel::PerFormat(format_levels)) => format_levels,
+    };
+
+    let mut levels = vec![None; formats.len()];
+    for (extension, level) in format_levels {
+        let format = match parse_format(OsStr::new(extension)).as_deref() {
+            Ok([extension]) if extension.compression_formats.len() == 1 => extension.compression_formats[0],
+            _ => {
+                let error = FinalError::with_title(format!("Cannot set the compression level of '{extension}'."))
+                    .detail("It's not the extension of a single format")
+                    .hint("Use the extension of each format, like 'tar=0,gz=9' instead of 'tgz=9'");
+                return Err(error.into());
+            }
+        };
+        if !formats.contains(&format) {
+            let error = FinalError::with_title(format!("Cannot set the compression level of '{extension}'."))
+                .detail(format!(
+                    "'{}' isn't compressed with this format",
+                    EscapedPathDisplay::new(output_path)
+                ))
+                .hint("Only give levels for the formats of the output, like 'gz=6,xz=9' for 'file.tar.gz.xz'");
+            return Err(error.into());
+        }
+
+        for (format_level, _) in levels.iter_mut().zip(&formats).filter(|(_, other)| **other == format) {
+            *format_level = Some(*level);
+        }
+    }
+    Ok(levels)
+}
+
+/// Remove the input `files` after they were compressed into `output_path`.
+///
+/// Inputs that contain the output archive are kept, removing them would also delete the archive.
+///
+/// Failing to remove an input is not fatal, since the archive was already written, so the paths
+/// that couldn't be removed are only reported with a warning.
+pub fn remove_input_files(files: &[PathBuf], output_path: &Path) {
+    // Inputs are canonicalized, so the output path must be too in order to compare them
+    let output_path = fs::canonicalize(output_path).unwrap_or_else(|_| output_path.to_path_buf());
+    let mut failed_to_remove = vec![];
+
+    for path in files {
+        if output_path.starts_with(path) {
+            warning(format!(
+                "Not removing '{}' because it contains the output file.",
+                EscapedPathDisplay::new(path)
+            ));
+            continue;
+        }
+
+        match utils::remove_file_or_dir(path) {
+            Ok(()) => info(format!("Removed '{}'.", EscapedPathDisplay::new(path))),
+            Err(_) => failed_to_remove.push(path),
+        }
+    }
+
+    if !failed_to_remove.is_empty() {
+        warning(format!(
+            "Failed to remove the input files: {}",
+            pretty_format_list_of_paths(&failed_to_remove)
+        ));
+    }
+}
+
+/// Grab previous encoder and wrap it inside of a new one, `level` is applied to the new encoder
+///
+/// The encoders that compress in parallel use up to `threads`, see `encoder_threads`, with a single
+/// thread they would only add the overhead of passing the blocks around.
+pub fn chain_writer_encoder(
+    format: &CompressionFormat,
+    encoder: Box<dyn Send + Write>,
+    level: Option<i16>,
+    threads: usize,
+) -> crate::Result<Box<dyn Send + Write>> {
+    let encoder: Box<dyn Send + Write> = match format {
+        Gzip => {
+            // by default, ParCompress uses a default compression level of 3
+            // instead of the regular default that flate2 uses
+            let level = level.map_or_else(|| flate2::Compression::default().level(), |l| (l as u32).clamp(0, 9));
+            if threads == 1 {
+                Box::new(flate2::write::GzEncoder::new(encoder, flate2::Compression::new(level)))
+            } else {
+                // The blocks are stitched into a single gzip member, readable by any gzip decoder
+                Box::new(
+                    gzp::par::compress::ParCompress::<gzp::deflate::Gzip>::builder()
+                        .num_threads(threads)
+                        .expect("rayon has at least one thread")
+                        .compression_level(gzp::Compression::new(level))
+                        .from_writer(encoder),
+                )
+            }
+        }
+        Bzip => {
+            let level = level.map_or_else(Default::default, |l| bzip2::Compression::new((l as u32).clamp(1, 9)));
+            if threads == 1 {
+                Box::new(bzip2::write::BzEncoder::new(encoder, level))
+            } else {
+                // One stream per block, like pbzip2, readable by any bzip2 decoder
+                Box::new(utils::ParBzEncoder::new(encoder, level, threads))
+            }
+        }
+        Lz4 => Box::new(lz4_flex::frame::FrameEncoder::new(encoder).auto_finish()),
+        Lzma => Box::new(xz2::write::XzEncoder::new(
+            encoder,
+            level.map_or(6, |l| (l as u32).clamp(0, 9)),
+        )),
+        Snappy if threads == 1 => Box::new(snap::write::FrameEncoder::new(encoder)),
+        Snappy => Box::new(
+            gzp::par::compress::ParCompress::<gzp::snap::Snap>::builder()
+                .num_threads(threads)
+                .expect("rayon has at least one thread")
+                .compression_level(gzp::par::compress::Compression::new(
+                    level.map_or_else(Default::default, |l| (l as u32).clamp(0, 9)),
+                ))
+                .from_writer(encoder),
+        ),
+        Zstd => {
+            let zstd_encoder = zstd::stream::write::Encoder::new(
+                encoder,
+                level.map_or(zstd::DEFAULT_COMPRESSION_LEVEL, |l| {
+                    (l as i32).clamp(zstd::zstd_safe::min_c_level(), zstd::zstd_safe::max_c_level())
+                }),
+            );
+            // Safety:
+            //     Encoder::new() can only fail if `level` is invalid, but the level
+            //     is `clamp`ed and therefore guaranteed to be valid
+            let mut zstd_encoder = zstd_encoder.unwrap();
+            // The workers compress jobs of the input while this thread feeds them, the jobs are
+            // joined into a standard frame
+            if threads > 1 {
+                zstd_encoder.multithread(threads as u32)?;
+            }
+            Box::new(zstd_encoder.auto_finish())
+        }
+        External(index) => Box::new(ExternalEncoder::new(*index, encoder)?),
+        Tar | Zip | Rar | SevenZip => unreachable!(),
+    };
+    Ok(encoder)
+}
@@ -1,22 +1,38 @@
 use std::{
-    io::{self, BufReader, Read},
+    cmp::Reverse,
+    io::{self, Read, Seek},
     ops::ControlFlow,
     path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
 };
 
 use fs_err as fs;
+use same_file::Handle;
 
 use crate::{
-    commands::{warn_user_about_loading_sevenz_in_memory, warn_user_about_loading_zip_in_memory},
+    archive::{limits::ExtractionBudget, UnpackOptions},
+    commands::{list::for_each_archive_entry, user_wants_to_buffer},
+    error::FinalError,
     extension::{
-        split_first_compression_format,
+        self, split_first_compression_format,
         CompressionFormat::{self, *},
         Extension,
     },
     utils::{
-        self, io::lock_and_flush_output_stdio, logger::info_accessible, nice_directory_display, user_wants_to_continue,
+        self,
+        external_codec::ExternalDecoder,
+        framed::FramedReader,
+        interrupt::IncompleteOutput,
+        io::{buf_reader, buf_writer, DecoderReader, OffsetReader, ReadSeek},
+        logger::{info, info_accessible, warning},
+        nice_directory_display,
+        progress::ProgressReader,
+        try_infer_extension, user_wants_to_extract_entry, Bytes, EntryFilter, EscapedPathDisplay, SparseWriter,
     },
-    QuestionAction, QuestionPolicy, BUFFER_CAPACITY,
+    QuestionAction, QuestionPolicy,
 };
 
 /// Decompress a file
@@ -25,6 +41,19 @@ use crate::{
 /// formats contains each format necessary for decompression, example: [Gz, Tar] (in decompression order)
 /// output_dir it's where the file will be decompressed to, this function assumes that the directory exists
 /// output_file_path is only used when extracting single file formats, not archive formats like .tar or .zip
+/// options changes how archive entries are unpacked, only its `exec` hook applies to single file formats
+/// budget is what the output counts towards, shared with the archives nested in the one given by the user
+///
+/// # Return value
+/// - Returns `Ok(Some(outputs))` if decompressed all files normally, with the paths the files were
+///   placed at, a directory for archives that were extracted into one.
+/// - Returns `Ok(None)` if user opted to abort decompression mid-way.
+/// - Returns an error explaining which limit was exceeded if it expanded beyond `options.limits`.
+/// - Returns an error telling where the input is corrupted or truncated, and how much of it was
+///   recovered, if its data is invalid.
+/// - Returns an error naming the zip entries that don't match their checksum, once the others
+///   were extracted with `options.continue_on_error`.
+#[allow(clippy::too_many_arguments)]
 pub fn decompress_file(
     input_file_path: &Path,
     formats: Vec<Extension>,
@@ -32,32 +61,343 @@ pub fn decompress_file(
     output_file_path: PathBuf,
     question_policy: QuestionPolicy,
     quiet: bool,
-) -> crate::Result<()> {
+    options: &UnpackOptions,
+    budget: &ExtractionBudget,
+) -> crate::Result<Option<Vec<PathBuf>>> {
+    let input_size = fs::metadata(input_file_path)?.len();
+    let input_offset = Arc::default();
+    let result = decompress_file_within_budget(
+        input_file_path,
+        formats.clone(),
+        output_dir,
+        output_file_path,
+        question_policy,
+        quiet,
+        options,
+        budget,
+        &input_offset,
+    );
+
+    match budget.exceeded_error(input_file_path) {
+        Some(exceeded_error) if result.is_err() => Err(exceeded_error),
+        // The other entries were extracted, with '--continue-on-error'
+        _ if result.is_ok() => match budget.corrupt_entries_error(input_file_path) {
+            Some(corrupt_entries_error) => Err(corrupt_entries_error),
+            None => result,
+        },
+        // The entry that doesn't match its checksum was already named
+        _ if budget.has_corrupt_entries() => result,
+        _ => result.map_err(|err| {
+            let input_offset = input_offset.load(Ordering::Relaxed);
+            corruption_error(err, input_file_path, &formats, input_size, input_offset, budget)
+        }),
+    }
+}
+
+/// Add to `err`, if the data of `input_file_path` is invalid, where the decoders stopped in it,
+/// whether it looks truncated or corrupted, how much was recovered and from which entry, with
+/// what can be done about it.
+///
+/// `input_offset` is how much of the input the decoders read, which is 0 for the archives read
+/// in place, like '.zip', whose readers seek around.
+fn corruption_error(
+    err: crate::Error,
+    input_file_path: &Path,
+    formats: &[Extension],
+    input_size: u64,
+    input_offset: u64,
+    budget: &ExtractionBudget,
+) -> crate::Error {
+    let crate::Error::CorruptArchive { mut reason } = err else {
+        return err;
+    };
+
+    // Checksums are checked once the data they cover was read, so these fail at its end too
+    let title = reason.title().to_lowercase();
+    let checksum_mismatch = title.contains("checksum") || title.contains("crc");
+    // The decoders read all of it and still wanted more
+    let truncated = !checksum_mismatch && input_offset > 0 && input_offset >= input_size;
+    if checksum_mismatch {
+        reason = reason.detail("Its data doesn't match its checksum, it looks corrupted rather than truncated");
+    } else if truncated {
+        reason = reason.detail(format!(
+            "All of its {} were read before its data ended, it looks truncated",
+            Bytes::new(input_size)
+        ));
+    } else if input_offset > 0 {
+        reason = reason.detail(format!(
+            "The invalid data is around byte {input_offset} of {input_size}, it looks corrupted rather than truncated"
+        ));
+    }
+    reason = reason.detail(format!(
+        "{} were recovered before the error",
+        Bytes::new(budget.written())
+    ));
+    if let Some(entry) = budget.last_entry() {
+        reason = reason.detail(format!(
+            "It happened while extracting the entry '{}'",
+            EscapedPathDisplay::new(&entry)
+        ));
+    }
+
+    // The format of the extensions may not be the real one, if it was given with '--yes'
+    let formats = extension::flatten_compression_formats(formats);
+    let detected_format = try_infer_extension(input_file_path).filter(|detected_format| {
+        !matches!(formats.last(), Some(External(_))) && !formats.ends_with(detected_format.compression_formats)
+    });
+    reason = match detected_format {
+        Some(detected_format) => reason.hint(format!(
+            "Its contents look like a '{detected_format}' file, give its format with '--format {detected_format}'"
+        )),
+        None if truncated => {
+            reason.hint("If it was downloaded or copied, it was likely cut short, download or copy it again")
+        }
+        None => reason.hint("Download or copy it again, and check it against its published checksum if it has one"),
+    };
+
+    crate::Error::CorruptArchive { reason }
+}
+
+/// Ask which of the entries of the archive at `archive_path` selected by `filter` to extract, for
+/// `--interactive`, and restrict `filter` to them. Directories are extracted along with the files
+/// they contain, so they aren't asked about.
+///
+/// No entry is picked if the user opted not to buffer the archive to read its entries.
+pub fn pick_entries(
+    archive_path: &Path,
+    formats: &[Extension],
+    filter: &EntryFilter,
+    question_policy: QuestionPolicy,
+) -> crate::Result<EntryFilter> {
+    let formats = extension::flatten_compression_formats(formats);
+    let mut picked = vec![];
+    for_each_archive_entry(archive_path, formats, question_policy, |metadata, _| {
+        if !metadata.is_dir
+            && filter.is_match(&metadata.path)
+            && user_wants_to_extract_entry(&metadata.path, archive_path, question_policy)?
+        {
+            picked.push(metadata.path);
+        }
+        Ok(())
+    })?;
+    Ok(filter.clone().pick(picked))
+}
+
+/// Which files of each archive are extracted, set by `--select-newest` or `--select-largest`
+#[derive(Clone, Copy, Debug)]
+pub enum EntrySelection {
+    /// The most recently modified ones
+    Newest(u64),
+    /// The largest ones
+    Largest(u64),
+}
+
+/// Restrict `filter` to the files of the archive at `archive_path` picked by `selection`, among
+/// the ones it selects. Their metadata is read in a first pass over the entries, before the picked
+/// ones are extracted in a second one.
+///
+/// The files without modification time come last for `--select-newest`, and the ties are broken
+/// by the order of the archive. No file is picked if the user opted not to buffer the archive.
+pub fn select_entries(
+    archive_path: &Path,
+    formats: &[Extension],
+    filter: &EntryFilter,
+    selection: EntrySelection,
+    question_policy: QuestionPolicy,
+) -> crate::Result<EntryFilter> {
+    let formats = extension::flatten_compression_formats(formats);
+    let mut files = vec![];
+    for_each_archive_entry(archive_path, formats, question_policy, |metadata, _| {
+        if !metadata.is_dir && filter.is_match(&metadata.path) {
+            files.push(metadata);
+        }
+        Ok(())
+    })?;
+
+    let (count, which) = match selection {
+        EntrySelection::Newest(count) => {
+            files.sort_by_key(|metadata| Reverse(metadata.mtime));
+            (count, "newest")
+        }
+        EntrySelection::Largest(count) => {
+            files.sort_by_key(|metadata| Reverse(metadata.size));
+            (count, "largest")
+        }
+    };
+    let count = usize::try_from(count).unwrap_or(usize::MAX).min(files.len());
+    info(format!(
+        "Extracting the {count} {which} of the {} files of '{}'.",
+        files.len(),
+        EscapedPathDisplay::new(archive_path)
+    ));
+    files.truncate(count);
+    Ok(filter.clone().pick(files.into_iter().map(|metadata| metadata.path)))
+}
+
+/// Decompress the archives found among `outputs`, the files extracted from another archive, next
+/// to where they are, then the ones found among their own files, up to `max_nesting` levels deep.
+///
+/// Archives are recognized by their extensions, and the ones nested deeper than `max_nesting` are
+/// left as they are. With `rm_nested`, each one is removed once it was decompressed.
+///
+/// Their output counts towards `budget`, the one of the archive they were extracted from, so the
+/// limits apply to all of them together.
+///
+/// # Return value
+/// - Returns `Ok(true)` if decompressed all nested archives normally.
+/// - Returns `Ok(false)` if user opted to abort decompression mid-way.
+pub fn decompress_nested(
+    outputs: Vec<PathBuf>,
+    max_nesting: usize,
+    rm_nested: bool,
+    question_policy: QuestionPolicy,
+    quiet: bool,
+    options: &UnpackOptions,
+    budget: &ExtractionBudget,
+) -> crate::Result<bool> {
+    let mut outputs = outputs;
+
+    for depth in 1.. {
+        let mut archives = vec![];
+        for output in &outputs {
+            find_nested_archives(output, &mut archives)?;
+        }
+
+        if depth > max_nesting {
+            for archive in &archives {
+                warning(format!(
+                    "Not decompressing '{}', it's nested more than {max_nesting} archives deep, raise the limit with '--max-nesting'.",
+                    EscapedPathDisplay::new(archive)
+                ));
+            }
+            break;
+        }
+
+        outputs = vec![];
+        for archive in archives {
+            let output_dir = archive.parent().unwrap_or(Path::new("."));
+            let (file_name, formats) = extension::separate_known_extensions_from_name(&archive);
+            let output_file_path = output_dir.join(file_name);
+
+            info(format!(
+                "Decompressing the nested archive '{}'.",
+                EscapedPathDisplay::new(&archive)
+            ));
+            let Some(nested_outputs) = decompress_file(
+                &archive,
+                formats,
+                output_dir,
+                output_file_path,
+                question_policy,
+                quiet,
+                options,
+                budget,
+            )?
+            else {
+                return Ok(false);
+            };
+            outputs.extend(nested_outputs);
+
+            if rm_nested {
+                match utils::remove_file_or_dir(&archive) {
+                    Ok(()) => info(format!("Removed '{}'.", EscapedPathDisplay::new(&archive))),
+                    Err(_) => warning(format!(
+                        "Failed to remove the nested archive '{}'.",
+                        EscapedPathDisplay::new(&archive)
+                    )),
+                }
+            }
+        }
+
+        if outputs.is_empty() {
+            break;
+        }
+    }
+
+    Ok(true)
+}
+
+/// Collect the files under `path` whose extensions are of known formats into `archives`, in the
+/// order of their paths, without following symlinks.
+fn find_nested_archives(path: &Path, archives: &mut Vec<PathBuf>) -> crate::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+
+    if metadata.is_dir() {
+        let mut children = fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        children.sort();
+        children
+            .iter()
+            .try_for_each(|child| find_nested_archives(child, archives))?;
+    } else if metadata.is_file() && !extension::extensions_from_path(path).is_empty() {
+        archives.push(path.to_path_buf());
+    }
+
+    Ok(())
+}
+
+/// Same as `decompress_file`, with the output counted towards `budget`, and what's read of the
+/// input counted in `input_offset`.
+///
+/// Archives are unpacked into a temporary directory, which is removed if a limit is exceeded,
+/// while the output of single file formats is removed here.
+#[allow(clippy::too_many_arguments)]
+fn decompress_file_within_budget(
+    input_file_path: &Path,
+    formats: Vec<Extension>,
+    output_dir: &Path,
+    output_file_path: PathBuf,
+    question_policy: QuestionPolicy,
+    quiet: bool,
+    options: &UnpackOptions,
+    budget: &ExtractionBudget,
+    input_offset: &Arc<AtomicU64>,
+) -> crate::Result<Option<Vec<PathBuf>>> {
     assert!(output_dir.exists());
-    let reader = fs::File::open(input_file_path)?;
 
     // Zip archives are special, because they require io::Seek, so it requires it's logic separated
     // from decoder chaining.
     //
-    // This is the only case where we can read and unpack it directly, without having to do
-    // in-memory decompression/copying first.
+    // This is the only case where we can read and unpack it directly, seeking through its file
+    // without having to decompress/copy it into a buffer first, so memory usage stays bounded by
+    // the IO buffers however large the archive is.
     //
-    // Any other Zip decompression done can take up the whole RAM and freeze ouch.
-    if let [Extension {
-        compression_formats: [Zip],
-        ..
-    }] = formats.as_slice()
-    {
-        let zip_archive = zip::ZipArchive::new(reader)?;
-        let files_unpacked = if let ControlFlow::Continue(files) = smart_unpack(
-            |output_dir| crate::archive::zip::unpack_archive(zip_archive, output_dir, quiet),
+    // Any other Zip decompression is buffered, spilling to a temporary file when it's large.
+    if !options.framed && crate::archive::zip::is_read_in_place(&extension::flatten_compression_formats(&formats)) {
+        let zip_archive = crate::archive::zip::open_archive(input_file_path)?;
+        let (files_unpacked, outputs) = if let ControlFlow::Continue(unpacked) = smart_unpack(
+            |output_dir| {
+                // With a single thread, the entries are extracted in order, as they are planned
+                if rayon::current_num_threads() == 1 {
+                    crate::archive::zip::unpack_archive(
+                        zip_archive,
+                        output_dir,
+                        quiet,
+                        question_policy,
+                        options,
+                        budget,
+                    )
+                } else {
+                    crate::archive::zip::unpack_archive_in_parallel(
+                        zip_archive,
+                        output_dir,
+                        quiet,
+                        question_policy,
+                        options,
+                        budget,
+                    )
+                }
+            },
+            input_file_path,
             output_dir,
             &output_file_path,
             question_policy,
+            options,
         )? {
-            files
+            unpacked
         } else {
-            return Ok(());
+            return Ok(None);
         };
 
         // this is only printed once, so it doesn't result in much text. On the other hand,
@@ -70,26 +410,15 @@ pub fn decompress_file(
             files_unpacked
         ));
 
-        return Ok(());
+        return Ok(Some(outputs));
     }
 
     // Will be used in decoder chaining
-    let reader = BufReader::with_capacity(BUFFER_CAPACITY, reader);
-    let mut reader: Box<dyn Read> = Box::new(reader);
-
-    // Grab previous decoder and wrap it inside of a new one
-    let chain_reader_decoder = |format: &CompressionFormat, decoder: Box<dyn Read>| -> crate::Result<Box<dyn Read>> {
-        let decoder: Box<dyn Read> = match format {
-            Gzip => Box::new(flate2::read::GzDecoder::new(decoder)),
-            Bzip => Box::new(bzip2::read::BzDecoder::new(decoder)),
-            Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(decoder)),
-            Lzma => Box::new(xz2::read::XzDecoder::new(decoder)),
-            Snappy => Box::new(snap::read::FrameDecoder::new(decoder)),
-            Zstd => Box::new(zstd::stream::Decoder::new(decoder)?),
-            Tar | Zip | Rar | SevenZip => unreachable!(),
-        };
-        Ok(decoder)
-    };
+    let reader = buf_reader(fs::File::open(input_file_path)?);
+    let mut reader: Box<dyn Read + Send> = Box::new(OffsetReader(ProgressReader(reader), Arc::clone(input_offset)));
+    if options.framed {
+        reader = Box::new(FramedReader::new(reader));
+    }
 
     let (first_extension, extensions) = split_first_compression_format(&formats);
 
@@ -97,75 +426,146 @@ pub fn decompress_file(
         reader = chain_reader_decoder(format, reader)?;
     }
 
-    let files_unpacked = match first_extension {
-        Gzip | Bzip | Lz4 | Lzma | Snappy | Zstd => {
+    let (files_unpacked, outputs) = match first_extension {
+        Gzip | Bzip | Lz4 | Lzma | Snappy | Zstd | External(_) => {
             reader = chain_reader_decoder(&first_extension, reader)?;
 
-            let mut writer = match utils::ask_to_create_file(&output_file_path, question_policy)? {
-                Some(file) => file,
-                None => return Ok(()),
-            };
+            let output_file_path = output_path_besides_input(input_file_path, &output_file_path, question_policy)?;
+            if output_file_path.exists() && !utils::user_wants_to_overwrite(&output_file_path, question_policy)? {
+                return Ok(None);
+            }
 
-            io::copy(&mut reader, &mut writer)?;
+            // Written to a temporary file next to the output, which is only moved into place once
+            // it's complete, so a failure or an interruption never leaves a partial file there
+            let mut temp_builder = tempfile::Builder::new();
+            temp_builder.prefix(".ouch-");
+            // With the permissions a new file gets, instead of only being readable by its owner
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                temp_builder.permissions(std::fs::Permissions::from_mode(0o666));
+            }
+            let temp_path = temp_builder.tempfile_in(output_dir)?.into_temp_path();
+            let incomplete_output = IncompleteOutput::new(&temp_path);
+            let writer = buf_writer(fs::OpenOptions::new().write(true).open(&temp_path)?);
+            let mut writer = SparseWriter::new(writer, options.sparse);
+            let copied = io::copy(&mut budget.reader(reader, None), &mut writer);
+            if let Err(err) = copied.and_then(|_| writer.finish()) {
+                let err = utils::io::entry_write_error(err, &output_file_path, &temp_path, None);
+                if matches!(err, crate::Error::StorageFull { .. })
+                    && keeps_partial_output(&output_file_path, question_policy, options)
+                {
+                    if let Ok(partial) = temp_path.keep() {
+                        keep_partial_output(&partial, &output_file_path);
+                    }
+                }
+                return Err(err);
+            }
 
-            1
+            if output_file_path.is_dir() {
+                utils::remove_file_or_dir(&output_file_path)?;
+            }
+            utils::move_path(&temp_path, &output_file_path)?;
+            drop(incomplete_output);
+
+            if let Some(exec) = &options.exec {
+                exec.run_on_tree(&output_file_path)?;
+            }
+
+            (1, vec![output_file_path])
         }
         Tar => {
-            if let ControlFlow::Continue(files) = smart_unpack(
-                |output_dir| crate::archive::tar::unpack_archive(reader, output_dir, quiet),
+            // Tar entries aren't compressed on their own, so the whole stream is counted
+            let reader = Box::new(budget.reader(reader, None));
+            if let ControlFlow::Continue(unpacked) = smart_unpack(
+                |output_dir| {
+                    crate::archive::tar::unpack_archive(reader, output_dir, quiet, question_policy, options, budget)
+                },
+                input_file_path,
                 output_dir,
                 &output_file_path,
                 question_policy,
+                options,
             )? {
-                files
+                unpacked
             } else {
-                return Ok(());
+                return Ok(None);
             }
         }
         Zip => {
-            if formats.len() > 1 {
-                // Locking necessary to guarantee that warning and question
-                // messages stay adjacent
-                let _locks = lock_and_flush_output_stdio();
-
-                warn_user_about_loading_zip_in_memory();
-                if !user_wants_to_continue(input_file_path, question_policy, QuestionAction::Decompression)? {
-                    return Ok(());
-                }
+            if formats.len() > 1
+                && !user_wants_to_buffer(input_file_path, Zip, QuestionAction::Decompression, question_policy)?
+            {
+                return Ok(None);
             }
 
-            let mut vec = vec![];
-            io::copy(&mut reader, &mut vec)?;
-            let zip_archive = zip::ZipArchive::new(io::Cursor::new(vec))?;
+            let mut buffer = utils::io::spill_buffer();
+            io::copy(&mut reader, &mut buffer)?;
+            buffer.rewind()?;
+            let zip_archive = zip::ZipArchive::new(buffer)?;
 
-            if let ControlFlow::Continue(files) = smart_unpack(
-                |output_dir| crate::archive::zip::unpack_archive(zip_archive, output_dir, quiet),
+            if let ControlFlow::Continue(unpacked) = smart_unpack(
+                |output_dir| {
+                    crate::archive::zip::unpack_archive(
+                        zip_archive,
+                        output_dir,
+                        quiet,
+                        question_policy,
+                        options,
+                        budget,
+                    )
+                },
+                input_file_path,
                 output_dir,
                 &output_file_path,
                 question_policy,
+                options,
             )? {
-                files
+                unpacked
             } else {
-                return Ok(());
+                return Ok(None);
             }
         }
         #[cfg(feature = "unrar")]
         Rar => {
             type UnpackResult = crate::Result<usize>;
-            let unpack_fn: Box<dyn FnOnce(&Path) -> UnpackResult> = if formats.len() > 1 {
-                let mut temp_file = tempfile::NamedTempFile::new()?;
+            let unpack_fn: Box<dyn FnOnce(&Path) -> UnpackResult> = if formats.len() > 1 || options.framed {
+                let mut temp_file = tempfile::NamedTempFile::new_in(utils::io::temp_dir())?;
                 io::copy(&mut reader, &mut temp_file)?;
-                Box::new(move |output_dir| crate::archive::rar::unpack_archive(temp_file.path(), output_dir, quiet))
+                Box::new(move |output_dir| {
+                    crate::archive::rar::unpack_archive(
+                        temp_file.path(),
+                        output_dir,
+                        quiet,
+                        question_policy,
+                        options,
+                        budget,
+                    )
+                })
             } else {
-                Box::new(|output_dir| crate::archive::rar::unpack_archive(input_file_path, output_dir, quiet))
+                Box::new(|output_dir| {
+                    crate::archive::rar::unpack_archive(
+                        input_file_path,
+                        output_dir,
+                        quiet,
+                        question_policy,
+                        options,
+                        budget,
+                    )
+                })
             };
 
-            if let ControlFlow::Continue(files) =
-                smart_unpack(unpack_fn, output_dir, &output_file_path, question_policy)?
-            {
-                files
+            if let ControlFlow::Continue(unpacked) = smart_unpack(
+                unpack_fn,
+                input_file_path,
+                output_dir,
+                &output_file_path,
+                question_policy,
+                options,
+            )? {
+                unpacked
             } else {
-                return Ok(());
+                return Ok(None);
             }
         }
         #[cfg(not(feature = "unrar"))]
@@ -173,29 +573,46 @@ pub fn decompress_file(
             return Err(crate::archive::rar_stub::no_support());
         }
         SevenZip => {
-            if formats.len() > 1 {
-                // Locking necessary to guarantee that warning and question
-                // messages stay adjacent
-                let _locks = lock_and_flush_output_stdio();
-
-                warn_user_about_loading_sevenz_in_memory();
-                if !user_wants_to_continue(input_file_path, question_policy, QuestionAction::Decompression)? {
-                    return Ok(());
+            // A plain archive is read in place, its entries are decoded one folder at a time, so
+            // only the archives wrapped in other formats or in frames need to be buffered
+            let archive: Box<dyn ReadSeek> = if formats.len() > 1 || options.framed {
+                if !user_wants_to_buffer(
+                    input_file_path,
+                    SevenZip,
+                    QuestionAction::Decompression,
+                    question_policy,
+                )? {
+                    return Ok(None);
                 }
-            }
 
-            let mut vec = vec![];
-            io::copy(&mut reader, &mut vec)?;
+                let mut buffer = utils::io::spill_buffer();
+                io::copy(&mut reader, &mut buffer)?;
+                buffer.rewind()?;
+                Box::new(buffer)
+            } else {
+                Box::new(ProgressReader(buf_reader(fs::File::open(input_file_path)?)))
+            };
 
-            if let ControlFlow::Continue(files) = smart_unpack(
-                |output_dir| crate::archive::sevenz::decompress_sevenz(io::Cursor::new(vec), output_dir, quiet),
+            if let ControlFlow::Continue(unpacked) = smart_unpack(
+                |output_dir| {
+                    crate::archive::sevenz::decompress_sevenz(
+                        archive,
+                        output_dir,
+                        quiet,
+                        question_policy,
+                        options,
+                        budget,
+                    )
+                },
+                input_file_path,
                 output_dir,
                 &output_file_path,
                 question_policy,
+                options,
             )? {
-                files
+                unpacked
             } else {
-                return Ok(());
+                return Ok(None);
             }
         }
     };
@@ -210,30 +627,96 @@ pub fn decompress_file(
     ));
     info_accessible(format!("Files unpacked: {}", files_unpacked));
 
-    Ok(())
+    Ok(Some(outputs))
 }
 
 /// Unpacks an archive with some heuristics
 /// - If the archive contains only one file, it will be extracted to the `output_dir`
 /// - If the archive contains multiple files, it will be extracted to a subdirectory of the
 ///   output_dir named after the archive (given by `output_file_path`)
+/// - If `flatten` is set, all files are extracted to the `output_dir`
+/// - If there's an `exec` hook, it runs on the files once they were moved to their final location
+/// - If nothing was unpacked into the temporary directory, nothing is moved
+///
+/// Returns the number of unpacked files and the paths they were moved to.
 /// Note: This functions assumes that `output_dir` exists
 fn smart_unpack(
     unpack_fn: impl FnOnce(&Path) -> crate::Result<usize>,
+    input_file_path: &Path,
     output_dir: &Path,
     output_file_path: &Path,
     question_policy: QuestionPolicy,
-) -> crate::Result<ControlFlow<(), usize>> {
+    options: &UnpackOptions,
+) -> crate::Result<ControlFlow<(), (usize, Vec<PathBuf>)>> {
     assert!(output_dir.exists());
     let temp_dir = tempfile::tempdir_in(output_dir)?;
     let temp_dir_path = temp_dir.path();
+    let _incomplete_output = IncompleteOutput::new(temp_dir_path);
 
     info_accessible(format!(
         "Created temporary directory {} to hold decompressed elements.",
         nice_directory_display(temp_dir_path)
     ));
 
-    let files = unpack_fn(temp_dir_path)?;
+    let files = match unpack_fn(temp_dir_path) {
+        Err(err @ crate::Error::StorageFull { .. }) => {
+            if keeps_partial_output(output_file_path, question_policy, options) {
+                keep_partial_output(&temp_dir.into_path(), output_file_path);
+            }
+            return Err(err);
+        }
+        // Nothing is asked once interrupted
+        Err(err @ crate::Error::Interrupted) => {
+            if options.keep_partial {
+                keep_partial_output(&temp_dir.into_path(), output_file_path);
+            }
+            return Err(err);
+        }
+        files => files?,
+    };
+
+    if fs::read_dir(temp_dir_path)?.next().is_none() {
+        // Nothing to move, every entry was filtered out or unpacked to an absolute path
+        return Ok(ControlFlow::Continue((files, vec![])));
+    }
+
+    let mut changes = ChangeSummary::default();
+
+    if options.flatten {
+        // Files were unpacked directly into the temporary directory, so move them one by one
+        let mut skipped_any = false;
+        let mut moved_files = vec![];
+        for file in fs::read_dir(temp_dir_path)? {
+            let file_path = file?.path();
+            let file_name = file_path.file_name().expect("entries of a directory have a file name");
+            let correct_path =
+                output_path_besides_input(input_file_path, &output_dir.join(file_name), question_policy)?;
+
+            if !changes.move_path(&file_path, &correct_path, question_policy)? {
+                skipped_any = true;
+                continue;
+            }
+            moved_files.push(correct_path);
+        }
+
+        info_accessible(format!(
+            "Successfully moved files from {} to {}.",
+            nice_directory_display(temp_dir_path),
+            nice_directory_display(output_dir)
+        ));
+        changes.report();
+
+        moved_files.sort();
+        if let Some(exec) = &options.exec {
+            moved_files.iter().try_for_each(|file| exec.run_on_tree(file))?;
+        }
+
+        return Ok(if skipped_any {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue((files, moved_files))
+        });
+    }
 
     let root_contains_only_one_element = fs::read_dir(temp_dir_path)?.count() == 1;
     if root_contains_only_one_element {
@@ -243,32 +726,177 @@ fn smart_unpack(
         let file_name = file_path
             .file_name()
             .expect("Should be safe because paths in archives should not end with '..'");
-        let correct_path = output_dir.join(file_name);
+        let correct_path = output_path_besides_input(input_file_path, &output_dir.join(file_name), question_policy)?;
         // Before moving, need to check if a file with the same name already exists
-        if !utils::clear_path(&correct_path, question_policy)? {
+        let moved = changes.move_path(&file_path, &correct_path, question_policy)?;
+        changes.report();
+        if !moved {
             return Ok(ControlFlow::Break(()));
         }
-        fs::rename(&file_path, &correct_path)?;
 
         info_accessible(format!(
             "Successfully moved {} to {}.",
             nice_directory_display(&file_path),
             nice_directory_display(&correct_path)
         ));
+
+        if let Some(exec) = &options.exec {
+            exec.run_on_tree(&correct_path)?;
+        }
+
+        Ok(ControlFlow::Continue((files, vec![correct_path])))
     } else {
         // Multiple files in the root directory, so:
         // Rename the temporary directory to the archive name, which is output_file_path
         // One case to handle tough is we need to check if a file with the same name already exists
-        if !utils::clear_path(output_file_path, question_policy)? {
+        let output_file_path = &output_path_besides_input(input_file_path, output_file_path, question_policy)?;
+        let moved = changes.move_path(temp_dir_path, output_file_path, question_policy)?;
+        changes.report();
+        if !moved {
             return Ok(ControlFlow::Break(()));
         }
-        fs::rename(temp_dir_path, output_file_path)?;
         info_accessible(format!(
             "Successfully moved {} to {}.",
             nice_directory_display(temp_dir_path),
             nice_directory_display(output_file_path)
         ));
+
+        if let Some(exec) = &options.exec {
+            exec.run_on_tree(output_file_path)?;
+        }
+
+        Ok(ControlFlow::Continue((files, vec![output_file_path.to_path_buf()])))
+    }
+}
+
+/// `output_path` if it isn't the archive at `input_path` itself, otherwise another name that the
+/// user picked for it, or an error suggesting `--dir` or `--format`.
+///
+/// They're compared as files rather than as canonicalized paths, which also catches the same file
+/// given through a symlinked directory or with another case on case-insensitive filesystems.
+fn output_path_besides_input(
+    input_path: &Path,
+    output_path: &Path,
+    question_policy: QuestionPolicy,
+) -> crate::Result<PathBuf> {
+    // Opening a FIFO to get its handle would block, and the input is a file anyway
+    let is_input = output_path.is_file()
+        && match (Handle::from_path(input_path), Handle::from_path(output_path)) {
+            (Ok(input), Ok(output)) => input == output,
+            _ => false,
+        };
+    if !is_input {
+        return Ok(output_path.to_path_buf());
     }
 
-    Ok(ControlFlow::Continue(files))
+    let renamed = utils::free_path(output_path);
+    if utils::user_wants_to_rename_output(input_path, &renamed, question_policy)? {
+        return Ok(renamed);
+    }
+
+    let input_path = EscapedPathDisplay::new(input_path);
+    let error = FinalError::with_title(format!("Cannot decompress '{input_path}' into itself."))
+        .detail(format!(
+            "Its output '{}' is the archive that's being decompressed",
+            EscapedPathDisplay::new(output_path)
+        ))
+        .hint("Decompress it into another directory with '--dir'")
+        .hint("Or, if its extensions are wrong, give its actual format with '--format'");
+    Err(error.into())
+}
+
+/// What the files moved out of the temporary directory of [`smart_unpack`] did to the output
+/// directory, reported once they were all moved if anything was already there.
+#[derive(Default)]
+struct ChangeSummary {
+    created: Vec<PathBuf>,
+    overwritten: Vec<PathBuf>,
+    skipped: Vec<PathBuf>,
+}
+
+impl ChangeSummary {
+    /// Move `from` to `to`, unless something is already there and the user doesn't want it
+    /// overwritten, returning whether it was moved.
+    fn move_path(&mut self, from: &Path, to: &Path, question_policy: QuestionPolicy) -> crate::Result<bool> {
+        let existed = to.exists();
+        if !utils::clear_path(to, question_policy)? {
+            self.skipped.push(to.to_path_buf());
+            return Ok(false);
+        }
+        utils::move_path(from, to)?;
+
+        if existed {
+            self.overwritten.push(to.to_path_buf());
+        } else {
+            self.created.push(to.to_path_buf());
+        }
+        Ok(true)
+    }
+
+    /// Tell how many files were created, overwritten and skipped, listing them outside of
+    /// accessible mode, unless nothing was already in the output directory.
+    fn report(&mut self) {
+        if self.overwritten.is_empty() && self.skipped.is_empty() {
+            return;
+        }
+
+        for (action, paths) in [
+            ("Created", &mut self.created),
+            ("Overwrote", &mut self.overwritten),
+            ("Skipped", &mut self.skipped),
+        ] {
+            paths.sort();
+            for path in paths.iter() {
+                info(format!("{action} {}.", nice_directory_display(path)));
+            }
+        }
+        info_accessible(format!(
+            "Extracted over existing files: {} created, {} overwritten and {} skipped.",
+            self.created.len(),
+            self.overwritten.len(),
+            self.skipped.len()
+        ));
+    }
+}
+
+/// Whether what was extracted to `output_path` before the disk got full is kept, with
+/// `--keep-partial`, or if the user doesn't want it removed.
+fn keeps_partial_output(output_path: &Path, question_policy: QuestionPolicy, options: &UnpackOptions) -> bool {
+    options.keep_partial || !utils::user_wants_to_remove_partial_output(output_path, question_policy)
+}
+
+/// Move what was extracted to `partial` before the disk got full or ouch was interrupted to
+/// `output_path`, unless something is already there, in which case it's left where it is.
+fn keep_partial_output(partial: &Path, output_path: &Path) {
+    let kept = if !output_path.exists() && utils::move_path(partial, output_path).is_ok() {
+        output_path
+    } else {
+        partial
+    };
+    warning(format!(
+        "Kept what was extracted so far in '{}'.",
+        EscapedPathDisplay::new(kept)
+    ));
+}
+
+/// Grab previous decoder and wrap it inside of a new one
+///
+/// Streams made of several concatenated members, like `cat a.gz b.gz > c.gz`, are decoded to the
+/// concatenation of all of them, the zstd decoder already reads every frame.
+pub fn chain_reader_decoder(
+    format: &CompressionFormat,
+    decoder: Box<dyn Read + Send>,
+) -> crate::Result<Box<dyn Read + Send>> {
+    let decoder: Box<dyn Read + Send> = match format {
+        Gzip => Box::new(flate2::read::MultiGzDecoder::new(decoder)),
+        Bzip if rayon::current_num_threads() == 1 => Box::new(bzip2::read::MultiBzDecoder::new(decoder)),
+        Bzip => Box::new(utils::ParBzDecoder::new(decoder, rayon::current_num_threads())),
+        Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(decoder)),
+        Lzma => Box::new(xz2::read::XzDecoder::new(decoder)),
+        Snappy => Box::new(snap::read::FrameDecoder::new(decoder)),
+        Zstd => Box::new(zstd::stream::Decoder::new(decoder)?),
+        External(index) => Box::new(ExternalDecoder::new(*index, decoder)?),
+        Tar | Zip | Rar | SevenZip => unreachable!(),
+    };
+    Ok(Box::new(DecoderReader(decoder)))
 }
@@ -0,0 +1,289 @@
+//! Comparison of the contents of two archives, used by `ouch diff`, and of an archive with a
+//! directory, used by `ouch verify`.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, stdout, Write},
+    path::{Path, PathBuf},
+};
+
+use fs_err as fs;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    archive::EntryMetadata,
+    commands::list::for_each_archive_entry,
+    extension::CompressionFormat,
+    utils::{json_string, strip_cur_dir, EscapedPathDisplay, FileVisibilityPolicy},
+    QuestionPolicy,
+};
+
+/// An entry, summarized to be compared with the entry of the same path in the other archive
+struct EntrySummary {
+    metadata: EntryMetadata,
+    /// Hash of the contents, `None` for directories and links
+    hash: Option<[u8; 32]>,
+}
+
+/// Differences between the entries of two archives, or of an archive and a directory
+#[derive(Debug, Default)]
+pub struct Diff {
+    pub only_in_first: Vec<PathBuf>,
+    pub only_in_second: Vec<PathBuf>,
+    /// Entries present in both archives, along with what changed between them
+    pub changed: Vec<(PathBuf, Vec<&'static str>)>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.only_in_first.is_empty() && self.only_in_second.is_empty() && self.changed.is_empty()
+    }
+
+    /// Print the differences, one entry per line, each path only in one side is preceded by the
+    /// label of that side.
+    pub fn print(&self, [first_label, second_label]: [&str; 2]) {
+        let out = &mut stdout().lock();
+
+        for (label, paths) in [(first_label, &self.only_in_first), (second_label, &self.only_in_second)] {
+            for path in paths {
+                let _ = writeln!(out, "{label}: {}", EscapedPathDisplay::new(path));
+            }
+        }
+        for (path, changes) in &self.changed {
+            let _ = writeln!(
+                out,
+                "Changed: {} ({})",
+                EscapedPathDisplay::new(path),
+                changes.join(", ")
+            );
+        }
+    }
+
+    /// Print the differences as a single JSON object, the paths only in one side are listed under
+    /// the key of that side.
+    pub fn print_json(&self, [first_key, second_key]: [&str; 2]) {
+        let paths = |paths: &[PathBuf]| {
+            let paths: Vec<_> = paths.iter().map(|path| json_string(&path.to_string_lossy())).collect();
+            format!("[{}]", paths.join(","))
+        };
+        let changed: Vec<_> = self
+            .changed
+            .iter()
+            .map(|(path, changes)| {
+                let changes: Vec<_> = changes.iter().map(|change| json_string(change)).collect();
+                format!(
+                    r#"{{"path":{},"changes":[{}]}}"#,
+                    json_string(&path.to_string_lossy()),
+                    changes.join(",")
+                )
+            })
+            .collect();
+
+        println!(
+            r#"{{"identical":{},"{first_key}":{},"{second_key}":{},"changed":[{}]}}"#,
+            self.is_empty(),
+            paths(&self.only_in_first),
+            paths(&self.only_in_second),
+            changed.join(",")
+        );
+    }
+}
+
+/// Compare the entries of the archives at `first` and `second`, by path.
+///
+/// The contents are always compared, by hash, while the size, permissions and modification
+/// time are only compared if `compare_metadata` is set.
+///
+/// Returns `None` if the user opted not to buffer one of the archives.
+pub fn diff_archives(
+    first: &Path,
+    first_formats: Vec<CompressionFormat>,
+    second: &Path,
+    second_formats: Vec<CompressionFormat>,
+    compare_metadata: bool,
+    question_policy: QuestionPolicy,
+) -> crate::Result<Option<Diff>> {
+    let Some(first_entries) = summarize_archive(first, first_formats, question_policy)? else {
+        return Ok(None);
+    };
+    let Some(second_entries) = summarize_archive(second, second_formats, question_policy)? else {
+        return Ok(None);
+    };
+
+    Ok(Some(diff_entries(first_entries, second_entries, compare_metadata)))
+}
+
+/// Compare the entries of the archive at `archive_path` with the files under `directory`, that
+/// it was extracted to.
+///
+/// Files of the directory that aren't in the archive are only reported if `strict` is set, and
+/// the directory is walked according to `file_visibility_policy`.
+///
+/// Returns `None` if the user opted not to buffer the archive.
+pub fn verify_against_directory(
+    archive_path: &Path,
+    formats: Vec<CompressionFormat>,
+    directory: &Path,
+    compare_metadata: bool,
+    strict: bool,
+    question_policy: QuestionPolicy,
+    file_visibility_policy: FileVisibilityPolicy,
+) -> crate::Result<Option<Diff>> {
+    let Some(archive_entries) = summarize_archive(archive_path, formats, question_policy)? else {
+        return Ok(None);
+    };
+    let directory_entries = summarize_directory(directory, file_visibility_policy)?;
+
+    let mut diff = diff_entries(archive_entries, directory_entries, compare_metadata);
+    if !strict {
+        diff.only_in_second.clear();
+    }
+
+    Ok(Some(diff))
+}
+
+/// Compare two sets of entries by path.
+fn diff_entries(
+    mut first_entries: BTreeMap<PathBuf, EntrySummary>,
+    second_entries: BTreeMap<PathBuf, EntrySummary>,
+    compare_metadata: bool,
+) -> Diff {
+    let mut diff = Diff::default();
+    for (path, second_entry) in second_entries {
+        match first_entries.remove(&path) {
+            Some(first_entry) => {
+                let changes = changes(&first_entry, &second_entry, compare_metadata);
+                if !changes.is_empty() {
+                    diff.changed.push((path, changes));
+                }
+            }
+            None => diff.only_in_second.push(path),
+        }
+    }
+    diff.only_in_first = first_entries.into_keys().collect();
+
+    diff
+}
+
+/// Read every entry of the archive, hashing their contents, returns `None` if the user opted not
+/// to buffer it.
+fn summarize_archive(
+    archive_path: &Path,
+    formats: Vec<CompressionFormat>,
+    question_policy: QuestionPolicy,
+) -> crate::Result<Option<BTreeMap<PathBuf, EntrySummary>>> {
+    let mut entries = BTreeMap::new();
+
+    let finished = for_each_archive_entry(archive_path, formats, question_policy, |metadata, contents| {
+        let hash = if metadata.is_dir || metadata.link_target.is_some() {
+            None
+        } else {
+            let mut hasher = Sha256::new();
+            io::copy(contents, &mut hasher)?;
+            Some(hasher.finalize().into())
+        };
+
+        entries.insert(
+            strip_cur_dir(&metadata.path).to_path_buf(),
+            EntrySummary { metadata, hash },
+        );
+        Ok(())
+    })?;
+
+    Ok(finished.then_some(entries))
+}
+
+/// Read every file under `directory`, hashing their contents, with paths relative to it.
+fn summarize_directory(
+    directory: &Path,
+    file_visibility_policy: FileVisibilityPolicy,
+) -> crate::Result<BTreeMap<PathBuf, EntrySummary>> {
+    let mut entries = BTreeMap::new();
+
+    for entry in file_visibility_policy.build_walker(directory) {
+        let path = entry?.into_path();
+        let relative = path
+            .strip_prefix(directory)
+            .expect("walked paths start with the directory");
+        if relative.as_os_str().is_empty() {
+            continue;
+        }
+
+        let file_metadata = fs::symlink_metadata(&path)?;
+        let hash = if file_metadata.is_file() {
+            let mut hasher = Sha256::new();
+            io::copy(&mut fs::File::open(&path)?, &mut hasher)?;
+            Some(hasher.finalize().into())
+        } else {
+            None
+        };
+
+        let metadata = EntryMetadata {
+            path: relative.to_path_buf(),
+            is_dir: file_metadata.is_dir(),
+            size: file_metadata.len(),
+            mode: file_mode(&file_metadata),
+            mtime: file_metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|duration| duration.as_secs() as i64),
+            link_target: file_metadata.is_symlink().then(|| fs::read_link(&path)).transpose()?,
+        };
+        entries.insert(relative.to_path_buf(), EntrySummary { metadata, hash });
+    }
+
+    Ok(entries)
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &std::fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+
+    Some(metadata.permissions().mode())
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &std::fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// What changed between two entries with the same path.
+fn changes(first: &EntrySummary, second: &EntrySummary, compare_metadata: bool) -> Vec<&'static str> {
+    let (first_metadata, second_metadata) = (&first.metadata, &second.metadata);
+
+    if first_metadata.is_dir != second_metadata.is_dir {
+        return vec!["type"];
+    }
+
+    // Directories and links have no contents to compare
+    let hashes = first.hash.zip(second.hash);
+
+    let mut changes = vec![];
+    if first_metadata.link_target != second_metadata.link_target {
+        changes.push("target");
+    }
+    if hashes.is_some_and(|(first_hash, second_hash)| first_hash != second_hash) {
+        changes.push("content");
+    }
+
+    if compare_metadata {
+        if hashes.is_some() && first_metadata.size != second_metadata.size {
+            changes.push("size");
+        }
+        // Not every format stores these, so they are only compared if both entries have them
+        if let (Some(first_mode), Some(second_mode)) = (first_metadata.mode, second_metadata.mode) {
+            if first_mode & 0o7777 != second_mode & 0o7777 {
+                changes.push("mode");
+            }
+        }
+        // Zip stores modification times with a precision of 2 seconds
+        if let (Some(first_mtime), Some(second_mtime)) = (first_metadata.mtime, second_metadata.mtime) {
+            if first_mtime.abs_diff(second_mtime) > 1 {
+                changes.push("mtime");
+            }
+        }
+    }
+
+    changes
+}
@@ -1,90 +1,76 @@
 use std::{
-    io::{self, BufReader, Read},
+    io::{self, Read, Seek},
     path::Path,
 };
 
 use fs_err as fs;
 
 use crate::{
-    commands::warn_user_about_loading_zip_in_memory,
+    archive::EntryMetadata,
+    commands::{decompress::chain_reader_decoder, user_wants_to_buffer},
     extension::CompressionFormat::{self, *},
     list::{self, FileInArchive, ListOptions},
-    utils::{io::lock_and_flush_output_stdio, user_wants_to_continue},
-    QuestionAction, QuestionPolicy, BUFFER_CAPACITY,
+    utils::{self, io::buf_reader},
+    QuestionAction, QuestionPolicy,
 };
 
 /// File at input_file_path is opened for reading, example: "archive.tar.gz"
 /// formats contains each format necessary for decompression, example: [Gz, Tar] (in decompression order)
+///
+/// Returns how many entries were listed, or `None` if the user opted not to buffer the archive.
 pub fn list_archive_contents(
     archive_path: &Path,
     formats: Vec<CompressionFormat>,
-    list_options: ListOptions,
+    list_options: &ListOptions,
     question_policy: QuestionPolicy,
-) -> crate::Result<()> {
-    let reader = fs::File::open(archive_path)?;
-
+) -> crate::Result<Option<usize>> {
     // Zip archives are special, because they require io::Seek, so it requires it's logic separated
     // from decoder chaining.
     //
-    // This is the only case where we can read and unpack it directly, without having to do
-    // in-memory decompression/copying first.
+    // This is the only case where we can read and unpack it directly, seeking through its file
+    // without having to decompress/copy it into a buffer first, so memory usage stays bounded by
+    // the IO buffers however large the archive is.
     //
-    // Any other Zip decompression done can take up the whole RAM and freeze ouch.
-    if let &[Zip] = formats.as_slice() {
-        let zip_archive = zip::ZipArchive::new(reader)?;
+    // Any other Zip decompression is buffered, spilling to a temporary file when it's large.
+    if crate::archive::zip::is_read_in_place(&formats) {
+        let zip_archive = crate::archive::zip::open_archive(archive_path)?;
+        let comment = String::from_utf8_lossy(zip_archive.comment()).into_owned();
         let files = crate::archive::zip::list_archive(zip_archive);
-        list::list_files(archive_path, files, list_options)?;
-
-        return Ok(());
+        return list::list_files(archive_path, Some(&comment), files, list_options).map(Some);
     }
 
     // Will be used in decoder chaining
-    let reader = BufReader::with_capacity(BUFFER_CAPACITY, reader);
+    let reader = buf_reader(fs::File::open(archive_path)?);
     let mut reader: Box<dyn Read + Send> = Box::new(reader);
 
-    // Grab previous decoder and wrap it inside of a new one
-    let chain_reader_decoder =
-        |format: &CompressionFormat, decoder: Box<dyn Read + Send>| -> crate::Result<Box<dyn Read + Send>> {
-            let decoder: Box<dyn Read + Send> = match format {
-                Gzip => Box::new(flate2::read::GzDecoder::new(decoder)),
-                Bzip => Box::new(bzip2::read::BzDecoder::new(decoder)),
-                Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(decoder)),
-                Lzma => Box::new(xz2::read::XzDecoder::new(decoder)),
-                Snappy => Box::new(snap::read::FrameDecoder::new(decoder)),
-                Zstd => Box::new(zstd::stream::Decoder::new(decoder)?),
-                Tar | Zip | Rar | SevenZip => unreachable!(),
-            };
-            Ok(decoder)
-        };
-
     for format in formats.iter().skip(1).rev() {
         reader = chain_reader_decoder(format, reader)?;
     }
 
+    // Only set for formats that can store an archive comment
+    let mut comment = None;
+
     let files: Box<dyn Iterator<Item = crate::Result<FileInArchive>>> = match formats[0] {
         Tar => Box::new(crate::archive::tar::list_archive(tar::Archive::new(reader))),
         Zip => {
-            if formats.len() > 1 {
-                // Locking necessary to guarantee that warning and question
-                // messages stay adjacent
-                let _locks = lock_and_flush_output_stdio();
-
-                warn_user_about_loading_zip_in_memory();
-                if !user_wants_to_continue(archive_path, question_policy, QuestionAction::Decompression)? {
-                    return Ok(());
-                }
+            if formats.len() > 1
+                && !user_wants_to_buffer(archive_path, Zip, QuestionAction::Decompression, question_policy)?
+            {
+                return Ok(None);
             }
 
-            let mut vec = vec![];
-            io::copy(&mut reader, &mut vec)?;
-            let zip_archive = zip::ZipArchive::new(io::Cursor::new(vec))?;
+            let mut buffer = utils::io::spill_buffer();
+            io::copy(&mut reader, &mut buffer)?;
+            buffer.rewind()?;
+            let zip_archive = zip::ZipArchive::new(buffer)?;
+            comment = Some(String::from_utf8_lossy(zip_archive.comment()).into_owned());
 
             Box::new(crate::archive::zip::list_archive(zip_archive))
         }
         #[cfg(feature = "unrar")]
         Rar => {
             if formats.len() > 1 {
-                let mut temp_file = tempfile::NamedTempFile::new()?;
+                let mut temp_file = tempfile::NamedTempFile::new_in(utils::io::temp_dir())?;
                 io::copy(&mut reader, &mut temp_file)?;
                 Box::new(crate::archive::rar::list_archive(temp_file.path()))
             } else {
@@ -96,32 +82,102 @@ pub fn list_archive_contents(
             return Err(crate::archive::rar_stub::no_support());
         }
         SevenZip => {
-            if formats.len() > 1 {
-                // Locking necessary to guarantee that warning and question
-                // messages stay adjacent
-                let _locks = lock_and_flush_output_stdio();
-
-                warn_user_about_loading_zip_in_memory();
-                if !user_wants_to_continue(archive_path, question_policy, QuestionAction::Decompression)? {
-                    return Ok(());
+            let files = if formats.len() > 1 {
+                if !user_wants_to_buffer(archive_path, SevenZip, QuestionAction::Decompression, question_policy)? {
+                    return Ok(None);
                 }
-            }
 
-            let mut files = Vec::new();
+                let mut buffer = utils::io::spill_buffer();
+                io::copy(&mut reader, &mut buffer)?;
+                crate::archive::sevenz::list_archive(buffer)?
+            } else {
+                let reader = buf_reader(fs::File::open(archive_path)?);
+                crate::archive::sevenz::list_archive(reader)?
+            };
 
-            sevenz_rust::decompress_file_with_extract_fn(archive_path, ".", |entry, _, _| {
-                files.push(Ok(FileInArchive {
-                    path: entry.name().into(),
-                    is_dir: entry.is_directory(),
-                }));
-                Ok(true)
-            })?;
-            Box::new(files.into_iter())
+            Box::new(files.into_iter().map(Ok))
         }
-        Gzip | Bzip | Lz4 | Lzma | Snappy | Zstd => {
+        Gzip | Bzip | Lz4 | Lzma | Snappy | Zstd | External(_) => {
             panic!("Not an archive! This should never happen, if it does, something is wrong with `CompressionFormat::is_archive()`. Please report this error!");
         }
     };
-    list::list_files(archive_path, files, list_options)?;
-    Ok(())
+    list::list_files(archive_path, comment.as_deref(), files, list_options).map(Some)
+}
+
+/// Calls `visit` with the metadata and contents of every entry of the archive at `archive_path`,
+/// streaming them without extracting anything to disk.
+///
+/// File at archive_path is opened for reading, example: "archive.tar.gz"
+/// formats contains each format necessary for decompression, example: [Gz, Tar] (in decompression order)
+///
+/// # Return value
+/// - Returns `Ok(true)` if all entries were visited.
+/// - Returns `Ok(false)` if user opted not to buffer the archive.
+pub fn for_each_archive_entry(
+    archive_path: &Path,
+    formats: Vec<CompressionFormat>,
+    question_policy: QuestionPolicy,
+    visit: impl FnMut(EntryMetadata, &mut dyn Read) -> crate::Result<()>,
+) -> crate::Result<bool> {
+    // Zip archives are special, because they require io::Seek, see `list_archive_contents`
+    if crate::archive::zip::is_read_in_place(&formats) {
+        crate::archive::zip::for_each_entry(crate::archive::zip::open_archive(archive_path)?, visit)?;
+        return Ok(true);
+    }
+
+    // Will be used in decoder chaining
+    let reader = buf_reader(fs::File::open(archive_path)?);
+    let mut reader: Box<dyn Read + Send> = Box::new(reader);
+
+    for format in formats.iter().skip(1).rev() {
+        reader = chain_reader_decoder(format, reader)?;
+    }
+
+    match formats[0] {
+        Tar => crate::archive::tar::for_each_entry(reader, visit)?,
+        Zip => {
+            if !user_wants_to_buffer(archive_path, Zip, QuestionAction::Decompression, question_policy)? {
+                return Ok(false);
+            }
+
+            let mut buffer = utils::io::spill_buffer();
+            io::copy(&mut reader, &mut buffer)?;
+            buffer.rewind()?;
+            crate::archive::zip::for_each_entry(zip::ZipArchive::new(buffer)?, visit)?;
+        }
+        #[cfg(feature = "unrar")]
+        Rar => {
+            if formats.len() > 1 {
+                let mut temp_file = tempfile::NamedTempFile::new_in(utils::io::temp_dir())?;
+                io::copy(&mut reader, &mut temp_file)?;
+                crate::archive::rar::for_each_entry(temp_file.path(), visit)?;
+            } else {
+                crate::archive::rar::for_each_entry(archive_path, visit)?;
+            }
+        }
+        #[cfg(not(feature = "unrar"))]
+        Rar => {
+            return Err(crate::archive::rar_stub::no_support());
+        }
+        SevenZip => {
+            if formats.len() > 1 {
+                if !user_wants_to_buffer(archive_path, SevenZip, QuestionAction::Decompression, question_policy)? {
+                    return Ok(false);
+                }
+
+                let mut buffer = utils::io::spill_buffer();
+                io::copy(&mut reader, &mut buffer)?;
+                buffer.rewind()?;
+                crate::archive::sevenz::for_each_entry(buffer, visit)?;
+            } else {
+                let reader = buf_reader(fs::File::open(archive_path)?);
+                crate::archive::sevenz::for_each_entry(reader, visit)?;
+            }
+        }
+        Gzip | Bzip | Lz4 | Lzma | Snappy | Zstd | External(_) => {
+            panic!("Not an archive! This should never happen, if it does, something is wrong with `CompressionFormat::is_archive()`. Please report this error!");
+        }
+    }
+
+    Ok(true)
 }
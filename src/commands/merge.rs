@@ -0,0 +1,308 @@
+//! Merging of the entries of several archives into a new one, used by `ouch merge`.
+
+use std::{
+    collections::{btree_map, BTreeMap},
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+};
+
+use same_file::Handle;
+
+use crate::{
+    archive::{self, EntryMetadata},
+    commands::{
+        compress::{chain_writer_encoder, encoder_threads},
+        list::for_each_archive_entry,
+        user_wants_to_buffer,
+    },
+    error::FinalError,
+    extension::CompressionFormat::{self, *},
+    utils::{
+        self,
+        interrupt::IncompleteOutput,
+        io::buf_writer,
+        logger::{info_accessible, warning},
+        strip_cur_dir, user_wants_to_replace_entry, EscapedPathDisplay,
+    },
+    QuestionAction, QuestionPolicy,
+};
+
+/// The entry that ends up in the merged archive for one path
+struct Owner {
+    /// Index of the archive it comes from
+    archive: usize,
+    /// Index of the entry inside of that archive
+    entry: usize,
+    is_dir: bool,
+}
+
+/// Merge the entries of `archives` into a new archive at `output_path`, without extracting them.
+///
+/// When several archives have an entry with the same path, the user is asked which one to keep,
+/// `--yes` keeps the last one and `--no` the first one. Directories are always merged.
+///
+/// # Return value
+/// - Returns `Ok(true)` if merged all archives normally.
+/// - Returns `Ok(false)` if user opted to abort merging mid-way.
+pub fn merge_archives(
+    archives: &[PathBuf],
+    archive_formats: Vec<Vec<CompressionFormat>>,
+    output_path: &Path,
+    output_formats: Vec<CompressionFormat>,
+    question_policy: QuestionPolicy,
+) -> crate::Result<bool> {
+    let output_display = EscapedPathDisplay::new(output_path);
+
+    match output_formats.as_slice() {
+        [Tar | Zip | SevenZip, compression_formats @ ..]
+            if !compression_formats.iter().any(|format| format.is_archive_format()) => {}
+        _ => {
+            let error = FinalError::with_title(format!("Cannot merge into '{output_display}'."))
+                .detail("The output must be an archive, optionally compressed, like '.tar.gz', '.zip' or '.7z'")
+                .hint("Use the '--format' flag to choose it:")
+                .hint(format!(
+                    "  ouch merge <ARCHIVES>... --output {output_display} --format tar.gz"
+                ));
+            return Err(error.into());
+        }
+    }
+
+    if let Ok(output_handle) = Handle::from_path(output_path) {
+        if archives
+            .iter()
+            .any(|archive| matches!(Handle::from_path(archive), Ok(handle) if handle == output_handle))
+        {
+            let error = FinalError::with_title(format!("Cannot merge into '{output_display}'."))
+                .detail("It's one of the archives being merged");
+            return Err(error.into());
+        }
+    }
+
+    let Some(owners) = plan_merge(archives, &archive_formats, question_policy)? else {
+        return Ok(false);
+    };
+
+    let Some(output_file) = utils::ask_to_create_file(output_path, question_policy)? else {
+        return Ok(false);
+    };
+    let incomplete_output = IncompleteOutput::new(output_path);
+
+    let merge_result = write_merged_archive(
+        archives,
+        &archive_formats,
+        &owners,
+        buf_writer(output_file),
+        output_path,
+        &output_formats,
+        question_policy,
+    );
+    drop(incomplete_output);
+
+    match merge_result {
+        Ok(Some(taken)) => {
+            for (archive, taken) in archives.iter().zip(taken) {
+                info_accessible(format!(
+                    "Took {taken} entries from '{}'.",
+                    EscapedPathDisplay::new(archive)
+                ));
+            }
+            info_accessible(format!(
+                "Successfully merged {} archives into '{output_display}'.",
+                archives.len()
+            ));
+            Ok(true)
+        }
+        result => {
+            // Delete the incomplete archive
+            if utils::remove_file_or_dir(output_path).is_err() {
+                warning(format!("Failed to remove the incomplete archive '{output_display}'."));
+            }
+            result.map(|_| false)
+        }
+    }
+}
+
+/// Decide which archive each path of the merged archive is taken from, returns `None` if the
+/// user opted not to buffer one of the archives.
+fn plan_merge(
+    archives: &[PathBuf],
+    archive_formats: &[Vec<CompressionFormat>],
+    question_policy: QuestionPolicy,
+) -> crate::Result<Option<BTreeMap<PathBuf, Owner>>> {
+    let mut owners = BTreeMap::new();
+
+    for (archive_idx, (archive_path, formats)) in archives.iter().zip(archive_formats).enumerate() {
+        let mut entry_idx = 0;
+
+        let finished = for_each_archive_entry(archive_path, formats.clone(), question_policy, |metadata, _| {
+            let owner = Owner {
+                archive: archive_idx,
+                entry: entry_idx,
+                is_dir: metadata.is_dir,
+            };
+            entry_idx += 1;
+
+            let previous = match owners.entry(strip_cur_dir(&metadata.path).to_path_buf()) {
+                btree_map::Entry::Vacant(vacant) => {
+                    vacant.insert(owner);
+                    return Ok(());
+                }
+                btree_map::Entry::Occupied(occupied) => occupied.into_mut(),
+            };
+
+            // Later entries of the same archive replace earlier ones, like when extracting it
+            let replace = if previous.is_dir && owner.is_dir {
+                false
+            } else if previous.archive == archive_idx {
+                true
+            } else {
+                let path = strip_cur_dir(&metadata.path);
+                let replace = user_wants_to_replace_entry(path, archive_path, question_policy)?;
+                if question_policy != QuestionPolicy::Ask {
+                    let kept = if replace {
+                        archive_path
+                    } else {
+                        &archives[previous.archive]
+                    };
+                    warning(format!(
+                        "'{}' is in more than one archive, keeping the one from '{}'.",
+                        EscapedPathDisplay::new(path),
+                        EscapedPathDisplay::new(kept)
+                    ));
+                }
+                replace
+            };
+
+            if replace {
+                *previous = owner;
+            }
+            Ok(())
+        })?;
+
+        if !finished {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(owners))
+}
+
+/// Write the entries chosen by `owners` into `writer`, encoded with `output_formats`.
+///
+/// Returns how many entries were taken from each archive, or `None` if the user opted not to
+/// buffer the output.
+fn write_merged_archive(
+    archives: &[PathBuf],
+    archive_formats: &[Vec<CompressionFormat>],
+    owners: &BTreeMap<PathBuf, Owner>,
+    writer: impl Write + Send + 'static,
+    output_path: &Path,
+    output_formats: &[CompressionFormat],
+    question_policy: QuestionPolicy,
+) -> crate::Result<Option<Vec<usize>>> {
+    let mut taken = vec![0; archives.len()];
+
+    // Stream the chosen entries of the archive `archive_idx` to `append`, returning how many there
+    // were, the archives were already read once while planning, so there's nothing left to ask
+    // about them
+    let merge_archive =
+        |archive_idx: usize, append: &mut dyn FnMut(&EntryMetadata, &mut dyn Read) -> crate::Result<()>| {
+            let mut entry_idx = 0;
+            let mut archive_taken = 0;
+
+            for_each_archive_entry(
+                &archives[archive_idx],
+                archive_formats[archive_idx].clone(),
+                QuestionPolicy::AlwaysYes,
+                |metadata, contents| {
+                    if is_chosen(owners, archive_idx, entry_idx, &metadata.path) {
+                        append(&metadata, contents)?;
+                        archive_taken += 1;
+                    }
+                    entry_idx += 1;
+                    Ok(())
+                },
+            )?;
+            crate::Result::Ok(archive_taken)
+        };
+
+    let mut writer: Box<dyn Send + Write> = Box::new(writer);
+    for format in output_formats.iter().skip(1).rev() {
+        writer = chain_writer_encoder(format, writer, None, encoder_threads())?;
+    }
+
+    match output_formats[0] {
+        Tar => {
+            let mut builder = tar::Builder::new(writer);
+            for (archive_idx, taken) in taken.iter_mut().enumerate() {
+                *taken = merge_archive(archive_idx, &mut |metadata, contents| {
+                    archive::tar::append_entry(&mut builder, metadata, contents)
+                })?;
+            }
+            writer = builder.into_inner()?;
+        }
+        Zip => {
+            if output_formats.len() > 1
+                && !user_wants_to_buffer(output_path, Zip, QuestionAction::Compression, question_policy)?
+            {
+                return Ok(None);
+            }
+
+            let mut zip_writer = zip::ZipWriter::new(utils::io::spill_buffer());
+            for (archive_idx, taken) in taken.iter_mut().enumerate() {
+                if !archive::zip::is_read_in_place(&archive_formats[archive_idx]) {
+                    *taken = merge_archive(archive_idx, &mut |metadata, contents| {
+                        archive::zip::append_entry(&mut zip_writer, metadata, contents)
+                    })?;
+                    continue;
+                }
+
+                // Entries of other zip archives are copied as they are, instead of being
+                // decompressed and compressed again
+                let mut zip_archive = archive::zip::open_archive(&archives[archive_idx])?;
+                let mut entry_idx = 0;
+                archive::zip::copy_raw_entries(&mut zip_archive, &mut zip_writer, |path| {
+                    let chosen = is_chosen(owners, archive_idx, entry_idx, path);
+                    entry_idx += 1;
+                    *taken += usize::from(chosen);
+                    chosen
+                })?;
+            }
+            let mut buffer = zip_writer.finish()?;
+            buffer.rewind()?;
+            io::copy(&mut buffer, &mut writer)?;
+        }
+        SevenZip => {
+            if output_formats.len() > 1
+                && !user_wants_to_buffer(output_path, SevenZip, QuestionAction::Compression, question_policy)?
+            {
+                return Ok(None);
+            }
+
+            let mut sevenz_writer = sevenz_rust::SevenZWriter::new(utils::io::spill_buffer())?;
+            for (archive_idx, taken) in taken.iter_mut().enumerate() {
+                *taken = merge_archive(archive_idx, &mut |metadata, contents| {
+                    archive::sevenz::append_entry(&mut sevenz_writer, metadata, contents)
+                })?;
+            }
+            let mut buffer = sevenz_writer.finish()?;
+            buffer.rewind()?;
+            io::copy(&mut buffer, &mut writer)?;
+        }
+        Gzip | Bzip | Lz4 | Lzma | Snappy | Zstd | Rar | External(_) => unreachable!("checked by `merge_archives`"),
+    }
+
+    // Dropping the encoders finishes their streams
+    writer.flush()?;
+    drop(writer);
+    utils::external_codec::check_encoders()?;
+
+    Ok(Some(taken))
+}
+
+/// Whether the entry number `entry_idx` of the archive `archive_idx`, at `path`, is the one that
+/// ends up in the merged archive.
+fn is_chosen(owners: &BTreeMap<PathBuf, Owner>, archive_idx: usize, entry_idx: usize, path: &Path) -> bool {
+    let owner = &owners[strip_cur_dir(path)];
+    owner.archive == archive_idx && owner.entry == entry_idx
+}
@@ -1,164 +1,560 @@
 //! Receive command from the cli and call the respective function for that command.
 
+mod append;
+mod cat;
 mod compress;
 mod decompress;
+mod diff;
 mod list;
+mod merge;
 
-use std::{ops::ControlFlow, path::PathBuf};
+pub use self::decompress::chain_reader_decoder;
 
+use std::{
+    ffi::{OsStr, OsString},
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+    slice,
+    time::{Duration, Instant},
+};
+
+use fs_err as fs;
 use rayon::prelude::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
-use utils::colors;
 
 use crate::{
+    archive::{
+        limits::{
+            ExtractionBudget, ExtractionLimits, DEFAULT_MAX_ENTRIES, DEFAULT_MAX_NESTING, DEFAULT_MAX_OUTPUT_SIZE,
+            DEFAULT_MAX_PATH_DEPTH, DEFAULT_MAX_PATH_LENGTH, DEFAULT_MAX_RATIO,
+        },
+        ArchiveOutput, ConflictPolicy, PackOptions, UnpackOptions,
+    },
     check,
-    cli::Subcommand,
-    commands::{compress::compress_files, decompress::decompress_file, list::list_archive_contents},
+    cli::{self, CompressionLevel, Subcommand, VerifyFormat},
+    commands::{
+        append::append_to_archive,
+        cat::{cat_archive_entry, decompress_to_stdout},
+        compress::{compress_files, format_levels, read_stdin_input, remove_input_files},
+        decompress::{decompress_file, decompress_nested, pick_entries, select_entries, EntrySelection},
+        diff::{diff_archives, verify_against_directory},
+        list::list_archive_contents,
+        merge::merge_archives,
+    },
     error::{Error, FinalError},
-    extension::{self, parse_format},
+    extension::{self, parse_format, CompressionFormat, Extension, FormatFlag},
     list::ListOptions,
-    utils::{self, colors::*, logger::info_accessible, to_utf, EscapedPathDisplay, FileVisibilityPolicy},
-    CliArgs, QuestionPolicy,
+    utils::{
+        self,
+        interrupt::IncompleteOutput,
+        logger::{info, info_accessible, print_error, print_warning, warning},
+        pretty_format_list_of_paths, progress, to_utf, Bytes, EntryFilter, EscapedPathDisplay, ExecHook,
+        FileVisibilityPolicy, QuestionAction, Transforms,
+    },
+    CliArgs, QuestionPolicy, EXIT_DIFFERENT, EXIT_PARTIAL, EXIT_SUCCESS,
 };
 
-/// Warn the user that (de)compressing this .zip archive might freeze their system.
-fn warn_user_about_loading_zip_in_memory() {
-    const ZIP_IN_MEMORY_LIMITATION_WARNING: &str = "\n  \
+/// Warn the user that (de)compressing this .zip archive requires buffering all of it in memory.
+fn warn_user_about_buffering_zip() {
+    const ZIP_BUFFERING_WARNING: &str = "\n  \
         The format '.zip' is limited by design and cannot be (de)compressed with encoding streams.\n  \
-        When chaining '.zip' with other formats, all (de)compression needs to be done in-memory\n  \
-        Careful, you might run out of RAM if the archive is too large!";
+        When chaining '.zip' with other formats, the whole archive is buffered first, and with\n  \
+        '--no-spill' it's kept in memory however big it gets.\n  \
+        Careful, you might run out of RAM if the archive is too large!\n  \
+        Use '--max-memory' instead to move it to a temporary file once it gets too big.";
 
-    eprintln!("{}[WARNING]{}: {ZIP_IN_MEMORY_LIMITATION_WARNING}", *ORANGE, *RESET);
+    print_warning(ZIP_BUFFERING_WARNING);
 }
 
-/// Warn the user that (de)compressing this .7z archive might freeze their system.
-fn warn_user_about_loading_sevenz_in_memory() {
-    const SEVENZ_IN_MEMORY_LIMITATION_WARNING: &str = "\n  \
+/// Warn the user that (de)compressing this .7z archive requires buffering all of it in memory.
+fn warn_user_about_buffering_sevenz() {
+    const SEVENZ_BUFFERING_WARNING: &str = "\n  \
         The format '.7z' is limited by design and cannot be (de)compressed with encoding streams.\n  \
-        When chaining '.7z' with other formats, all (de)compression needs to be done in-memory\n  \
-        Careful, you might run out of RAM if the archive is too large!";
+        When chaining '.7z' with other formats, the whole archive is buffered first, and with\n  \
+        '--no-spill' it's kept in memory however big it gets.\n  \
+        Careful, you might run out of RAM if the archive is too large!\n  \
+        Use '--max-memory' instead to move it to a temporary file once it gets too big.";
+
+    print_warning(SEVENZ_BUFFERING_WARNING);
+}
+
+/// Ask the user whether to buffer the whole `format` archive at `path`, warning them first, when
+/// it's kept in memory because of `--no-spill`. Otherwise it's moved to a temporary file once it
+/// gets too big, so there's nothing to ask.
+fn user_wants_to_buffer(
+    path: &Path,
+    format: CompressionFormat,
+    question_action: QuestionAction,
+    question_policy: QuestionPolicy,
+) -> crate::Result<bool> {
+    if utils::io::max_memory().is_some() {
+        return Ok(true);
+    }
+
+    // Locking necessary to guarantee that warning and question
+    // messages stay adjacent
+    let _locks = utils::io::lock_and_flush_output_stdio();
 
-    eprintln!("{}[WARNING]{}: {SEVENZ_IN_MEMORY_LIMITATION_WARNING}", *ORANGE, *RESET);
+    if format == CompressionFormat::Zip {
+        warn_user_about_buffering_zip();
+    } else {
+        warn_user_about_buffering_sevenz();
+    }
+    utils::user_wants_to_continue(path, question_policy, question_action)
 }
 
 /// This function checks what command needs to be run and performs A LOT of ahead-of-time checks
 /// to assume everything is OK.
 ///
 /// There are a lot of custom errors to give enough error description and explanation.
+///
+/// Returns the status code that `ouch` should exit with.
 pub fn run(
     args: CliArgs,
     question_policy: QuestionPolicy,
     file_visibility_policy: FileVisibilityPolicy,
-) -> crate::Result<()> {
+) -> crate::Result<i32> {
+    let report_time = args.time && !args.quiet;
+    let operation = operation_name(&args.cmd);
+    let compressing = matches!(args.cmd, Subcommand::Compress { .. });
+    let start = Instant::now();
+
+    let report_progress = args.numeric_progress && !args.quiet;
+    if report_progress {
+        match &args.cmd {
+            Subcommand::Compress { files, .. } | Subcommand::Decompress { files, .. } => {
+                progress::enable(progress::size_of_paths(files))
+            }
+            _ => {}
+        }
+    }
+
+    let status_code = run_subcommand(args, question_policy, file_visibility_policy)?;
+
+    if report_progress && status_code == EXIT_SUCCESS {
+        progress::finish();
+    }
+
+    // The size of the archives depends on how well they compress, so compressing is measured by
+    // how much of the inputs was read instead
+    if compressing {
+        utils::io::count_processed_bytes(progress::bytes_read());
+    }
+
+    if report_time {
+        let elapsed = start.elapsed();
+        let bytes = utils::io::processed_bytes();
+        if bytes > 0 {
+            // Avoid dividing by zero for operations faster than the clock's resolution
+            let throughput = bytes as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+            info_accessible(format!(
+                "{operation} in {} ({}/s).",
+                format_duration(elapsed),
+                Bytes::new(throughput as u64)
+            ));
+        } else {
+            info_accessible(format!("{operation} in {}.", format_duration(elapsed)));
+        }
+    }
+
+    Ok(status_code)
+}
+
+/// Past tense of what `cmd` does, to report how long it took
+fn operation_name(cmd: &Subcommand) -> &'static str {
+    match cmd {
+        Subcommand::Compress { .. } => "Compressed",
+        Subcommand::Decompress { .. } => "Decompressed",
+        Subcommand::Append { .. } => "Appended",
+        Subcommand::List { .. } => "Listed",
+        Subcommand::Cat { .. } => "Printed",
+        Subcommand::Merge { .. } => "Merged",
+        Subcommand::Diff { .. } => "Compared",
+        Subcommand::Verify { .. } => "Verified",
+    }
+}
+
+/// Split the results of a batch, processed for each of `inputs`, into the values of the ones that
+/// succeeded and the inputs that failed, printing their errors.
+///
+/// If every input failed, the last error is returned instead, to be printed by `main`.
+fn collect_batch_results<T>(
+    inputs: &[PathBuf],
+    results: Vec<crate::Result<T>>,
+) -> crate::Result<(Vec<T>, Vec<&PathBuf>)> {
+    let mut values = vec![];
+    let mut failed = vec![];
+    let mut errors = vec![];
+    for (input, result) in inputs.iter().zip(results) {
+        match result {
+            Ok(value) => values.push(value),
+            Err(err) => {
+                failed.push(input);
+                errors.push(err);
+            }
+        }
+    }
+
+    let last_error = errors.pop();
+    for err in errors {
+        print_error(&err);
+    }
+    match last_error {
+        Some(err) if values.is_empty() => Err(err),
+        Some(err) => {
+            print_error(&err);
+            Ok((values, failed))
+        }
+        None => Ok((values, failed)),
+    }
+}
+
+/// The umask applied to the stored permissions of unpacked files, only by default for other users
+/// than root, see `--same-permissions`.
+#[cfg(unix)]
+fn umask(same_permissions: bool, no_same_permissions: bool) -> Option<u32> {
+    let same_permissions = same_permissions || (!no_same_permissions && utils::is_root());
+    (!same_permissions).then(utils::umask)
+}
+
+/// Permissions are only restored on Unix.
+#[cfg(not(unix))]
+fn umask(_same_permissions: bool, _no_same_permissions: bool) -> Option<u32> {
+    None
+}
+
+/// Extended attributes are only stored and restored on Unix, `--xattrs` is ignored elsewhere.
+fn warn_about_unsupported_xattrs() {
+    if !cfg!(unix) {
+        warning("Extended attributes are only supported on Unix, '--xattrs' is ignored.".to_string());
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!("{:.2}s", duration.as_secs_f64())
+}
+
+fn run_subcommand(
+    args: CliArgs,
+    question_policy: QuestionPolicy,
+    file_visibility_policy: FileVisibilityPolicy,
+) -> crate::Result<i32> {
     match args.cmd {
         Subcommand::Compress {
-            files,
+            mut files,
+            // Already read into `files`
+            files_from: _,
+            null: _,
+            max_depth,
             output: output_path,
             level,
             fast,
             slow,
+            rm,
+            each,
+            comment,
+            transform,
+            absolute_paths,
+            input_dir,
+            name,
+            force_compress,
+            archive_mtime,
+            no_mmap,
+            xattrs,
+            strict,
+            framed,
+            stdin_name,
+            force,
         } => {
-            // After cleaning, if there are no input files left, exit
-            if files.is_empty() {
-                return Err(FinalError::with_title("No files to compress").into());
+            let file_visibility_policy = file_visibility_policy.max_depth(max_depth);
+            if xattrs {
+                warn_about_unsupported_xattrs();
             }
-
-            // Formats from path extension, like "file.tar.gz.xz" -> vec![Tar, Gzip, Lzma]
-            let (formats_from_flag, formats) = match args.format {
-                Some(formats) => {
-                    let parsed_formats = parse_format(&formats)?;
-                    (Some(formats), parsed_formats)
+            let input_dir = input_dir.map(fs::canonicalize).transpose()?;
+            if let Some(input_dir) = &input_dir {
+                if let Some(file) = files.iter().find(|file| !file.starts_with(input_dir)) {
+                    let error = FinalError::with_title(format!("Cannot compress '{}'.", EscapedPathDisplay::new(file)))
+                        .detail(format!(
+                            "It's outside of the input directory '{}'",
+                            EscapedPathDisplay::new(input_dir)
+                        ))
+                        .hint("Entries are stored relative to the input directory, so it must contain every input");
+                    return Err(error.into());
                 }
-                None => (None, extension::extensions_from_path(&output_path)),
-            };
+            }
 
-            check::check_invalid_compression_with_non_archive_format(
-                &formats,
-                &output_path,
-                &files,
-                formats_from_flag.as_ref(),
-            )?;
-            check::check_archive_formats_position(&formats, &output_path)?;
+            if let Some(name) = &name {
+                check::check_top_level_name(name, &files)?;
+            }
+
+            // Removed once the compression is done
+            let _stdin_dir = read_stdin_input(&mut files, &stdin_name)?;
 
-            let output_file = match utils::ask_to_create_file(&output_path, question_policy)? {
-                Some(writer) => writer,
-                None => return Ok(()),
+            let pack_options = PackOptions {
+                transforms: Transforms::new(&transform)?,
+                absolute_paths,
+                input_dir,
+                top_level_name: name,
+                force_compress,
+                archive_mtime,
+                no_mmap,
+                xattrs,
+                strict,
+                framed,
+                force,
             };
 
+            // After cleaning, if there are no input files left, exit
+            if files.is_empty() {
+                return Err(FinalError::with_title("No files to compress").into());
+            }
+
             let level = if fast {
-                Some(1) // Lowest level of compression
+                Some(CompressionLevel::All(1)) // Lowest level of compression
             } else if slow {
-                Some(i16::MAX) // Highest level of compression
+                Some(CompressionLevel::All(i16::MAX)) // Highest level of compression
             } else {
                 level
             };
 
-            let compress_result = compress_files(
-                files,
+            if each {
+                // Each input gets its own archive, named after it, inside of the output directory
+                let Some(format_flag) = args.format else {
+                    let output_path = EscapedPathDisplay::new(&output_path);
+                    let error = FinalError::with_title("Cannot compress with '--each'.")
+                        .detail("The compression format of each archive can't be inferred from a directory")
+                        .hint("Use the '--format' flag to choose it:")
+                        .hint(format!(
+                            "  ouch compress <FILES>... {output_path} --each --format tar.gz"
+                        ));
+                    return Err(error.into());
+                };
+                let formats = parse_format(&format_flag)?;
+
+                utils::create_dir_if_non_existent(&output_path)?;
+
+                // The inputs are independent, so they're compressed in parallel, and a failure
+                // doesn't stop the others
+                let results: Vec<crate::Result<()>> = files
+                    .par_iter()
+                    .map(|file| {
+                        let mut file_name = file.file_name().ok_or(Error::CompressingRootFolder)?.to_os_string();
+                        file_name.push(".");
+                        file_name.push(&format_flag);
+
+                        let start = Instant::now();
+                        compress_to_output(
+                            slice::from_ref(file),
+                            formats.clone(),
+                            Some(&format_flag),
+                            &output_path.join(file_name),
+                            level.as_ref(),
+                            comment.as_deref(),
+                            &pack_options,
+                            rm,
+                            args.quiet,
+                            question_policy,
+                            file_visibility_policy,
+                        )?;
+
+                        if args.time && !args.quiet {
+                            info(format!(
+                                "Compressed '{}' in {}.",
+                                EscapedPathDisplay::new(file),
+                                format_duration(start.elapsed())
+                            ));
+                        }
+                        Ok(())
+                    })
+                    .collect();
+
+                let (_, failed) = collect_batch_results(&files, results)?;
+                if !failed.is_empty() {
+                    warning(format!(
+                        "Failed to compress {} of {} inputs: {}.",
+                        failed.len(),
+                        files.len(),
+                        pretty_format_list_of_paths(&failed)
+                    ));
+                    return Ok(EXIT_PARTIAL);
+                }
+
+                info_accessible(format!(
+                    "Successfully compressed {} inputs into '{}'.",
+                    files.len(),
+                    EscapedPathDisplay::new(&output_path)
+                ));
+                return Ok(EXIT_SUCCESS);
+            }
+
+            if output_path.is_dir() {
+                let output_path = EscapedPathDisplay::new(&output_path);
+                let error = FinalError::with_title(format!("Cannot compress to '{output_path}'."))
+                    .detail("The output path is an existing directory")
+                    .hint("To create one archive, add its file name to the output path:")
+                    .hint(format!("  ouch compress <FILES>... {output_path}/archive.tar.gz"))
+                    .hint("")
+                    .hint("To compress each input into its own archive inside of this directory, use '--each':")
+                    .hint(format!(
+                        "  ouch compress <FILES>... {output_path} --each --format tar.gz"
+                    ));
+                return Err(error.into());
+            }
+
+            // Formats from path extension, like "file.tar.gz.xz" -> vec![Tar, Gzip, Lzma]
+            let (formats_from_flag, formats, output_path) = match args.format {
+                Some(formats) => {
+                    let parsed_formats = parse_format(&formats)?;
+                    let output_path = output_path_with_formats(output_path, &formats, &parsed_formats);
+                    (Some(formats), parsed_formats, output_path)
+                }
+                None => (None, extension::extensions_from_path(&output_path), output_path),
+            };
+
+            compress_to_output(
+                &files,
                 formats,
-                output_file,
+                formats_from_flag.as_ref(),
                 &output_path,
+                level.as_ref(),
+                comment.as_deref(),
+                &pack_options,
+                rm,
                 args.quiet,
                 question_policy,
                 file_visibility_policy,
-                level,
-            );
-
-            if let Ok(true) = compress_result {
-                // this is only printed once, so it doesn't result in much text. On the other hand,
-                // having a final status message is important especially in an accessibility context
-                // as screen readers may not read a commands exit code, making it hard to reason
-                // about whether the command succeeded without such a message
-                info_accessible(format!("Successfully compressed '{}'.", to_utf(&output_path)));
+            )?;
+
+            Ok(EXIT_SUCCESS)
+        }
+        Subcommand::Decompress {
+            mut files,
+            output_dir,
+            to_stdout,
+            rm,
+            recursive,
+            max_nesting,
+            rm_nested,
+            flatten,
+            rename,
+            skip,
+            overwrite,
+            filter,
+            exclude,
+            exclude_from,
+            ignore_case,
+            interactive,
+            select_newest,
+            select_largest,
+            exec,
+            exec_ignore_errors,
+            transform,
+            absolute_paths,
+            strict,
+            no_symlinks,
+            first_wins,
+            sparse,
+            xattrs,
+            keep_partial,
+            same_permissions,
+            no_same_permissions,
+            password_file,
+            continue_on_error,
+            ignore_zeros,
+            max_output_size,
+            max_ratio,
+            max_entries,
+            max_path_depth,
+            max_path_length,
+            no_limits,
+            framed,
+        } => {
+            let conflict_policy = if rename {
+                ConflictPolicy::Rename
+            } else if skip {
+                ConflictPolicy::Skip
+            } else if overwrite {
+                ConflictPolicy::Overwrite
             } else {
-                // If Ok(false) or Err() occurred, delete incomplete file at `output_path`
-                //
-                // if deleting fails, print an extra alert message pointing
-                // out that we left a possibly CORRUPTED file at `output_path`
-                if utils::remove_file_or_dir(&output_path).is_err() {
-                    eprintln!("{red}FATAL ERROR:\n", red = *colors::RED);
-                    eprintln!(
-                        "  Ouch failed to delete the file '{}'.",
-                        EscapedPathDisplay::new(&output_path)
-                    );
-                    eprintln!("  Please delete it manually.");
-                    eprintln!("  This file is corrupted if compression didn't finished.");
-
-                    if compress_result.is_err() {
-                        eprintln!("  Compression failed for reasons below.");
-                    }
-                }
+                ConflictPolicy::Ask
+            };
+            if xattrs {
+                warn_about_unsupported_xattrs();
             }
+            let mut excludes = exclude;
+            if let Some(exclude_from) = &exclude_from {
+                excludes.extend(EntryFilter::read_patterns(exclude_from)?);
+            }
+            let unpack_options = UnpackOptions {
+                flatten,
+                conflict_policy,
+                filter: EntryFilter::new(&filter, ignore_case)?.exclude(&excludes, ignore_case)?,
+                exec: exec.map(|command| ExecHook::new(command, exec_ignore_errors)),
+                transforms: Transforms::new(&transform)?,
+                absolute_paths,
+                strict,
+                no_symlinks,
+                first_wins,
+                limits: if no_limits {
+                    ExtractionLimits::none()
+                } else {
+                    ExtractionLimits {
+                        max_output_size: max_output_size.or(Some(DEFAULT_MAX_OUTPUT_SIZE)),
+                        max_ratio: max_ratio.or(Some(DEFAULT_MAX_RATIO)),
+                        max_entries: max_entries.or(Some(DEFAULT_MAX_ENTRIES)),
+                        max_path_depth: max_path_depth.or(Some(DEFAULT_MAX_PATH_DEPTH)),
+                        max_path_length: max_path_length.or(Some(DEFAULT_MAX_PATH_LENGTH)),
+                    }
+                },
+                password: password_file.as_deref().map(utils::read_password_file).transpose()?,
+                sparse,
+                xattrs,
+                keep_partial,
+                umask: umask(same_permissions, no_same_permissions),
+                framed,
+                continue_on_error,
+                ignore_zeros,
+            };
+
+            let file_formats = split_archive_formats(&mut files)?;
+            let format_flag = args.format.as_deref().map(FormatFlag::parse).transpose()?;
 
-            compress_result.map(|_| ())
-        }
-        Subcommand::Decompress { files, output_dir } => {
             let mut output_paths = vec![];
             let mut formats = vec![];
+            // The inputs decompressed with the format of their contents instead of their extensions
+            let mut overridden_formats = vec![];
 
-            if let Some(format) = args.format {
-                let format = parse_format(&format)?;
-                for path in files.iter() {
-                    let file_name = path.file_name().ok_or_else(|| Error::NotFound {
-                        error_title: format!("{} does not have a file name", EscapedPathDisplay::new(path)),
-                    })?;
-                    output_paths.push(file_name.as_ref());
-                    formats.push(format.clone());
-                }
-            } else {
-                for path in files.iter() {
-                    let (pathbase, mut file_formats) = extension::separate_known_extensions_from_name(path);
-
-                    if let ControlFlow::Break(_) = check::check_mime_type(path, &mut file_formats, question_policy)? {
-                        return Ok(());
-                    }
+            for (path, file_formats) in files.iter().zip(file_formats) {
+                let Some((output_path, file_formats, overridden)) = input_formats(
+                    path,
+                    file_formats,
+                    format_flag.as_ref(),
+                    question_policy,
+                    args.verify_format,
+                )?
+                else {
+                    return Ok(EXIT_SUCCESS);
+                };
 
-                    output_paths.push(pathbase);
-                    formats.push(file_formats);
+                if overridden {
+                    overridden_formats.push((path.as_path(), extension::display_formats(&file_formats)));
                 }
+                output_paths.push(output_path);
+                formats.push(file_formats);
             }
 
             check::check_missing_formats_when_decompressing(&files, &formats)?;
 
+            if to_stdout {
+                // Written one after the other, like `gunzip -c` does with several files
+                for (input_path, formats) in files.iter().zip(formats) {
+                    let formats = extension::flatten_compression_formats(&formats);
+                    if !decompress_to_stdout(input_path, formats, &filter, question_policy)? {
+                        return Ok(EXIT_PARTIAL);
+                    }
+                }
+                return Ok(EXIT_SUCCESS);
+            }
+
             // The directory that will contain the output files
             // We default to the current directory if the user didn't specify an output directory with --dir
             let output_dir = if let Some(dir) = output_dir {
@@ -168,56 +564,614 @@ pub fn run(
                 PathBuf::from(".")
             };
 
-            files
-                .par_iter()
-                .zip(formats)
-                .zip(output_paths)
-                .try_for_each(|((input_path, formats), file_name)| {
-                    let output_file_path = output_dir.join(file_name); // Path used by single file format archives
-                    decompress_file(
-                        input_path,
-                        formats,
-                        &output_dir,
-                        output_file_path,
+            // Asked for or selected for every archive before any is extracted, as they're extracted in
+            // parallel
+            let picked_filters: Vec<Option<EntryFilter>> = if interactive {
+                if question_policy != QuestionPolicy::Ask {
+                    let error = FinalError::with_title("Cannot ask which entries to extract.")
+                        .detail("With --yes or --no, the questions of --interactive would all get the same answer")
+                        .hint("Use --filter and --exclude to select the entries to extract instead");
+                    return Err(error.into());
+                }
+                files
+                    .iter()
+                    .zip(&formats)
+                    .map(|(path, formats)| {
+                        let is_archive = formats.first().is_some_and(Extension::is_archive);
+                        is_archive
+                            .then(|| pick_entries(path, formats, &unpack_options.filter, question_policy))
+                            .transpose()
+                    })
+                    .collect::<crate::Result<_>>()?
+            } else if let Some(selection) = select_newest
+                .map(EntrySelection::Newest)
+                .or(select_largest.map(EntrySelection::Largest))
+            {
+                files
+                    .iter()
+                    .zip(&formats)
+                    .map(|(path, formats)| {
+                        let is_archive = formats.first().is_some_and(Extension::is_archive);
+                        is_archive
+                            .then(|| select_entries(path, formats, &unpack_options.filter, selection, question_policy))
+                            .transpose()
+                    })
+                    .collect::<crate::Result<_>>()?
+            } else {
+                vec![None; files.len()]
+            };
+
+            let inputs = files.par_iter().zip(formats).zip(output_paths).zip(picked_filters);
+            let decompress_input = |(((input_path, formats), file_name), picked_filter): (
+                ((&PathBuf, _), &Path),
+                Option<EntryFilter>,
+            )|
+             -> crate::Result<bool> {
+                let picked_options;
+                let unpack_options = match picked_filter {
+                    Some(filter) => {
+                        picked_options = UnpackOptions {
+                            filter,
+                            ..unpack_options.clone()
+                        };
+                        &picked_options
+                    }
+                    None => &unpack_options,
+                };
+                let output_file_path = output_dir.join(file_name); // Path used by single file format archives
+                let start = Instant::now();
+                // Shared with the archives nested in it, extracted with '--recursive'
+                let budget = ExtractionBudget::new(unpack_options.limits, fs::metadata(input_path)?.len());
+                let decompressed = decompress_file(
+                    input_path,
+                    formats,
+                    &output_dir,
+                    output_file_path,
+                    question_policy,
+                    args.quiet,
+                    unpack_options,
+                    &budget,
+                )?;
+
+                let decompressed = match decompressed {
+                    Some(outputs) if recursive => decompress_nested(
+                        outputs,
+                        max_nesting.unwrap_or(DEFAULT_MAX_NESTING),
+                        rm_nested,
                         question_policy,
                         args.quiet,
-                    )
-                })
-        }
-        Subcommand::List { archives: files, tree } => {
-            let mut formats = vec![];
+                        unpack_options,
+                        &budget,
+                    )?,
+                    decompressed => decompressed.is_some(),
+                };
 
-            if let Some(format) = args.format {
-                let format = parse_format(&format)?;
-                for _ in 0..files.len() {
-                    formats.push(format.clone());
+                if decompressed {
+                    utils::io::count_processed_bytes(fs::metadata(input_path).map_or(0, |metadata| metadata.len()));
+                    if let Some((_, formats)) = overridden_formats.iter().find(|(path, _)| path == input_path) {
+                        info_accessible(format!(
+                            "Decompressed '{}' as `{formats}`, the format of its contents, rename it after it to \
+                             skip the question.",
+                            EscapedPathDisplay::new(input_path)
+                        ));
+                    }
+                    if args.time && !args.quiet {
+                        info(format!(
+                            "Decompressed '{}' in {}.",
+                            EscapedPathDisplay::new(input_path),
+                            format_duration(start.elapsed())
+                        ));
+                    }
                 }
-            } else {
-                for path in files.iter() {
-                    let mut file_formats = extension::extensions_from_path(path);
 
-                    if let ControlFlow::Break(_) = check::check_mime_type(path, &mut file_formats, question_policy)? {
-                        return Ok(());
+                // Only remove archives that were fully extracted, not the ones the user gave up on
+                if decompressed && rm {
+                    match utils::remove_file_or_dir(input_path) {
+                        Ok(()) => info(format!("Removed '{}'.", EscapedPathDisplay::new(input_path))),
+                        Err(_) => warning(format!(
+                            "Failed to remove the archive '{}'.",
+                            EscapedPathDisplay::new(input_path)
+                        )),
                     }
+                }
+
+                Ok(decompressed)
+            };
 
-                    formats.push(file_formats);
+            let decompressed: Vec<bool> = if continue_on_error {
+                // Every archive is decompressed, a failure doesn't stop the others
+                let results: Vec<crate::Result<bool>> = inputs.map(decompress_input).collect();
+                let (decompressed, failed) = collect_batch_results(&files, results)?;
+                if !failed.is_empty() {
+                    warning(format!(
+                        "Failed to decompress {} of {} archives: {}.",
+                        failed.len(),
+                        files.len(),
+                        pretty_format_list_of_paths(&failed)
+                    ));
+                    return Ok(EXIT_PARTIAL);
                 }
+                decompressed
+            } else {
+                inputs.map(decompress_input).collect::<crate::Result<_>>()?
+            };
+
+            // Some of the archives were skipped by the user
+            if decompressed.contains(&false) {
+                return Ok(EXIT_PARTIAL);
+            }
+
+            Ok(EXIT_SUCCESS)
+        }
+        Subcommand::Append { archive, files, update } => {
+            let formats = match args.format {
+                Some(format) => parse_format(&format)?,
+                None => extension::extensions_from_path(&archive),
+            };
+
+            if formats.is_empty() {
+                let archive = EscapedPathDisplay::new(&archive);
+                let error = FinalError::with_title(format!("Cannot append to '{archive}'."))
+                    .detail("The archive format couldn't be inferred from its extension")
+                    .hint("Use the '--format' flag to choose it:")
+                    .hint(format!("  ouch append {archive} <FILES>... --format tar.gz"));
+                return Err(error.into());
             }
 
-            // Ensure we were not told to list the content of a non-archive compressed file
-            check::check_for_non_archive_formats(&files, &formats)?;
+            let formats = extension::flatten_compression_formats(&formats);
+            append_to_archive(&archive, &files, formats, update, args.quiet, file_visibility_policy)?;
+
+            Ok(EXIT_SUCCESS)
+        }
+        Subcommand::List {
+            archives: mut files,
+            patterns,
+            tree,
+            print0,
+            long,
+            filter,
+            ignore_case,
+            limit,
+            offset,
+        } => {
+            let Some(formats) = archive_formats(&mut files, args.format, question_policy, args.verify_format)? else {
+                return Ok(EXIT_SUCCESS);
+            };
 
-            let list_options = ListOptions { tree };
+            let patterns: Vec<String> = patterns.into_iter().chain(filter).collect();
+            let list_options = ListOptions {
+                tree,
+                print0,
+                long,
+                filter: EntryFilter::new(&patterns, ignore_case)?,
+                limit,
+                offset,
+            };
 
+            let mut listed = Some(0);
             for (i, (archive_path, formats)) in files.iter().zip(formats).enumerate() {
-                if i > 0 {
+                if i > 0 && !print0 {
                     println!();
                 }
-                let formats = extension::flatten_compression_formats(&formats);
-                list_archive_contents(archive_path, formats, list_options, question_policy)?;
+                let archive_listed = list_archive_contents(archive_path, formats, &list_options, question_policy)?;
+                listed = listed
+                    .zip(archive_listed)
+                    .map(|(listed, archive_listed)| listed + archive_listed);
             }
 
-            Ok(())
+            // Only reported if every archive was listed, as the skipped ones could have matches
+            if !patterns.is_empty() && listed == Some(0) {
+                let patterns: Vec<_> = patterns.iter().map(|pattern| format!("'{pattern}'")).collect();
+                let error = FinalError::with_title(format!("No entries match {}.", patterns.join(", ")))
+                    .hint("To see all of the entries, list the archives without patterns");
+                return Err(error.into());
+            }
+
+            Ok(EXIT_SUCCESS)
+        }
+        Subcommand::Cat { archive, entry } => {
+            let mut files = [archive];
+            let Some(formats) = archive_formats(&mut files, args.format, question_policy, args.verify_format)? else {
+                return Ok(EXIT_SUCCESS);
+            };
+            let [archive] = &files;
+            let [formats]: [_; 1] = formats.try_into().expect("one per archive");
+
+            cat_archive_entry(archive, formats, &entry, question_policy)?;
+
+            Ok(EXIT_SUCCESS)
         }
+        Subcommand::Merge { mut archives, output } => {
+            let Some(formats) = archive_formats(&mut archives, None, question_policy, args.verify_format)? else {
+                return Ok(EXIT_SUCCESS);
+            };
+            let output_formats = match args.format {
+                Some(format) => parse_format(&format)?,
+                None => extension::extensions_from_path(&output),
+            };
+            let output_formats = extension::flatten_compression_formats(&output_formats);
+
+            merge_archives(&archives, formats, &output, output_formats, question_policy)?;
+
+            Ok(EXIT_SUCCESS)
+        }
+        Subcommand::Diff {
+            first,
+            second,
+            metadata,
+            json,
+        } => {
+            let mut files = [first, second];
+            let Some(formats) = archive_formats(&mut files, args.format, question_policy, args.verify_format)? else {
+                return Ok(EXIT_SUCCESS);
+            };
+            let [first, second] = &files;
+            let [first_formats, second_formats]: [_; 2] = formats.try_into().expect("one per archive");
+
+            let Some(diff) = diff_archives(first, first_formats, second, second_formats, metadata, question_policy)?
+            else {
+                return Ok(EXIT_SUCCESS);
+            };
+
+            if json {
+                diff.print_json(["only_in_first", "only_in_second"]);
+            } else if diff.is_empty() {
+                info_accessible("The archives are identical.".to_string());
+            } else {
+                diff.print([
+                    &format!("Only in '{}'", EscapedPathDisplay::new(first)),
+                    &format!("Only in '{}'", EscapedPathDisplay::new(second)),
+                ]);
+            }
+
+            Ok(if diff.is_empty() { EXIT_SUCCESS } else { EXIT_DIFFERENT })
+        }
+        Subcommand::Verify {
+            archive,
+            against,
+            metadata,
+            strict,
+            json,
+        } => {
+            if !against.is_dir() {
+                let error = FinalError::with_title(format!(
+                    "Cannot verify against '{}'.",
+                    EscapedPathDisplay::new(&against)
+                ))
+                .detail("It's not an existing directory");
+                return Err(error.into());
+            }
+
+            let mut files = [archive];
+            let Some(formats) = archive_formats(&mut files, args.format, question_policy, args.verify_format)? else {
+                return Ok(EXIT_SUCCESS);
+            };
+            let [archive] = &files;
+            let [formats]: [_; 1] = formats.try_into().expect("one per archive");
+
+            let Some(diff) = verify_against_directory(
+                archive,
+                formats,
+                &against,
+                metadata,
+                strict,
+                question_policy,
+                file_visibility_policy,
+            )?
+            else {
+                return Ok(EXIT_SUCCESS);
+            };
+
+            if json {
+                diff.print_json(["missing", "extra"]);
+            } else if diff.is_empty() {
+                info_accessible(format!("'{}' matches the archive.", EscapedPathDisplay::new(&against)));
+            } else {
+                diff.print(["Missing", "Extra"]);
+            }
+
+            Ok(if diff.is_empty() { EXIT_SUCCESS } else { EXIT_DIFFERENT })
+        }
+    }
+}
+
+/// Formats of each archive in `files`, from `--format`, `path:format` or their extensions, in
+/// decompression order.
+///
+/// The formats given with `path:format` are removed from `files`.
+///
+/// Returns `None` if the user aborted when asked about a file without known extensions.
+fn archive_formats(
+    files: &mut [PathBuf],
+    format: Option<OsString>,
+    question_policy: QuestionPolicy,
+    verify_format: VerifyFormat,
+) -> crate::Result<Option<Vec<Vec<CompressionFormat>>>> {
+    let file_formats = split_archive_formats(files)?;
+    let format_flag = format.as_deref().map(FormatFlag::parse).transpose()?;
+    let mut formats = vec![];
+
+    for (path, file_formats) in files.iter().zip(file_formats) {
+        let Some((_, file_formats, _)) =
+            input_formats(path, file_formats, format_flag.as_ref(), question_policy, verify_format)?
+        else {
+            return Ok(None);
+        };
+
+        formats.push(file_formats);
+    }
+
+    // Ensure we were not told to read the content of a non-archive compressed file
+    check::check_for_non_archive_formats(files, &formats)?;
+
+    let formats = formats
+        .iter()
+        .map(|formats| extension::flatten_compression_formats(formats))
+        .collect();
+    Ok(Some(formats))
+}
+
+/// Remove the formats given to archives with `path:format` from `files`, returning them.
+fn split_archive_formats(files: &mut [PathBuf]) -> crate::Result<Vec<Option<Vec<Extension>>>> {
+    files
+        .iter_mut()
+        .map(|file| {
+            let Some((path, format)) = cli::split_format_suffix(file) else {
+                return Ok(None);
+            };
+            let format = parse_format(OsStr::new(format))?;
+            *file = path.to_path_buf();
+            Ok(Some(format))
+        })
+        .collect()
+}
+
+/// Formats of the archive at `path`, in compression order, along with the name of its output
+/// when it's a compressed file rather than an archive.
+///
+/// `file_formats`, given with `path:format`, take precedence over `--format`. When neither
+/// replaces the formats, they're detected from the extensions of `path`, with the formats given
+/// to `--format +<FORMATS>` as outer layers.
+///
+/// The last value tells whether the formats of the extensions were overridden by the detected
+/// ones, see [`check::check_mime_type`].
+///
+/// Returns `None` if the user aborted when asked about a file without known extensions.
+fn input_formats<'a>(
+    path: &'a Path,
+    file_formats: Option<Vec<Extension>>,
+    format_flag: Option<&FormatFlag>,
+    question_policy: QuestionPolicy,
+    verify_format: VerifyFormat,
+) -> crate::Result<Option<(&'a Path, Vec<Extension>, bool)>> {
+    let replaced_formats = file_formats.or_else(|| match format_flag {
+        Some(FormatFlag::Replace(formats)) => Some(formats.clone()),
+        _ => None,
+    });
+
+    if let Some(formats) = replaced_formats {
+        let file_name = path.file_name().ok_or_else(|| Error::NotFound {
+            error_title: format!("{} does not have a file name", EscapedPathDisplay::new(path)),
+        })?;
+        return Ok(Some((file_name.as_ref(), formats, false)));
+    }
+
+    let (pathbase, mut formats) = extension::separate_known_extensions_from_name(path);
+    if let Some(FormatFlag::Append(outer_formats)) = format_flag {
+        formats.extend(outer_formats.iter().cloned());
+    }
+
+    match check::check_mime_type(path, &mut formats, question_policy, verify_format)? {
+        ControlFlow::Break(()) => Ok(None),
+        ControlFlow::Continue(overridden) => Ok(Some((pathbase, formats, overridden))),
+    }
+}
+
+/// Add the extensions of `formats`, given with `--format` as `format_flag`, to the output path
+/// of `ouch compress` when it has none of them, so that its format can be detected from its name.
+///
+/// An output that already has some of them, like 'archive.tar' for 'tar.gz', gets the missing
+/// ones, while one named after other formats is kept as it is, with a warning. '-' is never changed.
+fn output_path_with_formats(output_path: PathBuf, format_flag: &OsStr, formats: &[Extension]) -> PathBuf {
+    if output_path.as_os_str() == "-" {
+        return output_path;
+    }
+
+    let output_formats = extension::extensions_from_path(&output_path);
+    if extension::flatten_compression_formats(&output_formats) == extension::flatten_compression_formats(formats) {
+        return output_path;
+    }
+
+    let output_display = EscapedPathDisplay::new(&output_path);
+    let format_display = format_flag.to_string_lossy();
+    if !formats.starts_with(&output_formats) {
+        warning(format!(
+            "The output '{output_display}' doesn't end in '.{format_display}', so its format won't be detected from its name."
+        ));
+        return output_path;
+    }
+
+    let mut with_extensions = output_path.into_os_string();
+    for format in &formats[output_formats.len()..] {
+        with_extensions.push(format!(".{format}"));
+    }
+    let with_extensions = PathBuf::from(with_extensions);
+    info_accessible(format!(
+        "Compressing to '{}', with the extension of '--format {format_display}'.",
+        EscapedPathDisplay::new(&with_extensions)
+    ));
+    with_extensions
+}
+
+/// Compress `files` into a new archive at `output_path`, deleting the incomplete archive if it
+/// fails.
+///
+/// An `output_path` of '-' writes the archive to stdout instead.
+///
+/// `formats_from_flag` is only used to give better error messages.
+#[allow(clippy::too_many_arguments)]
+fn compress_to_output(
+    files: &[PathBuf],
+    formats: Vec<Extension>,
+    formats_from_flag: Option<&OsString>,
+    output_path: &Path,
+    level: Option<&CompressionLevel>,
+    comment: Option<&str>,
+    pack_options: &PackOptions,
+    rm: bool,
+    quiet: bool,
+    question_policy: QuestionPolicy,
+    file_visibility_policy: FileVisibilityPolicy,
+) -> crate::Result<()> {
+    check::check_invalid_compression_with_non_archive_format(&formats, output_path, files, formats_from_flag)?;
+    check::check_archive_formats_position(&formats, output_path)?;
+    check::check_archive_comment_support(&formats, output_path, comment)?;
+    if !pack_options.force {
+        check::warn_about_double_compression(files, &formats);
+    }
+    let levels = format_levels(level, &formats, output_path)?;
+
+    let to_stdout = output_path.as_os_str() == "-";
+    if pack_options.framed && !to_stdout {
+        let error = FinalError::with_title(format!(
+            "Cannot write '{}' as a framed stream.",
+            EscapedPathDisplay::new(output_path)
+        ))
+        .detail("Only the archives written to stdout are framed")
+        .hint("Give '-' as the output, with the format of the archive, like:")
+        .hint("  ouch compress <FILES>... - --format tar.gz --framed");
+        return Err(error.into());
+    }
+    if to_stdout {
+        let compressed = compress_files(
+            files,
+            formats,
+            utils::io::stdout_file()?,
+            &ArchiveOutput::stdout(output_path),
+            quiet,
+            question_policy,
+            file_visibility_policy,
+            &levels,
+            comment,
+            pack_options,
+        )?;
+        if compressed && rm {
+            remove_input_files(files, output_path);
+        }
+        return Ok(());
+    }
+
+    check::check_output_is_not_input(files, output_path, pack_options.strict)?;
+
+    if fs::symlink_metadata(output_path).is_ok() && !utils::user_wants_to_overwrite(output_path, question_policy)? {
+        return Ok(());
+    }
+    // Written to a temporary file next to the output, which only replaces it once it's complete,
+    // so a failure, an interruption or a crash never leaves a partial archive there
+    let (output_file, temp_path) = create_temp_output(output_path)?;
+    let incomplete_output = IncompleteOutput::new(&temp_path);
+
+    let compress_result = compress_files(
+        files,
+        formats,
+        output_file,
+        &ArchiveOutput::new(output_path, &temp_path),
+        quiet,
+        question_policy,
+        file_visibility_policy,
+        &levels,
+        comment,
+        pack_options,
+    )
+    .and_then(|compressed| {
+        if compressed {
+            replace_output(&temp_path, output_path)?;
+        }
+        Ok(compressed)
+    });
+    // Past this point, the failure branch below takes care of the output
+    drop(incomplete_output);
+
+    if let Ok(true) = compress_result {
+        if let Some(mtime) = pack_options.archive_mtime {
+            filetime_creation::set_file_mtime(output_path, filetime_creation::FileTime::from_unix_time(mtime, 0))?;
+        }
+
+        // this is only printed once, so it doesn't result in much text. On the other hand,
+        // having a final status message is important especially in an accessibility context
+        // as screen readers may not read a commands exit code, making it hard to reason
+        // about whether the command succeeded without such a message
+        info_accessible(format!("Successfully compressed '{}'.", to_utf(output_path)));
+
+        if rm {
+            remove_input_files(files, output_path);
+        }
+    } else {
+        // If Ok(false) or Err() occurred, delete the incomplete temporary file
+        //
+        // if deleting fails, print an extra alert message pointing
+        // out that we left a possibly CORRUPTED file at `temp_path`
+        if !utils::remove_incomplete_output(&temp_path) && compress_result.is_err() {
+            eprintln!("  Compression failed for reasons below.");
+        }
+    }
+
+    compress_result.map(|_| ())
+}
+
+/// Create the temporary file that the archive at `output_path` is written to until it's complete,
+/// like '.archive.tar.gz.XXXXXX.ouch-tmp', next to it.
+fn create_temp_output(output_path: &Path) -> crate::Result<(fs::File, PathBuf)> {
+    let dir = match output_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = output_path.file_name().unwrap_or_default().to_string_lossy();
+
+    let mut temp_builder = tempfile::Builder::new();
+    let prefix = format!(".{name}.");
+    temp_builder.prefix(&prefix).suffix(".ouch-tmp");
+    // With the permissions a new file gets, instead of only being readable by its owner
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        temp_builder.permissions(std::fs::Permissions::from_mode(0o666));
+    }
+    let (file, temp_path) = temp_builder
+        .tempfile_in(utils::long_path(dir))?
+        .keep()
+        .map_err(|err| err.error)?;
+    Ok((fs::File::from_parts(file, &temp_path), temp_path))
+}
+
+/// Move the complete archive at `temp_path` to `output_path`, replacing what's there.
+///
+/// It's synced to the disk first, so a crash right after can't leave an empty file in its place.
+/// If it can't be renamed there, like when `output_path` is mounted from another filesystem, it's
+/// written there directly instead.
+fn replace_output(temp_path: &Path, output_path: &Path) -> crate::Result<()> {
+    fs::File::open(temp_path)?.sync_all()?;
+    if output_path.is_dir() {
+        utils::remove_file_or_dir(output_path)?;
+    }
+    let Err(err) = std::fs::rename(utils::long_path(temp_path), utils::long_path(output_path)) else {
+        return Ok(());
+    };
+
+    warning(format!(
+        "Cannot move the archive into place at '{}' ({err}), writing it there directly.",
+        EscapedPathDisplay::new(output_path)
+    ));
+    let copied = (|| {
+        let mut output_file = fs::File::create(output_path)?;
+        std::io::copy(&mut fs::File::open(temp_path)?, &mut output_file)?;
+        output_file.sync_all()
+    })();
+    if let Err(err) = copied {
+        utils::remove_incomplete_output(output_path);
+        return Err(err.into());
     }
+    // The copy is complete, so the temporary file isn't needed anymore
+    let _ = fs::remove_file(temp_path);
+    Ok(())
 }
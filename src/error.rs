@@ -7,7 +7,10 @@ use std::{
     fmt::{self, Display},
 };
 
-use crate::{accessible::is_running_in_accessible_mode, utils::colors::*};
+use crate::{
+    accessible::is_running_in_accessible_mode,
+    utils::{colors::*, logger::json_logs},
+};
 
 /// All errors that can be generated by `ouch`
 #[derive(Debug)]
@@ -39,6 +42,40 @@ pub enum Error {
     /// Recognised but unsupported format
     // currently only RAR when built without the `unrar` feature
     UnsupportedFormat { reason: String },
+    /// Detected from io::Error if .kind() is io::ErrorKind::InvalidData, InvalidInput or UnexpectedEof,
+    /// see [`commands::decompress::decompress_file`](crate::commands::decompress::decompress_file)
+    /// for the details added to it
+    CorruptArchive { reason: FinalError },
+    /// Detected from io::Error if .kind() is io::ErrorKind::StorageFull or QuotaExceeded, see
+    /// [`utils::io::entry_write_error`](crate::utils::io::entry_write_error)
+    StorageFull { reason: FinalError },
+    /// Interrupted with Ctrl-C or SIGTERM, see [`utils::interrupt`](crate::utils::interrupt)
+    Interrupted,
+}
+
+impl Error {
+    /// The status code that `ouch` exits with when it fails with this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::IoError { .. }
+            | Error::NotFound { .. }
+            | Error::AlreadyExists { .. }
+            | Error::PermissionDenied { .. }
+            | Error::WalkdirError { .. }
+            | Error::SevenzipError(sevenz_rust::Error::FileOpen(..))
+            | Error::StorageFull { .. } => crate::EXIT_IO,
+            Error::Lz4Error { .. }
+            | Error::InvalidZipArchive(_)
+            | Error::UnsupportedZipArchive(_)
+            | Error::SevenzipError(_)
+            | Error::CorruptArchive { .. } => crate::EXIT_CORRUPT,
+            Error::CompressingRootFolder
+            | Error::Custom { .. }
+            | Error::InvalidFormat { .. }
+            | Error::UnsupportedFormat { .. } => crate::EXIT_FAILURE,
+            Error::Interrupted => crate::EXIT_INTERRUPTED,
+        }
+    }
 }
 
 /// Alias to std's Result with ouch's Error
@@ -62,8 +99,11 @@ impl Display for FinalError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         // Title
         //
-        // When in ACCESSIBLE mode, the square brackets are suppressed
-        if is_running_in_accessible_mode() {
+        // When in ACCESSIBLE mode, the square brackets are suppressed, and JSON logs already
+        // have the level
+        if json_logs() {
+            write!(f, "{}", self.title)?;
+        } else if is_running_in_accessible_mode() {
             write!(f, "{}ERROR{}: {}", *RED, *RESET, self.title)?;
         } else {
             write!(f, "{}[ERROR]{} {}", *RED, *RESET, self.title)?;
@@ -120,6 +160,11 @@ impl FinalError {
         self.hints.push(hint.into());
         self
     }
+
+    /// The title, without the "\[ERROR\]" part
+    pub fn title(&self) -> &str {
+        &self.title
+    }
 }
 
 impl fmt::Display for Error {
@@ -148,6 +193,9 @@ impl fmt::Display for Error {
             Error::UnsupportedFormat { reason } => {
                 FinalError::with_title("Recognised but unsupported format").detail(reason.clone())
             }
+            Error::CorruptArchive { reason } => reason.clone(),
+            Error::StorageFull { reason } => reason.clone(),
+            Error::Interrupted => FinalError::with_title("Interrupted"),
         };
 
         write!(f, "{err}")
@@ -166,6 +214,15 @@ impl From<std::io::Error> for Error {
             std::io::ErrorKind::AlreadyExists => Self::AlreadyExists {
                 error_title: err.to_string(),
             },
+            std::io::ErrorKind::InvalidData | std::io::ErrorKind::InvalidInput | std::io::ErrorKind::UnexpectedEof => {
+                Self::CorruptArchive {
+                    reason: FinalError::with_title(err.to_string())
+                        .detail("The archive might be corrupted or truncated"),
+                }
+            }
+            std::io::ErrorKind::StorageFull | std::io::ErrorKind::QuotaExceeded => Self::StorageFull {
+                reason: FinalError::with_title(err.to_string()).detail("There's no space left to write to"),
+            },
             _other => Self::IoError {
                 reason: err.to_string(),
             },
@@ -182,6 +239,11 @@ impl From<zip::result::ZipError> for Error {
             ZipError::FileNotFound => Self::Custom {
                 reason: FinalError::with_title("Unexpected error in zip archive").detail("File not found"),
             },
+            ZipError::UnsupportedArchive(ZipError::PASSWORD_REQUIRED) => Self::Custom {
+                reason: FinalError::with_title("The zip archive is encrypted.")
+                    .detail("Its entries can't be read without their password")
+                    .hint("To decompress it, give the password in a file with '--password-file'"),
+            },
             ZipError::UnsupportedArchive(filename) => Self::UnsupportedZipArchive(filename),
         }
     }
@@ -198,10 +260,46 @@ impl From<unrar::error::UnrarError> for Error {
 
 impl From<sevenz_rust::Error> for Error {
     fn from(err: sevenz_rust::Error) -> Self {
-        Self::SevenzipError(err)
+        use sevenz_rust::Error as SevenzError;
+        let feature = match &err {
+            SevenzError::UnsupportedCompressionMethod(method) => sevenz_method_name(method),
+            SevenzError::PasswordRequired => "encryption".into(),
+            SevenzError::ExternalUnsupported => "headers stored outside of the archive".into(),
+            SevenzError::Unsupported(reason) => format!("a feature that isn't supported ({reason})"),
+            _ => return Self::SevenzipError(err),
+        };
+        Self::Custom {
+            reason: FinalError::with_title("Unsupported 7z archive")
+                .detail(format!("It uses {feature}, which ouch can't decompress"))
+                .hint("Extract it with 7-Zip instead:")
+                .hint("  7z x <ARCHIVE>")
+                .hint("")
+                .hint("Or create it again with the default LZMA2 compression, which ouch supports:")
+                .hint("  7z a -m0=lzma2 <ARCHIVE> <FILES>"),
+        }
     }
 }
 
+/// Readable name of a 7z compression method, given by sevenz_rust either as its own name of the
+/// method, or as the bytes of its id for the methods it doesn't know
+fn sevenz_method_name(method: &str) -> String {
+    let name = match method {
+        "DEFLATE" => "Deflate",
+        "DEFLATE64" => "Deflate64",
+        "BZIP2" => "BZip2",
+        "ZSTD" => "Zstandard",
+        "AES256SHA256" => return "encryption".into(),
+        "BCJ_IA64" => return "the IA64 filter".into(),
+        "[3, 4, 1]" => "PPMd",
+        "[10]" => return "the ARM64 filter".into(),
+        "[11]" => return "the RISC-V filter".into(),
+        "[4, 247, 17, 2]" => "Brotli",
+        "[4, 247, 17, 4]" => "LZ4",
+        method => return format!("the compression method with id {method}"),
+    };
+    format!("{name} compression")
+}
+
 impl From<ignore::Error> for Error {
     fn from(err: ignore::Error) -> Self {
         Self::WalkdirError {
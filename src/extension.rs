@@ -5,7 +5,10 @@ use std::{ffi::OsStr, fmt, path::Path};
 use bstr::ByteSlice;
 
 use self::CompressionFormat::*;
-use crate::{error::Error, utils::logger::warning};
+use crate::{
+    error::Error,
+    utils::{external_codec, logger::warning},
+};
 
 pub const SUPPORTED_EXTENSIONS: &[&str] = &[
     "tar",
@@ -97,11 +100,14 @@ pub enum CompressionFormat {
     Rar,
     /// .7z
     SevenZip,
+    /// An extension registered with `--codec-cmd` or `--codec-compress-cmd`, the index of its
+    /// codec, see [`external_codec`]
+    External(usize),
 }
 
 impl CompressionFormat {
     /// Currently supported archive formats are .tar (and aliases to it) and .zip
-    fn is_archive_format(&self) -> bool {
+    pub fn is_archive_format(&self) -> bool {
         // Keep this match like that without a wildcard `_` so we don't forget to update it
         match self {
             Tar | Zip | Rar | SevenZip => true,
@@ -111,33 +117,41 @@ impl CompressionFormat {
             Lzma => false,
             Snappy => false,
             Zstd => false,
+            External(_) => false,
         }
     }
 }
 
 fn to_extension(ext: &[u8]) -> Option<Extension> {
-    Some(Extension::new(
-        match ext {
-            b"tar" => &[Tar],
-            b"tgz" => &[Tar, Gzip],
-            b"tbz" | b"tbz2" => &[Tar, Bzip],
-            b"tlz4" => &[Tar, Lz4],
-            b"txz" | b"tlzma" => &[Tar, Lzma],
-            b"tsz" => &[Tar, Snappy],
-            b"tzst" => &[Tar, Zstd],
-            b"zip" => &[Zip],
-            b"bz" | b"bz2" => &[Bzip],
-            b"gz" => &[Gzip],
-            b"lz4" => &[Lz4],
-            b"xz" | b"lzma" => &[Lzma],
-            b"sz" => &[Snappy],
-            b"zst" => &[Zstd],
-            b"rar" => &[Rar],
-            b"7z" => &[SevenZip],
-            _ => return None,
-        },
-        ext.to_str_lossy(),
-    ))
+    let formats = builtin_formats(ext).or_else(|| Some(&external_codec::find(ext)?.formats[..]))?;
+    Some(Extension::new(formats, ext.to_str_lossy()))
+}
+
+/// Whether `ext` is one of the extensions supported by ouch itself, like 'gz' or 'tgz'.
+pub fn is_builtin_extension(ext: &str) -> bool {
+    builtin_formats(ext.as_bytes()).is_some()
+}
+
+fn builtin_formats(ext: &[u8]) -> Option<&'static [CompressionFormat]> {
+    Some(match ext {
+        b"tar" => &[Tar],
+        b"tgz" => &[Tar, Gzip],
+        b"tbz" | b"tbz2" => &[Tar, Bzip],
+        b"tlz4" => &[Tar, Lz4],
+        b"txz" | b"tlzma" => &[Tar, Lzma],
+        b"tsz" => &[Tar, Snappy],
+        b"tzst" => &[Tar, Zstd],
+        b"zip" => &[Zip],
+        b"bz" | b"bz2" => &[Bzip],
+        b"gz" => &[Gzip],
+        b"lz4" => &[Lz4],
+        b"xz" | b"lzma" => &[Lzma],
+        b"sz" => &[Snappy],
+        b"zst" => &[Zstd],
+        b"rar" => &[Rar],
+        b"7z" => &[SevenZip],
+        _ => return None,
+    })
 }
 
 fn split_extension(name: &mut &[u8]) -> Option<Extension> {
@@ -166,6 +180,24 @@ pub fn parse_format(fmt: &OsStr) -> crate::Result<Vec<Extension>> {
     Ok(extensions)
 }
 
+/// The formats given with `--format` to read archives
+#[derive(Debug, Clone)]
+pub enum FormatFlag {
+    /// Used instead of the formats detected from the extensions of each file
+    Replace(Vec<Extension>),
+    /// Given prefixed with '+', like `+xz`, added as outer layers to the detected formats
+    Append(Vec<Extension>),
+}
+
+impl FormatFlag {
+    pub fn parse(fmt: &OsStr) -> crate::Result<Self> {
+        match fmt.to_str().and_then(|fmt| fmt.strip_prefix('+')) {
+            Some(outer_formats) => Ok(Self::Append(parse_format(OsStr::new(outer_formats))?)),
+            None => Ok(Self::Replace(parse_format(fmt)?)),
+        }
+    }
+}
+
 /// Extracts extensions from a path.
 ///
 /// Returns both the remaining path and the list of extension objects
@@ -206,6 +238,11 @@ pub fn split_first_compression_format(formats: &[Extension]) -> (CompressionForm
     (first_extension, extensions)
 }
 
+/// `formats` joined like in a file name, like `tar.gz`
+pub fn display_formats(formats: &[Extension]) -> String {
+    formats.iter().map(Extension::to_string).collect::<Vec<_>>().join(".")
+}
+
 pub fn flatten_compression_formats(extensions: &[Extension]) -> Vec<CompressionFormat> {
     extensions
         .iter()
@@ -1,18 +1,37 @@
 //! Some implementation helpers related to the 'list' command.
 
 use std::{
-    io::{stdout, Write},
+    borrow::Cow,
+    io::{stdout, BufWriter, Write},
     path::{Path, PathBuf},
 };
 
 use self::tree::Tree;
-use crate::{accessible::is_running_in_accessible_mode, utils::EscapedPathDisplay};
+use crate::{
+    accessible::is_running_in_accessible_mode,
+    extension,
+    utils::{Bytes, EntryFilter, EscapedPathDisplay},
+};
+
+/// How many entries the threads reading archives decode ahead of the ones printed, so listing an
+/// archive takes as much memory however many entries it has
+pub const ENTRIES_AHEAD: usize = 1024;
 
 /// Options controlling how archive contents should be listed
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Default)]
 pub struct ListOptions {
     /// Whether to show a tree view
     pub tree: bool,
+    /// Whether to print only the raw paths of the entries, separated by NUL bytes
+    pub print0: bool,
+    /// Whether to show the compression method and sizes of each entry
+    pub long: bool,
+    /// Only the entries selected by this filter are listed
+    pub filter: EntryFilter,
+    /// How many of the entries selected by the filter are printed, all of them if `None`
+    pub limit: Option<usize>,
+    /// How many of the entries selected by the filter are skipped before printing them
+    pub offset: usize,
 }
 
 /// Represents a single file in an archive, used in `list::list_files()`
@@ -23,50 +42,257 @@ pub struct FileInArchive {
 
     /// Whether this file is a directory
     pub is_dir: bool,
+
+    /// Whether this file is a symbolic link
+    pub is_symlink: bool,
+
+    /// Unix permissions of the file, only read from tar archives to tell executables apart
+    pub mode: Option<u32>,
+
+    /// Uncompressed size, if the format stores it
+    pub size: Option<u64>,
+
+    /// Size of the stored data, only known for formats that compress each entry on its own
+    pub compressed_size: Option<u64>,
+
+    /// Compression method of the entry, like "Deflated", or the codecs of its 7z folder
+    pub method: Option<String>,
+
+    /// Which copy of its path the entry is, and how many the archive has, for the paths of
+    /// several entries, only looked for in zip archives
+    pub duplicate: Option<(usize, usize)>,
+}
+
+impl FileInArchive {
+    /// What the entry is, to color it when it's printed
+    pub fn kind(&self) -> EntryKind {
+        if self.is_dir {
+            EntryKind::Directory
+        } else if self.is_symlink {
+            EntryKind::Symlink
+        } else if self.is_executable() {
+            EntryKind::Executable
+        } else if !extension::extensions_from_path(&self.path).is_empty() {
+            EntryKind::Archive
+        } else {
+            EntryKind::File
+        }
+    }
+
+    /// Whether the entry is a program, from its permissions if they're known, or from its
+    /// extension otherwise
+    fn is_executable(&self) -> bool {
+        if let Some(mode) = self.mode {
+            return mode & 0o111 != 0;
+        }
+        self.path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| {
+                EXECUTABLE_EXTENSIONS
+                    .iter()
+                    .any(|executable| executable.eq_ignore_ascii_case(extension))
+            })
+    }
+}
+
+/// Extensions of the programs and scripts colored as executables, for the formats that don't
+/// store permissions
+const EXECUTABLE_EXTENSIONS: &[&str] = &["exe", "com", "bat", "cmd", "ps1", "msi", "sh", "appimage"];
+
+/// What an entry is, which decides its color, like `ls` does
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    Executable,
+    /// A file with an extension that ouch can decompress
+    Archive,
 }
 
-/// Actually print the files
-/// Returns an Error, if one of the files can't be read
+/// Actually print the files, along with the archive comment if it's not empty
+///
+/// Only the window of `--offset` and `--limit` is printed, followed by how many entries are left
+/// out after it.
+///
+/// Returns how many files matched the filter, printed or not, or an Error, if one of the files
+/// can't be read
 pub fn list_files(
     archive: &Path,
+    comment: Option<&str>,
     files: impl IntoIterator<Item = crate::Result<FileInArchive>>,
-    list_options: ListOptions,
-) -> crate::Result<()> {
+    list_options: &ListOptions,
+) -> crate::Result<usize> {
     let out = &mut stdout().lock();
-    let _ = writeln!(out, "Archive: {}", EscapedPathDisplay::new(archive));
+    if !list_options.print0 {
+        let _ = writeln!(out, "Archive: {}", EscapedPathDisplay::new(archive));
+        if let Some(comment) = comment.filter(|comment| !comment.is_empty()) {
+            let _ = writeln!(out, "Comment: {comment}");
+        }
+    }
+
+    let mut listed = 0;
+    let window_end = list_options
+        .limit
+        .map_or(usize::MAX, |limit| list_options.offset.saturating_add(limit));
+    // The entries after the window are still read, to tell how many were left out
+    let files = files
+        .into_iter()
+        .filter(|file| match file {
+            Ok(file) => list_options.filter.is_match(&file.path),
+            Err(_) => true,
+        })
+        .filter(|file| {
+            let index = listed;
+            listed += 1;
+            file.is_err() || (list_options.offset..window_end).contains(&index)
+        });
 
     if list_options.tree {
-        let tree = files.into_iter().collect::<crate::Result<Tree>>()?;
-        tree.print(out);
+        let tree = files.collect::<crate::Result<Tree>>()?;
+        // Stdout is flushed at every line otherwise, which is most of the time of printing big
+        // trees
+        let mut out = BufWriter::new(&mut *out);
+        tree.print(&mut out);
+        let _ = out.flush();
+    } else if list_options.print0 {
+        for file in files {
+            let _ = out.write_all(&path_bytes(&file?.path));
+            let _ = out.write_all(b"\0");
+        }
+    } else if list_options.long {
+        let _ = writeln!(
+            out,
+            "{:<12} {:>10} {:>10} {:>6}  Path",
+            "Method", "Compressed", "Size", "Ratio"
+        );
+        let mut original_size = 0;
+        for file in files {
+            let file = file?;
+            original_size += file.size.unwrap_or(0);
+            print_long_entry(out, &file);
+        }
+        print_left_out(out, listed.saturating_sub(window_end));
+        // The stored size is the one of the whole archive, so it includes headers and every entry
+        // even when only some are listed
+        let stored_size = archive.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        let _ = writeln!(
+            out,
+            "Total: {} stored, {} original ({})",
+            Bytes::new(stored_size),
+            Bytes::new(original_size),
+            ratio(stored_size, original_size)
+        );
     } else {
         for file in files {
-            let FileInArchive { path, is_dir } = file?;
-            print_entry(out, EscapedPathDisplay::new(&path), is_dir);
+            print_file(out, &file?);
         }
     }
-    Ok(())
+
+    // The output of --print0 is only paths, for other programs
+    if !list_options.print0 && !list_options.long {
+        print_left_out(out, listed.saturating_sub(window_end));
+    }
+    Ok(listed)
+}
+
+/// Tell how many entries were left out after the window of `--limit`
+fn print_left_out(out: &mut impl Write, left_out: usize) {
+    if left_out > 0 {
+        let _ = writeln!(out, "... and {left_out} more");
+    }
+}
+
+/// Print an entry along with its compression method and sizes, unknown values are shown as "-"
+fn print_long_entry(out: &mut impl Write, file: &FileInArchive) {
+    let unknown = || "-".to_string();
+    let (method, compressed_size, size, ratio) = if file.is_dir {
+        (unknown(), unknown(), unknown(), unknown())
+    } else {
+        (
+            file.method.clone().unwrap_or_else(unknown),
+            file.compressed_size
+                .map_or_else(unknown, |size| Bytes::new(size).to_string()),
+            file.size.map_or_else(unknown, |size| Bytes::new(size).to_string()),
+            file.compressed_size
+                .zip(file.size)
+                .map_or_else(unknown, |(compressed_size, size)| ratio(compressed_size, size)),
+        )
+    };
+
+    let _ = write!(out, "{method:<12} {compressed_size:>10} {size:>10} {ratio:>6}  ");
+    print_file(out, file);
+}
+
+/// Print the path of `file`, telling which copy of it the entry is if the archive has several
+fn print_file(out: &mut impl Write, file: &FileInArchive) {
+    let path = EscapedPathDisplay::new(&file.path);
+    match file.duplicate {
+        Some((copy, copies)) => print_entry(out, format_args!("{path} (copy {copy} of {copies})"), file.kind()),
+        None => print_entry(out, path, file.kind()),
+    }
+}
+
+/// How big the compressed data is compared to the original, as a percentage
+fn ratio(compressed_size: u64, size: u64) -> String {
+    if size == 0 {
+        return "-".to_string();
+    }
+    format!("{:.0}%", compressed_size as f64 * 100.0 / size as f64)
+}
+
+/// The raw bytes of `path`, only converted to UTF-8 on platforms whose paths aren't bytes
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Cow::Borrowed(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    match path.to_string_lossy() {
+        Cow::Borrowed(path) => Cow::Borrowed(path.as_bytes()),
+        Cow::Owned(path) => Cow::Owned(path.into_bytes()),
+    }
 }
 
 /// Print an entry and highlight directories, either by coloring them
-/// if that's supported or by adding a trailing /
-fn print_entry(out: &mut impl Write, name: impl std::fmt::Display, is_dir: bool) {
+/// if that's supported or by adding a trailing /, and color symlinks,
+/// executables and archives like `ls` does
+fn print_entry(out: &mut impl Write, name: impl std::fmt::Display, kind: EntryKind) {
     use crate::utils::colors::*;
 
-    if is_dir {
-        // if colors are deactivated, print final / to mark directories
-        if BLUE.is_empty() {
-            let _ = writeln!(out, "{name}/");
-        // if in ACCESSIBLE mode, use colors but print final / in case colors
-        // aren't read out aloud with a screen reader or aren't printed on a
-        // braille reader
-        } else if is_running_in_accessible_mode() {
-            let _ = writeln!(out, "{}{}{}/{}", *BLUE, *STYLE_BOLD, name, *ALL_RESET);
-        } else {
-            let _ = writeln!(out, "{}{}{}{}", *BLUE, *STYLE_BOLD, name, *ALL_RESET);
+    let color = match kind {
+        EntryKind::File => None,
+        EntryKind::Directory => {
+            // if colors are deactivated, print final / to mark directories
+            if BLUE.is_empty() {
+                let _ = writeln!(out, "{name}/");
+            // if in ACCESSIBLE mode, use colors but print final / in case colors
+            // aren't read out aloud with a screen reader or aren't printed on a
+            // braille reader
+            } else if is_running_in_accessible_mode() {
+                let _ = writeln!(out, "{}{}{}/{}", *BLUE, *STYLE_BOLD, name, *ALL_RESET);
+            } else {
+                let _ = writeln!(out, "{}{}{}{}", *BLUE, *STYLE_BOLD, name, *ALL_RESET);
+            }
+            return;
+        }
+        EntryKind::Symlink => Some(*CYAN),
+        EntryKind::Executable => Some(*GREEN),
+        EntryKind::Archive => Some(*RED),
+    };
+
+    match color {
+        // The other colors are only noise for a screen reader
+        Some(color) if !color.is_empty() && !is_running_in_accessible_mode() => {
+            let _ = writeln!(out, "{color}{name}{}", *ALL_RESET);
+        }
+        _ => {
+            let _ = writeln!(out, "{name}");
         }
-    } else {
-        // not a dir -> just print the file name
-        let _ = writeln!(out, "{name}");
     }
 }
 
@@ -76,90 +302,139 @@ fn print_entry(out: &mut impl Write, name: impl std::fmt::Display, is_dir: bool)
 /// display them as a tree
 mod tree {
     use std::{
-        ffi::{OsStr, OsString},
+        collections::{HashMap, HashSet},
+        ffi::OsStr,
         io::Write,
-        path,
+        rc::Rc,
     };
 
-    use bstr::{ByteSlice, ByteVec};
-    use linked_hash_map::LinkedHashMap;
+    use bstr::BStr;
 
-    use super::FileInArchive;
+    use super::{EntryKind, FileInArchive};
     use crate::utils::{logger::warning, EscapedPathDisplay};
 
-    /// Directory tree
-    #[derive(Debug, Default)]
+    /// Directory tree, stored as a trie of path components.
+    ///
+    /// Archives can have millions of entries, so nodes only keep what's printed, and each name is
+    /// stored once, however many directories have an entry with it.
+    #[derive(Debug)]
     pub struct Tree {
-        file: Option<FileInArchive>,
-        children: LinkedHashMap<OsString, Tree>,
+        /// The root is the first node, it has no name and isn't printed
+        nodes: Vec<Node>,
+        /// Node of each child, by the node of its parent and its name
+        children: HashMap<(usize, Rc<OsStr>), usize>,
+        /// Every name, to share it among the nodes that have it
+        names: HashSet<Rc<OsStr>>,
+    }
+
+    #[derive(Debug)]
+    struct Node {
+        name: Rc<OsStr>,
+        /// What the entry inserted at this node is, if any, nodes that are only parents of
+        /// entries are directories
+        entry: Option<EntryKind>,
+        /// In the order they were inserted
+        children: Vec<usize>,
+    }
+
+    impl Default for Tree {
+        fn default() -> Self {
+            let root = Node {
+                name: Rc::from(OsStr::new("")),
+                entry: None,
+                children: Vec::new(),
+            };
+            Self {
+                nodes: vec![root],
+                children: HashMap::new(),
+                names: HashSet::new(),
+            }
+        }
     }
 
     impl Tree {
         /// Insert a file into the tree
         pub fn insert(&mut self, file: FileInArchive) {
-            self.insert_(file.clone(), file.path.iter());
-        }
-        /// Insert file by traversing the tree recursively
-        fn insert_(&mut self, file: FileInArchive, mut path: path::Iter) {
-            // Are there more components in the path? -> traverse tree further
-            if let Some(part) = path.next() {
-                // Either insert into an existing child node or create a new one
-                if let Some(t) = self.children.get_mut(part) {
-                    t.insert_(file, path)
-                } else {
-                    let mut child = Tree::default();
-                    child.insert_(file, path);
-                    self.children.insert(part.to_os_string(), child);
-                }
-            } else {
-                // `path` was empty -> we reached our destination and can insert
-                // `file`, assuming there is no file already there (which meant
-                // there were 2 files with the same name in the same directory
-                // which should be impossible in any sane file system)
-                match &self.file {
-                    None => self.file = Some(file),
-                    Some(file) => {
-                        warning(format!(
-                            "multiple files with the same name in a single directory ({})",
-                            EscapedPathDisplay::new(&file.path),
-                        ));
-                    }
+            let mut node = 0;
+            for part in file.path.iter() {
+                node = self.child(node, part);
+            }
+
+            // There are 2 files with the same name in the same directory, which should be
+            // impossible in any sane file system
+            if self.nodes[node].entry.is_some() {
+                warning(format!(
+                    "multiple files with the same name in a single directory ({})",
+                    EscapedPathDisplay::new(&file.path),
+                ));
+                return;
+            }
+            self.nodes[node].entry = Some(file.kind());
+        }
+
+        /// The child of `parent` called `name`, created if there's none
+        fn child(&mut self, parent: usize, name: &OsStr) -> usize {
+            let name = self.intern(name);
+            let next = self.nodes.len();
+            let child = *self.children.entry((parent, name.clone())).or_insert(next);
+            if child == next {
+                self.nodes.push(Node {
+                    name,
+                    entry: None,
+                    children: Vec::new(),
+                });
+                self.nodes[parent].children.push(child);
+            }
+            child
+        }
+
+        fn intern(&mut self, name: &OsStr) -> Rc<OsStr> {
+            match self.names.get(name) {
+                Some(name) => name.clone(),
+                None => {
+                    let name: Rc<OsStr> = Rc::from(name);
+                    self.names.insert(name.clone());
+                    name
                 }
             }
         }
 
         /// Print the file tree using Unicode line characters
         pub fn print(&self, out: &mut impl Write) {
-            for (i, (name, subtree)) in self.children.iter().enumerate() {
-                subtree.print_(out, name, "", i == self.children.len() - 1);
-            }
+            // The prefix of each line is the one of its parent, with one more part, so a single
+            // buffer is grown and shrunk along the way instead of building one per line
+            let mut prefix = String::new();
+            self.print_children(out, 0, &mut prefix);
         }
-        /// Print the tree by traversing it recursively
-        fn print_(&self, out: &mut impl Write, name: &OsStr, prefix: &str, last: bool) {
-            // If there are no further elements in the parent directory, add
-            // "└── " to the prefix, otherwise add "├── "
-            let final_part = match last {
-                true => draw::FINAL_LAST,
-                false => draw::FINAL_BRANCH,
-            };
+        /// Print the children of `node` by traversing the tree recursively
+        fn print_children(&self, out: &mut impl Write, node: usize, prefix: &mut String) {
+            let children = &self.nodes[node].children;
+            for (i, &child) in children.iter().enumerate() {
+                let last = i == children.len() - 1;
+                let Node { name, entry, .. } = &self.nodes[child];
 
-            print!("{prefix}{final_part}");
-            let is_dir = match self.file {
-                Some(FileInArchive { is_dir, .. }) => is_dir,
-                None => true,
-            };
-            super::print_entry(out, <Vec<u8> as ByteVec>::from_os_str_lossy(name).as_bstr(), is_dir);
-
-            // Construct prefix for children, adding either a line if this isn't
-            // the last entry in the parent dir or empty space if it is.
-            let mut prefix = prefix.to_owned();
-            prefix.push_str(match last {
-                true => draw::PREFIX_EMPTY,
-                false => draw::PREFIX_LINE,
-            });
-            // Recursively print all children
-            for (i, (name, subtree)) in self.children.iter().enumerate() {
-                subtree.print_(out, name, &prefix, i == self.children.len() - 1);
+                // If there are no further elements in the parent directory, add
+                // "└── " to the prefix, otherwise add "├── "
+                let final_part = match last {
+                    true => draw::FINAL_LAST,
+                    false => draw::FINAL_BRANCH,
+                };
+                let _ = write!(out, "{prefix}{final_part}");
+                super::print_entry(
+                    out,
+                    BStr::new(name.as_encoded_bytes()),
+                    entry.unwrap_or(EntryKind::Directory),
+                );
+
+                // The prefix of its children has either a line if this isn't the last entry in
+                // the parent dir or empty space if it is.
+                let parent_len = prefix.len();
+                prefix.push_str(match last {
+                    true => draw::PREFIX_EMPTY,
+                    false => draw::PREFIX_LINE,
+                });
+                self.print_children(out, child, prefix);
+                prefix.truncate(parent_len);
             }
         }
     }
@@ -174,6 +449,67 @@ mod tree {
         }
     }
 
+    #[cfg(test)]
+    mod tests {
+        use std::time::{Duration, Instant};
+
+        use super::*;
+
+        fn tree(paths: impl IntoIterator<Item = String>) -> Tree {
+            paths
+                .into_iter()
+                .map(|path| FileInArchive {
+                    is_dir: path.ends_with('/'),
+                    is_symlink: false,
+                    mode: None,
+                    path: path.into(),
+                    size: None,
+                    compressed_size: None,
+                    method: None,
+                    duplicate: None,
+                })
+                .collect()
+        }
+
+        /// The printed tree, without colors or the slashes marking directories
+        fn print(tree: &Tree) -> String {
+            let mut out = vec![];
+            tree.print(&mut out);
+            let out = String::from_utf8(out).unwrap();
+            let mut plain = String::new();
+            let mut chars = out.chars();
+            while let Some(c) = chars.next() {
+                if c == '\u{1b}' {
+                    chars.by_ref().find(|&c| c == 'm');
+                } else {
+                    plain.push(c);
+                }
+            }
+            plain.replace("/\n", "\n")
+        }
+
+        #[test]
+        fn test_tree_print() {
+            let paths = ["src/", "src/main.rs", "src/list/tree.rs", "README.md", "src/lib.rs"];
+            let tree = tree(paths.map(String::from));
+            assert_eq!(
+                print(&tree),
+                "├── src\n│  ├── main.rs\n│  ├── list\n│  │  └── tree.rs\n│  └── lib.rs\n└── README.md\n"
+            );
+        }
+
+        #[test]
+        fn test_big_tree() {
+            const ENTRIES: usize = 500_000;
+
+            let start = Instant::now();
+            let tree = tree((0..ENTRIES).map(|i| format!("root/dir{}/sub{}/file{i}", i % 100, i % 7)));
+            // Every entry, along with 'root', its 100 directories and their 7 subdirectories
+            assert_eq!(print(&tree).lines().count(), ENTRIES + 1 + 100 + 100 * 7);
+            assert!(start.elapsed() < Duration::from_secs(10), "{:?}", start.elapsed());
+        }
+    }
+
     /// Constants containing the visual parts of which the displayed tree
     /// is constructed.
     ///
@@ -15,29 +15,53 @@ use error::{Error, Result};
 use once_cell::sync::Lazy;
 use utils::{QuestionAction, QuestionPolicy};
 
-use crate::utils::logger::spawn_logger_thread;
-
-// Used in BufReader and BufWriter to perform less syscalls
-const BUFFER_CAPACITY: usize = 1024 * 32;
+use crate::utils::{
+    interrupt::{disarm_interrupt_handler, set_up_interrupt_handler},
+    logger::{print_error, spawn_logger_thread},
+};
 
 /// Current directory or empty directory
 static CURRENT_DIRECTORY: Lazy<PathBuf> = Lazy::new(|| env::current_dir().unwrap_or_default());
 
-/// The status code returned from `ouch` on error
+/// The status code returned from `ouch` on success
+pub const EXIT_SUCCESS: i32 = libc::EXIT_SUCCESS;
+
+/// The status code returned from `ouch` on usage errors, and other errors without a specific code
 pub const EXIT_FAILURE: i32 = libc::EXIT_FAILURE;
 
+/// The status code returned from `ouch` when reading or writing files fails
+pub const EXIT_IO: i32 = 2;
+
+/// The status code returned from `ouch` when an archive is corrupted or can't be read
+pub const EXIT_CORRUPT: i32 = 3;
+
+/// The status code returned from `ouch` when only some of the inputs were processed
+pub const EXIT_PARTIAL: i32 = 4;
+
+/// The status code returned from `ouch diff` when the archives are different
+pub const EXIT_DIFFERENT: i32 = 1;
+
+/// The status code returned from `ouch` when it's interrupted with Ctrl-C
+pub const EXIT_INTERRUPTED: i32 = 130;
+
 fn main() {
-    let handler = spawn_logger_thread();
+    let logger = spawn_logger_thread();
+    set_up_interrupt_handler();
     let result = run();
-    handler.shutdown_and_wait();
+    disarm_interrupt_handler();
+    logger.shutdown();
 
-    if let Err(err) = result {
-        eprintln!("{err}");
-        std::process::exit(EXIT_FAILURE);
+    match result {
+        Ok(EXIT_SUCCESS) => {}
+        Ok(code) => std::process::exit(code),
+        Err(err) => {
+            print_error(&err);
+            std::process::exit(err.exit_code());
+        }
     }
 }
 
-fn run() -> Result<()> {
+fn run() -> Result<i32> {
     let (args, skip_questions_positively, file_visibility_policy) = CliArgs::parse_and_validate_args()?;
     commands::run(args, skip_questions_positively, file_visibility_policy)
 }
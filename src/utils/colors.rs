@@ -2,14 +2,33 @@
 
 #![allow(dead_code)]
 
-use std::env;
+use std::{env, sync::OnceLock};
 
 use once_cell::sync::Lazy;
 
+use crate::cli::ColorMode;
+
+/// Set by `--color`, colors are only printed to terminals if it's not
+static COLOR_MODE: OnceLock<ColorMode> = OnceLock::new();
+
 static DISABLE_COLORED_TEXT: Lazy<bool> = Lazy::new(|| {
-    env::var_os("NO_COLOR").is_some() || atty::isnt(atty::Stream::Stdout) || atty::isnt(atty::Stream::Stderr)
+    let enabled = match COLOR_MODE.get().copied().unwrap_or_default() {
+        ColorMode::Auto => {
+            env::var_os("NO_COLOR").is_none() && atty::is(atty::Stream::Stdout) && atty::is(atty::Stream::Stderr)
+        }
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+    };
+    // JSON logs are read by programs, escape codes would only be noise in them
+    !enabled || super::logger::json_logs()
 });
 
+/// When to color the output, to be set before anything is printed.
+pub fn set_color_mode(mode: ColorMode) {
+    // Only fails if it was already set, which doesn't happen before this
+    let _ = COLOR_MODE.set(mode);
+}
+
 macro_rules! color {
     ($name:ident = $value:literal) => {
         #[cfg(target_family = "unix")]
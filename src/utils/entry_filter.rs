@@ -0,0 +1,167 @@
+//! Selection of archive entries through glob patterns, used by `--filter` and `--exclude`.
+
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use fs_err as fs;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+use crate::error::FinalError;
+
+/// Decides which entries of an archive are extracted or listed.
+///
+/// An entry is selected if its path matches any of the patterns, if there are no patterns,
+/// every entry is selected. Entries matching any of the exclude patterns are never selected,
+/// even if they match the patterns too. Entries picked one by one, with `--interactive`, restrict
+/// the selection further.
+#[derive(Debug, Clone, Default)]
+pub struct EntryFilter {
+    patterns: Option<GlobSet>,
+    excludes: Option<GlobSet>,
+    picked: Option<HashSet<PathBuf>>,
+}
+
+impl EntryFilter {
+    /// Build a filter out of glob patterns, a pattern without special characters matches only
+    /// the entry with that exact path.
+    ///
+    /// With `ignore_case`, patterns are compared with entry paths without regard to case, the
+    /// paths stored in the archive are left untouched.
+    pub fn new(patterns: &[String], ignore_case: bool) -> crate::Result<Self> {
+        Ok(Self {
+            patterns: build_glob_set(patterns, ignore_case)?,
+            excludes: None,
+            picked: None,
+        })
+    }
+
+    /// Also leave out the entries matching any of the `excludes` glob patterns.
+    pub fn exclude(self, excludes: &[String], ignore_case: bool) -> crate::Result<Self> {
+        Ok(Self {
+            excludes: build_glob_set(excludes, ignore_case)?,
+            ..self
+        })
+    }
+
+    /// Only select the entries at these exact paths, as stored in the archive, and the directories
+    /// containing them.
+    pub fn pick(self, paths: impl IntoIterator<Item = PathBuf>) -> Self {
+        let mut picked = HashSet::new();
+        for path in paths {
+            picked.extend(path.ancestors().skip(1).map(Path::to_path_buf));
+            picked.insert(path);
+        }
+        Self {
+            picked: Some(picked),
+            ..self
+        }
+    }
+
+    /// Read the patterns of the file at `path`, one per line, skipping empty lines and comments
+    /// starting with '#'.
+    pub fn read_patterns(path: &Path) -> crate::Result<Vec<String>> {
+        let patterns = fs::read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(String::from)
+            .collect();
+        Ok(patterns)
+    }
+
+    /// Whether `pattern` has any of the special characters of glob patterns, instead of only
+    /// matching the entry with that exact path.
+    pub fn is_glob(pattern: &str) -> bool {
+        pattern.contains(['*', '?', '[', '{'])
+    }
+
+    /// Check if the entry at `path` is selected by this filter.
+    pub fn is_match(&self, path: &Path) -> bool {
+        if self.picked.as_ref().is_some_and(|picked| !picked.contains(path)) {
+            return false;
+        }
+
+        // Entries of archives created with relative paths may start with "./"
+        let path = path.strip_prefix(".").unwrap_or(path);
+
+        if self.excludes.as_ref().is_some_and(|excludes| excludes.is_match(path)) {
+            return false;
+        }
+
+        match &self.patterns {
+            Some(patterns) => patterns.is_match(path),
+            None => true,
+        }
+    }
+}
+
+fn build_glob_set(patterns: &[String], ignore_case: bool) -> crate::Result<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+
+    let invalid_pattern = |pattern: &str, error: globset::Error| {
+        FinalError::with_title(format!("Invalid filter pattern '{pattern}'")).detail(error.kind().to_string())
+    };
+
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .case_insensitive(ignore_case)
+            .build()
+            .map_err(|error| invalid_pattern(pattern, error))?;
+        builder.add(glob);
+    }
+    let patterns = builder
+        .build()
+        .map_err(|error| invalid_pattern(&patterns.join(", "), error))?;
+
+    Ok(Some(patterns))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(patterns: &[&str], ignore_case: bool) -> EntryFilter {
+        let patterns: Vec<String> = patterns.iter().map(|pattern| pattern.to_string()).collect();
+        EntryFilter::new(&patterns, ignore_case).unwrap()
+    }
+
+    #[test]
+    fn test_entry_filter() {
+        let no_patterns = filter(&[], false);
+        assert!(no_patterns.is_match(Path::new("anything")));
+
+        let globs = filter(&["*.md", "src/main.rs"], false);
+        assert!(globs.is_match(Path::new("readme.md")));
+        assert!(globs.is_match(Path::new("docs/readme.md")));
+        assert!(globs.is_match(Path::new("./src/main.rs")));
+        assert!(!globs.is_match(Path::new("src/lib.rs")));
+        assert!(!globs.is_match(Path::new("README.MD")));
+
+        let ignore_case = filter(&["README.md"], true);
+        assert!(ignore_case.is_match(Path::new("readme.md")));
+        assert!(ignore_case.is_match(Path::new("ReadMe.MD")));
+        assert!(!ignore_case.is_match(Path::new("readme.txt")));
+
+        assert!(EntryFilter::new(&["a{b".to_string()], false).is_err());
+
+        // Excludes win over the patterns
+        let excludes = filter(&["src/*"], false).exclude(&["*.so".to_string()], false).unwrap();
+        assert!(excludes.is_match(Path::new("src/main.rs")));
+        assert!(!excludes.is_match(Path::new("src/lib.so")));
+        assert!(!excludes.is_match(Path::new("docs/readme.md")));
+
+        // Picked entries are selected along with their directories, if the patterns match them
+        let picked = filter(&[], false).pick([PathBuf::from("src/bin/main.rs"), PathBuf::from("README.md")]);
+        assert!(picked.is_match(Path::new("src/bin/main.rs")));
+        assert!(picked.is_match(Path::new("src/bin/")));
+        assert!(picked.is_match(Path::new("src")));
+        assert!(!picked.is_match(Path::new("src/lib.rs")));
+        let picked = filter(&["*.rs"], false).pick([PathBuf::from("src/main.rs"), PathBuf::from("README.md")]);
+        assert!(!picked.is_match(Path::new("README.md")));
+    }
+}
@@ -0,0 +1,107 @@
+//! Running a command on each extracted file, used by `--exec`.
+
+use std::{
+    path::Path,
+    process::Command,
+    sync::{Mutex, PoisonError},
+};
+
+use fs_err as fs;
+
+use crate::{
+    error::FinalError,
+    utils::{logger::warning, EscapedPathDisplay},
+};
+
+/// Archives are decompressed in parallel, but the commands should run one at a time
+static EXEC_LOCK: Mutex<()> = Mutex::new(());
+
+/// A command that is run once for every extracted file, `{}` is replaced by the file path.
+///
+/// The path is never pasted into the command line, it's handed to the shell as a positional
+/// parameter instead, so it doesn't need to be escaped.
+#[derive(Debug, Clone)]
+pub struct ExecHook {
+    command: String,
+    ignore_errors: bool,
+}
+
+impl ExecHook {
+    /// If `ignore_errors` is set, failing commands only cause a warning, otherwise they stop
+    /// the decompression with an error.
+    pub fn new(command: String, ignore_errors: bool) -> Self {
+        Self { command, ignore_errors }
+    }
+
+    /// Run the command for every file at `path`, descending into directories.
+    pub fn run_on_tree(&self, path: &Path) -> crate::Result<()> {
+        let _lock = EXEC_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+        self.run_on_tree_locked(path)
+    }
+
+    fn run_on_tree_locked(&self, path: &Path) -> crate::Result<()> {
+        if !fs::symlink_metadata(path)?.is_dir() {
+            return self.run(path);
+        }
+
+        let mut children = fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        children.sort();
+
+        children.iter().try_for_each(|child| self.run_on_tree_locked(child))
+    }
+
+    fn run(&self, path: &Path) -> crate::Result<()> {
+        let status = self.build_command(path).status().map_err(|err| {
+            FinalError::with_title(format!("Failed to run the command '{}'", self.command)).detail(err.to_string())
+        })?;
+
+        if status.success() {
+            return Ok(());
+        }
+
+        let path = EscapedPathDisplay::new(path);
+        if self.ignore_errors {
+            warning(format!(
+                "The command '{}' failed for '{path}' ({status}).",
+                self.command
+            ));
+            Ok(())
+        } else {
+            let error = FinalError::with_title(format!("The command '{}' failed for '{path}'", self.command))
+                .detail(format!("Finished with {status}"))
+                .hint("Use '--exec-ignore-errors' to keep going when the command fails");
+            Err(error.into())
+        }
+    }
+
+    #[cfg(unix)]
+    fn build_command(&self, path: &Path) -> Command {
+        // The path is available to the script as "$1"
+        let script = if self.command.contains("{}") {
+            self.command.replace("{}", r#""$1""#)
+        } else {
+            format!(r#"{} "$1""#, self.command)
+        };
+
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script).arg("ouch").arg(path);
+        command
+    }
+
+    #[cfg(not(unix))]
+    fn build_command(&self, path: &Path) -> Command {
+        // Windows paths can't contain double quotes, so quoting them is enough
+        let path = format!(r#""{}""#, path.display());
+        let script = if self.command.contains("{}") {
+            self.command.replace("{}", &path)
+        } else {
+            format!("{} {path}", self.command)
+        };
+
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(script);
+        command
+    }
+}
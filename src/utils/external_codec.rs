@@ -0,0 +1,315 @@
+//! Formats without built-in support, compressed and decompressed by piping them through commands
+//! given with `--codec-compress-cmd` and `--codec-cmd`.
+//!
+//! The streams are piped through the commands as they're read or written, the data is never
+//! buffered, and what the commands print to stderr is logged as warnings.
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, ExitStatus, Stdio},
+    sync::{Mutex, OnceLock, PoisonError},
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    error::FinalError,
+    extension::{is_builtin_extension, CompressionFormat},
+    utils::logger::warning,
+};
+
+/// A format registered for an extension, like 'xyz', with the commands that handle it.
+#[derive(Debug)]
+pub struct ExternalCodec {
+    extension: String,
+    decompress_command: Option<String>,
+    compress_command: Option<String>,
+    /// The format of this codec, in a slice like the formats of the other extensions
+    pub formats: [CompressionFormat; 1],
+}
+
+static CODECS: OnceLock<Vec<ExternalCodec>> = OnceLock::new();
+
+/// Failures of the encoders, which are only known once they're dropped, see [`check_encoders`].
+static ENCODER_FAILURES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Register the codecs of the commands given as 'EXT=COMMAND', with `--codec-cmd` for the ones
+/// that decompress and `--codec-compress-cmd` for the ones that compress.
+pub fn set_external_codecs(decompress: &[String], compress: &[String]) -> crate::Result<()> {
+    let mut codecs: Vec<ExternalCodec> = vec![];
+    for (spec, is_compress) in decompress
+        .iter()
+        .map(|spec| (spec, false))
+        .chain(compress.iter().map(|spec| (spec, true)))
+    {
+        let (extension, command) = parse_spec(spec)?;
+        let index = match codecs.iter().position(|codec| codec.extension == extension) {
+            Some(index) => index,
+            None => {
+                codecs.push(ExternalCodec {
+                    extension: extension.to_string(),
+                    decompress_command: None,
+                    compress_command: None,
+                    formats: [CompressionFormat::External(codecs.len())],
+                });
+                codecs.len() - 1
+            }
+        };
+        let codec = &mut codecs[index];
+        let slot = if is_compress {
+            &mut codec.compress_command
+        } else {
+            &mut codec.decompress_command
+        };
+        *slot = Some(command.to_string());
+    }
+    // Only fails if they were already registered, which doesn't happen before this
+    let _ = CODECS.set(codecs);
+    Ok(())
+}
+
+fn parse_spec(spec: &str) -> crate::Result<(&str, &str)> {
+    let invalid_spec = |detail: &str| {
+        FinalError::with_title(format!("Invalid codec command '{spec}'"))
+            .detail(detail.to_string())
+            .hint("Give the extension and the command like 'xyz=mycodec -d'")
+    };
+
+    let (extension, command) = spec
+        .split_once('=')
+        .ok_or_else(|| invalid_spec("There's no '=' between the extension and the command"))?;
+    let extension = extension.strip_prefix('.').unwrap_or(extension);
+    if extension.is_empty() || extension.contains(['.', '/', '\\']) {
+        return Err(invalid_spec("The extension must be a single one, like 'xyz'").into());
+    }
+    if is_builtin_extension(extension) {
+        return Err(invalid_spec("Ouch already supports this extension").into());
+    }
+    if command.trim().is_empty() {
+        return Err(invalid_spec("The command is empty").into());
+    }
+    Ok((extension, command))
+}
+
+/// The codec registered for `extension`, if any.
+pub fn find(extension: &[u8]) -> Option<&'static ExternalCodec> {
+    CODECS
+        .get()?
+        .iter()
+        .find(|codec| codec.extension.as_bytes() == extension)
+}
+
+fn codec(index: usize) -> &'static ExternalCodec {
+    &CODECS.get().expect("external formats are only created once registered")[index]
+}
+
+fn missing_command_error(codec: &ExternalCodec, flag: &str, action: &str) -> crate::Error {
+    let extension = &codec.extension;
+    FinalError::with_title(format!("Cannot {action} '.{extension}' files."))
+        .detail(format!("No command was given to {action} them"))
+        .hint(format!("Give one with '{flag} {extension}=COMMAND'"))
+        .into()
+}
+
+/// Reads the output of the decompress command of a codec, which the input is piped to.
+pub struct ExternalDecoder {
+    command: &'static str,
+    child: Child,
+    stdout: ChildStdout,
+    /// The threads feeding the input to the command and logging its stderr, until it finished
+    threads: Option<(JoinHandle<io::Result<u64>>, JoinHandle<()>)>,
+}
+
+impl ExternalDecoder {
+    pub fn new(index: usize, mut input: Box<dyn Read + Send>) -> crate::Result<Self> {
+        let codec = codec(index);
+        let command = codec
+            .decompress_command
+            .as_deref()
+            .ok_or_else(|| missing_command_error(codec, "--codec-cmd", "decompress"))?;
+
+        let mut child = spawn(command)?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        // The input is closed once it's all written, so the command sees its end
+        let feeder = thread::spawn(move || io::copy(&mut input, &mut stdin));
+        let logger = log_stderr(command, &mut child);
+
+        Ok(Self {
+            command,
+            child,
+            stdout,
+            threads: Some((feeder, logger)),
+        })
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let Some((feeder, logger)) = self.threads.take() else {
+            return Ok(());
+        };
+        let fed = feeder.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        let status = self.child.wait()?;
+        let _ = logger.join();
+
+        match fed {
+            // The command may stop reading once it has all it needs
+            Err(err) if err.kind() != io::ErrorKind::BrokenPipe => Err(err),
+            _ => check_status(self.command, status),
+        }
+    }
+}
+
+impl Read for ExternalDecoder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.stdout.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            self.finish()?;
+        }
+        Ok(read)
+    }
+}
+
+impl Drop for ExternalDecoder {
+    fn drop(&mut self) {
+        // Dropped before reading all of the output, the rest isn't needed
+        if self.threads.is_some() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/// Pipes what's written to it through the compress command of a codec, into `output`.
+///
+/// Like the other encoders, the stream is finished once it's dropped, and its failures are
+/// reported by [`check_encoders`] afterwards.
+pub struct ExternalEncoder {
+    command: &'static str,
+    child: Child,
+    stdin: Option<ChildStdin>,
+    /// The threads copying the output of the command and logging its stderr
+    threads: Option<(JoinHandle<io::Result<()>>, JoinHandle<()>)>,
+}
+
+impl ExternalEncoder {
+    pub fn new(index: usize, mut output: Box<dyn Send + Write>) -> crate::Result<Self> {
+        let codec = codec(index);
+        let command = codec
+            .compress_command
+            .as_deref()
+            .ok_or_else(|| missing_command_error(codec, "--codec-compress-cmd", "compress"))?;
+
+        let mut child = spawn(command)?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let mut stdout = child.stdout.take().expect("stdout is piped");
+        let copier = thread::spawn(move || {
+            io::copy(&mut stdout, &mut output)?;
+            output.flush()
+        });
+        let logger = log_stderr(command, &mut child);
+
+        Ok(Self {
+            command,
+            child,
+            stdin: Some(stdin),
+            threads: Some((copier, logger)),
+        })
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        drop(self.stdin.take());
+        let Some((copier, logger)) = self.threads.take() else {
+            return Ok(());
+        };
+        let copied = copier.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        let status = self.child.wait()?;
+        let _ = logger.join();
+
+        check_status(self.command, status)?;
+        copied
+    }
+
+    fn stdin(&mut self) -> &mut ChildStdin {
+        self.stdin.as_mut().expect("stdin is only closed when dropped")
+    }
+}
+
+impl Write for ExternalEncoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdin().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdin().flush()
+    }
+}
+
+impl Drop for ExternalEncoder {
+    fn drop(&mut self) {
+        if let Err(err) = self.finish() {
+            ENCODER_FAILURES
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(err.to_string());
+        }
+    }
+}
+
+/// Fails if any of the [`ExternalEncoder`]s dropped so far failed, to be called once the
+/// encoders were dropped, which finishes their streams.
+pub fn check_encoders() -> crate::Result<()> {
+    let failures = std::mem::take(&mut *ENCODER_FAILURES.lock().unwrap_or_else(PoisonError::into_inner));
+    if failures.is_empty() {
+        return Ok(());
+    }
+
+    let mut error = FinalError::with_title("Failed to compress through an external command");
+    for failure in failures {
+        error = error.detail(failure);
+    }
+    Err(error.into())
+}
+
+fn spawn(command: &str) -> crate::Result<Child> {
+    shell_command(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| {
+            FinalError::with_title(format!("Failed to run the command '{command}'"))
+                .detail(err.to_string())
+                .into()
+        })
+}
+
+#[cfg(unix)]
+fn shell_command(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(not(unix))]
+fn shell_command(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+/// Log every line that `command` prints to stderr as a warning, until it exits.
+fn log_stderr(command: &'static str, child: &mut Child) -> JoinHandle<()> {
+    let stderr = child.stderr.take().expect("stderr is piped");
+    thread::spawn(move || {
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            warning(format!("{command}: {line}"));
+        }
+    })
+}
+
+fn check_status(command: &str, status: ExitStatus) -> io::Result<()> {
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("The command '{command}' failed ({status})")))
+    }
+}
@@ -1,6 +1,7 @@
 use std::path::Path;
 
 /// Determines which files should be read or ignored during directory walking
+#[derive(Clone, Copy)]
 pub struct FileVisibilityPolicy {
     /// Enables reading .ignore files.
     ///
@@ -19,6 +20,11 @@ pub struct FileVisibilityPolicy {
 
     /// Enables reading `.git/info/exclude` files.
     pub read_git_exclude: bool,
+
+    /// How deep directories are descended into, 0 being the walked path itself.
+    ///
+    /// Unlimited by default.
+    pub max_depth: Option<usize>,
 }
 
 impl Default for FileVisibilityPolicy {
@@ -28,6 +34,7 @@ impl Default for FileVisibilityPolicy {
             read_hidden: true,
             read_git_ignore: false,
             read_git_exclude: false,
+            max_depth: None,
         }
     }
 }
@@ -67,6 +74,12 @@ impl FileVisibilityPolicy {
         Self { read_hidden, ..self }
     }
 
+    #[must_use]
+    /// Limits how deep directories are descended into, the deeper ones are only walked themselves.
+    pub fn max_depth(self, max_depth: Option<usize>) -> Self {
+        Self { max_depth, ..self }
+    }
+
     /// Walks through a directory using [`ignore::Walk`]
     pub fn build_walker(&self, path: impl AsRef<Path>) -> ignore::Walk {
         ignore::WalkBuilder::new(path)
@@ -74,6 +87,7 @@ impl FileVisibilityPolicy {
             .git_ignore(self.read_git_ignore)
             .ignore(self.read_ignore)
             .hidden(self.read_hidden)
+            .max_depth(self.max_depth)
             .build()
     }
 }
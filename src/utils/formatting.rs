@@ -1,4 +1,9 @@
-use std::{borrow::Cow, cmp, fmt::Display, path::Path};
+use std::{
+    borrow::Cow,
+    cmp,
+    fmt::{Display, Write},
+    path::Path,
+};
 
 use crate::CURRENT_DIRECTORY;
 
@@ -87,6 +92,27 @@ pub fn nice_directory_display(path: &Path) -> Cow<str> {
     }
 }
 
+/// Quote and escape `text` as a JSON string, for the machine readable outputs.
+pub fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
 /// Struct useful to printing bytes as kB, MB, GB, etc.
 pub struct Bytes(f64);
 
@@ -163,4 +189,11 @@ mod tests {
         assert_eq!("999.90 GiB", format_bytes(999900000000));
         assert_eq!("1.00 TiB", format_bytes(999990000000));
     }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("dir/file.txt"), r#""dir/file.txt""#);
+        assert_eq!(json_string(r#"say "hi"\"#), r#""say \"hi\"\\""#);
+        assert_eq!(json_string("a\nb\u{1}"), r#""a\nb\u0001""#);
+    }
 }
@@ -0,0 +1,238 @@
+//! Framed streams, written by `ouch compress --framed` to stdout and read back by
+//! `ouch decompress --framed`, so that the receiver of a stream piped over a network can tell
+//! when it was cut short.
+//!
+//! The stream starts with [`MAGIC`], followed by chunks of the compressed data, each prefixed by
+//! its length as a little-endian u32. A chunk of length 0 ends the stream, followed by the total
+//! length of the data as a little-endian u64. A stream missing any of it is truncated.
+
+use std::{
+    io::{self, Read, Write},
+    sync::{Mutex, PoisonError},
+};
+
+use crate::error::FinalError;
+
+/// First bytes of a framed stream
+pub const MAGIC: &[u8; 8] = b"ouchfrm1";
+
+/// Most data held back before it's written as a chunk, unless the stream is flushed before
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Failures of the [`FramedWriter`]s, which are only known once they're dropped, see
+/// [`check_framed_writers`].
+static WRITER_FAILURES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Writes what's written to it as a framed stream into `inner`.
+///
+/// Like the encoders, the stream is ended once it's dropped, and its failures are reported by
+/// [`check_framed_writers`] afterwards.
+pub struct FramedWriter<W: Write> {
+    inner: W,
+    chunk: Vec<u8>,
+    /// Length of the data written in chunks so far
+    written: u64,
+}
+
+impl<W: Write> FramedWriter<W> {
+    pub fn new(mut inner: W) -> io::Result<Self> {
+        inner.write_all(MAGIC)?;
+        Ok(Self {
+            inner,
+            chunk: Vec::with_capacity(CHUNK_SIZE),
+            written: 0,
+        })
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        let length = u32::try_from(self.chunk.len()).expect("chunks are smaller than 4 GiB");
+        self.inner.write_all(&length.to_le_bytes())?;
+        self.inner.write_all(&self.chunk)?;
+        self.written += self.chunk.len() as u64;
+        self.chunk.clear();
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.write_chunk()?;
+        self.inner.write_all(&0u32.to_le_bytes())?;
+        self.inner.write_all(&self.written.to_le_bytes())?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for FramedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let length = buf.len().min(CHUNK_SIZE - self.chunk.len());
+        self.chunk.extend_from_slice(&buf[..length]);
+        if self.chunk.len() == CHUNK_SIZE {
+            self.write_chunk()?;
+        }
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_chunk()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for FramedWriter<W> {
+    fn drop(&mut self) {
+        if let Err(err) = self.finish() {
+            WRITER_FAILURES
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(err.to_string());
+        }
+    }
+}
+
+/// Fails if any of the [`FramedWriter`]s dropped so far failed to end their stream.
+pub fn check_framed_writers() -> crate::Result<()> {
+    let failures = std::mem::take(&mut *WRITER_FAILURES.lock().unwrap_or_else(PoisonError::into_inner));
+    if failures.is_empty() {
+        return Ok(());
+    }
+
+    let mut error = FinalError::with_title("Failed to end the framed stream");
+    for failure in failures {
+        error = error.detail(failure);
+    }
+    Err(error.into())
+}
+
+/// Reads the data of the framed stream read from `inner`, failing if it's truncated.
+pub struct FramedReader<R: Read> {
+    inner: R,
+    /// Whether `MAGIC` was read
+    started: bool,
+    /// What's left to read of the current chunk
+    remaining: u32,
+    /// Length of the data read so far
+    read: u64,
+    ended: bool,
+}
+
+impl<R: Read> FramedReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            started: false,
+            remaining: 0,
+            read: 0,
+            ended: false,
+        }
+    }
+
+    /// Read `buf` whole, failing as a truncated stream if it ends before.
+    fn read_header(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.read_exact(buf).map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => truncated_error(),
+            _ => err,
+        })
+    }
+
+    /// Read the header of the next chunk, or the end of the stream.
+    fn next_chunk(&mut self) -> io::Result<()> {
+        if !self.started {
+            let mut magic = [0; MAGIC.len()];
+            self.read_header(&mut magic)?;
+            if &magic != MAGIC {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a framed stream, it wasn't compressed with '--framed'",
+                ));
+            }
+            self.started = true;
+        }
+
+        let mut length = [0; 4];
+        self.read_header(&mut length)?;
+        self.remaining = u32::from_le_bytes(length);
+        if self.remaining == 0 {
+            let mut total = [0; 8];
+            self.read_header(&mut total)?;
+            let total = u64::from_le_bytes(total);
+            if total != self.read {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "the framed stream has {} bytes of data, but its end says {total}",
+                        self.read
+                    ),
+                ));
+            }
+            self.ended = true;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for FramedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.ended {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            self.next_chunk()?;
+            if self.ended {
+                return Ok(0);
+            }
+        }
+
+        let length = buf.len().min(self.remaining as usize);
+        let read = self.inner.read(&mut buf[..length])?;
+        if read == 0 {
+            return Err(truncated_error());
+        }
+        self.remaining -= read as u32;
+        self.read += read as u64;
+        Ok(read)
+    }
+}
+
+fn truncated_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "the framed stream ends before its end marker, it was truncated",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framed(data: &[u8]) -> Vec<u8> {
+        let mut output = vec![];
+        let mut writer = FramedWriter::new(&mut output).unwrap();
+        // Written in pieces, to get several chunks
+        for piece in data.chunks(CHUNK_SIZE / 3 + 1) {
+            writer.write_all(piece).unwrap();
+        }
+        drop(writer);
+        output
+    }
+
+    #[test]
+    fn round_trip_and_truncation() {
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE as u32).map(|i| i as u8).collect();
+        let stream = framed(&data);
+
+        let mut read = vec![];
+        FramedReader::new(stream.as_slice()).read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+
+        // Cut anywhere, even right before the end marker or the total length, it's truncated
+        for cut in [0, 5, 100, CHUNK_SIZE, stream.len() - 12, stream.len() - 1] {
+            let err = FramedReader::new(&stream[..cut]).read_to_end(&mut vec![]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "cut at {cut}");
+        }
+
+        let err = FramedReader::new(&data[..100]).read_to_end(&mut vec![]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,20 +1,98 @@
 //! Filesystem utility functions.
 
 use std::{
-    env,
-    io::Read,
+    borrow::Cow,
+    io::{self, Read},
     path::{Path, PathBuf},
 };
 
+use filetime_creation::{set_file_mtime, FileTime};
 use fs_err as fs;
 
 use super::user_wants_to_overwrite;
 use crate::{
+    error::FinalError,
     extension::Extension,
-    utils::{logger::info_accessible, EscapedPathDisplay},
+    utils::{
+        colors::RED,
+        logger::{info_accessible, warning},
+        EscapedPathDisplay,
+    },
     QuestionPolicy,
 };
 
+/// Length from which Windows only accepts paths in their extended-length form, 260 characters for
+/// files, but less for directories, which must leave room for the name of a file.
+#[cfg(windows)]
+const MAX_PATH: usize = 248;
+
+/// `path` in the extended-length form of Windows, absolute and with the `\\?\` prefix, if it's
+/// too long to be used as it is, like the deep paths of `node_modules` folders.
+///
+/// Shorter paths are left as they were given, which is how they're shown to the user, and other
+/// systems don't have this limit.
+#[cfg(windows)]
+pub fn long_path(path: &Path) -> Cow<'_, Path> {
+    use std::{
+        ffi::OsString,
+        path::{Component, Prefix},
+    };
+
+    if path.as_os_str().len() < MAX_PATH && path.is_absolute() {
+        return Cow::Borrowed(path);
+    }
+    // Also turns the '/' into '\' and removes the '.' and '..', which the prefix doesn't allow
+    let Ok(absolute) = std::path::absolute(path) else {
+        return Cow::Borrowed(path);
+    };
+    if absolute.as_os_str().len() < MAX_PATH {
+        return Cow::Borrowed(path);
+    }
+
+    let mut components = absolute.components();
+    let Some(Component::Prefix(prefix)) = components.next() else {
+        return Cow::Borrowed(path);
+    };
+    let mut extended = OsString::new();
+    match prefix.kind() {
+        Prefix::Disk(_) => {
+            extended.push(r"\\?\");
+            extended.push(&absolute);
+        }
+        // Like '\\server\share\dir', which becomes '\\?\UNC\server\share\dir'
+        Prefix::UNC(server, share) => {
+            extended.push(r"\\?\UNC\");
+            extended.push(server);
+            extended.push(r"\");
+            extended.push(share);
+            for component in components.filter(|component| matches!(component, Component::Normal(_))) {
+                extended.push(r"\");
+                extended.push(component);
+            }
+        }
+        // Already in the extended-length form, or a device path
+        _ => return Cow::Borrowed(path),
+    }
+    Cow::Owned(extended.into())
+}
+
+/// `path` in the extended-length form of Windows if it's too long to be used as it is, which
+/// other systems don't need.
+#[cfg(not(windows))]
+pub fn long_path(path: &Path) -> Cow<'_, Path> {
+    Cow::Borrowed(path)
+}
+
+/// Like `fs::create_dir_all`, but for paths of any length, see [`long_path`].
+pub fn create_dir_all(path: &Path) -> io::Result<()> {
+    fs::create_dir_all(long_path(path))
+}
+
+/// Set the modification time of the file at `path`, of any length, see [`long_path`].
+pub fn set_mtime(path: &Path, mtime: FileTime) -> io::Result<()> {
+    set_file_mtime(long_path(path), mtime)
+}
+
 /// Remove `path` asking the user to overwrite if necessary.
 ///
 /// * `Ok(true)` means the path is clear,
@@ -30,6 +108,25 @@ pub fn clear_path(path: &Path, question_policy: QuestionPolicy) -> crate::Result
     Ok(true)
 }
 
+/// First path that doesn't exist out of `path` with "_1", "_2"... appended to its stem.
+pub fn free_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_os_string();
+    let extension = path.extension();
+
+    (1..)
+        .map(|number| {
+            let mut file_name = stem.clone();
+            file_name.push(format!("_{number}"));
+            if let Some(extension) = extension {
+                file_name.push(".");
+                file_name.push(extension);
+            }
+            path.with_file_name(file_name)
+        })
+        .find(|path| !path.exists())
+        .expect("some number is free")
+}
+
 pub fn remove_file_or_dir(path: &Path) -> crate::Result<()> {
     if path.is_dir() {
         fs::remove_dir_all(path)?;
@@ -39,6 +136,137 @@ pub fn remove_file_or_dir(path: &Path) -> crate::Result<()> {
     Ok(())
 }
 
+/// Move the file or directory at `from` to `to`, replacing the file at `to` if there's one.
+///
+/// It's renamed when both are on the same filesystem, which is atomic, otherwise it's copied,
+/// synced to the disk, and only then removed from `from`.
+pub fn move_path(from: &Path, to: &Path) -> crate::Result<()> {
+    match fs::rename(long_path(from), long_path(to)) {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            copy_and_sync(from, to)?;
+            remove_file_or_dir(from)
+        }
+        result => Ok(result?),
+    }
+}
+
+/// Copy the tree at `from` to `to`, with its permissions and modification times, syncing every
+/// file to the disk.
+fn copy_and_sync(from: &Path, to: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(from)?;
+
+    if metadata.is_dir() {
+        fs::create_dir(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_and_sync(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        fs::set_permissions(to, metadata.permissions())?;
+    } else if metadata.is_symlink() {
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(fs::read_link(from)?, to)?;
+        #[cfg(not(unix))]
+        fs::copy(from, to)?;
+    } else {
+        fs::copy(from, to)?;
+        fs::File::open(to)?.sync_all()?;
+    }
+
+    if !metadata.is_symlink() {
+        set_mtime(to, FileTime::from_last_modification_time(&metadata))?;
+    }
+    Ok(())
+}
+
+/// Copy up to `len` bytes from the position of `from` to the position of `to` inside of the
+/// kernel, moving both positions, without going through a buffer of ours. Filesystems like Btrfs
+/// and XFS share the blocks between both files instead of copying them, like a reflink does.
+///
+/// Returns how much was copied, which is less than `len` if the kernel can't copy between these
+/// files, like across filesystems on older kernels, or on other systems than Linux, in which case
+/// the rest is left to be copied through a buffer.
+pub fn copy_file_range(from: &std::fs::File, to: &std::fs::File, len: u64) -> u64 {
+    #[cfg(target_os = "linux")]
+    {
+        use std::{os::fd::AsRawFd, ptr};
+
+        let mut copied = 0;
+        while copied < len {
+            // Bigger copies are refused on some kernels
+            let chunk = (len - copied).min(1 << 30) as usize;
+            // SAFETY: both file descriptors stay open during the call, and the null offsets make
+            // it use and move the positions of the files
+            let result = unsafe {
+                libc::copy_file_range(
+                    from.as_raw_fd(),
+                    ptr::null_mut(),
+                    to.as_raw_fd(),
+                    ptr::null_mut(),
+                    chunk,
+                    0,
+                )
+            };
+            // Errors, like unsupported files, are met again by the buffered copy if they're real
+            if result <= 0 {
+                break;
+            }
+            copied += result as u64;
+        }
+        copied
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (from, to, len);
+        0
+    }
+}
+
+/// Remove the incomplete output at `path`, left by a failed or interrupted command.
+///
+/// If that fails, an alert pointing out that the file is possibly corrupted is printed, and
+/// `false` is returned.
+pub fn remove_incomplete_output(path: &Path) -> bool {
+    if remove_file_or_dir(path).is_ok() {
+        return true;
+    }
+
+    eprintln!("{red}FATAL ERROR:\n", red = *RED);
+    eprintln!("  Ouch failed to delete the file '{}'.", EscapedPathDisplay::new(path));
+    eprintln!("  Please delete it manually.");
+    eprintln!("  This file is corrupted if compression didn't finished.");
+    false
+}
+
+/// Read the password stored in the file at `path`, its first line without surrounding whitespace.
+///
+/// On Unix, warns if other users can read the file.
+pub fn read_password_file(path: &Path) -> crate::Result<String> {
+    let contents = fs::read_to_string(path)?;
+    let password = contents.lines().next().unwrap_or_default().trim();
+    if password.is_empty() {
+        let error = FinalError::with_title(format!(
+            "Failed to read a password from '{}'.",
+            EscapedPathDisplay::new(path)
+        ))
+        .detail("Its first line is empty");
+        return Err(error.into());
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        if fs::metadata(path)?.permissions().mode() & 0o004 != 0 {
+            warning(format!(
+                "The password file '{}' can be read by any user, consider running 'chmod 600' on it.",
+                EscapedPathDisplay::new(path)
+            ));
+        }
+    }
+
+    Ok(password.to_string())
+}
+
 /// Creates a directory at the path, if there is nothing there.
 pub fn create_dir_if_non_existent(path: &Path) -> crate::Result<()> {
     if !path.exists() {
@@ -50,22 +278,31 @@ pub fn create_dir_if_non_existent(path: &Path) -> crate::Result<()> {
     Ok(())
 }
 
-/// Returns current directory, but before change the process' directory to the
-/// one that contains the file pointed to by `filename`.
-pub fn cd_into_same_dir_as(filename: &Path) -> crate::Result<PathBuf> {
-    let previous_location = env::current_dir()?;
+/// Length of the head of a file that [`infer_extension`] looks at, the tar magic is the furthest
+pub const MAGIC_HEAD_LEN: usize = 270;
+
+/// Try to detect the file extension by looking for known magic strings in the head of the file
+pub fn try_infer_extension(path: &Path) -> Option<Extension> {
+    let buf = {
+        let mut buf = [0; MAGIC_HEAD_LEN];
 
-    let parent = filename.parent().ok_or(crate::Error::CompressingRootFolder)?;
-    env::set_current_dir(parent)?;
+        // Error cause will be ignored, so use std::fs instead of fs_err
+        let result = std::fs::File::open(path).map(|mut file| file.read(&mut buf));
 
-    Ok(previous_location)
+        // In case of file open or read failure, could not infer a extension
+        if result.is_err() {
+            return None;
+        }
+        buf
+    };
+    infer_extension(&buf)
 }
 
-/// Try to detect the file extension by looking for known magic strings
+/// Detect the format of the data starting with `buf`, by looking for known magic strings
 /// Source: <https://en.wikipedia.org/wiki/List_of_file_signatures>
-pub fn try_infer_extension(path: &Path) -> Option<Extension> {
+pub fn infer_extension(buf: &[u8]) -> Option<Extension> {
     fn is_zip(buf: &[u8]) -> bool {
-        buf.len() >= 3
+        buf.len() >= 4
             && buf[..=1] == [0x50, 0x4B]
             && (buf[2..=3] == [0x3, 0x4] || buf[2..=3] == [0x5, 0x6] || buf[2..=3] == [0x7, 0x8])
     }
@@ -99,45 +336,76 @@ pub fn try_infer_extension(path: &Path) -> Option<Extension> {
         buf.starts_with(&[0x37, 0x7A, 0xBC, 0xAF, 0x27, 0x1C])
     }
 
-    let buf = {
-        let mut buf = [0; 270];
-
-        // Error cause will be ignored, so use std::fs instead of fs_err
-        let result = std::fs::File::open(path).map(|mut file| file.read(&mut buf));
-
-        // In case of file open or read failure, could not infer a extension
-        if result.is_err() {
-            return None;
-        }
-        buf
-    };
-
     use crate::extension::CompressionFormat::*;
-    if is_zip(&buf) {
+    if is_zip(buf) {
         Some(Extension::new(&[Zip], "zip"))
-    } else if is_tar(&buf) {
+    } else if is_tar(buf) {
         Some(Extension::new(&[Tar], "tar"))
-    } else if is_gz(&buf) {
+    } else if is_gz(buf) {
         Some(Extension::new(&[Gzip], "gz"))
-    } else if is_bz2(&buf) {
+    } else if is_bz2(buf) {
         Some(Extension::new(&[Bzip], "bz2"))
-    } else if is_xz(&buf) {
+    } else if is_xz(buf) {
         Some(Extension::new(&[Lzma], "xz"))
-    } else if is_lz4(&buf) {
+    } else if is_lz4(buf) {
         Some(Extension::new(&[Lz4], "lz4"))
-    } else if is_sz(&buf) {
+    } else if is_sz(buf) {
         Some(Extension::new(&[Snappy], "sz"))
-    } else if is_zst(&buf) {
+    } else if is_zst(buf) {
         Some(Extension::new(&[Zstd], "zst"))
-    } else if is_rar(&buf) {
+    } else if is_rar(buf) {
         Some(Extension::new(&[Rar], "rar"))
-    } else if is_sevenz(&buf) {
+    } else if is_sevenz(buf) {
         Some(Extension::new(&[SevenZip], "7z"))
     } else {
         None
     }
 }
 
+/// The umask of the process, which clears bits of the permissions of the files it creates.
+///
+/// It can only be read by replacing it, so it's set back right away, this is only called before
+/// the threads that create files are started.
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)] // `mode_t` is smaller than u32 on some systems
+pub fn umask() -> u32 {
+    // SAFETY: umask can't fail, and only changes the permissions of the files created meanwhile
+    let umask = unsafe { libc::umask(0) };
+    unsafe { libc::umask(umask) };
+    umask as u32
+}
+
+/// Whether the process runs as root, who gets the stored permissions as they are, like tar does.
+#[cfg(unix)]
+pub fn is_root() -> bool {
+    // SAFETY: geteuid can't fail
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// Whether `metadata` is of a FIFO or a device file, whose contents are never read when
+/// compressing, since reading them would block or never end.
+#[cfg(unix)]
+pub fn is_special_file(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    let file_type = metadata.file_type();
+    file_type.is_fifo() || file_type.is_char_device() || file_type.is_block_device()
+}
+
+/// Whether `metadata` is of a FIFO or a device file, which only exist on Unix.
+#[cfg(not(unix))]
+pub fn is_special_file(_metadata: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// Warn that the special file at `path` is skipped, only tar archives can store them.
+pub fn warn_about_special_file(path: &Path) {
+    warning(format!(
+        "Skipping '{}', FIFOs and device files can only be stored in tar archives.",
+        EscapedPathDisplay::new(path)
+    ));
+}
+
 /// Returns true if a path is a symlink.
 /// This is the same as the nightly <https://doc.rust-lang.org/std/path/struct.Path.html#method.is_symlink>
 /// Useful to detect broken symlinks when compressing. (So we can safely ignore them)
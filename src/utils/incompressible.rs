@@ -0,0 +1,75 @@
+//! Detection of data that barely shrinks when compressed, like images, videos and archives, so
+//! that no time is wasted compressing it again.
+
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+use flate2::{write::DeflateEncoder, Compression};
+use fs_err as fs;
+
+use crate::utils::FileVisibilityPolicy;
+
+/// How much of the beginning of a file is sampled
+const SAMPLE_SIZE: u64 = 64 * 1024;
+
+/// Samples smaller than this compress too poorly to tell anything, and are cheap to compress anyway
+const MIN_SAMPLE_SIZE: usize = 1024;
+
+/// Data is incompressible if its sample compresses to more than this percentage of its size
+const INCOMPRESSIBLE_PERCENTAGE: usize = 95;
+
+/// How many files are sampled at most to tell how much of the input is incompressible, so that
+/// big trees aren't walked and opened twice
+const MAX_SAMPLED_FILES: usize = 64;
+
+/// Whether the data of `reader` is incompressible, judging by how much its beginning shrinks
+/// when compressed with the fastest deflate level.
+///
+/// `reader` is rewound to where it was afterwards.
+pub fn is_incompressible(reader: &mut (impl Read + Seek)) -> io::Result<bool> {
+    let start = reader.stream_position()?;
+    let mut sample = Vec::new();
+    reader.take(SAMPLE_SIZE).read_to_end(&mut sample)?;
+    reader.seek(SeekFrom::Start(start))?;
+
+    if sample.len() < MIN_SAMPLE_SIZE {
+        return Ok(false);
+    }
+
+    let mut encoder = DeflateEncoder::new(Vec::with_capacity(sample.len()), Compression::fast());
+    encoder.write_all(&sample)?;
+    let compressed = encoder.finish()?;
+
+    Ok(compressed.len() * 100 > sample.len() * INCOMPRESSIBLE_PERCENTAGE)
+}
+
+/// Percentage of the bytes of the files in `files`, walked like when they're compressed, that
+/// are incompressible, or `None` if they're too small to tell.
+///
+/// Only the first files are sampled, see `MAX_SAMPLED_FILES`, and the ones that can't be read are
+/// left for the compression to report.
+pub fn incompressible_percentage(files: &[PathBuf], file_visibility_policy: FileVisibilityPolicy) -> Option<u64> {
+    let (mut total, mut incompressible) = (0, 0);
+
+    let entries = files
+        .iter()
+        .flat_map(|file| file_visibility_policy.build_walker(file))
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_some_and(|file_type| file_type.is_file()));
+    for entry in entries.take(MAX_SAMPLED_FILES) {
+        let Ok(mut file) = fs::File::open(entry.path()) else {
+            continue;
+        };
+        let (Ok(metadata), Ok(is_incompressible)) = (file.metadata(), is_incompressible(&mut file)) else {
+            continue;
+        };
+        total += metadata.len();
+        if is_incompressible {
+            incompressible += metadata.len();
+        }
+    }
+
+    (total >= SAMPLE_SIZE).then(|| incompressible * 100 / total)
+}
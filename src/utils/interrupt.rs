@@ -0,0 +1,124 @@
+//! Graceful handling of Ctrl-C, and of SIGTERM on Unix.
+//!
+//! Once interrupted, the compression and extraction loops stop before their next entry with
+//! [`Error::Interrupted`](crate::Error::Interrupted), which goes through the same cleanup as any
+//! other error. If ouch is stuck somewhere else, like waiting for the answer to a question, the
+//! incomplete outputs are removed by the handler after a grace period instead, and interrupting it
+//! a second time exits right away.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use super::{
+    logger::{shutdown_logger, warning},
+    remove_incomplete_output, EscapedPathDisplay,
+};
+
+/// Set by the signal handler, which can't do much more than that safely
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// How long `main` is given to stop and clean up by itself before the handler does it
+const GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// Outputs being written, locked for good once ouch starts exiting, so that only one of the
+/// interrupt handler and `main` gets to exit
+static INCOMPLETE_OUTPUTS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+extern "C" fn on_interrupt(signal: libc::c_int) {
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        // SAFETY: `_exit` is async-signal-safe, unlike running the cleanup again
+        unsafe { libc::_exit(crate::EXIT_INTERRUPTED) };
+    }
+    // Windows resets the handler once it's called
+    // SAFETY: `signal` is async-signal-safe
+    unsafe { libc::signal(signal, handler()) };
+}
+
+fn handler() -> libc::sighandler_t {
+    on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t
+}
+
+/// Whether ouch was interrupted, and should stop what it's doing.
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Fails with [`Error::Interrupted`](crate::Error::Interrupted) if ouch was interrupted, to be
+/// called between the entries of the archives being compressed or extracted.
+pub fn check_interrupted() -> crate::Result<()> {
+    if is_interrupted() {
+        Err(crate::Error::Interrupted)
+    } else {
+        Ok(())
+    }
+}
+
+/// Install the handler of Ctrl-C and SIGTERM which, if `main` didn't stop by itself in time,
+/// removes the incomplete outputs, flushes the logs and exits with `EXIT_INTERRUPTED`.
+pub fn set_up_interrupt_handler() {
+    // SAFETY: the handler only uses atomics and async-signal-safe functions
+    unsafe {
+        libc::signal(libc::SIGINT, handler());
+        libc::signal(libc::SIGTERM, handler());
+    }
+
+    // The cleanup can't run in the signal handler itself, so it's left to a thread watching it
+    thread::spawn(move || {
+        while !is_interrupted() {
+            thread::sleep(Duration::from_millis(50));
+        }
+        // `main` locks the outputs for good once it's exiting, which leaves this thread blocked
+        thread::sleep(GRACE_PERIOD);
+
+        let outputs = INCOMPLETE_OUTPUTS.lock().unwrap_or_else(|err| err.into_inner());
+        for output in outputs.iter() {
+            if remove_incomplete_output(output) {
+                warning(format!(
+                    "Interrupted, removed the incomplete '{}'.",
+                    EscapedPathDisplay::new(output)
+                ));
+            }
+        }
+
+        // Gives up after a while if a question holds the lock of stderr
+        shutdown_logger();
+
+        std::process::exit(crate::EXIT_INTERRUPTED);
+    });
+}
+
+/// Stop the Ctrl-C handler from exiting, blocks forever if it's already doing so.
+pub fn disarm_interrupt_handler() {
+    let outputs = INCOMPLETE_OUTPUTS.lock().unwrap_or_else(|err| err.into_inner());
+    std::mem::forget(outputs);
+}
+
+/// An output being written, removed on Ctrl-C until this is dropped.
+pub struct IncompleteOutput(PathBuf);
+
+impl IncompleteOutput {
+    pub fn new(path: &Path) -> Self {
+        let path = path.to_path_buf();
+        INCOMPLETE_OUTPUTS
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .push(path.clone());
+        Self(path)
+    }
+}
+
+impl Drop for IncompleteOutput {
+    fn drop(&mut self) {
+        let mut outputs = INCOMPLETE_OUTPUTS.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(idx) = outputs.iter().position(|output| *output == self.0) {
+            outputs.remove(idx);
+        }
+    }
+}
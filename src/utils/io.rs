@@ -1,4 +1,15 @@
-use std::io::{self, stderr, stdout, StderrLock, StdoutLock, Write};
+use std::{
+    env, fs,
+    io::{self, stderr, stdout, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, StderrLock, StdoutLock, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+};
+
+use super::{logger::info, Bytes, EscapedPathDisplay};
+use crate::error::FinalError;
 
 type StdioOutputLocks = (StdoutLock<'static>, StderrLock<'static>);
 
@@ -10,3 +21,462 @@ pub fn lock_and_flush_output_stdio() -> io::Result<StdioOutputLocks> {
 
     Ok((stdout, stderr))
 }
+
+/// Size of the buffers that archives are read and written through, unless `--buffer-size` changes
+/// it, big enough to spare syscalls on fast disks and network filesystems
+pub const DEFAULT_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Set by `--buffer-size`
+static BUFFER_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_BUFFER_SIZE);
+
+/// Read and write archives through buffers of `size` bytes from now on.
+pub fn set_buffer_size(size: usize) {
+    BUFFER_SIZE.store(size, Ordering::Relaxed);
+}
+
+/// Size of the buffers of `buf_reader` and `buf_writer`.
+pub fn buffer_size() -> usize {
+    BUFFER_SIZE.load(Ordering::Relaxed)
+}
+
+/// Buffered reader of an archive or input file, with a buffer of `buffer_size`.
+pub fn buf_reader<R: Read>(reader: R) -> BufReader<R> {
+    BufReader::with_capacity(buffer_size(), reader)
+}
+
+/// Buffered writer of an archive or output file, with a buffer of `buffer_size`.
+pub fn buf_writer<W: Write>(writer: W) -> BufWriter<W> {
+    BufWriter::with_capacity(buffer_size(), writer)
+}
+
+/// Archives up to this size are buffered in memory when the available memory is unknown, bigger
+/// ones are spilled to a temporary file
+const DEFAULT_MAX_MEMORY: u64 = 64 * 1024 * 1024;
+
+/// Set by `--max-memory`, or `None` with `--no-spill`
+static MAX_MEMORY: OnceLock<Option<u64>> = OnceLock::new();
+
+/// Buffer archives in up to `size` bytes of memory from now on, or in memory however big they get
+/// if it's `None`.
+pub fn set_max_memory(size: Option<u64>) {
+    let _ = MAX_MEMORY.set(size);
+}
+
+/// Most memory that `spill_buffer` uses, from `--max-memory` or a quarter of the available
+/// memory, `None` if it's never spilled.
+pub fn max_memory() -> Option<u64> {
+    *MAX_MEMORY.get_or_init(|| Some(available_memory().map_or(DEFAULT_MAX_MEMORY, |available| available / 4)))
+}
+
+/// Memory that can be used without swapping, in bytes.
+fn available_memory() -> Option<u64> {
+    if cfg!(target_os = "linux") {
+        let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+        let line = meminfo.lines().find(|line| line.starts_with("MemAvailable:"))?;
+        let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+        Some(kilobytes * 1024)
+    } else {
+        None
+    }
+}
+
+/// Set by `--temp-dir`, where temporary files are created instead of the system's directory
+static TEMP_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Create temporary files in `dir` from now on.
+pub fn set_temp_dir(dir: &Path) {
+    let _ = TEMP_DIR.set(dir.to_path_buf());
+}
+
+/// A handle to stdout as a file, for the archives written to it with '-' as their output path.
+pub fn stdout_file() -> io::Result<fs_err::File> {
+    #[cfg(unix)]
+    let handle = std::os::fd::AsFd::as_fd(&stdout()).try_clone_to_owned()?;
+    #[cfg(windows)]
+    let handle = std::os::windows::io::AsHandle::as_handle(&stdout()).try_clone_to_owned()?;
+    Ok(fs_err::File::from_parts(fs::File::from(handle), "-"))
+}
+
+/// Directory of the temporary files, from `--temp-dir`, or `TMPDIR` or the system's one.
+pub fn temp_dir() -> PathBuf {
+    TEMP_DIR.get().cloned().unwrap_or_else(env::temp_dir)
+}
+
+/// A buffer for formats that can't be streamed, like '.zip' and '.7z' when chained with others.
+///
+/// It's kept in memory while it's smaller than `max_memory`, and moved to a temporary file in
+/// `temp_dir` once it grows past it, so large archives don't need to fit in RAM.
+pub fn spill_buffer() -> SpillBuffer {
+    SpillBuffer::with_threshold(max_memory())
+}
+
+/// Error for `err`, which happened while extracting the entry `entry`, of `size` bytes if known, to
+/// the file `file`.
+///
+/// If the disk or the disk quota of the user is full, it tells which entry it was and how much it
+/// still needed, from what reached `file`.
+pub fn entry_write_error(err: io::Error, entry: &Path, file: &Path, size: Option<u64>) -> crate::Error {
+    if !matches!(err.kind(), io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded) {
+        return err.into();
+    }
+    let written = fs::metadata(file).map_or(0, |metadata| metadata.len());
+
+    let mut error = FinalError::with_title(format!(
+        "Not enough space left to extract '{}'.",
+        EscapedPathDisplay::new(entry)
+    ))
+    .detail(err.to_string());
+    error = match size {
+        Some(size) => error.detail(format!(
+            "{} of its {} were written, at least {} more are needed",
+            Bytes::new(written),
+            Bytes::new(size),
+            Bytes::new(size.saturating_sub(written))
+        )),
+        None => error.detail(format!("{} of it were written", Bytes::new(written))),
+    };
+    let error = error.hint("Free some space, or extract it somewhere else with '--dir'");
+    crate::Error::StorageFull { reason: error }
+}
+
+/// See `spill_buffer`.
+pub struct SpillBuffer {
+    contents: SpillContents,
+    threshold: Option<u64>,
+}
+
+enum SpillContents {
+    Memory(Cursor<Vec<u8>>),
+    /// Deleted as soon as it's closed
+    File(fs::File),
+}
+
+impl SpillBuffer {
+    fn with_threshold(threshold: Option<u64>) -> Self {
+        Self {
+            contents: SpillContents::Memory(Cursor::default()),
+            threshold,
+        }
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        if let SpillContents::Memory(cursor) = &self.contents {
+            let dir = temp_dir();
+            info(format!(
+                "Buffered archive went past {} of memory, moving it to a temporary file in '{}'",
+                Bytes::new(self.threshold.unwrap_or_default()),
+                EscapedPathDisplay::new(&dir)
+            ));
+            let mut file = tempfile::tempfile_in(&dir)?;
+            file.write_all(cursor.get_ref())?;
+            file.seek(SeekFrom::Start(cursor.position()))?;
+            self.contents = SpillContents::File(file);
+        }
+        Ok(())
+    }
+}
+
+impl Read for SpillBuffer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.contents {
+            SpillContents::Memory(cursor) => cursor.read(buf),
+            SpillContents::File(file) => file.read(buf),
+        }
+    }
+}
+
+impl Write for SpillBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let SpillContents::Memory(cursor) = &self.contents {
+            if self
+                .threshold
+                .is_some_and(|threshold| cursor.position() + buf.len() as u64 > threshold)
+            {
+                self.spill()?;
+            }
+        }
+
+        match &mut self.contents {
+            SpillContents::Memory(cursor) => cursor.write(buf),
+            SpillContents::File(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.contents {
+            SpillContents::Memory(cursor) => cursor.flush(),
+            SpillContents::File(file) => file.flush(),
+        }
+    }
+}
+
+impl Seek for SpillBuffer {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match &mut self.contents {
+            SpillContents::Memory(cursor) => cursor.seek(pos),
+            SpillContents::File(file) => file.seek(pos),
+        }
+    }
+}
+
+/// A reader that can seek, for the formats that can't be streamed, like '.zip' and '.7z'
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Wraps a decoder, reporting its errors as invalid data.
+///
+/// Some decoders, like the ones of '.lz4', '.sz' and '.zst', fail with `io::ErrorKind::Other`
+/// when their input is corrupted, which can't be told apart from other I/O errors otherwise.
+pub struct DecoderReader<R>(pub R);
+
+impl<R: Read> Read for DecoderReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf).map_err(|err| match err.kind() {
+            io::ErrorKind::Other => io::Error::new(io::ErrorKind::InvalidData, err),
+            _ => err,
+        })
+    }
+}
+
+/// Reader that counts the bytes read from it in a counter shared with the caller, which tells how
+/// far into the input a decoder wrapping it got, once the decoder is gone.
+pub struct OffsetReader<R>(pub R, pub Arc<AtomicU64>);
+
+impl<R: Read> Read for OffsetReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = self.0.read(buf)?;
+        self.1.fetch_add(bytes as u64, Ordering::Relaxed);
+        Ok(bytes)
+    }
+}
+
+/// Buffered reader of a file that keeps a position of its own, unlike file handles, which share
+/// theirs with their clones.
+///
+/// Its clones read the same file independently, so that several threads can read it at once.
+pub struct SharedFileReader {
+    file: Arc<fs::File>,
+    /// Position in the file of the start of `buffer`
+    buffer_pos: u64,
+    buffer: Vec<u8>,
+    /// How much of `buffer` was read
+    consumed: usize,
+}
+
+impl SharedFileReader {
+    pub fn new(file: fs::File) -> Self {
+        Self {
+            file: Arc::new(file),
+            buffer_pos: 0,
+            buffer: Vec::new(),
+            consumed: 0,
+        }
+    }
+
+    fn position(&self) -> u64 {
+        self.buffer_pos + self.consumed as u64
+    }
+}
+
+/// Read from `file` at `position`, without moving its cursor on Unix.
+fn read_at(file: &fs::File, buf: &mut [u8], position: u64) -> io::Result<usize> {
+    #[cfg(unix)]
+    return std::os::unix::fs::FileExt::read_at(file, buf, position);
+    #[cfg(windows)]
+    return std::os::windows::fs::FileExt::seek_read(file, buf, position);
+}
+
+impl Clone for SharedFileReader {
+    fn clone(&self) -> Self {
+        Self {
+            file: Arc::clone(&self.file),
+            buffer_pos: self.position(),
+            buffer: Vec::new(),
+            consumed: 0,
+        }
+    }
+}
+
+impl Read for SharedFileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.consumed == self.buffer.len() {
+            let position = self.position();
+            self.buffer_pos = position;
+            self.consumed = 0;
+
+            // Reads as big as the buffer wouldn't gain anything from it
+            if buf.len() >= buffer_size() {
+                self.buffer.clear();
+                let read = read_at(&self.file, buf, position)?;
+                self.buffer_pos += read as u64;
+                return Ok(read);
+            }
+
+            self.buffer.resize(buffer_size(), 0);
+            let read = read_at(&self.file, &mut self.buffer, position);
+            self.buffer.truncate(*read.as_ref().unwrap_or(&0));
+            read?;
+        }
+
+        let len = buf.len().min(self.buffer.len() - self.consumed);
+        buf[..len].copy_from_slice(&self.buffer[self.consumed..self.consumed + len]);
+        self.consumed += len;
+        Ok(len)
+    }
+}
+
+impl Seek for SharedFileReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position().checked_add_signed(offset),
+            SeekFrom::End(offset) => self.file.metadata()?.len().checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative position"))?;
+
+        // The buffer is kept when seeking inside of it, like short seeks between zip headers
+        if (self.buffer_pos..=self.buffer_pos + self.buffer.len() as u64).contains(&position) {
+            self.consumed = (position - self.buffer_pos) as usize;
+        } else {
+            self.buffer.clear();
+            self.buffer_pos = position;
+            self.consumed = 0;
+        }
+        Ok(position)
+    }
+}
+
+/// Size of the archives read, or of the inputs compressed, so far, for the throughput reported by
+/// `--time`
+static PROCESSED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Add the size of an archive that was read, or of inputs that were compressed, to the total
+/// processed.
+pub fn count_processed_bytes(bytes: u64) {
+    PROCESSED_BYTES.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Total size of the archives read, or of the inputs compressed, so far.
+pub fn processed_bytes() -> u64 {
+    PROCESSED_BYTES.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_spilled(buffer: &SpillBuffer) -> bool {
+        matches!(buffer.contents, SpillContents::File(_))
+    }
+
+    #[test]
+    fn test_spill_buffer() {
+        let mut buffer = SpillBuffer::with_threshold(Some(8));
+        buffer.write_all(b"12345").unwrap();
+        assert!(!is_spilled(&buffer));
+
+        // Overwriting doesn't grow it past the threshold
+        buffer.seek(SeekFrom::Start(2)).unwrap();
+        buffer.write_all(b"abc").unwrap();
+        assert!(!is_spilled(&buffer));
+
+        buffer.write_all(b"defg").unwrap();
+        assert!(is_spilled(&buffer));
+        buffer.write_all(b"h").unwrap();
+
+        let mut contents = String::new();
+        buffer.rewind().unwrap();
+        buffer.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "12abcdefgh");
+
+        // Without a threshold, it's never spilled
+        let mut buffer = SpillBuffer::with_threshold(None);
+        buffer.write_all(&[0; 1024]).unwrap();
+        assert!(!is_spilled(&buffer));
+    }
+
+    /// Reader that counts how many times it's read, like the syscalls of a file
+    struct CountingReader<R> {
+        inner: R,
+        reads: usize,
+    }
+
+    impl<R: Read> Read for CountingReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            self.inner.read(buf)
+        }
+    }
+
+    #[test]
+    fn test_buf_reader_reads() {
+        const SIZE: usize = 64 * 1024 * 1024;
+        let reads = |mut reader: BufReader<CountingReader<io::Repeat>>| {
+            io::copy(&mut (&mut reader).take(SIZE as u64), &mut io::sink()).unwrap();
+            reader.get_ref().reads
+        };
+
+        // Copying 64 MiB takes 8192 reads through the 8 KiB buffers of std, and 64 through ours
+        let std_reads = reads(BufReader::new(CountingReader {
+            inner: io::repeat(0),
+            reads: 0,
+        }));
+        let our_reads = reads(buf_reader(CountingReader {
+            inner: io::repeat(0),
+            reads: 0,
+        }));
+        assert_eq!(std_reads, SIZE / (8 * 1024));
+        assert_eq!(our_reads, SIZE / DEFAULT_BUFFER_SIZE);
+    }
+
+    #[test]
+    fn test_shared_file_reader() {
+        let mut file = tempfile::tempfile().unwrap();
+        let contents: Vec<u8> = (0..3 * DEFAULT_BUFFER_SIZE).map(|i| i as u8).collect();
+        file.write_all(&contents).unwrap();
+
+        let mut reader = SharedFileReader::new(file);
+        let mut buf = [0; 4];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, contents[..4]);
+
+        // Clones start where it is, and move on their own
+        let mut clone = reader.clone();
+        clone.seek(SeekFrom::Start(DEFAULT_BUFFER_SIZE as u64 + 1)).unwrap();
+        clone.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, contents[DEFAULT_BUFFER_SIZE + 1..][..4]);
+
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, contents[4..8]);
+        reader.seek(SeekFrom::Current(-2)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, contents[6..10]);
+
+        let mut rest = Vec::new();
+        clone.seek(SeekFrom::End(-10)).unwrap();
+        clone.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, contents[contents.len() - 10..]);
+    }
+
+    #[test]
+    fn test_entry_write_error() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), [0; 1000]).unwrap();
+
+        let full = io::Error::from(io::ErrorKind::StorageFull);
+        let err = entry_write_error(full, Path::new("dir/file"), file.path(), Some(4000));
+        assert!(matches!(err, crate::Error::StorageFull { .. }));
+        let message = err.to_string();
+        assert!(message.contains("'dir/file'"));
+        assert!(message.contains("at least 3.00 kiB more are needed"), "{message}");
+
+        let quota = io::Error::from(io::ErrorKind::QuotaExceeded);
+        let err = entry_write_error(quota, Path::new("file"), file.path(), None);
+        assert!(matches!(err, crate::Error::StorageFull { .. }));
+
+        let other = io::Error::from(io::ErrorKind::PermissionDenied);
+        let err = entry_write_error(other, Path::new("file"), file.path(), None);
+        assert!(matches!(err, crate::Error::PermissionDenied { .. }));
+    }
+}
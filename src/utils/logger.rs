@@ -1,9 +1,79 @@
-use std::sync::{mpsc, OnceLock};
+use std::{
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, OnceLock,
+    },
+};
+
+pub use logger_thread::{shutdown_logger, spawn_logger_thread, LoggerThreadHandle};
+
+use super::{
+    colors::{ORANGE, RESET, YELLOW},
+    json_string,
+};
+use crate::accessible::is_running_in_accessible_mode;
 
-pub use logger_thread::spawn_logger_thread;
+/// Set by `--quiet-errors`, drops every info and warning log, leaving only errors.
+static SILENCED: AtomicBool = AtomicBool::new(false);
 
-use super::colors::{ORANGE, RESET, YELLOW};
-use crate::accessible::is_running_in_accessible_mode;
+/// Drop every info and warning log from now on.
+pub fn silence_logs() {
+    SILENCED.store(true, Ordering::Relaxed);
+}
+
+/// Check if logs were dropped with `silence_logs`.
+pub fn logs_silenced() -> bool {
+    SILENCED.load(Ordering::Relaxed)
+}
+
+/// Set by `--log-format json`, prints every log as a JSON line.
+static JSON_LOGS: AtomicBool = AtomicBool::new(false);
+
+/// Print every log as a JSON line from now on, without colors, as soon as it's sent.
+///
+/// Must be called before anything is colored, as colors are only checked once.
+pub fn use_json_logs() {
+    JSON_LOGS.store(true, Ordering::Relaxed);
+}
+
+/// Check if logs are printed as JSON lines with `use_json_logs`.
+pub fn json_logs() -> bool {
+    JSON_LOGS.load(Ordering::Relaxed)
+}
+
+/// Print `error` to stderr, as a JSON line with `--log-format json`.
+///
+/// Errors aren't sent to the logging thread, they're printed right away, even by `--quiet-errors`.
+pub fn print_error(error: &impl Display) {
+    if json_logs() {
+        eprintln!("{}", json_line("error", &error.to_string(), true));
+    } else {
+        eprintln!("{error}");
+    }
+}
+
+/// Print a warning right away, without going through the logging thread, for the ones that must
+/// stay next to a question.
+pub fn print_warning(contents: &str) {
+    if logs_silenced() {
+        return;
+    }
+
+    if json_logs() {
+        eprintln!("{}", json_line("warning", contents.trim(), true));
+    } else {
+        eprintln!("{}[WARNING]{}: {contents}", *ORANGE, *RESET);
+    }
+}
+
+/// A log as a JSON line, like `{"level":"info","message":"...","accessible":true}`
+fn json_line(level: &str, message: &str, accessible: bool) -> String {
+    format!(
+        r#"{{"level":"{level}","message":{},"accessible":{accessible}}}"#,
+        json_string(message)
+    )
+}
 
 /// An `[INFO]` log to be displayed if we're not running accessibility mode.
 ///
@@ -46,12 +116,6 @@ pub fn warning(contents: String) {
     });
 }
 
-#[derive(Debug)]
-enum Message {
-    FlushAndShutdown,
-    PrintMessage(PrintMessage),
-}
-
 /// Message object used for sending logs from worker threads to a logging thread via channels.
 /// See <https://github.com/ouch-org/ouch/issues/643>
 #[derive(Debug)]
@@ -63,22 +127,37 @@ struct PrintMessage {
 
 impl PrintMessage {
     fn to_processed_message(&self) -> Option<String> {
+        if logs_silenced() {
+            return None;
+        }
+
+        // Every log is kept, the consumer can tell which ones accessibility mode would show
+        if json_logs() {
+            return Some(json_line(self.level.name(), &self.contents, self.accessible));
+        }
+
+        self.to_human_message(is_running_in_accessible_mode())
+    }
+
+    /// The message as printed for people, with or without `accessible_mode`, which drops the info
+    /// logs that aren't accessible.
+    fn to_human_message(&self, accessible_mode: bool) -> Option<String> {
         match self.level {
             MessageLevel::Info => {
                 if self.accessible {
-                    if is_running_in_accessible_mode() {
+                    if accessible_mode {
                         Some(format!("{}Info:{} {}", *YELLOW, *RESET, self.contents))
                     } else {
                         Some(format!("{}[INFO]{} {}", *YELLOW, *RESET, self.contents))
                     }
-                } else if !is_running_in_accessible_mode() {
+                } else if !accessible_mode {
                     Some(format!("{}[INFO]{} {}", *YELLOW, *RESET, self.contents))
                 } else {
                     None
                 }
             }
             MessageLevel::Warning => {
-                if is_running_in_accessible_mode() {
+                if accessible_mode {
                     Some(format!("{}Warning:{} {}", *ORANGE, *RESET, self.contents))
                 } else {
                     Some(format!("{}[WARNING]{} {}", *ORANGE, *RESET, self.contents))
@@ -94,114 +173,184 @@ enum MessageLevel {
     Warning,
 }
 
+impl MessageLevel {
+    /// Name of the level in JSON logs
+    fn name(&self) -> &'static str {
+        match self {
+            MessageLevel::Info => "info",
+            MessageLevel::Warning => "warning",
+        }
+    }
+}
+
 mod logger_thread {
     use std::{
-        sync::{mpsc::RecvTimeoutError, Arc, Barrier},
+        io::{self, Write},
+        iter,
+        sync::mpsc::Sender,
+        thread,
         time::Duration,
     };
 
     use super::*;
 
-    type LogReceiver = mpsc::Receiver<Message>;
-    type LogSender = mpsc::Sender<Message>;
-
-    static SENDER: OnceLock<LogSender> = OnceLock::new();
+    /// Longest wait for the logs to be printed when shutting down, past it they're given up on,
+    /// like when a question holds the lock of stderr while waiting for its answer
+    const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
 
-    #[track_caller]
-    fn setup_channel() -> LogReceiver {
-        let (tx, rx) = mpsc::channel();
-        SENDER.set(tx).expect("`setup_channel` should only be called once");
-        rx
+    #[derive(Debug)]
+    enum Message {
+        PrintMessage(PrintMessage),
+        /// Print the logs sent before it, then tell the sender of the shutdown
+        Flush(Sender<()>),
     }
 
-    #[track_caller]
-    fn get_sender() -> &'static LogSender {
-        SENDER.get().expect("No sender, you need to call `setup_channel` first")
-    }
+    static SENDER: OnceLock<Sender<Message>> = OnceLock::new();
 
-    #[track_caller]
+    /// Set once the logs were flushed for the last time, later ones are printed right away
+    static SHUT_DOWN: AtomicBool = AtomicBool::new(false);
+
+    /// Send `msg` to the logging thread, or print it right away if there's none, before it's
+    /// spawned, once it's shut down, or if it panicked.
     pub(super) fn send_log_message(msg: PrintMessage) {
-        get_sender()
-            .send(Message::PrintMessage(msg))
-            .expect("Failed to send print message");
+        let sender = SENDER.get().filter(|_| !SHUT_DOWN.load(Ordering::Acquire));
+        let Some(sender) = sender else {
+            print_lines(msg.to_processed_message());
+            return;
+        };
+        if let Err(mpsc::SendError(Message::PrintMessage(msg))) = sender.send(Message::PrintMessage(msg)) {
+            print_lines(msg.to_processed_message());
+        }
     }
 
-    #[track_caller]
-    fn send_shutdown_message() {
-        get_sender()
-            .send(Message::FlushAndShutdown)
-            .expect("Failed to send shutdown message");
+    /// Handle of the logging thread, the logs are flushed once it's shut down or dropped, which
+    /// `main` must do before exiting, as `std::process::exit` doesn't run destructors.
+    pub struct LoggerThreadHandle(());
+
+    impl LoggerThreadHandle {
+        /// Print every log sent so far, waiting up to a second for them, see [`shutdown_logger`].
+        pub fn shutdown(self) {
+            drop(self);
+        }
     }
 
-    pub struct LoggerThreadHandle {
-        shutdown_barrier: Arc<Barrier>,
+    impl Drop for LoggerThreadHandle {
+        fn drop(&mut self) {
+            shutdown_logger();
+        }
     }
 
-    impl LoggerThreadHandle {
-        /// Tell logger to shutdown and waits till it does.
-        pub fn shutdown_and_wait(self) {
-            // Signal the shutdown
-            send_shutdown_message();
-            // Wait for confirmation
-            self.shutdown_barrier.wait();
+    /// Print every log sent so far, waiting up to a second for them, the logs sent afterwards are
+    /// printed right away.
+    ///
+    /// Can be called several times, from any thread, like by the Ctrl-C handler, and doesn't
+    /// block if the logging thread panicked.
+    pub fn shutdown_logger() {
+        let Some(sender) = SENDER.get() else {
+            return;
+        };
+        SHUT_DOWN.store(true, Ordering::Release);
+
+        let (flushed_tx, flushed_rx) = mpsc::channel();
+        if sender.send(Message::Flush(flushed_tx)).is_ok() {
+            // Disconnected right away if the logging thread panicked, dropping `flushed_tx`
+            let _ = flushed_rx.recv_timeout(SHUTDOWN_TIMEOUT);
         }
     }
 
     pub fn spawn_logger_thread() -> LoggerThreadHandle {
-        let log_receiver = setup_channel();
+        let (sender, receiver) = mpsc::channel();
+        SENDER
+            .set(sender)
+            .expect("`spawn_logger_thread` should only be called once");
 
-        let shutdown_barrier = Arc::new(Barrier::new(2));
+        // Not spawned in rayon's pool, where it would take one of the threads used to decompress
+        // archives in parallel, which deadlocks on machines with a single core
+        thread::spawn(move || run_logger(receiver));
 
-        let handle = LoggerThreadHandle {
-            shutdown_barrier: shutdown_barrier.clone(),
-        };
+        LoggerThreadHandle(())
+    }
 
-        rayon::spawn(move || run_logger(log_receiver, shutdown_barrier));
+    /// Print each log as soon as it's received, along with the ones sent meanwhile, so that many
+    /// logs don't take a write each, without holding back the ones printed before a question.
+    ///
+    /// It keeps running once shut down, for the logs that were sent right before it.
+    fn run_logger(receiver: mpsc::Receiver<Message>) {
+        while let Ok(message) = receiver.recv() {
+            let mut lines = vec![];
+            let mut flushed = vec![];
+            for message in iter::once(message).chain(receiver.try_iter()) {
+                match message {
+                    Message::PrintMessage(msg) => lines.extend(msg.to_processed_message()),
+                    Message::Flush(flushed_tx) => flushed.push(flushed_tx),
+                }
+            }
+            print_lines(lines);
+            for flushed_tx in flushed {
+                let _ = flushed_tx.send(());
+            }
+        }
+    }
 
-        handle
+    /// Print `lines` to stderr, ignoring failures, a closed stderr must not make ouch panic.
+    fn print_lines(lines: impl IntoIterator<Item = String>) {
+        let mut text = String::new();
+        for line in lines {
+            text.push_str(&line);
+            text.push('\n');
+        }
+        if !text.is_empty() {
+            let _ = io::stderr().lock().write_all(text.as_bytes());
+        }
     }
+}
 
-    fn run_logger(log_receiver: LogReceiver, shutdown_barrier: Arc<Barrier>) {
-        const FLUSH_TIMEOUT: Duration = Duration::from_millis(250);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let mut buffer = Vec::<String>::with_capacity(16);
+    const CONTENTS: &str = "Reading 'archive.zip' requires buffering it in memory.";
 
-        loop {
-            let msg = match log_receiver.recv_timeout(FLUSH_TIMEOUT) {
-                Ok(msg) => msg,
-                Err(RecvTimeoutError::Timeout) => {
-                    flush_logs_to_stderr(&mut buffer);
-                    continue;
-                }
-                Err(RecvTimeoutError::Disconnected) => unreachable!("sender is static"),
-            };
-
-            match msg {
-                Message::PrintMessage(msg) => {
-                    // Append message to buffer
-                    if let Some(msg) = msg.to_processed_message() {
-                        buffer.push(msg);
-                    }
+    fn message(level: MessageLevel, accessible: bool) -> PrintMessage {
+        PrintMessage {
+            contents: CONTENTS.to_string(),
+            accessible,
+            level,
+        }
+    }
 
-                    if buffer.len() == buffer.capacity() {
-                        flush_logs_to_stderr(&mut buffer);
-                    }
-                }
-                Message::FlushAndShutdown => {
-                    flush_logs_to_stderr(&mut buffer);
-                    break;
+    #[test]
+    fn human_messages_keep_their_contents() {
+        let cases = [
+            (MessageLevel::Info, true, false, Some("[INFO]")),
+            (MessageLevel::Info, true, true, Some("Info:")),
+            (MessageLevel::Info, false, false, Some("[INFO]")),
+            (MessageLevel::Info, false, true, None),
+            (MessageLevel::Warning, true, false, Some("[WARNING]")),
+            (MessageLevel::Warning, true, true, Some("Warning:")),
+        ];
+        for (level, accessible, accessible_mode, tag) in cases {
+            let case = format!("{level:?}, accessible: {accessible}, accessible mode: {accessible_mode}");
+            let printed = message(level, accessible).to_human_message(accessible_mode);
+            match tag {
+                Some(tag) => {
+                    let printed = printed.unwrap_or_else(|| panic!("{case} isn't printed"));
+                    assert!(printed.contains(tag), "{case}: {printed}");
+                    assert!(printed.ends_with(&format!(" {CONTENTS}")), "{case}: {printed}");
                 }
+                None => assert_eq!(printed, None, "{case}"),
             }
         }
-
-        shutdown_barrier.wait();
     }
 
-    fn flush_logs_to_stderr(buffer: &mut Vec<String>) {
-        if !buffer.is_empty() {
-            let text = buffer.join("\n");
-            eprintln!("{text}");
-            buffer.clear();
-        }
+    #[test]
+    fn shutting_down_flushes_without_blocking() {
+        let logger = spawn_logger_thread();
+        info("Sent to the logging thread.".to_string());
+        logger.shutdown();
+
+        // Again, like the Ctrl-C handler can, then the logs are printed right away
+        shutdown_logger();
+        warning("Printed right away.".to_string());
     }
 }
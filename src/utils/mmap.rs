@@ -0,0 +1,51 @@
+//! Memory-mapped reading of big input files, which spares the encoders from many small buffered
+//! reads.
+
+use std::io::{self, Write};
+
+use memmap2::Mmap;
+
+use super::progress;
+
+/// Size of the slices of the mapping handed to the writer at once
+const SLICE_SIZE: usize = 1024 * 1024;
+
+/// Write the contents of `file`, a regular file, to `writer` from a read-only mapping of it, in
+/// big slices.
+///
+/// Returns `Ok(None)`, without writing anything, if the file can't be mapped, like on filesystems
+/// that don't support it, for it to be read through a buffer instead. Otherwise returns how many
+/// bytes were written.
+pub fn copy_mapped(file: &std::fs::File, writer: &mut impl Write) -> io::Result<Option<u64>> {
+    // SAFETY: the mapping is private and read-only, and only lives in this function. Another
+    // program writing to the file meanwhile can change what is read, like it would with buffered
+    // reads, while truncating it makes reading the missing pages kill ouch with SIGBUS on Unix,
+    // which is documented along with '--no-mmap'. Windows refuses to truncate mapped files.
+    let Ok(mapping) = (unsafe { Mmap::map(file) }) else {
+        return Ok(None);
+    };
+    #[cfg(unix)]
+    let _ = mapping.advise(memmap2::Advice::Sequential);
+
+    for slice in mapping.chunks(SLICE_SIZE) {
+        writer.write_all(slice)?;
+        progress::advance(slice.len() as u64);
+    }
+    Ok(Some(mapping.len() as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_mapped() {
+        let mut file = tempfile::tempfile().unwrap();
+        let contents: Vec<u8> = (0..3 * SLICE_SIZE + 1).map(|i| i as u8).collect();
+        file.write_all(&contents).unwrap();
+
+        let mut written = vec![];
+        assert_eq!(copy_mapped(&file, &mut written).unwrap(), Some(contents.len() as u64));
+        assert_eq!(written, contents);
+    }
+}
@@ -4,23 +4,46 @@
 //! stdin interaction helpers.
 
 pub mod colors;
+mod entry_filter;
+mod exec;
+pub mod external_codec;
 mod file_visibility;
 mod formatting;
+pub mod framed;
 mod fs;
+mod incompressible;
+pub mod interrupt;
 pub mod io;
 pub mod logger;
+pub mod mmap;
+mod par_bzip2;
+pub mod progress;
 mod question;
+mod sparse;
+mod transform;
 
+pub use entry_filter::EntryFilter;
+pub use exec::ExecHook;
 pub use file_visibility::FileVisibilityPolicy;
 pub use formatting::{
-    nice_directory_display, pretty_format_list_of_paths, strip_cur_dir, to_utf, Bytes, EscapedPathDisplay,
+    json_string, nice_directory_display, pretty_format_list_of_paths, strip_cur_dir, to_utf, Bytes, EscapedPathDisplay,
 };
 pub use fs::{
-    cd_into_same_dir_as, clear_path, create_dir_if_non_existent, is_symlink, remove_file_or_dir, try_infer_extension,
+    clear_path, copy_file_range, create_dir_all, create_dir_if_non_existent, free_path, infer_extension,
+    is_special_file, is_symlink, long_path, move_path, read_password_file, remove_file_or_dir,
+    remove_incomplete_output, set_mtime, try_infer_extension, warn_about_special_file, MAGIC_HEAD_LEN,
 };
+#[cfg(unix)]
+pub use fs::{is_root, umask};
+pub use incompressible::{incompressible_percentage, is_incompressible};
+pub use par_bzip2::{ParBzDecoder, ParBzEncoder};
 pub use question::{
-    ask_to_create_file, user_wants_to_continue, user_wants_to_overwrite, QuestionAction, QuestionPolicy,
+    ask_to_create_file, user_wants_to_continue, user_wants_to_extract_entry, user_wants_to_overwrite,
+    user_wants_to_remove_partial_output, user_wants_to_rename_output, user_wants_to_replace_entry,
+    user_wants_to_use_detected_format, QuestionAction, QuestionPolicy,
 };
+pub use sparse::SparseWriter;
+pub use transform::Transforms;
 pub use utf8::{get_invalid_utf8_paths, is_invalid_utf8};
 
 mod utf8 {
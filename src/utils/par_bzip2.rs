@@ -0,0 +1,419 @@
+//! Block-parallel bzip2, like pbzip2 and lbzip2.
+//!
+//! Every block of a bzip2 stream is compressed on its own, so the blocks can be encoded and
+//! decoded concurrently on the rayon pool. The encoder writes one stream per block of input,
+//! which standard bzip2 decoders read as a single file. The decoder finds the blocks by their
+//! magic numbers, which aren't aligned to bytes, and decodes each one as a stream of its own.
+
+use std::io::{self, Read, Write};
+
+use bzip2::{read::BzDecoder, write::BzEncoder, Compression};
+use rayon::prelude::*;
+
+/// Starts every block, the BCD of pi
+const BLOCK_MAGIC: u64 = 0x3141_5926_5359;
+
+/// Ends every stream, the BCD of the square root of pi
+const END_MAGIC: u64 = 0x1772_4538_5090;
+
+const MAGIC_BITS: u64 = 48;
+
+/// A block of at most 900k compressed by bzip2 is a little bigger in the worst case, so the data
+/// between two magic numbers can't be longer than this unless it's corrupted
+const MAX_BLOCK_SIZE: usize = 2 * 1024 * 1024;
+
+/// How much compressed data is read at once while looking for blocks
+const READ_SIZE: usize = 256 * 1024;
+
+/// Compresses into one bzip2 stream per block of input, `threads` blocks at a time.
+///
+/// Like [`BzEncoder`], flushing ends the current block, and dropping it finishes the stream.
+pub struct ParBzEncoder<W: Write> {
+    writer: W,
+    level: Compression,
+    threads: usize,
+    /// Input of the next blocks
+    buffer: Vec<u8>,
+    /// Whether a stream was written, an empty input still needs one
+    wrote_stream: bool,
+}
+
+impl<W: Write> ParBzEncoder<W> {
+    pub fn new(writer: W, level: Compression, threads: usize) -> Self {
+        Self {
+            writer,
+            level,
+            threads,
+            buffer: Vec::new(),
+            wrote_stream: false,
+        }
+    }
+
+    /// Input size of a block, the same as the block size of bzip2 at that level
+    fn block_size(&self) -> usize {
+        self.level.level() as usize * 100_000
+    }
+
+    /// Compress the buffered input, as many streams as there are blocks in it, and write them.
+    fn compress_buffer(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() && self.wrote_stream {
+            return Ok(());
+        }
+
+        let level = self.level;
+        let compress = |block: &[u8]| {
+            let mut encoder = BzEncoder::new(Vec::new(), level);
+            encoder.write_all(block)?;
+            encoder.finish()
+        };
+
+        let streams: Vec<io::Result<Vec<u8>>> = if self.buffer.is_empty() {
+            vec![compress(&[])]
+        } else {
+            self.buffer.par_chunks(self.block_size()).map(compress).collect()
+        };
+        for stream in streams {
+            self.writer.write_all(&stream?)?;
+        }
+
+        self.buffer.clear();
+        self.wrote_stream = true;
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ParBzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let batch_size = self.block_size() * self.threads;
+        let len = buf.len().min(batch_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..len]);
+
+        if self.buffer.len() == batch_size {
+            self.compress_buffer()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.compress_buffer()?;
+        }
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> Drop for ParBzEncoder<W> {
+    fn drop(&mut self) {
+        let _ = self.compress_buffer();
+    }
+}
+
+/// What a magic number found in the compressed data starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MarkerKind {
+    Block,
+    End,
+}
+
+/// A magic number, at a bit offset of the compressed data
+#[derive(Debug, Clone, Copy)]
+struct Marker {
+    bit: u64,
+    kind: MarkerKind,
+}
+
+/// Decompresses bzip2 data, single or multi-stream, decoding `threads` blocks at a time.
+pub struct ParBzDecoder<R: Read> {
+    reader: R,
+    threads: usize,
+    /// Compressed data read but not decoded yet, starting at the byte of the next block
+    input: Vec<u8>,
+    /// How much of `input` was searched for magic numbers
+    scanned: usize,
+    /// The last 8 bytes searched
+    window: u64,
+    /// Magic numbers found in `input`
+    markers: Vec<Marker>,
+    eof: bool,
+    /// Decoded data not read yet
+    output: Vec<u8>,
+    output_pos: usize,
+}
+
+impl<R: Read> ParBzDecoder<R> {
+    pub fn new(reader: R, threads: usize) -> Self {
+        Self {
+            reader,
+            threads,
+            input: Vec::new(),
+            scanned: 0,
+            window: 0,
+            markers: Vec::new(),
+            eof: false,
+            output: Vec::new(),
+            output_pos: 0,
+        }
+    }
+
+    /// Read more compressed data, and look for the magic numbers in it.
+    fn read_input(&mut self) -> io::Result<()> {
+        let first_read = self.input.is_empty() && self.scanned == 0;
+        let read = (&mut self.reader).take(READ_SIZE as u64).read_to_end(&mut self.input)?;
+        self.eof = read == 0;
+
+        let is_bzip2 =
+            |input: &[u8]| input.starts_with(b"BZh") && input.get(3).is_some_and(|level| (b'1'..=b'9').contains(level));
+        if first_read && !self.input.is_empty() && !is_bzip2(&self.input) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not bzip2 data"));
+        }
+
+        // Every bit where a magic number could end is checked once, as each byte is shifted in
+        for (idx, &byte) in self.input.iter().enumerate().skip(self.scanned) {
+            self.window = (self.window << 8) | u64::from(byte);
+            for shift in (0..8).rev() {
+                let end = (idx as u64 + 1) * 8 - shift;
+                let Some(bit) = end.checked_sub(MAGIC_BITS) else {
+                    continue;
+                };
+                let kind = match (self.window >> shift) & ((1 << MAGIC_BITS) - 1) {
+                    BLOCK_MAGIC => MarkerKind::Block,
+                    END_MAGIC => MarkerKind::End,
+                    _ => continue,
+                };
+                self.markers.push(Marker { bit, kind });
+            }
+        }
+        self.scanned = self.input.len();
+
+        let pending_start = self.markers.last().map_or(0, |marker| marker.bit / 8) as usize;
+        if self.input.len() - pending_start > MAX_BLOCK_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "bzip2 block is too large"));
+        }
+        Ok(())
+    }
+
+    /// Bit ranges of the blocks found in `input`, up to `threads` of them.
+    fn complete_blocks(&self) -> Vec<(u64, u64)> {
+        self.markers
+            .windows(2)
+            .filter(|pair| pair[0].kind == MarkerKind::Block)
+            .map(|pair| (pair[0].bit, pair[1].bit))
+            .take(self.threads)
+            .collect()
+    }
+
+    /// Decode the next blocks into `output`, returns `false` once there are none left.
+    fn decode_blocks(&mut self) -> io::Result<bool> {
+        loop {
+            let blocks = self.complete_blocks();
+            if blocks.is_empty() || (blocks.len() < self.threads && !self.eof) {
+                if !self.eof {
+                    self.read_input()?;
+                    continue;
+                }
+                let ends_in_block = match self.markers.last() {
+                    Some(marker) => marker.kind == MarkerKind::Block,
+                    None => !self.input.is_empty(),
+                };
+                if ends_in_block {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "bzip2 data ends in the middle of a block",
+                    ));
+                }
+                return Ok(false);
+            }
+
+            let input = &self.input;
+            let decoded: Vec<io::Result<Vec<u8>>> = blocks
+                .par_iter()
+                .map(|&(start, end)| decode_block(input, start, end))
+                .collect();
+
+            self.output.clear();
+            self.output_pos = 0;
+            let mut decoded_end = None;
+            for (&(_, end), block) in blocks.iter().zip(decoded) {
+                match block {
+                    Ok(block) => {
+                        self.output.extend(block);
+                        decoded_end = Some(end);
+                    }
+                    Err(err) if decoded_end.is_none() => {
+                        let (start, end) = blocks[0];
+                        // 48 bits can look like a magic number by chance, in which case the block
+                        // is cut short, so it's tried again up to the next one
+                        if ((end - start) / 8) as usize > MAX_BLOCK_SIZE {
+                            return Err(err);
+                        }
+                        let next = self.markers.iter().position(|marker| marker.bit == end);
+                        match next {
+                            Some(next) if next + 1 < self.markers.len() || !self.eof => {
+                                self.markers.remove(next);
+                            }
+                            _ => return Err(err),
+                        }
+                        break;
+                    }
+                    // Only the blocks before it are kept, it's the first one of the next batch
+                    Err(_) => break,
+                }
+            }
+
+            let Some(decoded_end) = decoded_end else {
+                continue;
+            };
+
+            // The marker that ends the last decoded block is kept, it may start the next one
+            let consumed = (decoded_end / 8) as usize;
+            self.input.drain(..consumed);
+            self.scanned -= consumed;
+            self.markers.retain(|marker| marker.bit >= decoded_end);
+            for marker in &mut self.markers {
+                marker.bit -= consumed as u64 * 8;
+            }
+            return Ok(true);
+        }
+    }
+}
+
+impl<R: Read> Read for ParBzDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.output_pos == self.output.len() {
+            if !self.decode_blocks()? {
+                return Ok(0);
+            }
+        }
+
+        let len = buf.len().min(self.output.len() - self.output_pos);
+        buf[..len].copy_from_slice(&self.output[self.output_pos..self.output_pos + len]);
+        self.output_pos += len;
+        Ok(len)
+    }
+}
+
+/// Decode the block at the bits `start..end` of `input`, by wrapping it in a stream of its own.
+fn decode_block(input: &[u8], start: u64, end: u64) -> io::Result<Vec<u8>> {
+    let mut stream = BitWriter::default();
+    stream.push_bytes(b"BZh9");
+    for bit in (start..end).step_by(8) {
+        let bits = (end - bit).min(8) as u32;
+        stream.push_bits(u64::from(read_byte(input, bit) >> (8 - bits)), bits);
+    }
+    stream.push_bits(END_MAGIC, MAGIC_BITS as u32);
+    // The stream holds a single block, so its CRC is the one of the block, that follows its magic
+    for byte in 0..4 {
+        stream.push_bits(u64::from(read_byte(input, start + MAGIC_BITS + byte * 8)), 8);
+    }
+
+    let mut decoded = Vec::new();
+    BzDecoder::new(stream.finish().as_slice()).read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+/// The 8 bits of `input` starting at the bit `bit`, padded with zeros past its end.
+fn read_byte(input: &[u8], bit: u64) -> u8 {
+    let idx = (bit / 8) as usize;
+    let shift = bit % 8;
+    let high = input.get(idx).copied().unwrap_or(0);
+    let low = input.get(idx + 1).copied().unwrap_or(0);
+    ((u16::from(high) << 8 | u16::from(low)) >> (8 - shift)) as u8
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    pending: u64,
+    pending_bits: u32,
+}
+
+impl BitWriter {
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.push_bits(u64::from(byte), 8);
+        }
+    }
+
+    /// Push the lowest `count` bits of `value`, up to 48.
+    fn push_bits(&mut self, value: u64, count: u32) {
+        self.pending = (self.pending << count) | (value & ((1 << count) - 1));
+        self.pending_bits += count;
+        while self.pending_bits >= 8 {
+            self.pending_bits -= 8;
+            self.bytes.push((self.pending >> self.pending_bits) as u8);
+        }
+    }
+
+    /// The bytes written, the last one padded with zeros.
+    fn finish(mut self) -> Vec<u8> {
+        if self.pending_bits > 0 {
+            self.bytes.push((self.pending << (8 - self.pending_bits)) as u8);
+        }
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len).map(|idx| (idx * idx / 7 % 251) as u8).collect()
+    }
+
+    fn par_compress(data: &[u8], level: u32, threads: usize) -> Vec<u8> {
+        let mut compressed = Vec::new();
+        let mut encoder = ParBzEncoder::new(&mut compressed, Compression::new(level), threads);
+        encoder.write_all(data).unwrap();
+        drop(encoder);
+        compressed
+    }
+
+    fn par_decompress(compressed: &[u8], threads: usize) -> io::Result<Vec<u8>> {
+        let mut decompressed = Vec::new();
+        ParBzDecoder::new(compressed, threads).read_to_end(&mut decompressed)?;
+        Ok(decompressed)
+    }
+
+    #[test]
+    fn test_par_bzip2_roundtrip() {
+        for len in [0, 1, 99_999, 100_000, 350_001] {
+            let data = sample(len);
+            for threads in [1, 3] {
+                let compressed = par_compress(&data, 1, threads);
+
+                let mut decompressed = Vec::new();
+                bzip2::read::MultiBzDecoder::new(compressed.as_slice())
+                    .read_to_end(&mut decompressed)
+                    .unwrap();
+                assert_eq!(decompressed, data);
+                assert_eq!(par_decompress(&compressed, threads).unwrap(), data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_par_bzip2_single_stream() {
+        // A stream of several blocks, whose boundaries aren't aligned to bytes
+        let data = sample(350_001);
+        let mut encoder = BzEncoder::new(Vec::new(), Compression::new(1));
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        for threads in [1, 2, 4] {
+            assert_eq!(par_decompress(&compressed, threads).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_par_bzip2_corrupted() {
+        let mut compressed = par_compress(&sample(250_000), 1, 2);
+        assert!(par_decompress(&compressed[..compressed.len() / 2], 2).is_err());
+
+        let middle = compressed.len() / 2;
+        compressed[middle] ^= 0xff;
+        assert!(par_decompress(&compressed, 2).is_err());
+
+        assert!(par_decompress(b"not bzip2 at all", 2).is_err());
+    }
+}
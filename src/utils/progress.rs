@@ -0,0 +1,118 @@
+//! Plain-text progress, printed by `--numeric-progress` as lines like `40% (X/Y bytes)`.
+//!
+//! Unlike a progress bar, every update is a new line, so it can be read from CI logs and log
+//! files that don't handle carriage returns.
+
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+
+use super::logger::info_accessible;
+
+/// A line is printed every time the progress goes past another multiple of this percentage
+const PERCENT_STEP: u64 = 10;
+
+static PROGRESS: OnceLock<Progress> = OnceLock::new();
+
+/// How much of the inputs was read so far, counted even when the progress isn't printed
+static READ: AtomicU64 = AtomicU64::new(0);
+
+struct Progress {
+    /// Size of the inputs, in bytes
+    total: u64,
+    /// Last percentage printed
+    printed: AtomicU64,
+}
+
+/// Print the progress of reading `total` bytes of inputs from now on.
+pub fn enable(total: u64) {
+    let _ = PROGRESS.set(Progress {
+        total,
+        printed: AtomicU64::new(0),
+    });
+}
+
+/// Count `bytes` more of the inputs as read.
+pub fn advance(bytes: u64) {
+    let read = READ.fetch_add(bytes, Ordering::Relaxed) + bytes;
+    let Some(progress) = PROGRESS.get() else {
+        return;
+    };
+
+    // Only `finish` prints 100%, the total is a guess that some inputs, like nested archives,
+    // go past
+    let percent = (percentage(read, progress.total) / PERCENT_STEP * PERCENT_STEP).min(100 - PERCENT_STEP);
+    if percent > 0 && progress.printed.fetch_max(percent, Ordering::Relaxed) < percent {
+        print_progress(percent, read.min(progress.total), progress.total);
+    }
+}
+
+/// Print that all of the inputs were read, if the progress is printed.
+pub fn finish() {
+    if let Some(progress) = PROGRESS.get() {
+        if progress.printed.swap(100, Ordering::Relaxed) < 100 {
+            print_progress(100, progress.total, progress.total);
+        }
+    }
+}
+
+/// How many bytes of the inputs were read so far, by [`ProgressReader`] and `advance`.
+pub fn bytes_read() -> u64 {
+    READ.load(Ordering::Relaxed)
+}
+
+fn percentage(read: u64, total: u64) -> u64 {
+    if total == 0 {
+        100
+    } else {
+        (read as u128 * 100 / total as u128) as u64
+    }
+}
+
+fn print_progress(percent: u64, read: u64, total: u64) {
+    info_accessible(format!("{percent}% ({read}/{total} bytes)"));
+}
+
+/// Size of the files at `paths`, and of the files inside of them if they're directories, without
+/// following symlinks.
+pub fn size_of_paths(paths: &[impl AsRef<Path>]) -> u64 {
+    fn size_of_path(path: &Path) -> u64 {
+        let Ok(metadata) = path.symlink_metadata() else {
+            return 0;
+        };
+        if !metadata.is_dir() {
+            return metadata.len();
+        }
+        path.read_dir()
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| size_of_path(&entry.path()))
+            .sum()
+    }
+
+    paths.iter().map(|path| size_of_path(path.as_ref())).sum()
+}
+
+/// Reader of an input that counts the bytes read from it in the progress.
+#[derive(Clone)]
+pub struct ProgressReader<R>(pub R);
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.0.read(buf)?;
+        advance(read as u64);
+        Ok(read)
+    }
+}
+
+impl<R: Seek> Seek for ProgressReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
@@ -15,6 +15,7 @@ use super::{strip_cur_dir, to_utf};
 use crate::{
     accessible::is_running_in_accessible_mode,
     error::{Error, FinalError, Result},
+    extension::Extension,
     utils::{self, colors, io::lock_and_flush_output_stdio},
 };
 
@@ -52,6 +53,44 @@ pub fn user_wants_to_overwrite(path: &Path, question_policy: QuestionPolicy) ->
     }
 }
 
+/// Check if QuestionPolicy flags were set, otherwise, ask the user if they want to replace the
+/// archive entry at `entry_path` with the one with the same path in `archive_path`.
+pub fn user_wants_to_replace_entry(
+    entry_path: &Path,
+    archive_path: &Path,
+    question_policy: QuestionPolicy,
+) -> crate::Result<bool> {
+    match question_policy {
+        QuestionPolicy::AlwaysYes => Ok(true),
+        QuestionPolicy::AlwaysNo => Ok(false),
+        QuestionPolicy::Ask => {
+            let entry_path = to_utf(entry_path);
+            let archive_path = to_utf(strip_cur_dir(archive_path));
+            let prompt = format!("Do you want to replace 'ENTRY' with the one from '{archive_path}'?");
+            Confirmation::new(&prompt, Some("ENTRY")).ask(Some(&entry_path))
+        }
+    }
+}
+
+/// Check if QuestionPolicy flags were set, otherwise, ask the user if they want to extract the
+/// entry at `entry_path` of `archive_path`.
+pub fn user_wants_to_extract_entry(
+    entry_path: &Path,
+    archive_path: &Path,
+    question_policy: QuestionPolicy,
+) -> crate::Result<bool> {
+    match question_policy {
+        QuestionPolicy::AlwaysYes => Ok(true),
+        QuestionPolicy::AlwaysNo => Ok(false),
+        QuestionPolicy::Ask => {
+            let entry_path = to_utf(entry_path);
+            let archive_path = to_utf(strip_cur_dir(archive_path));
+            let prompt = format!("Do you want to extract 'ENTRY' from '{archive_path}'?");
+            Confirmation::new(&prompt, Some("ENTRY")).ask(Some(&entry_path))
+        }
+    }
+}
+
 /// Create the file if it doesn't exist and if it does then ask to overwrite it.
 /// If the user doesn't want to overwrite then we return [`Ok(None)`]
 pub fn ask_to_create_file(path: &Path, question_policy: QuestionPolicy) -> Result<Option<fs::File>> {
@@ -91,6 +130,63 @@ pub fn user_wants_to_continue(
     }
 }
 
+/// Ask the user if they want to read the file at `path` as `detected_format`, the format of its
+/// contents, instead of the one of its extension.
+///
+/// `--yes` accepts the detected format, and `--no` skips the file.
+pub fn user_wants_to_use_detected_format(
+    path: &Path,
+    detected_format: &Extension,
+    question_policy: QuestionPolicy,
+) -> crate::Result<bool> {
+    match question_policy {
+        QuestionPolicy::AlwaysYes => Ok(true),
+        QuestionPolicy::AlwaysNo => Ok(false),
+        QuestionPolicy::Ask => {
+            let path = to_utf(strip_cur_dir(path));
+            let prompt = format!("Do you want to decompress 'FILE' as `{detected_format}` instead?");
+            Confirmation::new(&prompt, Some("FILE")).ask(Some(&path))
+        }
+    }
+}
+
+/// Ask the user if they want to decompress `archive_path` to `renamed`, since its output would be
+/// the archive itself.
+///
+/// Without being asked, it's never renamed, overwriting the archive isn't an option either way.
+pub fn user_wants_to_rename_output(
+    archive_path: &Path,
+    renamed: &Path,
+    question_policy: QuestionPolicy,
+) -> crate::Result<bool> {
+    match question_policy {
+        QuestionPolicy::AlwaysYes | QuestionPolicy::AlwaysNo => Ok(false),
+        QuestionPolicy::Ask => {
+            let archive_path = to_utf(strip_cur_dir(archive_path));
+            let renamed = to_utf(strip_cur_dir(renamed));
+            let prompt = format!(
+                "The output of '{archive_path}' is the archive itself, do you want to write it to 'FILE' instead?"
+            );
+            Confirmation::new(&prompt, Some("FILE")).ask(Some(&renamed))
+        }
+    }
+}
+
+/// Ask the user if they want to remove what was extracted to `path` before the disk got full.
+///
+/// Without being asked, it's removed, unless `--keep-partial` was used instead of asking, and so
+/// it is if the question can't be answered, like in a script.
+pub fn user_wants_to_remove_partial_output(path: &Path, question_policy: QuestionPolicy) -> bool {
+    match question_policy {
+        QuestionPolicy::AlwaysYes | QuestionPolicy::AlwaysNo => true,
+        QuestionPolicy::Ask => {
+            let path = to_utf(strip_cur_dir(path));
+            let prompt = "Do you want to remove what was extracted to 'FILE' so far?";
+            Confirmation::new(prompt, Some("FILE")).ask(Some(&path)).unwrap_or(true)
+        }
+    }
+}
+
 /// Confirmation dialog for end user with [Y/n] question.
 ///
 /// If the placeholder is found in the prompt text, it will be replaced to form the final message.
@@ -0,0 +1,157 @@
+//! Writing of extracted files with holes in place of their long runs of zeroes, which the
+//! filesystem doesn't allocate any blocks for, like `cp --sparse` does.
+//!
+//! Disk images are mostly made of zeroes, which would otherwise take as much space once
+//! extracted as the whole image.
+
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+
+use fs_err as fs;
+
+use crate::cli::SparseMode;
+
+/// Holes are made of whole blocks of this size, as filesystems allocate files in blocks
+const BLOCK_SIZE: u64 = 4096;
+
+/// With `--sparse=auto`, shorter runs of zeroes are written, so that files with scattered blocks of
+/// zeroes don't get fragmented
+const AUTO_MIN_HOLE: u64 = 64 * 1024;
+
+/// Writer of an extracted file, which leaves holes in place of the runs of zeroes written to it.
+///
+/// [`SparseWriter::finish`] must be called once everything was written, as the trailing zeroes
+/// are only written, or skipped, then.
+pub struct SparseWriter {
+    inner: BufWriter<fs::File>,
+    /// Shortest run of zeroes that becomes a hole, `None` if zeroes are always written
+    min_hole: Option<u64>,
+    /// Size of the file so far, holes and `zeroes` included
+    len: u64,
+    /// Length of the run of zeroes at the end of the file, which wasn't written yet
+    zeroes: u64,
+}
+
+impl SparseWriter {
+    pub fn new(inner: BufWriter<fs::File>, mode: SparseMode) -> Self {
+        let min_hole = match mode {
+            SparseMode::Auto => Some(AUTO_MIN_HOLE),
+            SparseMode::Always => Some(BLOCK_SIZE),
+            SparseMode::Never => None,
+        };
+        Self {
+            inner,
+            min_hole,
+            len: 0,
+            zeroes: 0,
+        }
+    }
+
+    /// Size of the file so far.
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+
+    /// Leave a hole of `len` bytes, like the ones of sparse entries, unless holes are disabled,
+    /// in which case zeroes are written.
+    pub fn hole(&mut self, len: u64) -> io::Result<()> {
+        if self.min_hole.is_none() {
+            self.zeroes += len;
+        } else {
+            self.write_zeroes()?;
+            self.inner.seek(SeekFrom::Current(len as i64))?;
+        }
+        self.len += len;
+        Ok(())
+    }
+
+    /// Write the trailing zeroes, and give back the file.
+    pub fn finish(mut self) -> io::Result<fs::File> {
+        self.write_zeroes()?;
+        self.inner.flush()?;
+        let file = self.inner.into_inner().map_err(|err| err.into_error())?;
+        // The file is only as long as its last write, a trailing hole is added by extending it
+        if self.min_hole.is_some() {
+            file.set_len(self.len)?;
+        }
+        Ok(file)
+    }
+
+    /// Write the pending run of zeroes, or skip the whole blocks of it if it's long enough.
+    fn write_zeroes(&mut self) -> io::Result<()> {
+        let end = self.len;
+        let start = end - self.zeroes;
+        self.zeroes = 0;
+
+        let hole_start = start.next_multiple_of(BLOCK_SIZE);
+        let hole_end = end / BLOCK_SIZE * BLOCK_SIZE;
+        match self.min_hole {
+            Some(min_hole) if hole_end > hole_start && hole_end - hole_start >= min_hole => {
+                io::copy(&mut io::repeat(0).take(hole_start - start), &mut self.inner)?;
+                self.inner.seek(SeekFrom::Start(hole_end))?;
+                io::copy(&mut io::repeat(0).take(end - hole_end), &mut self.inner)?;
+            }
+            _ => {
+                io::copy(&mut io::repeat(0).take(end - start), &mut self.inner)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for SparseWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.min_hole.is_none() {
+            self.write_zeroes()?;
+            let written = self.inner.write(buf)?;
+            self.len += written as u64;
+            return Ok(written);
+        }
+
+        // Checked up to the end of the current block, so that whole blocks of zeroes are found
+        // no matter how the writes are split
+        let len = buf.len().min((BLOCK_SIZE - self.len % BLOCK_SIZE) as usize);
+        let chunk = &buf[..len];
+        if chunk.iter().all(|&byte| byte == 0) {
+            self.zeroes += len as u64;
+        } else {
+            self.write_zeroes()?;
+            self.inner.write_all(chunk)?;
+        }
+        self.len += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sparse_writer() {
+        let mut contents = vec![0; 1024 * 1024];
+        contents[..1000].fill(1);
+        contents[500_000..500_100].fill(2);
+        contents[510_000] = 3;
+
+        for mode in [SparseMode::Auto, SparseMode::Always, SparseMode::Never] {
+            let dir = tempfile::tempdir().unwrap();
+            let path = dir.path().join("file");
+            let mut writer = SparseWriter::new(BufWriter::new(fs::File::create(&path).unwrap()), mode);
+            // In uneven pieces, which don't line up with the blocks
+            for piece in contents.chunks(3001) {
+                writer.write_all(piece).unwrap();
+            }
+            writer.hole(10_000).unwrap();
+            assert_eq!(writer.size(), contents.len() as u64 + 10_000);
+            writer.finish().unwrap();
+
+            let mut expected = contents.clone();
+            expected.resize(contents.len() + 10_000, 0);
+            assert_eq!(fs::read(&path).unwrap(), expected, "mode: {mode:?}");
+        }
+    }
+}
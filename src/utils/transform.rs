@@ -0,0 +1,123 @@
+//! Renaming of archive entries through regex substitutions, used by `--transform`.
+
+use std::path::{Component, Path, PathBuf};
+
+use regex::Regex;
+
+use crate::{error::FinalError, utils::EscapedPathDisplay};
+
+/// Substitutions applied in order to the path of each entry, when archiving or extracting.
+#[derive(Debug, Clone, Default)]
+pub struct Transforms {
+    rules: Vec<(Regex, String)>,
+}
+
+impl Transforms {
+    /// Parse `rules` in the `<regex>=<replacement>` form, the replacement can refer to capture
+    /// groups of the regex, like `$1`.
+    pub fn new(rules: &[String]) -> crate::Result<Self> {
+        let rules = rules
+            .iter()
+            .map(|rule| {
+                let invalid_rule = |detail: String| {
+                    FinalError::with_title(format!("Invalid transform '{rule}'"))
+                        .detail(detail)
+                        .hint("Transforms are written as '<regex>=<replacement>', for example:")
+                        .hint("  --transform '^=myapp-1.2/'")
+                };
+
+                let (regex, replacement) = rule
+                    .split_once('=')
+                    .ok_or_else(|| invalid_rule("Missing '=' between the regex and the replacement".to_string()))?;
+                let regex = Regex::new(regex).map_err(|err| invalid_rule(err.to_string()))?;
+
+                Ok((regex, replacement.to_string()))
+            })
+            .collect::<crate::Result<_>>()?;
+
+        Ok(Self { rules })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Apply every substitution to `path`, in order.
+    ///
+    /// Fails if the result is empty, absolute or goes up with `..`, since it could be written
+    /// outside of the output directory.
+    pub fn apply(&self, path: &Path) -> crate::Result<PathBuf> {
+        if self.is_empty() {
+            return Ok(path.to_path_buf());
+        }
+
+        let display = EscapedPathDisplay::new(path);
+        let Some(name) = path.to_str() else {
+            let error = FinalError::with_title(format!("Cannot transform '{display}'"))
+                .detail("Transforms only work on valid UTF-8 paths");
+            return Err(error.into());
+        };
+
+        let mut name = name.to_string();
+        for (regex, replacement) in &self.rules {
+            name = regex.replace(&name, replacement.as_str()).into_owned();
+        }
+
+        let transformed = PathBuf::from(name);
+        let is_safe = transformed
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+
+        if transformed.as_os_str().is_empty() || !is_safe {
+            let error = FinalError::with_title(format!("Cannot transform '{display}'"))
+                .detail(format!("It resulted in the invalid path '{}'", transformed.display()))
+                .detail("Transformed paths can't be empty, absolute or contain '..'");
+            return Err(error.into());
+        }
+
+        Ok(transformed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transforms(rules: &[&str]) -> crate::Result<Transforms> {
+        let rules: Vec<String> = rules.iter().map(|rule| rule.to_string()).collect();
+        Transforms::new(&rules)
+    }
+
+    #[test]
+    fn test_transforms() {
+        let prefix = transforms(&["^=myapp-1.2/"]).unwrap();
+        assert_eq!(
+            prefix.apply(Path::new("src/main.rs")).unwrap(),
+            Path::new("myapp-1.2/src/main.rs")
+        );
+
+        let in_order = transforms(&["^build/=", r"\.txt$=.md", "(.*)/(.*)=$2/$1"]).unwrap();
+        assert_eq!(
+            in_order.apply(Path::new("build/docs/a.txt")).unwrap(),
+            Path::new("a.md/docs")
+        );
+
+        let none = transforms(&[]).unwrap();
+        assert_eq!(none.apply(Path::new("a/b")).unwrap(), Path::new("a/b"));
+    }
+
+    #[test]
+    fn test_invalid_transforms() {
+        assert!(transforms(&["no-separator"]).is_err());
+        assert!(transforms(&["(unclosed=x"]).is_err());
+
+        let escape = transforms(&["^=../"]).unwrap();
+        assert!(escape.apply(Path::new("file")).is_err());
+
+        let absolute = transforms(&["^=/"]).unwrap();
+        assert!(absolute.apply(Path::new("file")).is_err());
+
+        let empty = transforms(&[".*="]).unwrap();
+        assert!(empty.apply(Path::new("file")).is_err());
+    }
+}
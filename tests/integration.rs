@@ -6,11 +6,11 @@ use std::{iter::once, path::PathBuf};
 use fs_err as fs;
 use parse_display::Display;
 use proptest::sample::size_range;
-use rand::{rngs::SmallRng, Rng, SeedableRng};
+use rand::{rngs::SmallRng, Rng, RngCore, SeedableRng};
 use tempfile::tempdir;
 use test_strategy::{proptest, Arbitrary};
 
-use crate::utils::{assert_same_directory, write_random_content};
+use crate::utils::{assert_same_directory, ouch_in, write_random_content};
 
 /// tar and zip extensions
 #[derive(Arbitrary, Debug, Display)]
@@ -151,6 +151,3371 @@ fn multiple_files(
     assert_same_directory(before, after, !matches!(ext, DirectoryExtension::Zip));
 }
 
+#[test]
+fn compress_and_remove_inputs() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let input_dir = &dir.join("logs");
+    let input_file = &dir.join("file");
+    let archive = &dir.join("archive.tar.zst");
+    fs::create_dir(input_dir).unwrap();
+    fs::write(input_dir.join("log"), "log").unwrap();
+    fs::write(input_file, "file").unwrap();
+
+    ouch!("-A", "c", input_dir, input_file, archive, "--rm");
+
+    assert!(archive.exists());
+    assert!(!input_dir.exists());
+    assert!(!input_file.exists());
+}
+
+#[test]
+fn compress_each_into_directory() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let output_dir = &dir.join("output");
+    fs::create_dir(output_dir).unwrap();
+    fs::write(dir.join("a"), "a").unwrap();
+    fs::write(dir.join("b"), "b").unwrap();
+
+    ouch!(
+        "-A",
+        "c",
+        dir.join("a"),
+        dir.join("b"),
+        output_dir,
+        "--each",
+        "--format",
+        "tar.gz"
+    );
+
+    assert!(output_dir.join("a.tar.gz").is_file());
+    assert!(output_dir.join("b.tar.gz").is_file());
+}
+
+#[test]
+fn compress_format_adds_extension() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    fs::write(dir.join("file"), "file").unwrap();
+
+    ouch_in(dir, &["c", "file", "archive", "--format", "tar.gz"]).success();
+    assert!(dir.join("archive.tar.gz").is_file());
+    assert!(!dir.join("archive").exists());
+
+    // Only the missing extensions are added
+    ouch_in(dir, &["c", "file", "partial.tar", "--format", "tar.gz"]).success();
+    assert!(dir.join("partial.tar.gz").is_file());
+
+    ouch_in(dir, &["c", "file", "complete.tgz", "--format", "tar.gz"]).success();
+    assert!(dir.join("complete.tgz").is_file());
+
+    // Named after other formats, it's kept as it is
+    let other = ouch_in(dir, &["c", "file", "other.zip", "--format", "tar.gz"]).success();
+    let stderr = String::from_utf8(other.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains("doesn't end in '.tar.gz'"));
+    assert!(dir.join("other.zip").is_file());
+
+    ouch_in(dir, &["d", "archive.tar.gz", "--dir", "out"]).success();
+    assert_eq!(fs::read(dir.join("out/file")).unwrap(), b"file");
+}
+
+#[test]
+fn numeric_progress() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+
+    // Incompressible, so that the archive is about as big as the file
+    let mut data = vec![0; 1024 * 1024];
+    SmallRng::from_entropy().fill(&mut data[..]);
+    fs::write(dir.join("file"), &data).unwrap();
+
+    let percentages = |output: &assert_cmd::assert::Assert| -> Vec<u64> {
+        let stderr = String::from_utf8(output.get_output().stderr.clone()).unwrap();
+        stderr
+            .lines()
+            .filter_map(|line| line.strip_prefix("[INFO] ")?.split_once("% (")?.0.parse().ok())
+            .collect()
+    };
+
+    // The progress moves one buffer at a time, so they're kept small for a line every 10%
+    let compressed = ouch_in(
+        dir,
+        &["c", "file", "file.gz", "--numeric-progress", "--buffer-size", "64KiB"],
+    )
+    .success();
+    assert_eq!(percentages(&compressed), [10, 20, 30, 40, 50, 60, 70, 80, 90, 100]);
+    let stderr = String::from_utf8(compressed.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains("100% (1048576/1048576 bytes)"), "{stderr}");
+
+    let decompressed = ouch_in(
+        dir,
+        &[
+            "d",
+            "file.gz",
+            "--dir",
+            "out",
+            "--numeric-progress",
+            "--buffer-size",
+            "64KiB",
+        ],
+    )
+    .success();
+    let percentages = percentages(&decompressed);
+    assert!(percentages.len() > 1, "{percentages:?}");
+    assert!(percentages.windows(2).all(|pair| pair[0] < pair[1]), "{percentages:?}");
+    assert_eq!(percentages.last(), Some(&100));
+    assert_eq!(fs::read(dir.join("out/file")).unwrap(), data);
+}
+
+#[test]
+fn compress_big_file_mapped() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+
+    // Past the size from which single files are memory-mapped, read through small buffers too
+    let data: Vec<u8> = (0..33 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    fs::write(dir.join("file"), &data).unwrap();
+
+    ouch_in(dir, &["c", "file", "mapped.lz4"]).success();
+    ouch_in(
+        dir,
+        &["c", "file", "buffered.lz4", "--no-mmap", "--buffer-size", "4096"],
+    )
+    .success();
+    for archive in ["mapped.lz4", "buffered.lz4"] {
+        ouch_in(dir, &["d", archive, "--dir", archive.trim_end_matches(".lz4")]).success();
+    }
+    assert_eq!(fs::read(dir.join("mapped/mapped")).unwrap(), data);
+    assert_eq!(fs::read(dir.join("buffered/buffered")).unwrap(), data);
+}
+
+#[test]
+fn compress_with_top_level_name() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+
+    fs::create_dir_all(dir.join("build/bin")).unwrap();
+    fs::write(dir.join("build/bin/app"), "app").unwrap();
+    fs::write(dir.join("build/README"), "readme").unwrap();
+
+    for archive in ["release.tar.gz", "release.zip", "release.7z"] {
+        ouch_in(dir, &["c", "./build", archive, "--name", "project-1.2"]).success();
+        let out = format!("out-{archive}");
+        ouch_in(dir, &["d", archive, "--dir", &out]).success();
+
+        let out = dir.join(out);
+        assert_eq!(fs::read(out.join("project-1.2/bin/app")).unwrap(), b"app");
+        assert_eq!(fs::read(out.join("project-1.2/README")).unwrap(), b"readme");
+        assert!(!out.join("build").exists());
+    }
+
+    // Only a single directory can be renamed
+    ouch_in(dir, &["c", "build/README", "file.tar", "--name", "project"]).failure();
+    ouch_in(dir, &["c", "build", "build", "two.tar", "--name", "project"]).failure();
+    ouch_in(dir, &["c", "build", "escape.tar", "--name", "../project"]).failure();
+}
+
+#[test]
+fn custom_buffer_size() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+
+    fs::create_dir(dir.join("input")).unwrap();
+    let mut data = vec![0; 100_000];
+    SmallRng::from_entropy().fill(&mut data[..]);
+    fs::write(dir.join("input/file"), &data).unwrap();
+
+    // Buffers way smaller and way bigger than the input
+    for (small, big) in [("4KiB", "16MiB"), ("16MiB", "1")] {
+        for archive in ["archive.tar.gz", "archive.zip", "archive.7z"] {
+            ouch_in(dir, &["-y", "c", "input", archive, "--buffer-size", small]).success();
+            let out = format!("out-{small}-{archive}");
+            ouch_in(dir, &["d", archive, "--dir", &out, "--buffer-size", big]).success();
+            assert_eq!(fs::read(dir.join(out).join("input/file")).unwrap(), data);
+        }
+    }
+
+    ouch_in(dir, &["c", "input", "zero.tar", "--buffer-size", "0"]).failure();
+    ouch_in(dir, &["c", "input", "huge.tar", "--buffer-size", "2GiB"]).failure();
+}
+
+#[test]
+fn compress_each_in_parallel() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let output_dir = &dir.join("output");
+    let files: Vec<_> = (0..10).map(|i| dir.join(format!("file{i}"))).collect();
+    for (i, file) in files.iter().enumerate() {
+        fs::write(file, i.to_string()).unwrap();
+    }
+
+    crate::utils::cargo_bin()
+        .args(["-A", "c", "--each", "--format", "zip", "--threads", "4"])
+        .args(&files)
+        .arg(output_dir)
+        .assert()
+        .success();
+
+    for (i, file) in files.iter().enumerate() {
+        let archive = output_dir.join(format!("file{i}.zip"));
+        assert!(archive.is_file());
+
+        // Every archive has the contents of its own input
+        let unpacked = &dir.join(format!("unpacked{i}"));
+        ouch!("-A", "d", &archive, "-d", unpacked);
+        assert_eq!(
+            fs::read(unpacked.join(file.file_name().unwrap())).unwrap(),
+            i.to_string().as_bytes()
+        );
+    }
+}
+
+#[test]
+fn report_time() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let input = &dir.join("file");
+    fs::write(input, "file").unwrap();
+
+    let output = crate::utils::cargo_bin()
+        .args(["-A", "c", "--time"])
+        .arg(input)
+        .arg(dir.join("file.tar.gz"))
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let report = stderr.lines().find(|line| line.contains("Compressed in")).unwrap();
+
+    // Like "Compressed in 0.01s (1.20 kB/s)."
+    let (duration, throughput) = report.split_once(" (").unwrap();
+    assert!(duration.ends_with('s'));
+    assert!(duration
+        .rsplit(' ')
+        .next()
+        .unwrap()
+        .trim_end_matches('s')
+        .parse::<f64>()
+        .is_ok());
+    assert!(throughput.ends_with("B/s)."));
+
+    // Nothing is reported with --quiet
+    let output = crate::utils::cargo_bin()
+        .args(["-A", "-q", "-y", "c", "--time"])
+        .arg(input)
+        .arg(dir.join("file.tar.gz"))
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    assert!(!String::from_utf8(output.stderr).unwrap().contains("Compressed in"));
+}
+
+/// Start compressing `input` into `archive`, which then waits for an answer on stdin that never
+/// comes, and wait for its temporary output to be created
+#[cfg(unix)]
+fn spawn_stuck_compression(dir: &std::path::Path) -> std::process::Child {
+    use std::{
+        process::{Command, Stdio},
+        thread,
+        time::Duration,
+    };
+
+    let input = &dir.join("input");
+    let archive = &dir.join("archive.zip.gz");
+    fs::write(input, "input").unwrap();
+
+    // Buffering the zip archive in memory before compressing it is asked about after creating the
+    // temporary output, and stdin is never written to, so compression stays in progress until interrupted
+    let child = Command::new(assert_cmd::cargo::cargo_bin("ouch"))
+        .args([
+            "c".as_ref(),
+            input.as_os_str(),
+            archive.as_os_str(),
+            "--no-spill".as_ref(),
+        ])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    // The archive is written to a temporary file next to it until it's complete
+    let entries = || fs::read_dir(dir).unwrap().count();
+    for _ in 0..100 {
+        if entries() == 2 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(entries(), 2);
+    child
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupt_removes_incomplete_output() {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        let dir = tempdir().unwrap();
+        let dir = dir.path();
+        let mut child = spawn_stuck_compression(dir);
+
+        // Waiting closes stdin, which would answer the question
+        let _stdin = child.stdin.take();
+        unsafe { libc::kill(child.id() as libc::pid_t, signal) };
+        let status = child.wait().unwrap();
+        assert_eq!(status.code(), Some(130));
+        assert!(!dir.join("archive.zip.gz").exists());
+        assert_eq!(fs::read_dir(dir).unwrap().count(), 1);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupt_twice_exits_right_away() {
+    use std::time::{Duration, Instant};
+
+    let dir = tempdir().unwrap();
+    let mut child = spawn_stuck_compression(dir.path());
+
+    let _stdin = child.stdin.take();
+    let start = Instant::now();
+    for _ in 0..2 {
+        unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    let status = child.wait().unwrap();
+    assert_eq!(status.code(), Some(130));
+    // Without waiting for the grace period given to the cleanup
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[cfg(unix)]
+#[test]
+fn decompress_same_permissions() {
+    use std::{os::unix::fs::PermissionsExt, process::Command};
+
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    fs::create_dir(dir.join("input")).unwrap();
+    fs::write(dir.join("input/file"), "file").unwrap();
+    fs::set_permissions(dir.join("input/file"), std::fs::Permissions::from_mode(0o777)).unwrap();
+
+    for format in ["tar", "zip"] {
+        let archive = format!("archive.{format}");
+        crate::utils::cargo_bin()
+            .args(["-A", "c", "input", &archive])
+            .current_dir(dir)
+            .assert()
+            .success();
+
+        // Under a known umask, which is only applied without `--same-permissions`
+        let mode = |flag: &str| {
+            let output = format!("{format}{flag}");
+            let status = Command::new("sh")
+                .arg("-c")
+                .arg(r#"umask 027 && exec "$0" -A d "$1" -d "$2" "$3""#)
+                .args([assert_cmd::cargo::cargo_bin("ouch").as_os_str(), archive.as_ref()])
+                .args([&output, flag])
+                .current_dir(dir)
+                .status()
+                .unwrap();
+            assert!(status.success());
+            fs::metadata(dir.join(output).join("input/file"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777
+        };
+        assert_eq!(mode("--no-same-permissions"), 0o750, "{format}");
+        assert_eq!(mode("--same-permissions"), 0o777, "{format}");
+    }
+}
+
+#[test]
+fn decompress_failure_leaves_no_partial_file() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+
+    let mut data = vec![0; 100_000];
+    SmallRng::seed_from_u64(0).fill(&mut data[..]);
+    fs::write(dir.join("file"), &data).unwrap();
+    ouch_in(dir, &["c", "file", "file.gz"]).success();
+    fs::remove_file(dir.join("file")).unwrap();
+
+    // Cut in half, so it fails halfway through being written
+    let compressed = fs::read(dir.join("file.gz")).unwrap();
+    fs::write(dir.join("file.gz"), &compressed[..compressed.len() / 2]).unwrap();
+    ouch_in(dir, &["d", "file.gz"]).failure();
+    let leftovers: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(leftovers, ["file.gz"]);
+
+    // The file it would have replaced is left as it was
+    fs::write(dir.join("file"), "old").unwrap();
+    ouch_in(dir, &["d", "file.gz", "--yes"]).failure();
+    assert_eq!(fs::read(dir.join("file")).unwrap(), b"old");
+
+    // Complete, it replaces it, with the permissions of a new file
+    fs::write(dir.join("file.gz"), &compressed).unwrap();
+    ouch_in(dir, &["d", "file.gz", "--yes"]).success();
+    assert_eq!(fs::read(dir.join("file")).unwrap(), data);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::write(dir.join("new"), "new").unwrap();
+        let mode = |path: &str| fs::metadata(dir.join(path)).unwrap().permissions().mode();
+        assert_eq!(mode("file"), mode("new"));
+    }
+}
+
+#[test]
+fn decompress_limits() {
+    for format in ["zip", "7z", "tar.gz", "gz"] {
+        let dir = tempdir().unwrap();
+        let dir = dir.path();
+        let input = &dir.join("zeros");
+        let archive = &dir.join(format!("zeros.{format}"));
+        let output = &dir.join("output");
+        fs::write(input, vec![0; 20_000_000]).unwrap();
+        ouch!("-A", "c", input, archive);
+
+        let failed = crate::utils::cargo_bin()
+            .args(["-A", "d"])
+            .arg(archive)
+            .arg("-d")
+            .arg(output)
+            .args(["--max-output-size", "5M", "--max-ratio", "1000000", "--yes"])
+            .assert()
+            .code(1);
+        let stderr = String::from_utf8(failed.get_output().stderr.clone()).unwrap();
+        assert!(stderr.contains("the maximum output size"), "format: {format}");
+        assert_eq!(fs::read_dir(output).unwrap().count(), 0, "format: {format}");
+
+        ouch!("-A", "d", archive, "-d", output, "--no-limits");
+        assert_eq!(fs::metadata(output.join("zeros")).unwrap().len(), 20_000_000);
+    }
+
+    // Zeros compress well beyond the default ratio
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let input = &dir.join("zeros");
+    let archive = &dir.join("zeros.zip");
+    fs::write(input, vec![0; 20_000_000]).unwrap();
+    ouch!("-A", "c", input, archive);
+
+    let failed = crate::utils::cargo_bin()
+        .args(["-A", "d"])
+        .arg(archive)
+        .arg("-d")
+        .arg(dir.join("output"))
+        .arg("--yes")
+        .assert()
+        .code(1);
+    let stderr = String::from_utf8(failed.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains("times its compressed size"));
+}
+
+#[test]
+fn decompress_entry_limits() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let input = &dir.join("input");
+    let deep = PathBuf::from_iter(["a", "b", "c", "d"]);
+    fs::create_dir_all(input.join(&deep)).unwrap();
+    for i in 0..5 {
+        fs::write(input.join(format!("{i}")), "file").unwrap();
+    }
+
+    for format in ["zip", "7z", "tar.gz"] {
+        let archive = &dir.join(format!("archive.{format}"));
+        let output = &dir.join(format!("output-{format}"));
+        ouch!("-A", "c", input, archive);
+
+        for (flag, value, reason) in [
+            ("--max-entries", "3", "more than 3 entries"),
+            ("--max-path-depth", "4", "more than the maximum path depth of 4"),
+            ("--max-path-length", "8", "more than the maximum path length of 8"),
+        ] {
+            let failed = crate::utils::cargo_bin()
+                .args(["-A", "d"])
+                .arg(archive)
+                .arg("-d")
+                .arg(output)
+                .args([flag, value, "--yes"])
+                .assert()
+                .code(1);
+            let stderr = String::from_utf8(failed.get_output().stderr.clone()).unwrap();
+            assert!(
+                stderr.contains("Stopped extracting at the entry"),
+                "format: {format}, {flag}"
+            );
+            assert!(stderr.contains(reason), "format: {format}, {flag}");
+            assert_eq!(fs::read_dir(output).unwrap().count(), 0, "format: {format}, {flag}");
+        }
+
+        // Listing still shows everything
+        let listed = crate::utils::cargo_bin()
+            .args(["-A", "l"])
+            .arg(archive)
+            .assert()
+            .success();
+        let stdout = String::from_utf8(listed.get_output().stdout.clone()).unwrap();
+        assert!(stdout.contains("input/a/b/c/d"), "format: {format}");
+
+        ouch!("-A", "d", archive, "-d", output, "--no-limits");
+        assert!(output.join("input").join(&deep).is_dir());
+    }
+}
+
+#[test]
+fn compress_empty_inputs() {
+    for format in ["tar.gz", "zip", "7z"] {
+        let dir = tempdir().unwrap();
+        let dir = dir.path();
+        let archive = &dir.join(format!("archive.{format}"));
+        let after = &dir.join("after");
+        fs::create_dir(dir.join("empty")).unwrap();
+        fs::write(dir.join("zero"), "").unwrap();
+
+        let output = crate::utils::cargo_bin()
+            .args(["c", "empty", "zero", archive.to_str().unwrap()])
+            .current_dir(dir)
+            .assert()
+            .success();
+        let stderr = String::from_utf8(output.get_output().stderr.clone()).unwrap();
+        assert!(stderr.contains("'empty' is an empty directory"), "format: {format}");
+        assert!(stderr.contains("'zero' is an empty file"), "format: {format}");
+
+        ouch!("-A", "d", archive, "-d", after);
+        let unpacked = &after.join("archive");
+        assert_eq!(
+            fs::read_dir(unpacked.join("empty")).unwrap().count(),
+            0,
+            "format: {format}"
+        );
+        assert_eq!(fs::read(unpacked.join("zero")).unwrap(), b"", "format: {format}");
+    }
+
+    // Single file formats also keep zero-byte files
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    fs::write(dir.join("zero"), "").unwrap();
+    ouch!("-A", "c", dir.join("zero"), dir.join("zero.gz"));
+    ouch!("-A", "d", dir.join("zero.gz"), "-d", dir.join("after"));
+    assert_eq!(fs::read(dir.join("after/zero")).unwrap(), b"");
+}
+
+#[test]
+fn decompress_and_remove_archive() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let input = &dir.join("file");
+    let archive = &dir.join("file.gz");
+    let after = &dir.join("after");
+    fs::write(input, "file").unwrap();
+    ouch!("-A", "c", input, archive);
+
+    // The archive must be kept if the user refuses to overwrite the output
+    fs::create_dir(after).unwrap();
+    fs::write(after.join("file"), "").unwrap();
+    crate::utils::cargo_bin()
+        .args(["-A", "d", "--rm", "--no", "-d"])
+        .arg(after)
+        .arg(archive)
+        .assert()
+        .code(4);
+    assert!(archive.exists());
+
+    fs::remove_file(after.join("file")).unwrap();
+    ouch!("-A", "d", archive, "-d", after, "--rm");
+    assert!(!archive.exists());
+    assert_eq!(fs::read(after.join("file")).unwrap(), b"file");
+}
+
+#[test]
+fn zip_archive_comment() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let input = &dir.join("file");
+    let archive = &dir.join("archive.zip");
+    fs::write(input, "file").unwrap();
+
+    ouch!("-A", "c", input, archive, "--comment", "nightly build");
+    let output = ouch!("-A", "l", archive);
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .contains("Comment: nightly build"));
+
+    // Other formats can't store the comment
+    crate::utils::cargo_bin()
+        .args(["-A", "c", "--comment", "nightly build"])
+        .arg(input)
+        .arg(dir.join("archive.tar"))
+        .assert()
+        .failure();
+}
+
+#[test]
+fn decompress_flatten() {
+    for format in ["zip", "tar.gz", "7z"] {
+        let dir = tempdir().unwrap();
+        let dir = dir.path();
+        let input = &dir.join("input");
+        let archive = &dir.join(format!("archive.{format}"));
+        let after = &dir.join("after");
+        fs::create_dir_all(input.join("a/b")).unwrap();
+        fs::create_dir_all(input.join("c")).unwrap();
+        fs::write(input.join("a/x"), "x").unwrap();
+        fs::write(input.join("a/b/y"), "y").unwrap();
+        fs::write(input.join("a/same"), "first").unwrap();
+        fs::write(input.join("c/same"), "second").unwrap();
+
+        ouch!("-A", "c", input, archive);
+        ouch!("-A", "d", archive, "-d", after, "--flatten");
+
+        let mut files: Vec<_> = fs::read_dir(after)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["same", "x", "y"], "format: {format}");
+    }
+}
+
+#[test]
+fn decompress_flatten_rename() {
+    for format in ["zip", "tar.gz", "7z"] {
+        let dir = tempdir().unwrap();
+        let dir = dir.path();
+        let input = &dir.join("input");
+        let archive = &dir.join(format!("archive.{format}"));
+        let after = &dir.join("after");
+        fs::create_dir_all(input.join("a/b")).unwrap();
+        fs::write(input.join("a/photo.jpg"), "first").unwrap();
+        fs::write(input.join("a/b/photo.jpg"), "second").unwrap();
+        fs::write(input.join("photo.jpg"), "third").unwrap();
+
+        ouch!("-A", "c", input, archive);
+        ouch!("-A", "d", archive, "-d", after, "--flatten", "--rename");
+
+        let mut files: Vec<_> = fs::read_dir(after)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        files.sort();
+        assert_eq!(files, ["photo.jpg", "photo_1.jpg", "photo_2.jpg"], "format: {format}");
+
+        // Every file is kept, in whichever order they were extracted
+        let mut contents: Vec<_> = files
+            .iter()
+            .map(|file| fs::read_to_string(after.join(file)).unwrap())
+            .collect();
+        contents.sort();
+        assert_eq!(contents, ["first", "second", "third"], "format: {format}");
+    }
+}
+
+#[test]
+fn decompress_over_existing_files_summary() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let input = &dir.join("input");
+    let archive = &dir.join("archive.tar");
+    let after = &dir.join("after");
+    fs::create_dir_all(input.join("sub")).unwrap();
+    fs::create_dir(after).unwrap();
+    for file in ["new", "sub/other", "first", "sub/second"] {
+        fs::write(input.join(file), "extracted").unwrap();
+    }
+    fs::write(after.join("first"), "existing").unwrap();
+    fs::write(after.join("second"), "existing").unwrap();
+    ouch!("-A", "c", input, archive);
+
+    // One of the existing files is overwritten and the other one is kept, whichever comes first
+    let output = crate::utils::cargo_bin()
+        .args([
+            "d".as_ref(),
+            archive.as_os_str(),
+            "-d".as_ref(),
+            after.as_os_str(),
+            "--flatten".as_ref(),
+        ])
+        .write_stdin("y\nn\n")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("Extracted over existing files: 2 created, 1 overwritten and 1 skipped."),
+        "{stderr}"
+    );
+    assert!(stderr.contains("Created ") && stderr.contains("Overwrote ") && stderr.contains("Skipped "));
+
+    let mut contents: Vec<_> = ["new", "other", "first", "second"]
+        .iter()
+        .map(|file| fs::read_to_string(after.join(file)).unwrap())
+        .collect();
+    contents.sort();
+    assert_eq!(contents, ["existing", "extracted", "extracted", "extracted"]);
+}
+
+#[test]
+fn decompress_filter_ignore_case() {
+    for format in ["zip", "tar.gz", "7z"] {
+        let dir = tempdir().unwrap();
+        let dir = dir.path();
+        let input = &dir.join("input");
+        let archive = &dir.join(format!("archive.{format}"));
+        let after = &dir.join("after");
+        fs::create_dir(input).unwrap();
+        fs::write(input.join("readme.md"), "readme").unwrap();
+        fs::write(input.join("main.rs"), "main").unwrap();
+        ouch!("-A", "c", input, archive);
+
+        // Matching is case-sensitive by default
+        ouch!("-A", "d", archive, "-d", after, "--filter", "*/README.md");
+        assert!(!after.join("input").exists(), "format: {format}");
+
+        ouch!(
+            "-A",
+            "d",
+            archive,
+            "-d",
+            after,
+            "--filter",
+            "*/README.md",
+            "--ignore-case"
+        );
+        assert_eq!(
+            fs::read(after.join("input/readme.md")).unwrap(),
+            b"readme",
+            "format: {format}"
+        );
+        assert!(!after.join("input/main.rs").exists(), "format: {format}");
+
+        let output = ouch!("-A", "l", archive, "--filter", "*/README.MD", "--ignore-case");
+        let output = String::from_utf8(output.stdout).unwrap();
+        assert!(output.contains("input/readme.md"), "format: {format}");
+        assert!(!output.contains("main.rs"), "format: {format}");
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn external_codec_command() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    fs::create_dir(dir.join("input")).unwrap();
+    fs::write(dir.join("input/first"), "first").unwrap();
+    fs::write(dir.join("input/second"), "second".repeat(1000)).unwrap();
+
+    // Through a command that passes the stream as it is, the archive is a plain tar
+    ouch_in(
+        dir,
+        &["c", "input", "archive.tar.xyz", "--codec-compress-cmd", "xyz=cat"],
+    )
+    .success();
+    let mut archive = tar::Archive::new(fs::File::open(dir.join("archive.tar.xyz")).unwrap());
+    assert_eq!(archive.entries().unwrap().count(), 3);
+
+    // What the command prints to stderr is shown
+    let assert = ouch_in(
+        dir,
+        &[
+            "d",
+            "archive.tar.xyz",
+            "-d",
+            "output",
+            "--codec-cmd",
+            ".xyz=echo decoding >&2; cat",
+        ],
+    )
+    .success();
+    let stderr = String::from_utf8(assert.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains("decoding"), "{stderr}");
+    assert_eq!(fs::read(dir.join("output/input/first")).unwrap(), b"first");
+    assert_eq!(
+        fs::read(dir.join("output/input/second")).unwrap(),
+        "second".repeat(1000).as_bytes()
+    );
+
+    // Each direction needs its own command
+    ouch_in(
+        dir,
+        &[
+            "d",
+            "archive.tar.xyz",
+            "-d",
+            "missing",
+            "--codec-compress-cmd",
+            "xyz=cat",
+        ],
+    )
+    .failure();
+    assert!(!dir.join("missing/input").exists());
+
+    // A command that fails makes the compression fail
+    ouch_in(
+        dir,
+        &[
+            "c",
+            "input/first",
+            "first.xyz",
+            "--codec-compress-cmd",
+            "xyz=cat >/dev/null; exit 3",
+        ],
+    )
+    .failure();
+
+    // Built-in extensions can't be replaced
+    ouch_in(dir, &["c", "input", "archive.tar.gz", "--codec-compress-cmd", "gz=cat"]).failure();
+}
+
+#[test]
+fn decompress_interactive() {
+    use std::io::Write;
+
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let entries = ["dir/first", "dir/second", "dir/sub/third"];
+
+    let mut builder = tar::Builder::new(vec![]);
+    for name in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(name.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, name, name.as_bytes()).unwrap();
+    }
+    fs::write(dir.join("archive.tar"), builder.into_inner().unwrap()).unwrap();
+
+    let mut writer = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+    for name in entries {
+        writer.start_file(name, zip::write::FileOptions::default()).unwrap();
+        writer.write_all(name.as_bytes()).unwrap();
+    }
+    fs::write(dir.join("archive.zip"), writer.finish().unwrap().into_inner()).unwrap();
+
+    for format in ["tar", "zip"] {
+        let output = dir.join(format!("output-{format}"));
+
+        // Only the entries answered with yes are extracted, along with their directories
+        crate::utils::cargo_bin()
+            .arg("d")
+            .arg(format!("archive.{format}"))
+            .arg("-d")
+            .arg(&output)
+            .arg("--interactive")
+            .current_dir(dir)
+            .write_stdin("y\nn\ny\n")
+            .assert()
+            .success();
+        assert_eq!(
+            fs::read(output.join("dir/first")).unwrap(),
+            b"dir/first",
+            "format: {format}"
+        );
+        assert!(!output.join("dir/second").exists(), "format: {format}");
+        assert_eq!(
+            fs::read(output.join("dir/sub/third")).unwrap(),
+            b"dir/sub/third",
+            "format: {format}"
+        );
+
+        // The answers can't be given by --yes or --no
+        crate::utils::cargo_bin()
+            .args(["d", &format!("archive.{format}"), "-d", "yes", "--interactive", "--yes"])
+            .current_dir(dir)
+            .assert()
+            .failure();
+    }
+}
+
+#[test]
+fn decompress_exclude() {
+    for format in ["zip", "tar.gz", "7z"] {
+        let dir = tempdir().unwrap();
+        let dir = dir.path();
+        let input = &dir.join("input");
+        let archive = &dir.join(format!("archive.{format}"));
+        let after = &dir.join("after");
+        fs::create_dir_all(input.join("lib")).unwrap();
+        fs::write(input.join("lib/libfoo.so"), "debug").unwrap();
+        fs::write(input.join("lib/notes.txt"), "notes").unwrap();
+        fs::write(input.join("main.rs"), "main").unwrap();
+        fs::write(input.join("README.md"), "readme").unwrap();
+        ouch!("-A", "c", input, archive);
+
+        let patterns = &dir.join("patterns.txt");
+        fs::write(patterns, "# Debug blobs\n*.so\n\n  *.txt  \n").unwrap();
+
+        // Excludes win over --filter
+        ouch!(
+            "-A",
+            "d",
+            archive,
+            "-d",
+            after,
+            "--filter",
+            "input/lib/*",
+            "--filter",
+            "*.rs",
+            "--exclude",
+            "*.txt"
+        );
+        assert!(after.join("input/lib/libfoo.so").exists(), "format: {format}");
+        assert!(after.join("input/main.rs").exists(), "format: {format}");
+        assert!(!after.join("input/lib/notes.txt").exists(), "format: {format}");
+        assert!(!after.join("input/README.md").exists(), "format: {format}");
+        fs::remove_dir_all(after).unwrap();
+
+        ouch!("-A", "d", archive, "-d", after, "--exclude-from", patterns);
+        assert!(!after.join("input/lib/libfoo.so").exists(), "format: {format}");
+        assert!(!after.join("input/lib/notes.txt").exists(), "format: {format}");
+        assert_eq!(
+            fs::read(after.join("input/main.rs")).unwrap(),
+            b"main",
+            "format: {format}"
+        );
+        assert_eq!(
+            fs::read(after.join("input/README.md")).unwrap(),
+            b"readme",
+            "format: {format}"
+        );
+    }
+}
+
+#[test]
+fn decompress_to_stdout() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+
+    let contents: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+    fs::write(dir.join("file"), &contents).unwrap();
+    ouch_in(dir, &["-A", "c", "file", "file.gz"]).success();
+    fs::remove_file(dir.join("file")).unwrap();
+
+    let output = ouch_in(dir, &["d", "file.gz", "-c"]).success();
+    assert_eq!(output.get_output().stdout, contents);
+    assert!(!dir.join("file").exists());
+
+    fs::create_dir(dir.join("input")).unwrap();
+    fs::write(dir.join("input/a"), "a").unwrap();
+    fs::write(dir.join("input/b"), "b").unwrap();
+    ouch_in(dir, &["-A", "c", "input", "archive.tar.gz"]).success();
+
+    // Archives need a --filter that selects one of their files
+    ouch_in(dir, &["d", "archive.tar.gz", "--stdout"]).failure();
+    ouch_in(dir, &["d", "archive.tar.gz", "--stdout", "--filter", "input/a"])
+        .success()
+        .stdout("a");
+
+    // Other files have no entries to filter, and the flags that refine the filter aren't supported
+    ouch_in(dir, &["d", "file.gz", "--stdout", "--filter", "file"]).failure();
+    for flag in [&["--exclude", "input/b"][..], &["--ignore-case"]] {
+        let args = [&["d", "archive.tar.gz", "--stdout", "--filter", "input/*"], flag].concat();
+        ouch_in(dir, &args).failure().stdout("");
+    }
+}
+
+#[test]
+fn compress_files_from() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+
+    fs::create_dir_all(dir.join("input/sub dir")).unwrap();
+    fs::write(dir.join("input/plain"), "plain").unwrap();
+    fs::write(dir.join("input/with space"), "with space").unwrap();
+    fs::write(dir.join("input/sub dir/nested"), "nested").unwrap();
+    let mut names = vec!["input/plain", "input/with space", "input/sub dir"];
+    #[cfg(unix)]
+    {
+        fs::write(dir.join("input/with\nnewline"), "with newline").unwrap();
+        names.push("input/with\nnewline");
+    }
+
+    let list: Vec<u8> = names
+        .iter()
+        .flat_map(|name| [name.as_bytes(), b"\0"].concat())
+        .collect();
+    crate::utils::cargo_bin()
+        .args(["-A", "c", "--files-from", "-", "--null", "archive.tar"])
+        .current_dir(dir)
+        .write_stdin(list)
+        .assert()
+        .success();
+
+    // The files are stored by their names, so they're extracted into a directory named like the
+    // archive
+    fs::create_dir(dir.join("output")).unwrap();
+    ouch_in(dir, &["-A", "d", "archive.tar", "--dir", "output"]).success();
+    for name in &names {
+        let name = name.strip_prefix("input/").unwrap();
+        assert!(dir.join("output/archive").join(name).exists(), "missing {name:?}");
+    }
+    assert_eq!(fs::read(dir.join("output/archive/sub dir/nested")).unwrap(), b"nested");
+    assert_eq!(fs::read(dir.join("output/archive/with space")).unwrap(), b"with space");
+
+    // Without --null, the names are read line by line
+    fs::write(dir.join("list"), "input/plain\ninput/with space\n").unwrap();
+    ouch_in(dir, &["-A", "c", "-T", "list", "lines.zip"]).success();
+    ouch_in(dir, &["-A", "d", "lines.zip", "--dir", "lines"]).success();
+    assert!(dir.join("lines/lines/plain").exists());
+    assert!(dir.join("lines/lines/with space").exists());
+}
+
+#[test]
+fn compress_max_depth() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+
+    fs::create_dir_all(dir.join("tree/one/two/three")).unwrap();
+    fs::write(dir.join("tree/top"), "top").unwrap();
+    fs::write(dir.join("tree/one/first"), "first").unwrap();
+    fs::write(dir.join("tree/one/two/second"), "second").unwrap();
+    fs::write(dir.join("tree/one/two/three/third"), "third").unwrap();
+
+    ouch_in(dir, &["-A", "c", "--max-depth", "1", "tree", "archive.zip"]).success();
+
+    let archive = zip::ZipArchive::new(fs::File::open(dir.join("archive.zip")).unwrap()).unwrap();
+    let mut names: Vec<_> = archive.file_names().collect();
+    names.sort_unstable();
+    // The directory deeper than the limit is kept, without its contents
+    assert_eq!(names, ["tree/", "tree/one/", "tree/top"]);
+}
+
+#[test]
+fn compress_level_per_format() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+
+    let contents: String = (0..20_000).map(|i| format!("{} ", i * 7919 % 1000)).collect();
+    fs::write(dir.join("file"), &contents).unwrap();
+
+    ouch_in(dir, &["-A", "c", "file", "file.gz.xz", "--level", "gz=1,xz=9"]).success();
+    ouch_in(dir, &["-A", "d", "file.gz.xz", "-d", "out"]).success();
+    assert_eq!(fs::read_to_string(dir.join("out/file")).unwrap(), contents);
+
+    // Each format gets its own level, the same as with a single one
+    ouch_in(dir, &["-A", "c", "file", "fast.gz", "--level", "1"]).success();
+    ouch_in(dir, &["-A", "c", "file", "slow.gz", "--level", "9"]).success();
+    // Only the xz layer is decompressed, which leaves the gzip stream
+    fs::copy(dir.join("file.gz.xz"), dir.join("gz.xz")).unwrap();
+    ouch_in(dir, &["-A", "d", "gz.xz", "-d", "layer"]).success();
+    assert_eq!(
+        fs::read(dir.join("layer/gz")).unwrap(),
+        fs::read(dir.join("fast.gz")).unwrap()
+    );
+    assert_ne!(
+        fs::read(dir.join("fast.gz")).unwrap(),
+        fs::read(dir.join("slow.gz")).unwrap()
+    );
+
+    // Formats that the output isn't compressed with
+    let output = ouch_in(dir, &["-A", "c", "file", "file.gz", "--level", "gz=1,zst=19"]).failure();
+    let stderr = String::from_utf8(output.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains("Cannot set the compression level of 'zst'"), "{stderr}");
+    ouch_in(dir, &["-A", "c", "file", "file.tgz", "--level", "tgz=1"]).failure();
+}
+
+#[cfg(unix)]
+#[test]
+fn compress_replaces_output_once_complete() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let entries = |dir: &std::path::Path| {
+        let mut entries: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        entries.sort();
+        entries
+    };
+
+    fs::create_dir(dir.join("input")).unwrap();
+    fs::write(dir.join("input/file"), "file").unwrap();
+    fs::write(dir.join("input/archive.tar"), "previous").unwrap();
+
+    // A failure leaves the previous archive as it was, and no temporary file
+    fs::write(dir.join("input/archive.tar.xyz"), "previous").unwrap();
+    ouch_in(
+        dir,
+        &[
+            "-y",
+            "c",
+            "input/file",
+            "input/archive.tar.xyz",
+            "--codec-compress-cmd",
+            "xyz=cat >/dev/null; exit 3",
+        ],
+    )
+    .failure();
+    assert_eq!(
+        fs::read_to_string(dir.join("input/archive.tar.xyz")).unwrap(),
+        "previous"
+    );
+    fs::remove_file(dir.join("input/archive.tar.xyz")).unwrap();
+    assert_eq!(entries(&dir.join("input")), ["archive.tar", "file"]);
+
+    // The archive replaces the previous one, which isn't compressed into it, like the temporary
+    // file it's written to
+    ouch_in(dir, &["-y", "-A", "c", "input", "input/archive.tar"]).success();
+    assert_eq!(entries(&dir.join("input")), ["archive.tar", "file"]);
+    ouch_in(dir, &["-A", "d", "input/archive.tar", "-d", "out"]).success();
+    assert_eq!(entries(&dir.join("out/input")), ["file"]);
+}
+
+#[test]
+fn compress_output_into_itself() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let input = &dir.join("input");
+    fs::create_dir(input).unwrap();
+    fs::write(input.join("file"), "file").unwrap();
+    let stderr = |assert: assert_cmd::assert::Assert| String::from_utf8(assert.get_output().stderr.clone()).unwrap();
+
+    // Compressing the current directory into it leaves the archive out
+    let compressed = ouch_in(input, &["c", ".", "backup.zip"]).success();
+    assert!(stderr(compressed).contains("it's left out of the archive"));
+    ouch_in(input, &["-A", "d", "backup.zip", "-d", "../out"]).success();
+    assert_eq!(fs::read_dir(dir.join("out")).unwrap().count(), 1);
+    assert!(dir.join("out/file").exists() || dir.join("out/input/file").exists());
+    assert!(!dir.join("out/backup.zip").exists() && !dir.join("out/input/backup.zip").exists());
+
+    // Unless it should fail
+    let failed = ouch_in(input, &["-y", "c", ".", "backup.zip", "--strict"]).failure();
+    assert!(stderr(failed).contains("The output archive is inside of the input directory"));
+
+    // And the archive can't be its own input
+    let failed = ouch_in(input, &["-y", "c", "backup.zip", "backup.zip"]).failure();
+    assert!(stderr(failed).contains("Cannot compress 'backup.zip' into itself."));
+    ouch_in(input, &["-A", "l", "backup.zip"]).success();
+}
+
+#[test]
+fn framed_stream_round_trip() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let stderr = |assert: assert_cmd::assert::Assert| String::from_utf8(assert.get_output().stderr.clone()).unwrap();
+
+    fs::create_dir(dir.join("input")).unwrap();
+    fs::write(dir.join("input/a.txt"), "a".repeat(100_000)).unwrap();
+    fs::write(dir.join("input/b.txt"), "b").unwrap();
+
+    for format in ["tar.gz", "zip", "gz"] {
+        let input = if format == "gz" { "input/a.txt" } else { "input" };
+        let compressed = ouch_in(dir, &["-q", "c", input, "-", "--format", format, "--framed"]).success();
+        let stream = compressed.get_output().stdout.clone();
+        assert!(stream.starts_with(b"ouchfrm1"));
+        let archive = dir.join(format!("stream.{format}"));
+        fs::write(&archive, &stream).unwrap();
+
+        let output = format!("out-{format}");
+        ouch_in(dir, &["-A", "d", archive.to_str().unwrap(), "-d", &output, "--framed"]).success();
+        let extracted = if format == "gz" {
+            dir.join(&output).join("stream")
+        } else {
+            dir.join(&output).join("input/a.txt")
+        };
+        assert_eq!(fs::read_to_string(extracted).unwrap(), "a".repeat(100_000));
+
+        // Cut short, even if only its end marker is missing
+        fs::write(&archive, &stream[..stream.len() - 12]).unwrap();
+        let failed = ouch_in(dir, &["-A", "d", archive.to_str().unwrap(), "-d", "cut", "--framed"]).code(3);
+        assert!(stderr(failed).contains("it was truncated"));
+        assert!(!dir.join("cut/stream").exists());
+    }
+
+    // Only streams to stdout are framed
+    let failed = ouch_in(dir, &["c", "input", "out.tar.gz", "--framed"]).failure();
+    assert!(stderr(failed).contains("Only the archives written to stdout are framed"));
+    assert!(!dir.join("out.tar.gz").exists());
+}
+
+#[test]
+fn corruption_diagnostics() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let stderr = |assert: assert_cmd::assert::Assert| String::from_utf8(assert.get_output().stderr.clone()).unwrap();
+
+    // Random data, so that the compressed files are large enough to cut in the middle
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let data: Vec<u8> = (0..400_000)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    fs::write(dir.join("data.txt"), &data).unwrap();
+    ouch_in(dir, &["-A", "c", "data.txt", "data.txt.gz"]).success();
+    ouch_in(dir, &["-A", "c", "data.txt", "data.tar.zst"]).success();
+    let gz = fs::read(dir.join("data.txt.gz")).unwrap();
+    let zst = fs::read(dir.join("data.tar.zst")).unwrap();
+
+    // Cut short
+    fs::write(dir.join("cut.txt.gz"), &gz[..gz.len() / 2]).unwrap();
+    let failed = ouch_in(dir, &["-A", "d", "cut.txt.gz", "-d", "cut"]).code(3);
+    let stderr_text = stderr(failed);
+    assert!(stderr_text.contains("it looks truncated"));
+    assert!(stderr_text.contains("were recovered before the error"));
+    assert!(stderr_text.contains("download or copy it again"));
+
+    // A flipped bit, only caught by the checksum at the end
+    let mut flipped = gz.clone();
+    flipped[gz.len() - 6] ^= 1;
+    fs::write(dir.join("flipped.txt.gz"), &flipped).unwrap();
+    let failed = ouch_in(dir, &["-A", "d", "flipped.txt.gz", "-d", "flipped"]).code(3);
+    assert!(stderr(failed).contains("doesn't match its checksum"));
+
+    // The entry being extracted when the archive ended
+    fs::write(dir.join("cut.tar.zst"), &zst[..zst.len() / 2]).unwrap();
+    let failed = ouch_in(dir, &["-A", "d", "cut.tar.zst", "-d", "cut-tar"]).code(3);
+    let stderr_text = stderr(failed);
+    assert!(stderr_text.contains("it looks truncated"));
+    assert!(stderr_text.contains("while extracting the entry 'data.txt'"));
+
+    // Not the format of its extension
+    fs::copy(dir.join("data.txt.gz"), dir.join("misnamed.tar.zst")).unwrap();
+    let failed = ouch_in(dir, &["-A", "d", "misnamed.tar.zst", "--verify-format", "off"]).code(3);
+    let stderr_text = stderr(failed);
+    assert!(stderr_text.contains("it looks corrupted rather than truncated"));
+    assert!(stderr_text.contains("--format gz"));
+}
+
+#[test]
+fn verify_format_modes() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let stderr = |assert: assert_cmd::assert::Assert| String::from_utf8(assert.get_output().stderr.clone()).unwrap();
+
+    fs::write(dir.join("data.txt"), "data").unwrap();
+    ouch_in(dir, &["-A", "c", "data.txt", "data.txt.gz"]).success();
+    ouch_in(dir, &["-A", "c", "data.txt", "data.txt.zst"]).success();
+    fs::create_dir(dir.join("misnamed")).unwrap();
+    fs::rename(dir.join("data.txt.zst"), dir.join("misnamed/data.txt.gz")).unwrap();
+
+    // Files matching their extensions pass the strict check
+    ouch_in(
+        dir,
+        &["-A", "d", "data.txt.gz", "-d", "out", "--verify-format", "strict"],
+    )
+    .success();
+    assert_eq!(fs::read(dir.join("out/data.txt")).unwrap(), b"data");
+
+    // A mismatch is an error, even when questions are skipped
+    let failed = ouch_in(
+        dir,
+        &[
+            "-y",
+            "d",
+            "misnamed/data.txt.gz",
+            "-d",
+            "strict",
+            "--verify-format",
+            "strict",
+        ],
+    )
+    .failure();
+    let stderr_text = stderr(failed);
+    assert!(stderr_text.contains("don't match its extension"));
+    assert!(stderr_text.contains("zst"));
+    assert!(!dir.join("strict").exists());
+    let failed = ouch_in(dir, &["-y", "l", "misnamed/data.txt.gz", "--verify-format", "strict"]).failure();
+    assert!(stderr(failed).contains("don't match its extension"));
+
+    // By default the detected format is offered instead, '--yes' accepts it for that file only
+    fs::copy(dir.join("data.txt.gz"), dir.join("other.txt.gz")).unwrap();
+    let accepted = ouch_in(dir, &["-y", "d", "misnamed/data.txt.gz", "other.txt.gz", "-d", "ask"]).success();
+    let stderr_text = stderr(accepted);
+    assert!(stderr_text.contains("but its contents look like"));
+    assert!(stderr_text.contains("data.txt.gz' as `zst`, the format of its contents"));
+    assert!(!stderr_text.contains("other.txt.gz' as"));
+    assert_eq!(fs::read(dir.join("ask/data.txt")).unwrap(), b"data");
+    assert_eq!(fs::read(dir.join("ask/other.txt")).unwrap(), b"data");
+
+    // And '--no' refuses it
+    let refused = ouch_in(dir, &["-n", "d", "misnamed/data.txt.gz", "-d", "refused"]);
+    assert!(stderr(refused).contains("but its contents look like"));
+    assert!(!dir.join("refused/data.txt").exists());
+
+    // And with 'off' the contents aren't looked at, so it's decompressed as gzip and fails there
+    let failed = ouch_in(
+        dir,
+        &["-y", "d", "misnamed/data.txt.gz", "-d", "off", "--verify-format", "off"],
+    )
+    .failure();
+    let stderr_text = stderr(failed);
+    assert!(!stderr_text.contains("but its contents look like"));
+    assert!(!stderr_text.contains("don't match its extension"));
+}
+
+#[cfg(unix)]
+#[test]
+fn decompress_output_is_input() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let stderr = |assert: assert_cmd::assert::Assert| String::from_utf8(assert.get_output().stderr.clone()).unwrap();
+
+    // A gzip file without its extension decompresses to itself with '--format'
+    fs::write(dir.join("data.txt"), "data").unwrap();
+    ouch_in(dir, &["-A", "c", "data.txt", "data.gz"]).success();
+    fs::rename(dir.join("data.gz"), dir.join("data")).unwrap();
+    let failed = ouch_in(dir, &["-y", "d", "data", "--format", "gz"]).failure();
+    assert!(stderr(failed).contains("into itself"));
+    assert!(fs::read(dir.join("data")).unwrap() != b"data");
+
+    // An archive holding an entry with its own name, given through a symlinked directory
+    fs::create_dir(dir.join("real")).unwrap();
+    std::os::unix::fs::symlink("real", dir.join("link")).unwrap();
+    let mut builder = tar::Builder::new(vec![]);
+    let mut header = tar::Header::new_gnu();
+    header.set_size(5);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, "data.txt", &b"entry"[..]).unwrap();
+    let archive = builder.into_inner().unwrap();
+    fs::write(dir.join("real/data.txt"), &archive).unwrap();
+    let failed = ouch_in(dir, &["-y", "d", "link/data.txt", "--format", "tar", "-d", "real"]).failure();
+    assert!(stderr(failed).contains("into itself"));
+    assert_eq!(fs::read(dir.join("real/data.txt")).unwrap(), archive);
+
+    // Unless another name is picked when asked
+    crate::utils::cargo_bin()
+        .args(["d", "real/data.txt", "--format", "tar", "-d", "link"])
+        .current_dir(dir)
+        .write_stdin("y\n")
+        .assert()
+        .success();
+    assert_eq!(fs::read(dir.join("real/data.txt")).unwrap(), archive);
+    assert_eq!(fs::read(dir.join("real/data_1.txt")).unwrap(), b"entry");
+}
+
+#[cfg(unix)]
+#[test]
+fn compress_special_files() {
+    use std::{ffi::CString, os::unix::fs::FileTypeExt};
+
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let mknod = |path: &str, mode: libc::mode_t, device: libc::dev_t| {
+        let path = CString::new(dir.join(path).into_os_string().into_encoded_bytes()).unwrap();
+        unsafe { libc::mknod(path.as_ptr(), mode | 0o644, device) == 0 }
+    };
+
+    fs::create_dir(dir.join("input")).unwrap();
+    fs::write(dir.join("input/file"), "file").unwrap();
+    assert!(mknod("input/fifo", libc::S_IFIFO, 0));
+    // Creating device files requires root
+    let is_root = unsafe { libc::geteuid() } == 0;
+    if is_root {
+        assert!(mknod("input/null", libc::S_IFCHR, libc::makedev(1, 3)));
+    }
+
+    // Stored without reading them, which would never end
+    ouch_in(dir, &["-A", "c", "input", "archive.tar"]).success();
+    let mut archive = tar::Archive::new(fs::File::open(dir.join("archive.tar")).unwrap());
+    let mut entries = vec![];
+    for entry in archive.entries().unwrap() {
+        let entry = entry.unwrap();
+        let header = entry.header();
+        let name = entry.path().unwrap().into_owned();
+        entries.push((
+            name,
+            header.entry_type(),
+            header.device_major().unwrap(),
+            header.device_minor().unwrap(),
+        ));
+    }
+    let find = |name: &str| {
+        entries
+            .iter()
+            .find(|entry| entry.0 == std::path::Path::new(name))
+            .unwrap()
+    };
+    assert_eq!(find("input/fifo").1, tar::EntryType::Fifo);
+    if is_root {
+        assert_eq!(find("input/null").1, tar::EntryType::Char);
+        assert_eq!((find("input/null").2, find("input/null").3), (Some(1), Some(3)));
+    }
+
+    ouch_in(dir, &["-A", "d", "archive.tar", "-d", "output"]).success();
+    let file_type = |path: &str| fs::symlink_metadata(dir.join(path)).unwrap().file_type();
+    assert!(file_type("output/input/fifo").is_fifo());
+    assert_eq!(fs::read(dir.join("output/input/file")).unwrap(), b"file");
+    if is_root {
+        assert!(file_type("output/input/null").is_char_device());
+    }
+
+    // Other formats can't store them, so they're skipped
+    ouch_in(dir, &["-A", "c", "input", "archive.zip"]).success();
+    ouch_in(dir, &["-A", "d", "archive.zip", "-d", "zip"]).success();
+    assert!(!dir.join("zip/input/fifo").exists());
+    assert!(dir.join("zip/input/file").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn compress_xattrs() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+
+    fs::create_dir(dir.join("input")).unwrap();
+    fs::write(dir.join("input/file"), "file").unwrap();
+    if let Err(err) = xattr::set(dir.join("input/file"), "user.ouch.test", b"value") {
+        // Like on filesystems without extended attributes
+        eprintln!("Skipping the test, extended attributes can't be set: {err}");
+        return;
+    }
+    xattr::set(dir.join("input"), "user.ouch.dir", b"dir").unwrap();
+
+    ouch_in(dir, &["-A", "c", "input", "archive.tar.gz", "--xattrs"]).success();
+
+    ouch_in(dir, &["-A", "d", "archive.tar.gz", "-d", "restored", "--xattrs"]).success();
+    assert_eq!(
+        xattr::get(dir.join("restored/input/file"), "user.ouch.test").unwrap(),
+        Some(b"value".to_vec())
+    );
+    assert_eq!(
+        xattr::get(dir.join("restored/input"), "user.ouch.dir").unwrap(),
+        Some(b"dir".to_vec())
+    );
+
+    // They're only restored when asked for
+    ouch_in(dir, &["-A", "d", "archive.tar.gz", "-d", "plain"]).success();
+    assert_eq!(
+        xattr::get(dir.join("plain/input/file"), "user.ouch.test").unwrap(),
+        None
+    );
+}
+
+#[test]
+fn list_matching_patterns() {
+    for format in ["zip", "tar.gz", "7z"] {
+        let dir = tempdir().unwrap();
+        let dir = dir.path();
+        let input = &dir.join("src");
+        let archive = &format!("archive.{format}");
+        fs::create_dir_all(input.join("commands")).unwrap();
+        fs::write(input.join("main.rs"), "main").unwrap();
+        fs::write(input.join("commands/list.rs"), "list").unwrap();
+        fs::write(input.join("README.md"), "readme").unwrap();
+        ouch!("-A", "c", input, dir.join(archive));
+
+        let list = |args: &[&str]| {
+            crate::utils::cargo_bin()
+                .args(["-A", "l", archive])
+                .args(args)
+                .current_dir(dir)
+                .assert()
+        };
+
+        let output = list(&["src/**/*.rs"]).success();
+        let stdout = String::from_utf8(output.get_output().stdout.clone()).unwrap();
+        assert!(stdout.contains("src/main.rs"), "format: {format}");
+        assert!(stdout.contains("src/commands/list.rs"), "format: {format}");
+        assert!(!stdout.contains("README.md"), "format: {format}");
+
+        // The tree only has the branches that lead to matches
+        let output = list(&["*/commands/*", "--tree"]).success();
+        let stdout = String::from_utf8(output.get_output().stdout.clone()).unwrap();
+        assert!(stdout.contains("list.rs"), "format: {format}");
+        assert!(!stdout.contains("main.rs"), "format: {format}");
+
+        let output = list(&["*.py", "*.c"]).code(1);
+        let stderr = String::from_utf8(output.get_output().stderr.clone()).unwrap();
+        assert!(stderr.contains("No entries match '*.py', '*.c'."), "format: {format}");
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn list_print0() {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let input = &dir.join("input");
+    let archive = &dir.join("archive.tar.gz");
+    fs::create_dir(input).unwrap();
+    // Neither a newline nor invalid UTF-8 can break the output
+    let name = OsStr::from_bytes(b"new\nline \xff");
+    fs::write(input.join(name), "file").unwrap();
+    ouch!("-A", "c", input, archive);
+
+    let output = ouch!("-A", "l", archive, "-0");
+    let mut entries: Vec<&[u8]> = output.stdout.split(|&byte| byte == b'\0').collect();
+    assert_eq!(entries.pop(), Some(&b""[..]));
+    entries.sort();
+    assert_eq!(entries, [&b"input"[..], &b"input/new\nline \xff"[..]]);
+}
+
+#[test]
+fn list_long() {
+    for format in ["zip", "7z", "tar.gz"] {
+        let dir = tempdir().unwrap();
+        let dir = dir.path();
+        let input = &dir.join("input");
+        let archive = &dir.join(format!("archive.{format}"));
+        fs::create_dir(input).unwrap();
+        fs::write(input.join("text.txt"), "text ".repeat(1000)).unwrap();
+        ouch!("-A", "c", input, archive);
+
+        let output = ouch!("-A", "l", archive, "--long");
+        let output = String::from_utf8(output.stdout).unwrap();
+        let line = output.lines().find(|line| line.ends_with("input/text.txt")).unwrap();
+        let method = match format {
+            "zip" => "Deflated",
+            "7z" => "LZMA2",
+            // Tar entries aren't compressed on their own
+            _ => "-",
+        };
+        assert!(line.starts_with(method), "format: {format}, line: {line}");
+        assert!(line.contains("5.00 kiB"), "format: {format}, line: {line}");
+        assert!(output.contains("Total: "), "format: {format}");
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn list_colors() {
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let input = &dir.join("input");
+    let archive = &dir.join("archive.tar");
+    fs::create_dir(input).unwrap();
+    fs::write(input.join("text.txt"), "text").unwrap();
+    fs::write(input.join("inner.zip"), "zip").unwrap();
+    fs::write(input.join("script"), "#!/bin/sh").unwrap();
+    fs::set_permissions(input.join("script"), std::fs::Permissions::from_mode(0o755)).unwrap();
+    symlink("text.txt", input.join("link")).unwrap();
+    // Ouch stores the targets of symlinks in tar archives, instead of the links
+    let mut builder = tar::Builder::new(fs::File::create(archive).unwrap());
+    builder.follow_symlinks(false);
+    builder.append_dir_all("input", input).unwrap();
+    builder.finish().unwrap();
+
+    let list = |color: &str| {
+        let output = ouch!("--color", color, "l", archive);
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let colored = list("always");
+    let line = |name: &str| colored.lines().find(|line| line.contains(name)).unwrap().to_owned();
+    assert_eq!(line("input/\u{1b}"), "\u{1b}[38;5;12m\u{1b}[1minput/\u{1b}[0;39m");
+    assert_eq!(line("script"), "\u{1b}[38;5;10minput/script\u{1b}[0;39m");
+    assert_eq!(line("link"), "\u{1b}[38;5;14minput/link\u{1b}[0;39m");
+    assert_eq!(line("inner.zip"), "\u{1b}[38;5;9minput/inner.zip\u{1b}[0;39m");
+    assert_eq!(line("text.txt"), "input/text.txt");
+
+    let plain = list("never");
+    assert!(!plain.contains('\u{1b}'), "{plain}");
+    assert!(plain.lines().any(|line| line == "input/script"), "{plain}");
+}
+
+#[test]
+fn list_window() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let input = &dir.join("input");
+    let archive = &dir.join("archive.tar");
+    fs::create_dir(input).unwrap();
+    for i in 0..20 {
+        fs::write(input.join(format!("file-{i}.txt")), "file").unwrap();
+        fs::write(input.join(format!("file-{i}.md")), "file").unwrap();
+    }
+    ouch!("-A", "c", input, archive);
+
+    let entries = |args: &[&str]| -> Vec<String> {
+        let output = crate::utils::cargo_bin()
+            .args(["-A", "l", archive.to_str().unwrap()])
+            .args(args)
+            .assert()
+            .success();
+        let stdout = String::from_utf8(output.get_output().stdout.clone()).unwrap();
+        stdout.lines().skip(1).map(str::to_owned).collect()
+    };
+
+    let all = entries(&[]);
+    assert_eq!(all.len(), 41);
+    let mut expected = all[10..15].to_vec();
+    expected.push("... and 26 more".to_owned());
+    assert_eq!(entries(&["--limit", "5", "--offset", "10"]), expected);
+
+    // The window is taken from the entries that match
+    let txt: Vec<_> = all.iter().filter(|entry| entry.ends_with(".txt")).cloned().collect();
+    let mut expected = txt[10..15].to_vec();
+    expected.push("... and 5 more".to_owned());
+    assert_eq!(
+        entries(&["--filter", "*.txt", "--limit", "5", "--offset", "10"]),
+        expected
+    );
+
+    // Nothing is left out past the end
+    assert_eq!(entries(&["--offset", "38"]), all[38..]);
+}
+
+#[test]
+fn transform_entries() {
+    for format in ["zip", "tar.gz", "7z"] {
+        let dir = tempdir().unwrap();
+        let dir = dir.path();
+        let input = &dir.join("input");
+        let archive = &dir.join(format!("archive.{format}"));
+        let after = &dir.join("after");
+        fs::create_dir_all(input.join("src")).unwrap();
+        fs::write(input.join("src/main.rs"), "main").unwrap();
+        fs::write(input.join("notes.txt"), "notes").unwrap();
+        ouch!("-A", "c", input, archive, "--transform", "^input=myapp-1.2");
+
+        let output = ouch!("-A", "l", archive);
+        let output = String::from_utf8(output.stdout).unwrap();
+        assert!(output.contains("myapp-1.2/src/main.rs"), "format: {format}");
+        assert!(!output.contains("input"), "format: {format}");
+
+        // Rules apply in order, so the second one sees the result of the first
+        ouch!(
+            "-A",
+            "d",
+            archive,
+            "-d",
+            after,
+            "--transform",
+            "^myapp-1.2=pkg",
+            "--transform",
+            r"\.txt$=.md"
+        );
+        assert_eq!(
+            fs::read(after.join("pkg/src/main.rs")).unwrap(),
+            b"main",
+            "format: {format}"
+        );
+        assert_eq!(
+            fs::read(after.join("pkg/notes.md")).unwrap(),
+            b"notes",
+            "format: {format}"
+        );
+    }
+
+    // Rules are validated before doing anything, and can't escape the output directory
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    fs::write(dir.join("file"), "file").unwrap();
+    crate::utils::cargo_bin()
+        .args(["c", "file", "archive.tar", "--transform", "(unclosed=x"])
+        .current_dir(dir)
+        .assert()
+        .failure();
+    assert!(!dir.join("archive.tar").exists());
+
+    ouch!("-A", "c", dir.join("file"), dir.join("archive.tar"));
+    crate::utils::cargo_bin()
+        .args(["d", "archive.tar", "-d", "out", "--transform", "^=../"])
+        .current_dir(dir)
+        .assert()
+        .failure();
+    assert!(!dir.parent().unwrap().join("file").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn absolute_paths() {
+    for format in ["tar", "zip", "7z"] {
+        let dir = tempdir().unwrap();
+        let dir = &dir.path().canonicalize().unwrap();
+        let input = &dir.join("input");
+        // Long enough to not fit in a tar header
+        let file = &input.join("x".repeat(100)).join("file");
+        let archive = &dir.join(format!("archive.{format}"));
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+        fs::write(file, "file").unwrap();
+
+        // Entries are relative to the input by default
+        ouch!("-A", "c", input, archive);
+        let output = String::from_utf8(ouch!("-A", "l", archive).stdout).unwrap();
+        assert!(output.contains("input/xxx"), "format: {format}");
+        assert!(!output.contains(file.to_str().unwrap()), "format: {format}");
+
+        ouch!("-A", "c", input, archive, "--absolute-paths");
+        let output = String::from_utf8(ouch!("-A", "l", archive).stdout).unwrap();
+        assert!(output.contains(file.to_str().unwrap()), "format: {format}");
+
+        // Extracting absolute paths is refused, unless they are explicitly allowed, but tar
+        // archives are extracted inside of the output folder instead, like GNU tar does
+        fs::remove_dir_all(input).unwrap();
+        let assert = crate::utils::cargo_bin()
+            .arg("d")
+            .arg(archive)
+            .args(["-d", "out", "--yes"])
+            .current_dir(dir)
+            .assert();
+        assert!(!input.exists(), "format: {format}");
+        if format == "tar" {
+            let stderr = String::from_utf8(assert.success().get_output().stderr.clone()).unwrap();
+            assert_eq!(stderr.matches("Removing leading '/'").count(), 1);
+            let relative = file.strip_prefix("/").unwrap();
+            assert_eq!(fs::read(dir.join("out").join(relative)).unwrap(), b"file");
+            fs::remove_dir_all(dir.join("out")).unwrap();
+        } else {
+            assert.failure();
+        }
+
+        ouch!("-A", "d", archive, "-d", dir.join("out"), "-P");
+        assert_eq!(fs::read(file).unwrap(), b"file", "format: {format}");
+        assert_eq!(fs::read_dir(dir.join("out")).unwrap().count(), 0, "format: {format}");
+    }
+}
+
+#[test]
+fn store_incompressible_files() {
+    use std::time::Instant;
+
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let input = &dir.join("input");
+    fs::create_dir(input).unwrap();
+    let mut rng = SmallRng::from_entropy();
+    for i in 0..4 {
+        let mut data = vec![0; 4 * 1024 * 1024];
+        rng.fill(&mut data[..]);
+        fs::write(input.join(format!("random{i}")), data).unwrap();
+    }
+    fs::write(input.join("text"), "compressible ".repeat(10_000)).unwrap();
+
+    let compress = |archive: &str, extra_args: &[&str]| {
+        let start = Instant::now();
+        let output = crate::utils::cargo_bin()
+            .args(["-A", "c"])
+            .arg(input)
+            .arg(dir.join(archive))
+            .args(extra_args)
+            .assert()
+            .success();
+        let stderr = String::from_utf8(output.get_output().stderr.clone()).unwrap();
+        (start.elapsed(), fs::metadata(dir.join(archive)).unwrap().len(), stderr)
+    };
+
+    let (stored_time, stored_size, _) = compress("stored.zip", &[]);
+    let (forced_time, forced_size, _) = compress("forced.zip", &["--force-compress"]);
+    assert!(stored_time < forced_time, "{stored_time:?} >= {forced_time:?}");
+    assert!(stored_size <= forced_size);
+
+    // The random files are stored, while the text is still compressed
+    let listed = crate::utils::cargo_bin()
+        .args(["-A", "l", "--long"])
+        .arg(dir.join("stored.zip"))
+        .assert()
+        .success();
+    let stdout = String::from_utf8(listed.get_output().stdout.clone()).unwrap();
+    for line in stdout.lines().filter(|line| line.contains("input/")) {
+        let expected = if line.ends_with("input/text") {
+            "Deflated"
+        } else if line.contains("random") {
+            "Stored"
+        } else {
+            continue;
+        };
+        assert!(line.starts_with(expected), "{line}");
+    }
+
+    let (_, _, stderr) = compress("archive.tar.gz", &[]);
+    assert!(stderr.contains("of the input is already compressed"));
+    let (_, _, stderr) = compress("forced.tar.gz", &["--force-compress"]);
+    assert!(!stderr.contains("of the input is already compressed"));
+
+    ouch!("-A", "d", dir.join("stored.zip"), "-d", dir.join("output"));
+    assert_same_directory(input, dir.join("output/input"), false);
+}
+
+#[test]
+fn compress_relative_to_input_dir() {
+    for format in ["tar.gz", "zip", "7z"] {
+        let dir = tempdir().unwrap();
+        let dir = dir.path();
+        let site = &dir.join("var/www/site");
+        let archive = &dir.join(format!("archive.{format}"));
+        fs::create_dir_all(site.join("css")).unwrap();
+        fs::write(site.join("index.html"), "index").unwrap();
+        fs::write(site.join("css/style.css"), "style").unwrap();
+
+        ouch!(
+            "-A",
+            "c",
+            site.join("index.html"),
+            site.join("css"),
+            archive,
+            "--input-dir",
+            dir.join("var/www")
+        );
+        let output = String::from_utf8(ouch!("-A", "l", archive).stdout).unwrap();
+        let mut entries: Vec<_> = output.lines().skip(1).map(|line| line.trim_end_matches('/')).collect();
+        entries.sort();
+        assert_eq!(
+            entries,
+            ["site/css", "site/css/style.css", "site/index.html"],
+            "format: {format}"
+        );
+
+        // The input directory itself has no entry
+        ouch!("-A", "c", site, archive, "--input-dir", site);
+        let output = String::from_utf8(ouch!("-A", "l", archive).stdout).unwrap();
+        let mut entries: Vec<_> = output.lines().skip(1).map(|line| line.trim_end_matches('/')).collect();
+        entries.sort();
+        assert_eq!(entries, ["css", "css/style.css", "index.html"], "format: {format}");
+
+        // Inputs must be inside of the input directory
+        crate::utils::cargo_bin()
+            .args(["-A", "c", "--yes"])
+            .arg(site)
+            .arg(archive)
+            .arg("--input-dir")
+            .arg(site.join("css"))
+            .assert()
+            .code(1);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn decompress_exec() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let input = &dir.join("input");
+    let archive = &dir.join("archive.tar.gz");
+    let after = &dir.join("after");
+    fs::create_dir_all(input.join("dir")).unwrap();
+    fs::write(input.join("a"), "a").unwrap();
+    fs::write(input.join("dir/b c"), "b").unwrap();
+    ouch!("-A", "c", input, archive);
+
+    ouch!("-A", "d", archive, "-d", after, "--exec", "touch {}.marker");
+    assert!(after.join("input/a.marker").exists());
+    assert!(after.join("input/dir/b c.marker").exists());
+
+    // A failing command stops the decompression, unless told to ignore it
+    fs::remove_dir_all(after).unwrap();
+    crate::utils::cargo_bin()
+        .args(["-A", "d", "--exec", "false", "-d"])
+        .arg(after)
+        .arg(archive)
+        .assert()
+        .failure();
+    fs::remove_dir_all(after).unwrap();
+    ouch!(
+        "-A",
+        "d",
+        archive,
+        "-d",
+        after,
+        "--exec",
+        "false",
+        "--exec-ignore-errors"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn decompress_sparse_tar() {
+    use std::{
+        io::{Seek, SeekFrom, Write},
+        os::unix::fs::MetadataExt,
+        process::Command,
+    };
+
+    // The archives are created by GNU tar, the sparse formats are its own
+    let is_gnu_tar = Command::new("tar")
+        .arg("--version")
+        .output()
+        .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).contains("GNU tar"));
+    if !is_gnu_tar {
+        return;
+    }
+
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let mut file = fs::File::create(dir.join("sparse")).unwrap();
+    file.set_len(50_000_000).unwrap();
+    file.seek(SeekFrom::Start(20_000_000)).unwrap();
+    file.write_all(b"data").unwrap();
+    drop(file);
+    let contents = fs::read(dir.join("sparse")).unwrap();
+
+    for (format, sparse_version) in [
+        ("gnu", None),
+        ("posix", Some("0.0")),
+        ("posix", Some("0.1")),
+        ("posix", Some("1.0")),
+    ] {
+        let archive = dir.join(format!("{format}-{}.tar", sparse_version.unwrap_or("gnu")));
+        let mut tar = Command::new("tar");
+        tar.current_dir(dir)
+            .args(["--sparse", "--format", format])
+            .arg("-cf")
+            .arg(&archive);
+        if let Some(sparse_version) = sparse_version {
+            tar.args(["--sparse-version", sparse_version]);
+        }
+        assert!(tar.arg("sparse").status().unwrap().success());
+
+        let output = dir.join("output");
+        ouch!("-A", "d", &archive, "-d", &output);
+        let extracted = output.join("sparse");
+        assert_eq!(fs::read(&extracted).unwrap(), contents);
+
+        // Best-effort, the file system could allocate the holes anyway
+        let metadata = fs::metadata(&extracted).unwrap();
+        assert!(metadata.blocks() * 512 < metadata.len());
+        fs::remove_dir_all(output).unwrap();
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn decompress_sparse() {
+    use std::os::unix::fs::MetadataExt;
+
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+
+    // Like a disk image, mostly made of zeroes
+    let mut rng = SmallRng::seed_from_u64(0);
+    let mut contents = vec![0; 13 * 1024 * 1024];
+    rng.fill_bytes(&mut contents[..256 * 1024]);
+    rng.fill_bytes(&mut contents[8 * 1024 * 1024..8 * 1024 * 1024 + 256 * 1024]);
+    fs::write(dir.join("image"), &contents).unwrap();
+
+    for format in ["tar.gz", "zip", "7z", "gz"] {
+        let archive = &dir.join(format!("image.{format}"));
+        ouch!("-A", "c", dir.join("image"), archive);
+
+        for sparse in ["--sparse=auto", "--sparse", "--sparse=never"] {
+            let output = &dir.join("output");
+            ouch!("-A", "d", archive, "-d", output, sparse);
+            let extracted = output.join("image");
+            assert_eq!(fs::read(&extracted).unwrap(), contents, "format: {format}, {sparse}");
+
+            // Best-effort, the file system could allocate the holes anyway
+            if sparse != "--sparse=never" {
+                let metadata = fs::metadata(&extracted).unwrap();
+                assert!(
+                    metadata.blocks() * 512 < 2 * 1024 * 1024,
+                    "format: {format}, {sparse}, allocated: {}",
+                    metadata.blocks() * 512
+                );
+            }
+            fs::remove_dir_all(output).unwrap();
+        }
+    }
+}
+
+#[test]
+fn decompress_unsupported_7z() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    fs::write(dir.join("file"), "contents").unwrap();
+    ouch_in(dir, &["-A", "c", "file", "archive.7z"]).success();
+    let archive = fs::read(dir.join("archive.7z")).unwrap();
+
+    // Only the first half, like the first volume of a split archive
+    fs::write(dir.join("archive.7z.001"), &archive[..archive.len() / 2]).unwrap();
+    let output = ouch_in(dir, &["d", "archive.7z.001", "--format", "7z", "-d", "output"]).failure();
+    let stderr = String::from_utf8(output.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains("split into volumes"), "{stderr}");
+
+    // The LZMA2 coder replaced by the ARM64 filter, which ouch can't decompress, the header
+    // isn't compressed, and its checksum is in the start header, which has its own
+    let mut archive = archive;
+    let header_start = 32 + u64::from_le_bytes(archive[12..20].try_into().unwrap()) as usize;
+    let coder = archive[header_start..]
+        .windows(6)
+        .position(|bytes| bytes == [0x0B, 0x01, 0x00, 0x01, 0x21, 0x21])
+        .unwrap();
+    archive[header_start + coder + 5] = 0x0A;
+    let checksum = |bytes: &[u8]| {
+        let mut crc = flate2::Crc::new();
+        crc.update(bytes);
+        crc.sum().to_le_bytes()
+    };
+    let header_checksum = checksum(&archive[header_start..]);
+    archive[28..32].copy_from_slice(&header_checksum);
+    let start_header_checksum = checksum(&archive[12..32]);
+    archive[8..12].copy_from_slice(&start_header_checksum);
+    fs::write(dir.join("arm64.7z"), &archive).unwrap();
+
+    let output = ouch_in(dir, &["d", "arm64.7z", "-d", "output"]).failure();
+    let stderr = String::from_utf8(output.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains("It uses the ARM64 filter"), "{stderr}");
+}
+
+#[test]
+fn decompress_path_traversal() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    fs::create_dir(dir.join("xx")).unwrap();
+    fs::write(dir.join("xx/evil"), "evil").unwrap();
+    fs::write(dir.join("safe"), "safe").unwrap();
+
+    // The tar crate refuses to write '..', so the name is set in the header directly
+    let mut builder = tar::Builder::new(vec![]);
+    let mut header = tar::Header::new_gnu();
+    header.as_old_mut().name[..7].copy_from_slice(b"../evil");
+    header.set_size(4);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append(&header, &b"evil"[..]).unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_size(4);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, "safe", &b"safe"[..]).unwrap();
+    fs::write(dir.join("archive.tar"), builder.into_inner().unwrap()).unwrap();
+
+    // In zip archives, 'xx' is renamed to '..', the names aren't covered by their checksums
+    let mut zip = {
+        ouch_in(dir, &["-A", "c", "xx", "safe", "archive.zip"]).success();
+        fs::read(dir.join("archive.zip")).unwrap()
+    };
+    let positions: Vec<_> = zip
+        .windows(3)
+        .enumerate()
+        .filter(|(_, name)| *name == b"xx/")
+        .map(|(i, _)| i)
+        .collect();
+    assert!(!positions.is_empty());
+    for i in positions {
+        zip[i..i + 3].copy_from_slice(b"../");
+    }
+    fs::write(dir.join("archive.zip"), zip).unwrap();
+
+    let mut writer = sevenz_rust::SevenZWriter::new(std::io::Cursor::new(vec![])).unwrap();
+    for (path, name) in [("xx/evil", "../evil"), ("safe", "safe")] {
+        let entry = sevenz_rust::SevenZArchiveEntry::from_path(dir.join(path), name.into());
+        writer
+            .push_archive_entry(entry, Some(fs::File::open(dir.join(path)).unwrap()))
+            .unwrap();
+    }
+    fs::write(dir.join("archive.7z"), writer.finish().unwrap().into_inner()).unwrap();
+    fs::remove_dir_all(dir.join("xx")).unwrap();
+
+    fn contains_file(dir: &std::path::Path, name: &str) -> bool {
+        fs::read_dir(dir).unwrap().any(|entry| {
+            let path = entry.unwrap().path();
+            path.file_name().unwrap() == name || (path.is_dir() && contains_file(&path, name))
+        })
+    }
+
+    for format in ["tar", "zip", "7z"] {
+        let archive = format!("archive.{format}");
+        let output = format!("output-{format}");
+
+        // The entry is skipped, with a warning, and the others are extracted
+        let assert = ouch_in(dir, &["d", &archive, "-d", &output]).success();
+        let stderr = String::from_utf8(assert.get_output().stderr.clone()).unwrap();
+        assert!(stderr.contains("Skipping '../evil'"), "format: {format}, {stderr}");
+        assert!(!contains_file(dir, "evil"), "format: {format}");
+        assert!(contains_file(&dir.join(&output), "safe"), "format: {format}");
+
+        // With --strict, the extraction fails
+        let strict_output = format!("strict-{format}");
+        let assert = ouch_in(dir, &["d", &archive, "-d", &strict_output, "--strict"]).failure();
+        let stderr = String::from_utf8(assert.get_output().stderr.clone()).unwrap();
+        assert!(
+            stderr.contains("Refusing to extract '../"),
+            "format: {format}, {stderr}"
+        );
+        assert!(!contains_file(dir, "evil"), "format: {format}");
+    }
+
+    // Without its leading '/', an absolute tar entry that still leads outside fails the extraction
+    let mut builder = tar::Builder::new(vec![]);
+    let mut header = tar::Header::new_gnu();
+    header.as_old_mut().name[..8].copy_from_slice(b"/../evil");
+    header.set_size(4);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append(&header, &b"evil"[..]).unwrap();
+    fs::write(dir.join("absolute.tar"), builder.into_inner().unwrap()).unwrap();
+    let assert = ouch_in(dir, &["d", "absolute.tar", "-d", "output-absolute"]).failure();
+    let stderr = String::from_utf8(assert.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains("Refusing to extract '/../evil'"), "{stderr}");
+    assert!(!contains_file(dir, "evil"));
+
+    // The real path of PAX sparse files is in their extended header, it's checked all the same
+    let mut extensions = vec![];
+    for (key, value) in [
+        ("GNU.sparse.name", "../evil"),
+        ("GNU.sparse.realsize", "4"),
+        ("GNU.sparse.map", "0,4"),
+    ] {
+        let record = format!(" {key}={value}\n");
+        // The length of a record counts its own digits
+        let length = record.len() + (record.len() + 2).to_string().len();
+        extensions.extend(format!("{length}{record}").into_bytes());
+    }
+    let mut builder = tar::Builder::new(vec![]);
+    let mut header = tar::Header::new_ustar();
+    header.set_entry_type(tar::EntryType::XHeader);
+    header.set_size(extensions.len() as u64);
+    builder
+        .append_data(&mut header, "PaxHeaders/evil", &extensions[..])
+        .unwrap();
+    let mut header = tar::Header::new_ustar();
+    header.set_size(4);
+    header.set_mode(0o644);
+    builder
+        .append_data(&mut header, "GNUSparseFile.0/evil", &b"evil"[..])
+        .unwrap();
+    fs::write(dir.join("sparse.tar"), builder.into_inner().unwrap()).unwrap();
+    let assert = ouch_in(dir, &["d", "sparse.tar", "-d", "output-sparse"]).success();
+    let stderr = String::from_utf8(assert.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains("Skipping '../evil'"), "{stderr}");
+    assert!(!contains_file(dir, "evil"));
+}
+
+#[cfg(windows)]
+#[test]
+fn decompress_windows_reserved_names() {
+    use std::io::Write;
+
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+
+    let names = ["dir/con", "dir/aux.txt", "dir/what?", "dir/notes.", "dir/100%.txt"];
+    let mut writer = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+    for name in names {
+        writer.start_file(name, zip::write::FileOptions::default()).unwrap();
+        writer.write_all(name.as_bytes()).unwrap();
+    }
+    fs::write(dir.join("archive.zip"), writer.finish().unwrap().into_inner()).unwrap();
+
+    // The names are escaped, with a warning for each of them
+    let assert = ouch_in(dir, &["d", "archive.zip", "-d", "output"]).success();
+    let stderr = String::from_utf8(assert.get_output().stderr.clone()).unwrap();
+    assert_eq!(stderr.matches("isn't valid on Windows").count(), 4, "{stderr}");
+    for (name, escaped) in [
+        ("dir/con", "co%6E"),
+        ("dir/aux.txt", "au%78.txt"),
+        ("dir/what?", "what%3F"),
+        ("dir/notes.", "notes%2E"),
+        ("dir/100%.txt", "100%.txt"),
+    ] {
+        let contents = fs::read(dir.join("output/dir").join(escaped)).unwrap();
+        assert_eq!(contents, name.as_bytes());
+    }
+
+    // Compressing them again stores their original names
+    ouch_in(dir, &["c", "output/dir", "again.zip"]).success();
+    let archive = zip::ZipArchive::new(fs::File::open(dir.join("again.zip")).unwrap()).unwrap();
+    let mut stored: Vec<_> = archive.file_names().filter(|name| !name.ends_with('/')).collect();
+    stored.sort_unstable();
+    let mut expected = names.to_vec();
+    expected.sort_unstable();
+    assert_eq!(stored, expected);
+
+    // With --strict, the extraction fails instead
+    ouch_in(dir, &["d", "archive.zip", "-d", "strict", "--strict"]).failure();
+}
+
+#[cfg(windows)]
+#[test]
+fn decompress_long_paths() {
+    use std::io::Write;
+
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+
+    // Like the deep paths of 'node_modules' folders, past the 260 characters of Windows
+    let nested = vec!["node_modules/some-package-with-a-long-name"; 8].join("/");
+    let name = format!("{nested}/index.js");
+    assert!(name.len() > 300);
+
+    for format in ["tar", "zip"] {
+        let archive = dir.join(format!("archive.{format}"));
+        if format == "tar" {
+            let mut builder = tar::Builder::new(fs::File::create(&archive).unwrap());
+            let mut header = tar::Header::new_gnu();
+            header.set_size(5);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, &name, &b"index"[..]).unwrap();
+            builder.finish().unwrap();
+        } else {
+            let mut writer = zip::ZipWriter::new(fs::File::create(&archive).unwrap());
+            writer.start_file(&name, zip::write::FileOptions::default()).unwrap();
+            writer.write_all(b"index").unwrap();
+            writer.finish().unwrap();
+        }
+
+        let output = format!("output-{format}");
+        ouch_in(dir, &["d", archive.to_str().unwrap(), "-d", &output]).success();
+        assert_eq!(
+            fs::read(dir.join(&output).join(&name)).unwrap(),
+            b"index",
+            "format: {format}"
+        );
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn decompress_symlink_escape() {
+    use std::io::Write;
+
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let outside = dir.join("outside");
+    fs::create_dir(&outside).unwrap();
+
+    // A symlink to outside of the output folder, followed by a file written through it
+    let mut builder = tar::Builder::new(vec![]);
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_size(0);
+    builder.append_link(&mut header, "link", &outside).unwrap();
+    for (name, contents) in [("link/passwd", "pwned"), ("safe", "safe")] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, name, contents.as_bytes()).unwrap();
+    }
+    fs::write(dir.join("archive.tar"), builder.into_inner().unwrap()).unwrap();
+
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+    let options = zip::write::FileOptions::default();
+    zip.add_symlink("link", outside.to_str().unwrap(), options).unwrap();
+    for (name, contents) in [("link/passwd", "pwned"), ("safe", "safe")] {
+        zip.start_file(name, options).unwrap();
+        zip.write_all(contents.as_bytes()).unwrap();
+    }
+    fs::write(dir.join("archive.zip"), zip.finish().unwrap().into_inner()).unwrap();
+
+    for format in ["tar", "zip"] {
+        let archive = format!("archive.{format}");
+
+        // Nothing is written through the link, zip archives fail to extract as they write the
+        // link as a plain file, which the next entry can't be created in
+        let output = ouch_in(dir, &["d", &archive, "-d", &format!("output-{format}")]);
+        if format == "zip" {
+            output.failure();
+        }
+        ouch_in(dir, &["d", &archive, "-d", &format!("strict-{format}"), "--strict"]).failure();
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0, "format: {format}");
+
+        // Without the links, the file is extracted into a folder of its own
+        let output = dir.join(format!("no-symlinks-{format}"));
+        ouch_in(dir, &["d", &archive, "-d", output.to_str().unwrap(), "--no-symlinks"]).success();
+        assert_eq!(
+            fs::read(output.join("archive/link/passwd")).unwrap(),
+            b"pwned",
+            "format: {format}"
+        );
+        assert!(
+            output.join("archive/link").symlink_metadata().unwrap().is_dir(),
+            "format: {format}"
+        );
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0, "format: {format}");
+    }
+
+    // The file is skipped with a warning, and the rest is extracted
+    let output = ouch_in(dir, &["d", "archive.tar", "-d", "warned"]).success();
+    let stderr = String::from_utf8(output.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains("Skipping 'link/passwd'"), "{stderr}");
+    assert_eq!(fs::read(dir.join("warned/archive/safe")).unwrap(), b"safe");
+}
+
+#[test]
+fn zip_legacy_name_encoding() {
+    use std::io::Write;
+
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let list = |args: &[&str]| String::from_utf8(ouch_in(dir, args).success().get_output().stdout.clone()).unwrap();
+
+    // Written with ASCII names, which are then replaced by encoded ones of the same length, as
+    // the zip crate marks the non-ASCII names as UTF-8
+    let mut writer = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+    for name in ["cafX.txt", "abcd.txt"] {
+        writer.start_file(name, zip::write::FileOptions::default()).unwrap();
+        writer.write_all(name.as_bytes()).unwrap();
+    }
+    // ASCII names with a Unicode Path extra field, which is ignored if made for another name, its
+    // header ID is replaced below, as the zip crate doesn't write it
+    for (name, made_for, unicode_name) in [
+        ("na?ve.txt", "na?ve.txt", "naïve.txt"),
+        ("old.txt", "new.txt", "älter.txt"),
+    ] {
+        let mut crc = flate2::Crc::new();
+        crc.update(made_for.as_bytes());
+        let mut field = vec![1];
+        field.extend(crc.sum().to_le_bytes());
+        field.extend(unicode_name.as_bytes());
+
+        writer
+            .start_file_with_extra_data(name, zip::write::FileOptions::default())
+            .unwrap();
+        writer.write_all(&0xCAFEu16.to_le_bytes()).unwrap();
+        writer.write_all(&(field.len() as u16).to_le_bytes()).unwrap();
+        writer.write_all(&field).unwrap();
+        writer.end_extra_data().unwrap();
+        writer.write_all(name.as_bytes()).unwrap();
+    }
+    let mut archive = writer.finish().unwrap().into_inner();
+    // 'café' in CP437, and '日本' in Shift-JIS
+    for (from, to) in [(b"cafX", b"caf\x82"), (b"abcd", b"\x93\xFA\x96\x7B")] {
+        let positions: Vec<_> = (0..archive.len() - 4).filter(|&i| &archive[i..i + 4] == from).collect();
+        for i in positions {
+            archive[i..i + 4].copy_from_slice(to);
+        }
+    }
+    for i in 0..archive.len() - 2 {
+        if archive[i..i + 2] == 0xCAFEu16.to_le_bytes() {
+            archive[i..i + 2].copy_from_slice(&0x7075u16.to_le_bytes());
+        }
+    }
+    fs::write(dir.join("legacy.zip"), archive).unwrap();
+
+    // CP437 by default, as the zip specification says
+    let output = list(&["l", "legacy.zip"]);
+    assert!(output.contains("café.txt"), "{output}");
+    assert!(!output.contains("日本.txt"), "{output}");
+    assert!(output.contains("naïve.txt"), "{output}");
+    assert!(output.contains("old.txt"), "{output}");
+    assert!(!output.contains("älter.txt"), "{output}");
+
+    let output = list(&["l", "legacy.zip", "--encoding", "shift_jis"]);
+    assert!(output.contains("日本.txt"), "{output}");
+    assert!(output.contains("naïve.txt"), "{output}");
+
+    ouch_in(dir, &["d", "legacy.zip", "--zip-encoding", "shift_jis", "-d", "out"]).success();
+    assert_eq!(fs::read(dir.join("out/legacy/日本.txt")).unwrap(), b"abcd.txt");
+    assert_eq!(fs::read(dir.join("out/legacy/naïve.txt")).unwrap(), b"na?ve.txt");
+
+    // Printed entries are looked up by the same names
+    ouch_in(
+        dir,
+        &[
+            "d",
+            "legacy.zip",
+            "--zip-encoding",
+            "shift_jis",
+            "--stdout",
+            "--filter",
+            "日本.txt",
+        ],
+    )
+    .success()
+    .stdout("abcd.txt");
+    ouch_in(dir, &["cat", "legacy.zip", "naïve.txt"])
+        .success()
+        .stdout("na?ve.txt");
+
+    ouch_in(dir, &["l", "legacy.zip", "--zip-encoding", "klingon"]).failure();
+}
+
+#[test]
+fn zip_duplicated_paths() {
+    use std::io::Write;
+
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+
+    let mut writer = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+    for (name, contents) in [("file", "harmless"), ("other", "other"), ("file", "malicious")] {
+        writer.start_file(name, zip::write::FileOptions::default()).unwrap();
+        writer.write_all(contents.as_bytes()).unwrap();
+    }
+    fs::write(dir.join("dup.zip"), writer.finish().unwrap().into_inner()).unwrap();
+
+    let output = String::from_utf8(ouch_in(dir, &["l", "dup.zip"]).success().get_output().stdout.clone()).unwrap();
+    assert!(
+        output.contains("file (copy 1 of 2)\nother\nfile (copy 2 of 2)"),
+        "{output}"
+    );
+
+    // The last copy by default, with a warning, in parallel or not
+    for threads in ["1", "4"] {
+        let output_dir = format!("last-{threads}");
+        let output = ouch_in(dir, &["d", "dup.zip", "--threads", threads, "-d", &output_dir]).success();
+        let stderr = String::from_utf8(output.get_output().stderr.clone()).unwrap();
+        assert!(stderr.contains("Several entries are at file"), "{stderr}");
+        assert_eq!(fs::read(dir.join(&output_dir).join("dup/file")).unwrap(), b"malicious");
+
+        let output_dir = format!("first-{threads}");
+        ouch_in(
+            dir,
+            &["d", "dup.zip", "--threads", threads, "-d", &output_dir, "--first-wins"],
+        )
+        .success();
+        assert_eq!(fs::read(dir.join(&output_dir).join("dup/file")).unwrap(), b"harmless");
+        assert_eq!(fs::read(dir.join(&output_dir).join("dup/other")).unwrap(), b"other");
+    }
+
+    ouch_in(dir, &["d", "dup.zip", "-d", "strict", "--strict"]).failure();
+    assert!(!dir.join("strict/dup").exists());
+}
+
+#[test]
+fn tar_copied_by_kernel() {
+    use sha2::{Digest, Sha256};
+
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+
+    // Big files are copied by the kernel, at sizes that aren't whole blocks, and with a name too
+    // long for the header, small files through a buffer
+    let mut rng = SmallRng::seed_from_u64(0);
+    let input = dir.join("input");
+    fs::create_dir(&input).unwrap();
+    for (name, size) in [
+        ("big", 3 * 1024 * 1024 + 1),
+        ("medium", 100_000),
+        ("small", 1000),
+        (&"long".repeat(40), 200_000),
+    ] {
+        let mut contents = vec![0; size];
+        rng.fill_bytes(&mut contents);
+        fs::write(input.join(name), contents).unwrap();
+    }
+
+    ouch_in(dir, &["-A", "c", "input", "archive.tar"]).success();
+    // Compressed, the archive is written through the encoder instead
+    ouch_in(dir, &["-A", "c", "input", "archive.tar.gz"]).success();
+    let mut buffered = vec![];
+    let archive = fs::File::open(dir.join("archive.tar.gz")).unwrap();
+    std::io::Read::read_to_end(&mut flate2::read::MultiGzDecoder::new(archive), &mut buffered).unwrap();
+
+    let copied = fs::read(dir.join("archive.tar")).unwrap();
+    assert_eq!(Sha256::digest(&copied), Sha256::digest(&buffered));
+
+    ouch_in(dir, &["-A", "d", "archive.tar", "-d", "output"]).success();
+    assert_same_directory(&input, dir.join("output/input"), true);
+}
+
+#[test]
+fn append_update() {
+    for format in ["tar", "tar.gz", "zip"] {
+        let dir = tempdir().unwrap();
+        let dir = dir.path();
+        let input = &dir.join("input");
+        let archive = &dir.join(format!("archive.{format}"));
+        fs::create_dir(input).unwrap();
+        fs::write(input.join("a"), "a").unwrap();
+        fs::write(input.join("b"), "b").unwrap();
+        ouch!("-A", "c", input, archive);
+
+        // Only the file that is newer than its entry replaces it
+        let past = filetime_creation::FileTime::from_unix_time(1_000_000_000, 0);
+        let future = filetime_creation::FileTime::from_unix_time(4_000_000_000, 0);
+        fs::write(input.join("a"), "new a").unwrap();
+        filetime_creation::set_file_mtime(input.join("a"), future).unwrap();
+        fs::write(input.join("b"), "new b").unwrap();
+        filetime_creation::set_file_mtime(input.join("b"), past).unwrap();
+        fs::write(input.join("c"), "c").unwrap();
+        ouch!("-A", "append", archive, input, "-u");
+
+        // Each file is archived once, the older copy of the newer file isn't kept next to it
+        let listing = crate::utils::cargo_bin()
+            .args(["list", "-0"])
+            .arg(archive)
+            .assert()
+            .success();
+        let mut entries: Vec<_> = listing.get_output().stdout.split(|&byte| byte == 0).collect();
+        entries.retain(|entry| entry.starts_with(b"input/") && !entry.ends_with(b"/"));
+        entries.sort();
+        assert_eq!(entries, [&b"input/a"[..], b"input/b", b"input/c"], "format: {format}");
+
+        let after = &dir.join("after");
+        ouch!("-A", "d", archive, "-d", after);
+        assert_eq!(fs::read(after.join("input/a")).unwrap(), b"new a", "format: {format}");
+        assert_eq!(fs::read(after.join("input/b")).unwrap(), b"b", "format: {format}");
+        assert_eq!(fs::read(after.join("input/c")).unwrap(), b"c", "format: {format}");
+
+        // Without --update, every entry is replaced
+        ouch!("-A", "append", archive, input);
+        fs::remove_dir_all(after).unwrap();
+        ouch!("-A", "d", archive, "-d", after);
+        assert_eq!(fs::read(after.join("input/b")).unwrap(), b"new b", "format: {format}");
+    }
+}
+
+#[test]
+fn append_over_duplicate_entries() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    fs::write(dir.join("a"), "new a").unwrap();
+
+    // Like an archive appended to with `tar -r`, it has two entries for the same file
+    let mut builder = tar::Builder::new(vec![]);
+    for contents in [&b"a"[..], b"a again"] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, "a", contents).unwrap();
+    }
+    fs::write(dir.join("archive.tar"), builder.into_inner().unwrap()).unwrap();
+
+    let appended = ouch_in(dir, &["append", "archive.tar", "a"]).success();
+    let stderr = String::from_utf8(appended.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains("0 added, 1 replaced and 0 skipped"), "{stderr}");
+
+    // Both of the entries were replaced by the file
+    let listing = ouch_in(dir, &["list", "-0", "archive.tar"]).success();
+    let entries: Vec<_> = listing.get_output().stdout.split(|&byte| byte == 0).collect();
+    assert_eq!(entries, [&b"a"[..], b""]);
+    ouch_in(dir, &["d", "archive.tar", "-d", "after"]).success();
+    assert_eq!(fs::read(dir.join("after/a")).unwrap(), b"new a");
+}
+
+/// Chained zip archives bigger than the in-memory limit are buffered in a temporary file
+#[test]
+fn diff_archives() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let input = &dir.join("input");
+    fs::create_dir(input).unwrap();
+    fs::write(input.join("a"), "a").unwrap();
+    fs::write(input.join("b"), "b").unwrap();
+    ouch!("-A", "c", input, dir.join("old.zip"));
+    ouch!("-A", "c", input, dir.join("new.tar.zst"));
+
+    // Only the entries are compared, not the formats
+    crate::utils::cargo_bin()
+        .args(["diff", "old.zip", "new.tar.zst"])
+        .current_dir(dir)
+        .assert()
+        .success();
+
+    fs::write(input.join("a"), "changed").unwrap();
+    fs::remove_file(input.join("b")).unwrap();
+    fs::write(input.join("c"), "c").unwrap();
+    ouch!("-A", "c", input, dir.join("newer.tar.zst"));
+
+    let output = crate::utils::cargo_bin()
+        .args(["diff", "old.zip", "newer.tar.zst", "--json"])
+        .current_dir(dir)
+        .assert()
+        .code(1);
+    assert_eq!(
+        String::from_utf8(output.get_output().stdout.clone())
+            .unwrap()
+            .trim_end(),
+        r#"{"identical":false,"only_in_first":["input/b"],"only_in_second":["input/c"],"changed":[{"path":"input/a","changes":["content"]}]}"#
+    );
+
+    // Metadata is only compared when asked to
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::set_permissions(input.join("c"), std::fs::Permissions::from_mode(0o600)).unwrap();
+        ouch!("-A", "c", input, dir.join("newest.tar.zst"));
+
+        crate::utils::cargo_bin()
+            .args(["diff", "newer.tar.zst", "newest.tar.zst"])
+            .current_dir(dir)
+            .assert()
+            .success();
+        let output = crate::utils::cargo_bin()
+            .args(["diff", "newer.tar.zst", "newest.tar.zst", "--metadata"])
+            .current_dir(dir)
+            .assert()
+            .code(1);
+        let stdout = String::from_utf8(output.get_output().stdout.clone()).unwrap();
+        assert_eq!(stdout.trim_end(), "Changed: input/c (mode)");
+    }
+}
+
+#[test]
+fn cat_archive_entry() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let input = &dir.join("app");
+    fs::create_dir_all(input.join("old")).unwrap();
+    fs::write(input.join("today.log"), "today").unwrap();
+    fs::write(input.join("old/yesterday.log"), "yesterday").unwrap();
+
+    for archive in ["logs.tar.zst", "logs.zip", "logs.7z"] {
+        ouch!("-A", "c", input, dir.join(archive));
+        let cat = |entry: &str| {
+            crate::utils::cargo_bin()
+                .args(["cat", archive, entry])
+                .current_dir(dir)
+                .assert()
+        };
+
+        let output = cat("app/today.log").success();
+        assert_eq!(output.get_output().stdout, b"today");
+        let output = cat("*/yesterday.*").success();
+        assert_eq!(output.get_output().stdout, b"yesterday");
+
+        // Diagnostics never end up mixed with the contents
+        for entry in ["*.log", "app/missing.log"] {
+            let output = cat(entry).code(1);
+            assert!(output.get_output().stdout.is_empty());
+            assert!(!output.get_output().stderr.is_empty());
+        }
+    }
+}
+
+#[test]
+fn merge_archives() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    for (part, files) in [("part1", ["a", "b"]), ("part2", ["b", "c"])] {
+        let input = &dir.join(part).join("input");
+        fs::create_dir_all(input).unwrap();
+        for file in files {
+            fs::write(input.join(file), format!("{file} from {part}")).unwrap();
+        }
+    }
+    ouch!("-A", "c", dir.join("part1/input"), dir.join("part1.tar.gz"));
+    ouch!("-A", "c", dir.join("part2/input"), dir.join("part2.zip"));
+
+    // The last archive wins with '--yes', and the first one with '--no'
+    for (flag, output, b_from) in [("--yes", "merged.tar.zst", "part2"), ("--no", "merged.7z", "part1")] {
+        crate::utils::cargo_bin()
+            .args(["merge", "part1.tar.gz", "part2.zip", "--output", output, flag])
+            .current_dir(dir)
+            .assert()
+            .success();
+
+        let out = &dir.join(format!("out{flag}"));
+        ouch!("-A", "d", dir.join(output), "-d", out);
+        let merged = out.join("input");
+        assert_eq!(fs::read_to_string(merged.join("a")).unwrap(), "a from part1");
+        assert_eq!(
+            fs::read_to_string(merged.join("b")).unwrap(),
+            format!("b from {b_from}")
+        );
+        assert_eq!(fs::read_to_string(merged.join("c")).unwrap(), "c from part2");
+    }
+}
+
+#[test]
+fn merge_zip_archives_copies_entries() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let mut rng = SmallRng::seed_from_u64(0);
+    for (part, file) in [("part1", "random"), ("part2", "text")] {
+        let input = &dir.join(part).join("input");
+        fs::create_dir_all(input).unwrap();
+        if file == "random" {
+            // Incompressible, so it's stored
+            let mut data = vec![0; 64 * 1024];
+            rng.fill_bytes(&mut data);
+            fs::write(input.join(file), data).unwrap();
+        } else {
+            fs::write(input.join(file), "text ".repeat(1000)).unwrap();
+        }
+        ouch!("-A", "c", input, dir.join(format!("{part}.zip")));
+    }
+    crate::utils::cargo_bin()
+        .args(["merge", "part1.zip", "part2.zip", "--output", "merged.zip"])
+        .current_dir(dir)
+        .assert()
+        .success();
+
+    let open = |name: &str| zip::ZipArchive::new(fs::File::open(dir.join(name)).unwrap()).unwrap();
+    let mut merged = open("merged.zip");
+    for (part, file) in [("part1.zip", "input/random"), ("part2.zip", "input/text")] {
+        let mut source = open(part);
+        let raw_entry = |archive: &mut zip::ZipArchive<fs::File>| {
+            let index = (0..archive.len())
+                .find(|&index| archive.by_index_raw(index).unwrap().name() == file)
+                .unwrap();
+            let mut entry = archive.by_index_raw(index).unwrap();
+            let mut data = vec![];
+            std::io::Read::read_to_end(&mut entry, &mut data).unwrap();
+            (entry.compression(), entry.crc32(), entry.size(), data)
+        };
+        assert_eq!(raw_entry(&mut merged), raw_entry(&mut source));
+
+        // Reading the whole entry checks its CRC
+        let mut contents = vec![];
+        std::io::Read::read_to_end(&mut merged.by_name(file).unwrap(), &mut contents).unwrap();
+        assert_eq!(
+            contents,
+            fs::read(dir.join(part.replace(".zip", "")).join(file)).unwrap()
+        );
+    }
+}
+
+#[test]
+fn verify_against_directory() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let input = &dir.join("input");
+    fs::create_dir(input).unwrap();
+    fs::write(input.join("a"), "a").unwrap();
+    fs::write(input.join("b"), "b").unwrap();
+    ouch!("-A", "c", input, dir.join("backup.tar.zst"));
+    ouch!("-A", "d", dir.join("backup.tar.zst"), "-d", dir.join("restored"));
+
+    let verify = |args: &[&str]| {
+        crate::utils::cargo_bin()
+            .args(["verify", "backup.tar.zst", "--against", "restored"])
+            .args(args)
+            .current_dir(dir)
+            .assert()
+    };
+    verify(&[]).success();
+
+    let restored = &dir.join("restored/input");
+    fs::write(restored.join("a"), "changed").unwrap();
+    fs::remove_file(restored.join("b")).unwrap();
+    fs::write(restored.join("c"), "c").unwrap();
+
+    // Extra files are only reported with '--strict'
+    let output = verify(&[]).code(1);
+    let stdout = String::from_utf8(output.get_output().stdout.clone()).unwrap();
+    assert_eq!(stdout, "Missing: input/b\nChanged: input/a (content)\n");
+
+    let output = verify(&["--strict", "--json"]).code(1);
+    assert_eq!(
+        String::from_utf8(output.get_output().stdout.clone())
+            .unwrap()
+            .trim_end(),
+        r#"{"identical":false,"missing":["input/b"],"extra":["input/c"],"changed":[{"path":"input/a","changes":["content"]}]}"#
+    );
+}
+
+#[test]
+fn exit_codes() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    fs::write(dir.join("file"), "file").unwrap();
+    fs::write(dir.join("other"), "other").unwrap();
+
+    // Success, silently
+    ouch_in(dir, &["c", "file", "file.tar.gz", "--quiet-errors"])
+        .code(0)
+        .stderr("");
+    ouch_in(dir, &["c", "other", "other.zip", "--quiet-errors"])
+        .code(0)
+        .stderr("");
+
+    // Usage errors
+    ouch_in(dir, &["c", "file", "file.unknown", "--quiet-errors"]).code(1);
+    ouch_in(dir, &["d", "file.tar.gz", "--no-such-flag"]).code(1);
+
+    // I/O errors
+    ouch_in(dir, &["d", "missing.tar.gz", "--quiet-errors"]).code(2);
+
+    // Corrupted archives
+    for archive in ["corrupt.tar.gz", "corrupt.zip", "corrupt.7z", "corrupt.tar.zst"] {
+        fs::write(dir.join(archive), "not an archive").unwrap();
+        ouch_in(dir, &["d", archive, "--quiet-errors"]).code(3);
+    }
+
+    // Partial success, 'file.tar.gz' is skipped since its output already exists
+    fs::create_dir(dir.join("out")).unwrap();
+    fs::write(dir.join("out/file"), "existing").unwrap();
+    ouch_in(
+        dir,
+        &[
+            "d",
+            "file.tar.gz",
+            "other.zip",
+            "--dir",
+            "out",
+            "--no",
+            "--quiet-errors",
+        ],
+    )
+    .code(4)
+    .stderr("");
+    assert_eq!(fs::read(dir.join("out/file")).unwrap(), b"existing");
+    assert_eq!(fs::read(dir.join("out/other")).unwrap(), b"other");
+}
+
+#[test]
+fn decompress_continue_on_error() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    fs::write(dir.join("file"), "file").unwrap();
+    fs::write(dir.join("corrupt.zip"), "not an archive").unwrap();
+    ouch_in(dir, &["c", "file", "good.tar.gz"]).success();
+
+    // Without the flag, the corrupt archive fails the whole batch
+    ouch_in(dir, &["d", "corrupt.zip", "good.tar.gz", "--dir", "stopped"]).code(3);
+
+    let failed = ouch_in(dir, &["d", "corrupt.zip", "good.tar.gz", "--dir", "out", "-k"]).code(4);
+    let stderr = String::from_utf8(failed.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains("Failed to decompress 1 of 2 archives"));
+    assert_eq!(fs::read(dir.join("out/file")).unwrap(), b"file");
+
+    // When every archive fails, the exit code is the one of the error
+    ouch_in(dir, &["d", "corrupt.zip", "--dir", "none", "-k"]).code(3);
+}
+
+#[test]
+fn infer_subcommand() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    fs::write(dir.join("file"), "file").unwrap();
+    fs::create_dir(dir.join("dir")).unwrap();
+    fs::write(dir.join("dir/other"), "other").unwrap();
+
+    // Files followed by a new archive are compressed, flags keep working anywhere
+    ouch_in(dir, &["-q", "file", "dir", "archive.tar.gz", "--level", "1"]).success();
+    ouch_in(dir, &["list", "archive.tar.gz"]).success();
+
+    // Archives are decompressed, with the flags of `decompress`
+    ouch_in(dir, &["archive.tar.gz", "-d", "out", "--yes"]).success();
+    assert_eq!(fs::read(dir.join("out/archive/file")).unwrap(), b"file");
+    assert_eq!(fs::read(dir.join("out/archive/dir/other")).unwrap(), b"other");
+
+    // Ambiguous, the output already exists, it could be an append
+    ouch_in(dir, &["file", "archive.tar.gz"]).code(1);
+    // A typo of a subcommand is still reported by clap
+    ouch_in(dir, &["lst", "archive.tar.gz"]).code(1);
+}
+
+#[test]
+fn decompress_format_override() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    fs::write(dir.join("file"), "file").unwrap();
+    ouch_in(dir, &["c", "file", "data.tar.xz"]).success();
+    fs::rename(dir.join("data.tar.xz"), dir.join("data.tar")).unwrap();
+    ouch_in(dir, &["c", "file", "file.gz"]).success();
+    fs::rename(dir.join("file.gz"), dir.join("blob")).unwrap();
+
+    // '+xz' is added as an outer layer to the 'tar' of the extension
+    ouch_in(dir, &["d", "data.tar", "--format", "+xz", "--dir", "appended"]).success();
+    assert_eq!(fs::read(dir.join("appended/file")).unwrap(), b"file");
+
+    // Only 'blob' is given its format, 'data.tar' still gets '+xz'
+    ouch_in(
+        dir,
+        &["d", "data.tar", "blob:gz", "--format", "+xz", "--dir", "per_file"],
+    )
+    .success();
+    assert_eq!(fs::read(dir.join("per_file/file")).unwrap(), b"file");
+    assert_eq!(fs::read(dir.join("per_file/blob")).unwrap(), b"file");
+
+    ouch_in(dir, &["list", "data.tar:tar.xz"]).success();
+    // The suffix must be a format
+    ouch_in(dir, &["d", "blob:txt", "--dir", "unknown"]).code(1);
+}
+
+#[test]
+fn log_format_json() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    fs::write(dir.join("file"), "file").unwrap();
+    fs::write(dir.join("corrupt.zip"), "not an archive").unwrap();
+
+    let compressed = ouch_in(dir, &["c", "file", "archive.tar.gz", "--log-format", "json"]).success();
+    let stderr = String::from_utf8(compressed.get_output().stderr.clone()).unwrap();
+    assert_eq!(
+        stderr.lines().collect::<Vec<_>>(),
+        [
+            r#"{"level":"info","message":"Compressing 'file'.","accessible":false}"#,
+            r#"{"level":"info","message":"Successfully compressed 'archive.tar.gz'.","accessible":true}"#,
+        ]
+    );
+
+    // Errors are JSON lines too, without the '[ERROR]' of the human format
+    let failed = ouch_in(dir, &["d", "corrupt.zip", "--log-format", "json"]).code(3);
+    let stderr = String::from_utf8(failed.get_output().stderr.clone()).unwrap();
+    let error = stderr.lines().last().unwrap();
+    assert!(error.starts_with(r#"{"level":"error","message":""#), "{error}");
+    assert!(error.ends_with(r#"","accessible":true}"#), "{error}");
+    assert!(!error.contains("[ERROR]"), "{error}");
+}
+
+#[test]
+fn chained_sevenz_temp_dir() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    fs::write(dir.join("file"), "file").unwrap();
+    fs::create_dir(dir.join("temp")).unwrap();
+    ouch_in(dir, &["c", "file", "archive.7z.gz", "--yes", "--temp-dir", "temp"]).success();
+
+    ouch_in(dir, &["list", "archive.7z.gz", "--yes", "--temp-dir", "temp"]).success();
+    ouch_in(
+        dir,
+        &["d", "archive.7z.gz", "--yes", "--temp-dir", "temp", "--dir", "out"],
+    )
+    .success();
+    assert_eq!(fs::read(dir.join("out/file")).unwrap(), b"file");
+    // The temporary files are gone once they're read
+    assert_eq!(fs::read_dir(dir.join("temp")).unwrap().count(), 0);
+
+    ouch_in(dir, &["d", "archive.7z.gz", "--yes", "--temp-dir", "missing"]).code(1);
+}
+
+#[test]
+fn chained_zip_max_memory() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    fs::create_dir(dir.join("input")).unwrap();
+    fs::create_dir(dir.join("temp")).unwrap();
+    let mut data = vec![0; 100_000];
+    SmallRng::seed_from_u64(0).fill(&mut data[..]);
+    fs::write(dir.join("input/file"), &data).unwrap();
+    let stderr = |output: assert_cmd::assert::Assert| String::from_utf8(output.get_output().stderr.clone()).unwrap();
+
+    // Spilled to a temporary file past '--max-memory', without asking
+    let args = [
+        "c",
+        "input",
+        "archive.zip.gz",
+        "--max-memory",
+        "1KiB",
+        "--temp-dir",
+        "temp",
+    ];
+    let output = stderr(ouch_in(dir, &args).success());
+    assert!(output.contains("moving it to a temporary file"), "{output}");
+    assert!(!output.contains("limited by design"), "{output}");
+    assert_eq!(fs::read_dir(dir.join("temp")).unwrap().count(), 0);
+
+    // Kept in memory with '--no-spill', after warning about it
+    let output = stderr(ouch_in(dir, &["d", "archive.zip.gz", "--no-spill", "--yes", "--dir", "out"]).success());
+    assert!(output.contains("limited by design"), "{output}");
+    assert!(!output.contains("moving it to a temporary file"), "{output}");
+    assert_eq!(fs::read(dir.join("out/input/file")).unwrap(), data);
+
+    ouch_in(dir, &["d", "archive.zip.gz", "--no-spill", "--max-memory", "1KiB"]).failure();
+}
+
+#[test]
+fn spilled_zip_temp_dir() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    fs::create_dir(dir.join("input")).unwrap();
+    fs::create_dir(dir.join("flag")).unwrap();
+    fs::create_dir(dir.join("env")).unwrap();
+    let mut data = vec![0; 100_000];
+    SmallRng::seed_from_u64(0).fill(&mut data[..]);
+    fs::write(dir.join("input/file"), &data).unwrap();
+    let spill_message =
+        |temp_dir: &std::path::Path| format!("moving it to a temporary file in '{}'", temp_dir.display());
+
+    // The temporary file is deleted as soon as it's closed, which it's already once it's created
+    // on Linux, so where it goes is checked by the message
+    let output = crate::utils::cargo_bin()
+        .args([
+            "c",
+            "input",
+            "archive.zip.gz",
+            "--max-memory",
+            "1KiB",
+            "--tempdir",
+            "flag",
+        ])
+        .current_dir(dir)
+        .env("TMPDIR", dir.join("env"))
+        .assert()
+        .success();
+    let stderr = String::from_utf8(output.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains(&spill_message("flag".as_ref())), "{stderr}");
+
+    // Without the flag, it goes to TMPDIR
+    let output = crate::utils::cargo_bin()
+        .args(["d", "archive.zip.gz", "--max-memory", "1KiB", "--dir", "out"])
+        .current_dir(dir)
+        .env("TMPDIR", dir.join("env"))
+        .assert()
+        .success();
+    let stderr = String::from_utf8(output.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains(&spill_message(&dir.join("env"))), "{stderr}");
+    assert_eq!(fs::read(dir.join("out/input/file")).unwrap(), data);
+
+    assert_eq!(fs::read_dir(dir.join("flag")).unwrap().count(), 0);
+    assert_eq!(fs::read_dir(dir.join("env")).unwrap().count(), 0);
+}
+
+#[test]
+fn compress_archive_mtime() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    fs::write(dir.join("file"), "file").unwrap();
+    let mtime = |path: &str| {
+        let modified = fs::metadata(dir.join(path)).unwrap().modified().unwrap();
+        modified.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+    };
+
+    crate::utils::cargo_bin()
+        .args(["c", "file", "flag.tar.gz", "--archive-mtime", "1000000000"])
+        .current_dir(dir)
+        .assert()
+        .success();
+    assert_eq!(mtime("flag.tar.gz"), 1_000_000_000);
+
+    crate::utils::cargo_bin()
+        .args(["c", "file", "env.zip"])
+        .env("SOURCE_DATE_EPOCH", "1234567890")
+        .current_dir(dir)
+        .assert()
+        .success();
+    assert_eq!(mtime("env.zip"), 1_234_567_890);
+}
+
+#[test]
+fn parallel_compression_threads() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    // Big enough to be compressed in parallel, in more than one job of the zstd workers
+    let mut rng = SmallRng::seed_from_u64(0);
+    let contents: Vec<u8> = (0..10 * 1024 * 1024).map(|_| b"ouch!\n"[rng.gen_range(0..6)]).collect();
+    fs::write(dir.join("file"), &contents).unwrap();
+
+    for format in ["gz", "sz", "zst"] {
+        for threads in ["1", "4"] {
+            let archive = format!("file-{threads}.{format}");
+            ouch_in(dir, &["c", "file", &archive, "--threads", threads]).success();
+            ouch_in(dir, &["d", &archive, "--dir", &format!("out-{threads}-{format}")]).success();
+            assert_eq!(
+                fs::read(dir.join(format!("out-{threads}-{format}/file-{threads}"))).unwrap(),
+                contents
+            );
+        }
+    }
+
+    // The blocks compressed in parallel still make a single gzip member, like with one thread
+    for archive in ["file-1.gz", "file-4.gz"] {
+        let mut decoded = vec![];
+        let file = fs::File::open(dir.join(archive)).unwrap();
+        std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(file), &mut decoded).unwrap();
+        assert_eq!(decoded, contents);
+    }
+}
+
+#[test]
+fn parallel_bzip2() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let mut rng = SmallRng::seed_from_u64(0);
+    let contents: Vec<u8> = (0..9 * 1024 * 1024).map(|_| b"ouch!\n"[rng.gen_range(0..6)]).collect();
+    fs::write(dir.join("file"), &contents).unwrap();
+
+    // Big enough to be compressed in parallel, in 100k blocks with the fastest level
+    for threads in ["1", "4"] {
+        let archive = format!("file-{threads}.bz2");
+        ouch_in(dir, &["c", "file", &archive, "--fast", "--threads", threads]).success();
+
+        // Still readable by a standard bzip2 decoder
+        let mut decoded = vec![];
+        let file = fs::File::open(dir.join(&archive)).unwrap();
+        std::io::Read::read_to_end(&mut bzip2::read::MultiBzDecoder::new(file), &mut decoded).unwrap();
+        assert_eq!(decoded, contents);
+
+        for decode_threads in ["1", "4"] {
+            let out = format!("out-{threads}-{decode_threads}");
+            ouch_in(dir, &["d", &archive, "--dir", &out, "--threads", decode_threads]).success();
+            assert_eq!(
+                fs::read(dir.join(out).join(format!("file-{threads}"))).unwrap(),
+                contents
+            );
+        }
+    }
+
+    // Made like `pbzip2 -b1` does, with one stream for every 100k of input
+    let archive = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/data/multistream.txt.bz2");
+    let expected: String = (0..25000).map(|i| format!("line {i}\n")).collect();
+    for threads in ["1", "4"] {
+        let out = format!("multistream-{threads}");
+        ouch_in(
+            dir,
+            &["d", archive.to_str().unwrap(), "--dir", &out, "--threads", threads],
+        )
+        .success();
+        assert_eq!(
+            fs::read_to_string(dir.join(out).join("multistream.txt")).unwrap(),
+            expected
+        );
+    }
+}
+
+#[test]
+fn decompress_concatenated_members() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    fs::write(dir.join("first"), "first\n").unwrap();
+    fs::write(dir.join("second"), "second\n").unwrap();
+
+    for format in ["gz", "zst"] {
+        ouch_in(dir, &["c", "first", &format!("first.{format}")]).success();
+        ouch_in(dir, &["c", "second", &format!("second.{format}")]).success();
+
+        // Like `cat first.gz second.gz > log.gz`
+        let mut concatenated = fs::read(dir.join(format!("first.{format}"))).unwrap();
+        concatenated.extend(fs::read(dir.join(format!("second.{format}"))).unwrap());
+        fs::write(dir.join(format!("log.{format}")), concatenated).unwrap();
+
+        ouch_in(dir, &["d", &format!("log.{format}"), "--dir", format]).success();
+        assert_eq!(fs::read(dir.join(format).join("log")).unwrap(), b"first\nsecond\n");
+    }
+}
+
+#[test]
+fn decompress_recursive() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let outer = dir.join("outer");
+    fs::create_dir_all(outer.join("inner")).unwrap();
+    fs::write(outer.join("inner/a.txt"), "a").unwrap();
+    fs::write(outer.join("inner/b.txt"), "b").unwrap();
+    fs::write(outer.join("log.txt"), "log").unwrap();
+    fs::write(outer.join("readme"), "readme").unwrap();
+
+    ouch_in(dir, &["c", "outer/inner/a.txt", "outer/inner/b.txt", "outer/inner.zip"]).success();
+    ouch_in(dir, &["c", "outer/log.txt", "outer/log.txt.gz"]).success();
+    fs::remove_dir_all(outer.join("inner")).unwrap();
+    fs::remove_file(outer.join("log.txt")).unwrap();
+    ouch_in(dir, &["c", "outer", "outer.tar.gz"]).success();
+
+    ouch_in(dir, &["d", "outer.tar.gz", "--dir", "kept", "--recursive"]).success();
+    let kept = dir.join("kept/outer");
+    assert_eq!(fs::read(kept.join("inner/a.txt")).unwrap(), b"a");
+    assert_eq!(fs::read(kept.join("inner/b.txt")).unwrap(), b"b");
+    assert_eq!(fs::read(kept.join("log.txt")).unwrap(), b"log");
+    assert_eq!(fs::read(kept.join("readme")).unwrap(), b"readme");
+    assert!(kept.join("inner.zip").exists());
+    assert!(kept.join("log.txt.gz").exists());
+
+    ouch_in(dir, &["d", "outer.tar.gz", "--dir", "removed", "-r", "--rm-nested"]).success();
+    let removed = dir.join("removed/outer");
+    assert_eq!(fs::read(removed.join("inner/a.txt")).unwrap(), b"a");
+    assert_eq!(fs::read(removed.join("log.txt")).unwrap(), b"log");
+    assert!(!removed.join("inner.zip").exists());
+    assert!(!removed.join("log.txt.gz").exists());
+}
+
+#[test]
+fn decompress_recursive_max_nesting() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    fs::write(dir.join("file.txt"), "file").unwrap();
+
+    // Each archive holds the previous one, three levels deep
+    ouch_in(dir, &["c", "file.txt", "file.txt.gz"]).success();
+    ouch_in(dir, &["c", "file.txt.gz", "middle.tar"]).success();
+    ouch_in(dir, &["c", "middle.tar", "outer.zip"]).success();
+
+    let shallow = ouch_in(dir, &["d", "outer.zip", "--dir", "shallow", "-r", "--max-nesting", "1"]).success();
+    let stderr = String::from_utf8(shallow.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains("--max-nesting"));
+    assert!(dir.join("shallow/middle.tar").exists());
+    assert!(dir.join("shallow/file.txt.gz").exists());
+    assert!(!dir.join("shallow/file.txt").exists());
+
+    ouch_in(dir, &["d", "outer.zip", "--dir", "deep", "-r"]).success();
+    assert_eq!(fs::read(dir.join("deep/file.txt")).unwrap(), b"file");
+}
+
+#[test]
+fn decompress_recursive_limits() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    fs::write(dir.join("file"), vec![b'a'; 3000]).unwrap();
+
+    // The outer archive and the inner one only expand beyond the limit together
+    ouch_in(dir, &["c", "file", "inner.tar"]).success();
+    ouch_in(dir, &["c", "inner.tar", "outer.tar"]).success();
+
+    let failed = ouch_in(
+        dir,
+        &["d", "outer.tar", "--dir", "limited", "-r", "--max-output-size", "7000"],
+    )
+    .code(1);
+    let stderr = String::from_utf8(failed.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains("the maximum output size"));
+    assert!(!dir.join("limited/file").exists());
+
+    ouch_in(
+        dir,
+        &[
+            "d",
+            "outer.tar",
+            "--dir",
+            "unlimited",
+            "-r",
+            "--max-output-size",
+            "12000",
+        ],
+    )
+    .success();
+    assert_eq!(fs::read(dir.join("unlimited/file")).unwrap(), vec![b'a'; 3000]);
+}
+
+#[test]
+fn decompress_zip_in_parallel() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+
+    let input = dir.join("input");
+    for i in 0..40 {
+        let parent = input.join(format!("dir-{}/nested-{}", i % 4, i % 3));
+        fs::create_dir_all(&parent).unwrap();
+        write_random_content(
+            &mut fs::File::create(parent.join(format!("file-{i}"))).unwrap(),
+            &mut SmallRng::from_entropy(),
+        );
+    }
+    fs::create_dir_all(input.join("empty")).unwrap();
+    ouch_in(dir, &["c", "input", "archive.zip"]).success();
+
+    for threads in ["1", "4"] {
+        let out = format!("out-{threads}");
+        ouch_in(dir, &["d", "archive.zip", "--dir", &out, "--threads", threads]).success();
+        assert_same_directory(&input, dir.join(out).join("input"), false);
+    }
+
+    // Every entry that failed is named, not only the first one
+    for name in ["a", "b", "c"] {
+        let mut data = vec![0; 4096];
+        SmallRng::from_entropy().fill(&mut data[..]);
+        fs::write(dir.join(name), data).unwrap();
+    }
+    ouch_in(dir, &["c", "a", "b", "c", "corrupt.zip"]).success();
+    let mut archive = fs::read(dir.join("corrupt.zip")).unwrap();
+    let headers: Vec<_> = archive
+        .windows(4)
+        .enumerate()
+        .filter_map(|(i, window)| (window == b"PK\x03\x04").then_some(i))
+        .collect();
+    for header in headers {
+        let name_len = u16::from_le_bytes([archive[header + 26], archive[header + 27]]) as usize;
+        let extra_len = u16::from_le_bytes([archive[header + 28], archive[header + 29]]) as usize;
+        archive[header + 30 + name_len + extra_len + 100] ^= 0xff;
+    }
+    fs::write(dir.join("corrupt.zip"), archive).unwrap();
+
+    let output = ouch_in(dir, &["d", "corrupt.zip", "--dir", "corrupt", "--threads", "4"]).failure();
+    let stderr = String::from_utf8(output.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains("Failed to extract 3 entries: a, b, c."), "{stderr}");
+}
+
+#[test]
+fn large_chained_zip() {
+    // Incompressible, so that the archives themselves grow past the limit of 64 MiB
+    let mut data = vec![0; 72 * 1024 * 1024];
+    SmallRng::from_entropy().fill(&mut data[..]);
+
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let before = &dir.join("before");
+    let archive = &dir.join("archive.zip.zst");
+    let after = &dir.join("after");
+    fs::create_dir(before).unwrap();
+    fs::write(before.join("file"), &data).unwrap();
+
+    ouch!("-A", "c", before.join("file"), archive);
+    ouch!("-A", "l", archive);
+    ouch!("-A", "d", archive, "-d", after);
+    assert_same_directory(before, after, false);
+}
+
+#[test]
+fn zip_checksum_mismatch() {
+    // Stored entries, the first letter of 'corrupt.txt' was made uppercase after it was made
+    let archive = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/data/checksum-mismatch.zip");
+    let archive = archive.to_str().unwrap();
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let stderr = |assert: assert_cmd::assert::Assert| String::from_utf8(assert.get_output().stderr.clone()).unwrap();
+
+    for threads in ["1", "4"] {
+        // Nothing is extracted
+        let output = format!("stopped-{threads}");
+        let failed = ouch_in(dir, &["-A", "--threads", threads, "d", archive, "-d", &output]).code(3);
+        let stderr_text = stderr(failed);
+        assert!(stderr_text.contains("The entry 'corrupt.txt' doesn't match its checksum"));
+        assert!(stderr_text.contains("Its CRC32 is f92c3857, but the archive records 569f7388"));
+        assert_eq!(fs::read_dir(dir.join(&output)).unwrap().count(), 0);
+
+        // Or everything else is, and the corrupted entry is named at the end
+        let output = format!("kept-{threads}");
+        let failed = ouch_in(
+            dir,
+            &["-A", "--threads", threads, "d", archive, "-d", &output, "--keep-going"],
+        )
+        .code(3);
+        let stderr_text = stderr(failed);
+        assert!(stderr_text.contains("Some entries of"));
+        assert!(stderr_text.contains("'corrupt.txt' has the CRC32 f92c3857"));
+        let output = dir.join(&output).join("checksum-mismatch");
+        assert_eq!(
+            fs::read_to_string(output.join("good.txt")).unwrap(),
+            "this entry is fine\n"
+        );
+        assert_eq!(
+            fs::read_to_string(output.join("other.txt")).unwrap(),
+            "this one is fine too\n"
+        );
+        assert!(!output.join("corrupt.txt").exists());
+    }
+
+    // Reading it to compare it with a directory fails the same way
+    let failed = ouch_in(dir, &["verify", archive, "--against", "."]).code(3);
+    assert!(stderr(failed).contains("'corrupt.txt' has the CRC32 f92c3857"));
+}
+
+#[test]
+fn decompress_encrypted_zip() {
+    // Created by Info-ZIP with `zip -r -P "correct horse" encrypted.zip secret`
+    let archive = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/data/encrypted.zip");
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let password_file = &dir.join("password");
+
+    let failed = crate::utils::cargo_bin()
+        .args(["-A", "d"])
+        .arg(&archive)
+        .arg("-d")
+        .arg(dir.join("no-password"))
+        .assert()
+        .code(1);
+    let stderr = String::from_utf8(failed.get_output().stderr.clone()).unwrap();
+    assert!(stderr.contains("--password-file"));
+
+    fs::write(password_file, "wrong\n").unwrap();
+    crate::utils::cargo_bin()
+        .args(["-A", "d"])
+        .arg(&archive)
+        .arg("-d")
+        .arg(dir.join("wrong-password"))
+        .arg("--password-file")
+        .arg(password_file)
+        .assert()
+        .code(1);
+
+    // Only the first line is the password, without surrounding whitespace
+    fs::write(password_file, "  correct horse \nignored\n").unwrap();
+    ouch!(
+        "-A",
+        "d",
+        &archive,
+        "-d",
+        dir.join("output"),
+        "--password-file",
+        password_file
+    );
+    assert_eq!(
+        fs::read_to_string(dir.join("output/secret/note.txt")).unwrap(),
+        "top secret\n"
+    );
+    assert_eq!(
+        fs::read_to_string(dir.join("output/secret/other.txt")).unwrap(),
+        "more\n"
+    );
+
+    // Listing doesn't need the password
+    crate::utils::cargo_bin()
+        .args(["-A", "l"])
+        .arg(&archive)
+        .assert()
+        .success();
+}
+
 #[cfg(feature = "unrar")]
 #[test]
 fn unpack_rar() -> Result<(), Box<dyn std::error::Error>> {
@@ -173,3 +3538,275 @@ fn unpack_rar() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[test]
+fn compress_stdin_with_name() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let stderr = |assert: assert_cmd::assert::Assert| String::from_utf8(assert.get_output().stderr.clone()).unwrap();
+    let data = b"\x00\x01piped bytes\xff".repeat(1000);
+
+    crate::utils::cargo_bin()
+        .args([
+            "-A",
+            "c",
+            "-",
+            "out.tgz",
+            "--format",
+            "tar.gz",
+            "--stdin-name",
+            "data.bin",
+        ])
+        .current_dir(dir)
+        .write_stdin(data.clone())
+        .assert()
+        .success();
+    let listed = ouch_in(dir, &["-A", "l", "out.tgz"]).success();
+    let listed = String::from_utf8(listed.get_output().stdout.clone()).unwrap();
+    assert_eq!(listed.lines().skip(1).collect::<Vec<_>>(), ["data.bin"]);
+    ouch_in(dir, &["-A", "d", "out.tgz", "-d", "out"]).success();
+    assert_eq!(fs::read(dir.join("out/data.bin")).unwrap(), data);
+
+    // Named 'stdin' by default, and the name doesn't matter without an archive
+    crate::utils::cargo_bin()
+        .args(["-A", "c", "-", "out.zip"])
+        .current_dir(dir)
+        .write_stdin(data.clone())
+        .assert()
+        .success();
+    let listed = ouch_in(dir, &["-A", "l", "out.zip"]).success();
+    assert!(String::from_utf8_lossy(&listed.get_output().stdout).contains("stdin"));
+    crate::utils::cargo_bin()
+        .args(["-A", "c", "-", "piped.gz", "--stdin-name", "ignored.bin"])
+        .current_dir(dir)
+        .write_stdin(data.clone())
+        .assert()
+        .success();
+    ouch_in(dir, &["-A", "d", "piped.gz"]).success();
+    assert_eq!(fs::read(dir.join("piped")).unwrap(), data);
+
+    let failed = ouch_in(dir, &["c", "-", "out.tar", "--stdin-name", "../data.bin"]).failure();
+    assert!(stderr(failed).contains("It must be a file name, without directories"));
+}
+
+#[test]
+fn detect_format_chain_without_extension() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let stderr = |assert: assert_cmd::assert::Assert| String::from_utf8(assert.get_output().stderr.clone()).unwrap();
+
+    fs::create_dir(dir.join("input")).unwrap();
+    fs::write(dir.join("input/a.txt"), "a").unwrap();
+    for format in [
+        "tar.gz", "tar.zst", "tar.xz", "tar.bz2", "tar.lz4", "tar.sz", "zip", "7z",
+    ] {
+        let archive = format!("archive.{format}");
+        ouch_in(dir, &["-A", "c", "input", &archive]).success();
+        let blob = format!("blob-{}", format.replace('.', "-"));
+        fs::rename(dir.join(&archive), dir.join(&blob)).unwrap();
+
+        // The whole chain is detected, so it's unpacked as an archive
+        let output = format!("out-{blob}");
+        let decompressed = ouch_in(dir, &["-A", "-y", "d", &blob, "-d", &output]).success();
+        assert!(
+            stderr(decompressed).contains(&format!("extension as `{format}`")),
+            "{format}"
+        );
+        assert_eq!(fs::read_to_string(dir.join(&output).join("input/a.txt")).unwrap(), "a");
+    }
+
+    // A misnamed file tells what its extension claims and what it looks like
+    fs::rename(dir.join("blob-tar-zst"), dir.join("misnamed.tar.gz")).unwrap();
+    let failed = ouch_in(dir, &["-A", "-n", "d", "misnamed.tar.gz"]);
+    assert!(stderr(failed).contains("is named like a `gz` file, but its contents look like a `zst` file"));
+}
+
+#[test]
+fn warn_about_double_compression() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let stderr = |assert: assert_cmd::assert::Assert| String::from_utf8(assert.get_output().stderr.clone()).unwrap();
+
+    fs::write(dir.join("file.txt"), "data".repeat(1000)).unwrap();
+    ouch_in(dir, &["-A", "c", "file.txt", "file.txt.gz"]).success();
+
+    // Still compressed, it's only a warning
+    let warned = ouch_in(dir, &["-A", "c", "file.txt.gz", "twice.gz"]).success();
+    assert!(stderr(warned).contains("file.txt.gz' is already compressed as `gz`"));
+    assert!(dir.join("twice.gz").exists());
+
+    let forced = ouch_in(dir, &["-A", "-y", "c", "file.txt.gz", "twice.gz", "--force"]).success();
+    assert!(!stderr(forced).contains("is already compressed"));
+
+    // Other formats and archives aren't warned about
+    let other = ouch_in(dir, &["-A", "c", "file.txt.gz", "other.zst"]).success();
+    assert!(!stderr(other).contains("is already compressed"));
+    let archived = ouch_in(dir, &["-A", "c", "file.txt.gz", "archive.tar.gz"]).success();
+    assert!(!stderr(archived).contains("is already compressed"));
+}
+
+#[test]
+fn select_largest_and_newest_entries() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let extracted = |output: &str| {
+        let mut names: Vec<String> = fs::read_dir(dir.join(output).join("input"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    };
+
+    fs::create_dir_all(dir.join("input/sub")).unwrap();
+    let now = std::time::SystemTime::now();
+    for (name, size, age) in [
+        ("a", 10, 4),
+        ("b", 5000, 1),
+        ("c", 300, 3),
+        ("sub/d", 20_000, 2),
+        ("e", 1, 0),
+    ] {
+        let path = dir.join("input").join(name);
+        fs::write(&path, "x".repeat(size)).unwrap();
+        let modified = now - std::time::Duration::from_secs(age * 1000);
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    for format in ["tar.gz", "zip"] {
+        let archive = format!("archive.{format}");
+        ouch_in(dir, &["-A", "c", "input", &archive]).success();
+
+        let output = format!("largest-{format}");
+        ouch_in(dir, &["-A", "d", &archive, "-d", &output, "--select-largest", "2"]).success();
+        assert_eq!(extracted(&output), ["b", "sub"]);
+        assert!(dir.join(&output).join("input/sub/d").exists());
+
+        // Among the files selected by the filter
+        let output = format!("filtered-{format}");
+        ouch_in(
+            dir,
+            &[
+                "-A",
+                "d",
+                &archive,
+                "-d",
+                &output,
+                "--select-largest",
+                "1",
+                "--exclude",
+                "**/d",
+            ],
+        )
+        .success();
+        assert_eq!(extracted(&output), ["b"]);
+
+        let output = format!("newest-{format}");
+        ouch_in(dir, &["-A", "d", &archive, "-d", &output, "--select-newest", "2"]).success();
+        assert_eq!(extracted(&output), ["b", "e"]);
+    }
+}
+
+#[test]
+fn diagnostics_go_to_stderr() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+    let streams = |assert: assert_cmd::assert::Assert| {
+        let assert = assert.success();
+        let output = assert.get_output();
+        (
+            String::from_utf8(output.stdout.clone()).unwrap(),
+            String::from_utf8(output.stderr.clone()).unwrap(),
+        )
+    };
+
+    fs::create_dir(dir.join("input")).unwrap();
+    fs::write(dir.join("input/a.txt"), "a").unwrap();
+    fs::write(dir.join("input/empty.txt"), "").unwrap();
+
+    let (stdout, stderr) = streams(ouch_in(dir, &["c", "input", "archive.zip", "--numeric-progress"]));
+    assert_eq!(stdout, "");
+    assert!(stderr.contains("[INFO]") && stderr.contains("100%"));
+
+    let (stdout, stderr) = streams(ouch_in(dir, &["d", "archive.zip", "-d", "out", "--numeric-progress"]));
+    assert_eq!(stdout, "");
+    assert!(stderr.contains("[INFO]") && stderr.contains("100%"));
+
+    // Only the listing goes to stdout, so that it can be redirected to a file
+    let (stdout, stderr) = streams(ouch_in(
+        dir,
+        &["l", "archive.zip", "--format", "zip", "--verify-format", "strict"],
+    ));
+    assert!(stdout.contains("input/a.txt") && stdout.contains("input/empty.txt"));
+    assert!(!stdout.contains("[INFO]") && !stdout.contains("[WARNING]"));
+    assert!(!stderr.contains("input/a.txt"));
+
+    let (stdout, stderr) = streams(ouch_in(
+        dir,
+        &["--log-format", "json", "c", "input", "archive.tar", "-y"],
+    ));
+    assert_eq!(stdout, "");
+    assert!(stderr.lines().all(|line| line.starts_with('{')));
+}
+
+#[test]
+fn ignore_zeros_reads_concatenated_tars() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+
+    fs::write(dir.join("a.txt"), "a").unwrap();
+    fs::write(dir.join("b.txt"), "b").unwrap();
+    ouch_in(dir, &["-A", "c", "a.txt", "first.tar"]).success();
+    ouch_in(dir, &["-A", "c", "b.txt", "second.tar"]).success();
+    let mut concatenated = fs::read(dir.join("first.tar")).unwrap();
+    concatenated.extend(fs::read(dir.join("second.tar")).unwrap());
+    fs::write(dir.join("both.tar"), &concatenated).unwrap();
+    // The concatenated stream, compressed as a whole
+    ouch_in(dir, &["-A", "c", "both.tar", "both.gz"]).success();
+    fs::rename(dir.join("both.gz"), dir.join("both.tgz")).unwrap();
+
+    for archive in ["both.tar", "both.tgz"] {
+        // Reading stops at the end of the first archive by default
+        let output = format!("default-{archive}");
+        ouch_in(dir, &["-A", "d", archive, "-d", &output]).success();
+        assert_eq!(fs::read_to_string(dir.join(&output).join("a.txt")).unwrap(), "a");
+        assert!(!dir.join(&output).join("b.txt").exists());
+
+        let output = format!("ignore-zeros-{archive}");
+        ouch_in(dir, &["-A", "d", archive, "-d", &output, "--ignore-zeros"]).success();
+        // With two files, they're extracted into a directory named after the archive
+        assert_eq!(fs::read_to_string(dir.join(&output).join("both/a.txt")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(dir.join(&output).join("both/b.txt")).unwrap(), "b");
+    }
+}
+
+#[test]
+fn archives_are_stored_as_they_are() {
+    let dir = tempdir().unwrap();
+    let dir = dir.path();
+
+    fs::write(dir.join("a.txt"), "a".repeat(1000)).unwrap();
+    fs::write(dir.join("b.txt"), "b".repeat(1000)).unwrap();
+    ouch_in(dir, &["-A", "c", "a.txt", "first.zip"]).success();
+    ouch_in(dir, &["-A", "c", "b.txt", "second.zip"]).success();
+
+    for bundle in ["bundle.tar", "bundle.tar.gz"] {
+        ouch_in(dir, &["-A", "c", "first.zip", "second.zip", bundle]).success();
+        let listed = ouch_in(dir, &["-A", "l", bundle]).success();
+        let listed = String::from_utf8(listed.get_output().stdout.clone()).unwrap();
+        assert_eq!(listed.lines().skip(1).collect::<Vec<_>>(), ["first.zip", "second.zip"]);
+
+        // Without --recursive, the inner archives come out byte for byte
+        let output = format!("out-{bundle}");
+        ouch_in(dir, &["-A", "d", bundle, "-d", &output]).success();
+        for archive in ["first.zip", "second.zip"] {
+            let extracted = dir.join(&output).join("bundle").join(archive);
+            assert_eq!(fs::read(extracted).unwrap(), fs::read(dir.join(archive)).unwrap());
+        }
+    }
+}
@@ -59,6 +59,17 @@ fn ui_test_err_compress_missing_extension() {
     ui!(run_ouch("ouch compress input output", dir));
 }
 
+#[test]
+fn ui_test_err_compress_output_is_dir() {
+    let (_dropper, dir) = testdir().unwrap();
+
+    // prepare
+    create_files_in(dir, &["input"]);
+    std::fs::create_dir(dir.join("output")).unwrap();
+
+    ui!(run_ouch("ouch compress input output", dir));
+}
+
 #[test]
 fn ui_test_err_decompress_missing_extension() {
     let (_dropper, dir) = testdir().unwrap();
@@ -87,6 +98,16 @@ fn ui_test_err_missing_files() {
     ui!(run_ouch("ouch list a b", dir));
 }
 
+#[test]
+fn ui_test_err_infer_subcommand() {
+    let (_dropper, dir) = testdir().unwrap();
+
+    create_files_in(dir, &["input"]);
+
+    ui!(run_ouch("ouch input", dir));
+    ui!(run_ouch("ouch input missing output.zip", dir));
+}
+
 #[test]
 fn ui_test_ok_compress() {
     let (_dropper, dir) = testdir().unwrap();
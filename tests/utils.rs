@@ -7,7 +7,7 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use assert_cmd::Command;
+use assert_cmd::{assert::Assert, Command};
 use fs_err as fs;
 use rand::{Rng, RngCore};
 
@@ -35,6 +35,12 @@ pub fn cargo_bin() -> Command {
         .unwrap_or_else(|| Command::cargo_bin("ouch").expect("Failed to find ouch executable"))
 }
 
+/// Run ouch with the provided arguments inside of `dir`, returns [`assert_cmd::assert::Assert`]
+/// to check how it went
+pub fn ouch_in(dir: &Path, args: &[&str]) -> Assert {
+    cargo_bin().args(args).current_dir(dir).assert()
+}
+
 /// Creates files in the specified directory.
 ///
 /// ## Example